@@ -3,6 +3,8 @@ use std::sync::RwLock;
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 
+use crate::module::config::data_dir::DATA_DIR;
+
 // enum ConfigError {
 //     ConfigError(String),
 // }
@@ -19,14 +21,28 @@ pub struct AppConfig {
     pub downloader_config: DownloaderConfig,
     pub parser_config: ParserConfig,
     pub scrobbler_config: ScrobblerConfig,
+    #[serde(default)]
+    pub display_config: DisplayConfig,
+    #[serde(default)]
+    pub backup_config: BackupConfig,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
 pub struct DownloaderConfig {
+    /// Client the torrents are sent to, aria2 takes `password` as its RPC secret
+    #[serde(default)]
+    pub kind: DownloaderKind,
     pub host: String,
     pub port: i64,
+    /// Connect over https, e.g. through a reverse proxy
+    #[serde(default)]
+    pub https: bool,
+    /// Path the downloader is served under by a reverse proxy, e.g. `/qbittorrent`, empty if served at the root
+    #[serde(default)]
+    pub base_path: String,
     pub username: String,
     pub password: String,
+    /// Unused, kept for older config files; the qBittorrent session is renewed when the WebUI refuses it
     pub ttl: i64,
     pub download_dir: String,
     pub category: String,
@@ -34,6 +50,54 @@ pub struct DownloaderConfig {
     pub paused_after_add: bool,
     pub sequential_download: bool,
     pub first_last_piece_prio: bool,
+    /// Folders of shows organized by other tools, scanned with `download_dir` when importing existing files
+    #[serde(default)]
+    pub library_roots: Vec<String>,
+}
+
+impl DownloaderConfig {
+    /// Scheme, host and port, e.g. `https://nas.local:8443`
+    pub fn origin(&self) -> String {
+        format!("{}://{}:{}", if self.https { "https" } else { "http" }, self.host, self.port)
+    }
+
+    /// Origin followed by the base path, without a trailing slash, e.g. `https://nas.local:8443/qbittorrent`
+    pub fn base_url(&self) -> String {
+        match self.base_path.trim_matches('/') {
+            "" => self.origin(),
+            base_path => format!("{}/{}", self.origin(), base_path),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloaderKind {
+    #[default]
+    QBittorrent,
+    Transmission,
+    Aria2,
+}
+
+impl DownloaderKind {
+    pub const ALL: [DownloaderKind; 3] = [
+        DownloaderKind::QBittorrent,
+        DownloaderKind::Transmission,
+        DownloaderKind::Aria2,
+    ];
+
+    pub fn disp_name(&self) -> &'static str {
+        match self {
+            DownloaderKind::QBittorrent => "qBittorrent",
+            DownloaderKind::Transmission => "Transmission",
+            DownloaderKind::Aria2 => "aria2",
+        }
+    }
+
+    /// Whether files inside a torrent can be renamed, aria2 only names them when they are added
+    pub fn can_rename(&self) -> bool {
+        !matches!(self, DownloaderKind::Aria2)
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -47,18 +111,77 @@ pub struct LogConfig {
 pub struct RSSConfig {
     pub list: Vec<RSSItem>,
     pub interval_seconds: i64,
+    /// Fill missing episodes with releases of other subgroups without asking, see `auto_episode_gap_detect`
+    #[serde(default)]
+    pub auto_backfill_gaps: bool,
+    /// Archive the seasons whose main-story episodes are all downloaded, see `auto_season_status_update`
+    #[serde(default)]
+    pub auto_archive_complete: bool,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct RSSItem {
     pub name: String,
     pub url: String,
     pub active: bool,
+    /// Deactivated because its seasons are no longer polled, see `retire_finished_feeds`
+    #[serde(default)]
+    pub retired: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ParserConfig {
     pub tmdb_config: TMDBConfig,
+    /// Providers resolved besides Bangumi and TMDB, for their titles and ids
+    #[serde(default)]
+    pub metadata_providers: Vec<MetadataSource>,
+    /// Write the id of this source into series folder names, e.g. `[anilistid-1234]`, for media server agents
+    #[serde(default)]
+    pub folder_id_source: Option<MetadataSource>,
+    /// Offline id cross-reference datasets, e.g. anime-offline-database or Bangumi-MAL mappings, earlier ones take precedence
+    #[serde(default)]
+    pub xref_paths: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataSource {
+    Bangumi,
+    TMDB,
+    AniList,
+    MyAnimeList,
+    AniDB,
+}
+
+impl MetadataSource {
+    pub const ALL: [MetadataSource; 5] = [
+        MetadataSource::Bangumi,
+        MetadataSource::TMDB,
+        MetadataSource::AniList,
+        MetadataSource::MyAnimeList,
+        MetadataSource::AniDB,
+    ];
+
+    /// Id tag prefix used by media servers, e.g. `tmdbid`
+    pub fn id_tag(&self) -> &'static str {
+        match self {
+            MetadataSource::Bangumi => "bangumiid",
+            MetadataSource::TMDB => "tmdbid",
+            MetadataSource::AniList => "anilistid",
+            MetadataSource::MyAnimeList => "malid",
+            MetadataSource::AniDB => "anidbid",
+        }
+    }
+
+    pub fn disp_name(&self) -> &'static str {
+        match self {
+            MetadataSource::Bangumi => "Bangumi",
+            MetadataSource::TMDB => "TMDB",
+            MetadataSource::AniList => "AniList",
+            MetadataSource::MyAnimeList => "MyAnimeList",
+            MetadataSource::AniDB => "AniDB",
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -73,6 +196,131 @@ pub struct ScrobblerConfig {
     pub bangumi_access_token: String,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum TitleLanguage {
+    #[serde(rename = "tmdb:zh-CN")]
+    TMDBZhCN,
+    #[serde(rename = "tmdb:zh-TW")]
+    TMDBZhTW,
+    // Japanese script as TMDB lists it, romaji titles come from AniList and MAL only
+    #[serde(rename = "tmdb:ja")]
+    TMDBJa,
+    #[serde(rename = "tmdb:en-US")]
+    TMDBEnUS,
+    #[serde(rename = "bangumi:name_cn")]
+    BangumiNameCn,
+    #[serde(rename = "bangumi:name")]
+    BangumiName,
+    #[serde(rename = "anilist:romaji")]
+    AniListRomaji,
+    #[serde(rename = "anilist:english")]
+    AniListEnglish,
+    #[serde(rename = "anilist:native")]
+    AniListNative,
+    #[serde(rename = "mal:title")]
+    MALTitle,
+    #[serde(rename = "mal:title_english")]
+    MALTitleEnglish,
+}
+
+impl TitleLanguage {
+    pub const ALL: [TitleLanguage; 11] = [
+        TitleLanguage::TMDBZhCN,
+        TitleLanguage::TMDBZhTW,
+        TitleLanguage::TMDBJa,
+        TitleLanguage::TMDBEnUS,
+        TitleLanguage::BangumiNameCn,
+        TitleLanguage::BangumiName,
+        TitleLanguage::AniListRomaji,
+        TitleLanguage::AniListEnglish,
+        TitleLanguage::AniListNative,
+        TitleLanguage::MALTitle,
+        TitleLanguage::MALTitleEnglish,
+    ];
+
+    /// Key of the title in the title cache, same as the serialized name.
+    pub fn key(&self) -> &'static str {
+        match self {
+            TitleLanguage::TMDBZhCN => "tmdb:zh-CN",
+            TitleLanguage::TMDBZhTW => "tmdb:zh-TW",
+            TitleLanguage::TMDBJa => "tmdb:ja",
+            TitleLanguage::TMDBEnUS => "tmdb:en-US",
+            TitleLanguage::BangumiNameCn => "bangumi:name_cn",
+            TitleLanguage::BangumiName => "bangumi:name",
+            TitleLanguage::AniListRomaji => "anilist:romaji",
+            TitleLanguage::AniListEnglish => "anilist:english",
+            TitleLanguage::AniListNative => "anilist:native",
+            TitleLanguage::MALTitle => "mal:title",
+            TitleLanguage::MALTitleEnglish => "mal:title_english",
+        }
+    }
+
+    pub fn disp_name(&self) -> &'static str {
+        match self {
+            TitleLanguage::TMDBZhCN => "TMDB 简体中文",
+            TitleLanguage::TMDBZhTW => "TMDB 繁体中文",
+            TitleLanguage::TMDBJa => "TMDB 日文",
+            TitleLanguage::TMDBEnUS => "TMDB 英文",
+            TitleLanguage::BangumiNameCn => "Bangumi 中文名",
+            TitleLanguage::BangumiName => "Bangumi 原名",
+            TitleLanguage::AniListRomaji => "AniList 罗马音",
+            TitleLanguage::AniListEnglish => "AniList 英文",
+            TitleLanguage::AniListNative => "AniList 原名",
+            TitleLanguage::MALTitle => "MAL 罗马音",
+            TitleLanguage::MALTitleEnglish => "MAL 英文",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DisplayConfig {
+    /// Preferred title languages, the first available title is displayed
+    pub title_languages: Vec<TitleLanguage>,
+    /// Show the archived seasons in the library
+    #[serde(default)]
+    pub show_archived_seasons: bool,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        DisplayConfig {
+            title_languages: vec![
+                TitleLanguage::TMDBZhCN,
+                TitleLanguage::BangumiNameCn,
+                TitleLanguage::BangumiName,
+            ],
+            show_archived_seasons: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct BackupConfig {
+    /// Back up the database periodically while the app is running
+    pub enabled: bool,
+    pub interval_hours: i64,
+    /// Number of scheduled backups kept, older ones are deleted
+    pub keep: usize,
+    /// Number of activity records kept, older ones are deleted by the backup scheduler
+    #[serde(default = "default_activity_keep")]
+    pub activity_keep: usize,
+}
+
+fn default_activity_keep() -> usize {
+    10000
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        BackupConfig {
+            enabled: true,
+            interval_hours: 24,
+            keep: 7,
+            activity_keep: default_activity_keep(),
+        }
+    }
+}
+
 impl AppConfig {
     fn default() -> Self {
         AppConfig {
@@ -80,6 +328,8 @@ impl AppConfig {
             rss_config: RSSConfig {
                 list: vec![],
                 interval_seconds: 900,
+                auto_backfill_gaps: false,
+                auto_archive_complete: false,
             },
             log_config: LogConfig {
                 log_level: "warn".to_string(),
@@ -87,8 +337,11 @@ impl AppConfig {
                 log_console: true,
             },
             downloader_config: DownloaderConfig {
+                kind: DownloaderKind::QBittorrent,
                 host: "localhost".to_string(),
                 port: 8080,
+                https: false,
+                base_path: "".to_string(),
                 username: "admin".to_string(),
                 password: "password".to_string(),
                 ttl: 1800,
@@ -98,44 +351,51 @@ impl AppConfig {
                 paused_after_add: false,
                 sequential_download: true,
                 first_last_piece_prio: true,
+                library_roots: vec![],
             },
             parser_config: ParserConfig {
                 tmdb_config: TMDBConfig {
                     api_access_token_auth: "FILL_IN_TMCB_ACCESS_TOKEN_AUTH_HERE".to_string(),
                     include_adult: false,
                 },
+                metadata_providers: vec![],
+                folder_id_source: None,
+                xref_paths: vec![],
             },
             scrobbler_config: ScrobblerConfig {
                 enabled: false,
                 bangumi_access_token: "FILL_IN_BANGUMI_ACCESS_TOKEN".to_string(),
-            }
+            },
+            display_config: DisplayConfig::default(),
+            backup_config: BackupConfig::default(),
         }
     }
 
     pub fn load() -> Self {
-        // Load config from data/config/app_config.toml
+        // Load config from {profile}/config/app_config.toml
         // If the file does not exist, make a new one with default values
         // If parse error, backup the old file and make a new one with default values
-        match std::fs::read_to_string("./data/config/app_config.toml") {
+        let config_file = DATA_DIR.config_file();
+        match std::fs::read_to_string(&config_file) {
             Err(_) => {
-                std::fs::create_dir_all("./data/config").unwrap_or_else(|err| {
+                std::fs::create_dir_all(DATA_DIR.config_dir()).unwrap_or_else(|err| {
                     panic!("Failed to create config directory: {}", err);
                 });
                 
                 let default_config = AppConfig::default();
-                std::fs::write("./data/config/app_config.toml", toml::to_string(&default_config).unwrap()).unwrap();
+                std::fs::write(&config_file, toml::to_string(&default_config).unwrap()).unwrap();
                 if default_config.first_run {
-                    panic!("Please manually configure the app_config.toml file");        // TODO: GUI first run setup
+                    panic!("Please manually configure {}", config_file.display());        // TODO: GUI first run setup
                 }
                 default_config
             }
             Ok(content) => {
                 toml::from_str(&content).unwrap_or_else(|_| {
-                    let backup_file = format!("data/config/app_config.toml.{}.broken", chrono::Local::now().format("%Y%m%d%H%M%S"));
-                    std::fs::rename("./data/config/app_config.toml", &backup_file).unwrap();
+                    let backup_file = DATA_DIR.config_dir().join(format!("app_config.toml.{}.broken", chrono::Local::now().format("%Y%m%d%H%M%S")));
+                    std::fs::rename(&config_file, &backup_file).unwrap();
                     let default_config = AppConfig::default();
-                    std::fs::write("./data/config/app_config.toml", toml::to_string(&default_config).unwrap()).unwrap();
-                    panic!("Please manually configure the app_config.toml file");        // TODO: GUI first run setup
+                    std::fs::write(&config_file, toml::to_string(&default_config).unwrap()).unwrap();
+                    panic!("Please manually configure {}", config_file.display());        // TODO: GUI first run setup
                     // default_config
                 })
             }
@@ -144,13 +404,13 @@ impl AppConfig {
 
     #[allow(dead_code)]
     pub fn save(&self) {
-        std::fs::write("data/config/app_config.toml", toml::to_string(&self).unwrap()).unwrap();
+        std::fs::write(DATA_DIR.config_file(), toml::to_string(&self).unwrap()).unwrap();
     }
 
     #[allow(dead_code)]
     pub fn reset(&self) {
         let default_config = AppConfig::default();
-        std::fs::write("data/config/app_config.toml", toml::to_string(&default_config).unwrap()).unwrap();
+        std::fs::write(DATA_DIR.config_file(), toml::to_string(&default_config).unwrap()).unwrap();
     }
 }
 
@@ -158,6 +418,26 @@ impl AppConfig {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_display_config() {
+        let display_config: DisplayConfig = toml::from_str(r#"title_languages = ["tmdb:ja", "bangumi:name"]"#).unwrap();
+        assert_eq!(display_config.title_languages, vec![TitleLanguage::TMDBJa, TitleLanguage::BangumiName]);
+        for language in TitleLanguage::ALL.iter() {
+            assert_eq!(toml::to_string(&DisplayConfig { title_languages: vec![*language], ..Default::default() }).unwrap(),
+                       format!("title_languages = [\"{}\"]\nshow_archived_seasons = false\n", language.key()));
+        }
+    }
+
+    #[test]
+    fn test_downloader_base_url() {
+        let mut config = AppConfig::default().downloader_config;
+        assert_eq!(config.base_url(), "http://localhost:8080");
+        config.https = true;
+        config.base_path = "/qbittorrent/".to_string();
+        assert_eq!(config.origin(), "https://localhost:8080");
+        assert_eq!(config.base_url(), "https://localhost:8080/qbittorrent");
+    }
+
     #[test]
     fn test_config() {
         println!("{:?}", CONFIG.read().unwrap().log_config.log_file);
@@ -165,11 +445,13 @@ mod tests {
             name: "test1".to_string(),
             url: "https://example1.com".to_string(),
             active: true,
+            retired: false,
         });
         CONFIG.write().unwrap().rss_config.list.push(RSSItem {
             name: "test2".to_string(),
             url: "https://example2.com".to_string(),
             active: true,
+            retired: false,
         });
         CONFIG.write().unwrap().save();
         CONFIG.write().unwrap().reset();
@@ -0,0 +1,251 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use lazy_static::lazy_static;
+
+use crate::module::utils::error::new_err;
+
+pub const DATA_DIR_ARG: &str = "--data-dir";
+pub const PROFILE_ARG: &str = "--profile";
+pub const DATA_DIR_ENV: &str = "BANGUMI007_DATA_DIR";
+pub const PROFILE_ENV: &str = "BANGUMI007_PROFILE";
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Data root of older builds, relative to the working directory, still used when it exists
+const LEGACY_DATA_DIR: &str = "data";
+/// Log of older builds, relative to the working directory whatever the data root
+const LEGACY_LOG_FILE: &str = "log/bangumi007.log";
+
+lazy_static! {
+    pub static ref DATA_DIR: DataDir = DataDir::resolve(
+        &std::env::args().collect::<Vec<_>>(),
+        |key| std::env::var(key).ok(),
+        Path::new(LEGACY_DATA_DIR).exists(),
+    ).unwrap_or_else(|e| panic!("Invalid data directory: {}", e));
+}
+
+/// Where the config, database and log of the running profile are stored
+///
+/// The default profile is stored in the data root itself, i.e. `{root}/config/app_config.toml`,
+/// `{root}/database/database.db` and `{root}/log/bangumi007.log`, the layout of older builds.
+/// Another profile is stored in `{root}/profiles/{profile}` with the same layout,
+/// so that e.g. a "family" and a "personal" library run on one machine.
+/// Older builds wrote the log to `./log` whatever the data root, it is moved to the default profile on start.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataDir {
+    pub root: PathBuf,
+    pub profile: String,
+}
+
+impl DataDir {
+    /// Resolve the data root and profile
+    ///
+    /// ## Input
+    ///
+    /// args : `&[String]`, command line arguments, `--data-dir <path>` and `--profile <name>` (or `--data-dir=<path>`) are read, others are ignored
+    /// env : `Fn(&str) -> Option<String>`, environment variables
+    /// legacy_exists : `bool`, whether `./data` of older builds exists
+    ///
+    /// ## Procedure
+    ///
+    /// 1. Data root: command line, then `BANGUMI007_DATA_DIR`, then `./data` if it exists, then the platform default
+    /// 2. Profile: command line, then `BANGUMI007_PROFILE`, then `default`
+    ///
+    /// ## Output
+    ///
+    /// DataDir : `DataDir`
+    pub fn resolve(args: &[String], env: impl Fn(&str) -> Option<String>, legacy_exists: bool) -> Result<DataDir, Box<dyn Error>> {
+        let env = |key: &str| env(key).filter(|x| !x.is_empty());
+        let root = match arg_value(args, DATA_DIR_ARG).or_else(|| env(DATA_DIR_ENV)) {
+            Some(root) => PathBuf::from(root),
+            None if legacy_exists => PathBuf::from(LEGACY_DATA_DIR),
+            None => default_data_root(&env),
+        };
+        let profile = arg_value(args, PROFILE_ARG)
+            .or_else(|| env(PROFILE_ENV))
+            .unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+        if !is_valid_profile_name(&profile) {
+            return Err(new_err(format!("Invalid profile name {:?}, use letters, digits, '-' and '_'", profile).as_str()));
+        }
+        Ok(DataDir { root, profile })
+    }
+
+    /// Directory of the profile, the data root for the default profile
+    pub fn profile_dir(&self) -> PathBuf {
+        if self.profile == DEFAULT_PROFILE {
+            self.root.clone()
+        } else {
+            self.root.join("profiles").join(&self.profile)
+        }
+    }
+
+    pub fn config_dir(&self) -> PathBuf {
+        self.profile_dir().join("config")
+    }
+
+    pub fn config_file(&self) -> PathBuf {
+        self.config_dir().join("app_config.toml")
+    }
+
+    pub fn database_dir(&self) -> PathBuf {
+        self.profile_dir().join("database")
+    }
+
+    pub fn database_file(&self) -> PathBuf {
+        self.database_dir().join("database.db")
+    }
+
+    pub fn database_backup_dir(&self) -> PathBuf {
+        self.database_dir().join("backup")
+    }
+
+    pub fn export_dir(&self) -> PathBuf {
+        self.profile_dir().join("export")
+    }
+
+    pub fn log_file(&self) -> PathBuf {
+        self.profile_dir().join("log").join("bangumi007.log")
+    }
+
+    /// Move the log of older builds from `./log` into the default profile, so that its history
+    /// stays next to the new log. Other profiles start a log of their own.
+    ///
+    /// ## Output
+    ///
+    /// The legacy log moved, if any : `Option<PathBuf>`
+    pub fn migrate_legacy_log(&self) -> Result<Option<PathBuf>, Box<dyn Error>> {
+        if self.profile != DEFAULT_PROFILE {
+            return Ok(None);
+        }
+        let legacy = PathBuf::from(LEGACY_LOG_FILE);
+        Ok(migrate_file(&legacy, &self.log_file())?.then_some(legacy))
+    }
+
+    /// Profiles found in the data root, the default profile first
+    pub fn list_profiles(&self) -> Vec<String> {
+        let mut profiles: Vec<String> = std::fs::read_dir(self.root.join("profiles"))
+            .map(|entries| entries
+                .filter_map(|x| x.ok())
+                .filter(|x| x.path().is_dir())
+                .filter_map(|x| x.file_name().into_string().ok())
+                .filter(|x| is_valid_profile_name(x))
+                .collect())
+            .unwrap_or_default();
+        profiles.sort();
+        profiles.insert(0, DEFAULT_PROFILE.to_string());
+        profiles
+    }
+}
+
+fn arg_value(args: &[String], name: &str) -> Option<String> {
+    let prefix = format!("{}=", name);
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == name {
+            return iter.next().cloned();
+        }
+        if let Some(value) = arg.strip_prefix(&prefix) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+/// Move a file unless the target already exists, returns whether it was moved
+fn migrate_file(legacy: &Path, target: &Path) -> Result<bool, Box<dyn Error>> {
+    if !legacy.is_file() || target.exists() {
+        return Ok(false);
+    }
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // Renaming fails across file systems, e.g. from the working directory to ~/.local/share
+    if std::fs::rename(legacy, target).is_err() {
+        std::fs::copy(legacy, target)?;
+        std::fs::remove_file(legacy)?;
+    }
+    Ok(true)
+}
+
+fn is_valid_profile_name(profile: &str) -> bool {
+    !profile.is_empty() && profile.chars().all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
+}
+
+/// `$XDG_DATA_HOME/bangumi007`, or `~/.local/share/bangumi007`
+#[cfg(target_os = "linux")]
+fn default_data_root(env: &impl Fn(&str) -> Option<String>) -> PathBuf {
+    match (env("XDG_DATA_HOME"), env("HOME")) {
+        (Some(data_home), _) => PathBuf::from(data_home).join("bangumi007"),
+        (None, Some(home)) => PathBuf::from(home).join(".local").join("share").join("bangumi007"),
+        (None, None) => PathBuf::from(LEGACY_DATA_DIR),
+    }
+}
+
+/// `./data` next to the working directory, as older builds
+#[cfg(not(target_os = "linux"))]
+fn default_data_root(_env: &impl Fn(&str) -> Option<String>) -> PathBuf {
+    PathBuf::from(LEGACY_DATA_DIR)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn resolve(args: &[&str], env: &[(&str, &str)], legacy_exists: bool) -> Result<DataDir, Box<dyn Error>> {
+        let args: Vec<String> = args.iter().map(|x| x.to_string()).collect();
+        let env: HashMap<String, String> = env.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        DataDir::resolve(&args, |key| env.get(key).cloned(), legacy_exists)
+    }
+
+    #[test]
+    fn test_resolve_data_dir() {
+        // Command line over environment
+        let data_dir = resolve(&["bangumi007", "--data-dir", "/srv/anime", "--profile=family"],
+                               &[(DATA_DIR_ENV, "/tmp/anime"), (PROFILE_ENV, "personal")], true).unwrap();
+        assert_eq!(data_dir.root, PathBuf::from("/srv/anime"));
+        assert_eq!(data_dir.database_file(), PathBuf::from("/srv/anime/profiles/family/database/database.db"));
+
+        // Environment over the legacy directory
+        let data_dir = resolve(&["bangumi007"], &[(DATA_DIR_ENV, "/tmp/anime"), (PROFILE_ENV, "personal")], true).unwrap();
+        assert_eq!(data_dir.config_file(), PathBuf::from("/tmp/anime/profiles/personal/config/app_config.toml"));
+
+        // The default profile keeps the layout of older builds
+        let data_dir = resolve(&["bangumi007"], &[(DATA_DIR_ENV, "")], true).unwrap();
+        assert_eq!(data_dir.profile, DEFAULT_PROFILE);
+        assert_eq!(data_dir.database_file(), PathBuf::from("data/database/database.db"));
+        assert_eq!(data_dir.log_file(), PathBuf::from("data/log/bangumi007.log"));
+
+        #[cfg(target_os = "linux")]
+        {
+            let data_dir = resolve(&["bangumi007"], &[("HOME", "/home/user")], false).unwrap();
+            assert_eq!(data_dir.root, PathBuf::from("/home/user/.local/share/bangumi007"));
+            let data_dir = resolve(&["bangumi007"], &[("HOME", "/home/user"), ("XDG_DATA_HOME", "/xdg")], false).unwrap();
+            assert_eq!(data_dir.root, PathBuf::from("/xdg/bangumi007"));
+        }
+
+        assert!(resolve(&["bangumi007", "--profile", "../other"], &[], true).is_err());
+    }
+
+    #[test]
+    fn test_migrate_file() {
+        let dir = std::env::temp_dir().join(format!("bangumi007-test-migrate-{}", std::process::id()));
+        let legacy = dir.join("log").join("bangumi007.log");
+        let target = dir.join("data").join("log").join("bangumi007.log");
+        std::fs::create_dir_all(legacy.parent().unwrap()).unwrap();
+        std::fs::write(&legacy, "old").unwrap();
+
+        assert!(migrate_file(&legacy, &target).unwrap());
+        assert!(!legacy.exists());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "old");
+
+        // An existing log is never overwritten
+        std::fs::write(&legacy, "older").unwrap();
+        assert!(!migrate_file(&legacy, &target).unwrap());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "old");
+        assert!(!migrate_file(&dir.join("missing.log"), &target).unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
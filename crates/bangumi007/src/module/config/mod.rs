@@ -1,4 +1,4 @@
 pub use config::*;
 
 pub mod config;
-
+pub mod data_dir;
\ No newline at end of file
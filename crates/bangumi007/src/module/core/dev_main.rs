@@ -1,13 +1,22 @@
 use crate::module::core::init::run_init;
 use crate::module::database::library::{read_all_items, read_season_items, read_seasons};
-use crate::module::downloader::qbittorrent::{download_items, rename_torrents_files};
-use crate::module::library::{auto_season_config_clean, update_library};
+use crate::module::downloader::{download_items, rename_torrents_files};
+use crate::module::database::cache::xref::refresh_anime_xref;
+use crate::module::library::{auto_season_config_clean, auto_subject_override_apply, auto_subject_title_backfill, update_library};
+use crate::module::library::episode_offset::auto_episode_offset_infer;
+use crate::module::library::removal::restore_resubscribed_seasons;
 use crate::module::parser::mikan_parser::{expand_history_episodes, update_rss};
 
 pub fn run() {
     run_init().unwrap();
 
     log::info!("Program started");
+    // Refresh the offline id cross-reference before parsing new subjects
+    if let Err(e) = refresh_anime_xref(false) {
+        log::warn!("Failed to refresh xref datasets: {}", e);
+    }
+    // Removed seasons subscribed again are added again
+    restore_resubscribed_seasons();
     // Fetch RSS feeds
     let rss_list = crate::module::config::CONFIG.read().unwrap().rss_config.list.clone();
     for rss in rss_list {
@@ -19,7 +28,10 @@ pub fn run() {
         }
     }
     // Rearrange the media library
+    auto_subject_override_apply();
+    auto_subject_title_backfill();
     auto_season_config_clean();
+    auto_episode_offset_infer();
     // Output media library
     for season in read_seasons() {
         println!("Season: {:?}", season.mikan_subject_name);
@@ -2,6 +2,9 @@ use crate::module;
 
 pub fn run_init() -> Result<(), Box<dyn std::error::Error>> {
     module::logger::init();
+    let data_dir = &module::config::data_dir::DATA_DIR;
+    log::info!("Profile {} in {}", data_dir.profile, data_dir.profile_dir().display());
     module::database::init_database()?;
+    module::database::backup::start_backup_scheduler();
     Ok(())
 }
\ No newline at end of file
@@ -10,11 +10,14 @@ use async_std::prelude::*;
 use async_std::task::spawn;
 use std::collections::HashMap;
 use lazy_static::lazy_static;
-use crate::module::database::library::find_season_by_disp;
-use crate::module::scrobbler::bangumi::{BangumiEpisodeStatus, update_bangumi_episode_status, update_bangumi_episode_status_send};
+use crate::module::database::activity::{Activity, ActivityKind, record_activity};
+use crate::module::database::item_state::mark_items_watched;
+use crate::module::database::library::AnimeSeason;
+use crate::module::library::arrangement::find_shown_episode;
+use crate::module::scrobbler::bangumi::{BangumiEpisodeStatus, update_bangumi_episode_status};
 use crate::module::utils::error::new_err;
 use crate::ui::apps::libraryapp;
-use crate::ui::apps::libraryapp::BANGUMI_STATUS_UPDATE;
+use crate::module::database::library_event::{LibraryEvent, publish_library_events};
 
 
 pub(crate) async fn http_main() {
@@ -95,35 +98,53 @@ async fn handle_connection(mut stream: TcpStream) {
     let episode = episode.unwrap();
     let status = status.unwrap();
 
-    // Find (disp_series_name, disp_season_num) in database and get bangumi_subject_id, conf_tmdb_episode_offset, conf_bangumi_episode_offset
-    let seasoninfo = find_season_by_disp(series.to_string(), season);
+    // Items shown as the episode, after merges, splits and moves,
+    // each scrobbled to the Bangumi subject and with the episode offset of its own season
+    let shown_items = find_shown_episode(&series, season, episode);
 
-    if let None = seasoninfo {
+    if shown_items.is_empty() {
         // return error
         let response = "HTTP/1.1 404 Not Found\r\n\r\n";
         stream.write(response.as_bytes()).await.unwrap();
         stream.flush().await.unwrap();
         return;
     }
-    let seasoninfo = seasoninfo.unwrap();
 
-    let bangumi_subject_id = seasoninfo.bangumi_subject_id;
-
-    // Push status to bangumi
-    let result = update_bangumi_episode_status_send(
-        bangumi_subject_id,
-        (episode - seasoninfo.conf_tmdb_episode_offset + seasoninfo.conf_bangumi_episode_offset).to_string(),
-        BangumiEpisodeStatus::Watched,
-    );
-    if !result {
+    // Push status to bangumi, once per Bangumi episode when several subgroups released it
+    let mut bangumi_episodes: Vec<((i32, String), &AnimeSeason)> = Vec::new();
+    for (item, seasoninfo) in shown_items.iter() {
+        let bangumi_episode = (seasoninfo.bangumi_subject_id, (item.mikan_parsed_episode_num + seasoninfo.conf_bangumi_episode_offset).to_string());
+        if !bangumi_episodes.iter().any(|(x, _)| *x == bangumi_episode) {
+            bangumi_episodes.push((bangumi_episode, seasoninfo));
+        }
+    }
+    let mut success = true;
+    for ((bangumi_subject_id, bangumi_episode_sort), seasoninfo) in bangumi_episodes.iter() {
+        let result = update_bangumi_episode_status(
+            *bangumi_subject_id,
+            bangumi_episode_sort.clone(),
+            BangumiEpisodeStatus::Watched,
+        );
+        record_activity(&Activity {
+            episode_num: episode,
+            ..Activity::of_season(ActivityKind::Scrobbled, seasoninfo)
+        }.change("", format!("Bangumi {} 第 {} 话 看过", bangumi_subject_id, bangumi_episode_sort)).result(&result));
+        success &= result.is_ok();
+    }
+    // Watched in the media server, whether Bangumi is updated or not
+    let mikan_item_uuids: Vec<String> = shown_items.iter().map(|(item, _)| item.mikan_item_uuid.clone()).collect();
+    if let Err(e) = mark_items_watched(&mikan_item_uuids) {
+        log::error!("Failed to mark episode {} of {} season {} watched: {}", episode, series, season, e);
+    }
+    if success {
         // return success
         let response ="HTTP/1.1 200 OK\r\n\r\n";
         stream.write(response.as_bytes()).await.unwrap();
         stream.flush().await.unwrap();
 
-        let mut handle = BANGUMI_STATUS_UPDATE.write().unwrap();
-        *handle = true;
-        drop(handle);
+        publish_library_events(bangumi_episodes.iter()
+            .map(|((bangumi_subject_id, _), _)| LibraryEvent::WatchStatusChanged { bangumi_subject_id: *bangumi_subject_id })
+            .collect());
     } else {
         // return error
         let response ="HTTP/1.1 500 Internal Server Error\r\n\r\n";
@@ -0,0 +1,219 @@
+use std::error::Error;
+
+use rusqlite::Connection;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+
+use crate::module::database::get_connection;
+use crate::module::database::library::{AnimeSeason, AnimeSeasonItem};
+use crate::module::database::repository::{ActivityRepository, SqliteRepository, with_repository};
+
+/// What the app did, stored by key
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ActivityKind {
+    #[default]
+    SeasonAdded,
+    ItemAdded,
+    SeasonConfChanged,
+    TorrentAdded,
+    TorrentMoved,
+    FileRenamed,
+    Scrobbled,
+    LibraryArranged,
+    SeasonRemoved,
+    GapBackfilled,
+    LibraryImported,
+    SeasonStatusChanged,
+}
+
+impl ActivityKind {
+    pub const ALL: [ActivityKind; 12] = [
+        ActivityKind::SeasonAdded,
+        ActivityKind::ItemAdded,
+        ActivityKind::SeasonConfChanged,
+        ActivityKind::TorrentAdded,
+        ActivityKind::TorrentMoved,
+        ActivityKind::FileRenamed,
+        ActivityKind::Scrobbled,
+        ActivityKind::LibraryArranged,
+        ActivityKind::SeasonRemoved,
+        ActivityKind::GapBackfilled,
+        ActivityKind::LibraryImported,
+        ActivityKind::SeasonStatusChanged,
+    ];
+
+    pub fn key(&self) -> &'static str {
+        match self {
+            ActivityKind::SeasonAdded => "season_added",
+            ActivityKind::ItemAdded => "item_added",
+            ActivityKind::SeasonConfChanged => "season_conf_changed",
+            ActivityKind::TorrentAdded => "torrent_added",
+            ActivityKind::TorrentMoved => "torrent_moved",
+            ActivityKind::FileRenamed => "file_renamed",
+            ActivityKind::Scrobbled => "scrobbled",
+            ActivityKind::LibraryArranged => "library_arranged",
+            ActivityKind::SeasonRemoved => "season_removed",
+            ActivityKind::GapBackfilled => "gap_backfilled",
+            ActivityKind::LibraryImported => "library_imported",
+            ActivityKind::SeasonStatusChanged => "season_status_changed",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<ActivityKind> {
+        ActivityKind::ALL.iter().find(|x| x.key() == key).copied()
+    }
+
+    pub fn disp_name(&self) -> &'static str {
+        match self {
+            ActivityKind::SeasonAdded => "新增季度",
+            ActivityKind::ItemAdded => "新增剧集",
+            ActivityKind::SeasonConfChanged => "修改季度设置",
+            ActivityKind::TorrentAdded => "添加种子",
+            ActivityKind::TorrentMoved => "移动种子",
+            ActivityKind::FileRenamed => "重命名文件",
+            ActivityKind::Scrobbled => "同步观看进度",
+            ActivityKind::LibraryArranged => "调整季度与剧集",
+            ActivityKind::SeasonRemoved => "移除季度",
+            ActivityKind::GapBackfilled => "补全缺集",
+            ActivityKind::LibraryImported => "导入本地文件",
+            ActivityKind::SeasonStatusChanged => "季度状态变化",
+        }
+    }
+}
+
+impl ToSql for ActivityKind {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.key()))
+    }
+}
+
+impl FromSql for ActivityKind {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let key = value.as_str()?;
+        ActivityKind::from_key(key).ok_or_else(|| FromSqlError::Other(format!("Unknown activity kind {}", key).into()))
+    }
+}
+
+/// A record of the activity history
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Activity {
+    pub id: i64,
+    pub timestamp: String,          // rfc3339, local time
+    pub kind: ActivityKind,
+    pub mikan_subject_id: i32,      // -1 if not about a season
+    pub mikan_subgroup_id: i32,     // -1 if not about a season
+    pub episode_num: i32,           // display episode number, -1 if not about an episode
+    pub title: String,              // e.g. the item title or the season name
+    pub before_value: String,
+    pub after_value: String,
+    pub success: bool,
+    pub error: String,
+}
+
+impl Activity {
+    pub fn of_season(kind: ActivityKind, season: &AnimeSeason) -> Self {
+        Activity {
+            timestamp: chrono::Local::now().to_rfc3339(),
+            kind,
+            mikan_subject_id: season.mikan_subject_id,
+            mikan_subgroup_id: season.mikan_subgroup_id,
+            episode_num: -1,
+            title: format!("{} 第 {} 季", season.disp_series_name, season.disp_season_num),
+            success: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn of_item(kind: ActivityKind, item: &AnimeSeasonItem) -> Self {
+        Activity {
+            timestamp: chrono::Local::now().to_rfc3339(),
+            kind,
+            mikan_subject_id: item.mikan_subject_id,
+            mikan_subgroup_id: item.mikan_subgroup_id,
+            episode_num: item.disp_episode_num,
+            title: item.mikan_item_title.clone(),
+            success: true,
+            ..Default::default()
+        }
+    }
+
+    /// Set the value before and after the change
+    pub fn change(self, before_value: impl ToString, after_value: impl ToString) -> Self {
+        Activity {
+            before_value: before_value.to_string(),
+            after_value: after_value.to_string(),
+            ..self
+        }
+    }
+
+    /// Set the outcome of the action
+    pub fn result<T>(self, result: &Result<T, Box<dyn Error>>) -> Self {
+        match result {
+            Ok(_) => Activity { success: true, ..self },
+            Err(e) => Activity { success: false, error: e.to_string(), ..self },
+        }
+    }
+}
+
+/// Filter of the timeline, the newest activities first
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActivityFilter {
+    pub kind: Option<ActivityKind>,
+    pub season: Option<(i32, i32)>,     // (mikan_subject_id, mikan_subgroup_id)
+    pub failed_only: bool,
+    pub keyword: String,                // matched against the title
+    pub limit: usize,
+}
+
+impl Default for ActivityFilter {
+    fn default() -> Self {
+        ActivityFilter {
+            kind: None,
+            season: None,
+            failed_only: false,
+            keyword: String::new(),
+            limit: 500,
+        }
+    }
+}
+
+#[deny(dead_code)]
+pub fn init_activity_table(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    conn.execute_batch(
+        "create table if not exists activity (
+            id integer primary key autoincrement,
+            timestamp text,
+            kind text,
+            mikan_subject_id integer default -1,
+            mikan_subgroup_id integer default -1,
+            episode_num integer default -1,
+            title text,
+            before_value text,
+            after_value text,
+            success integer,
+            error text
+        );
+        create index if not exists activity_season on activity (mikan_subject_id, mikan_subgroup_id);"
+    )?;
+    Ok(())
+}
+
+/// Record an activity outside of a transaction, a failure is logged and never fails the action itself.
+/// In a transaction use `ActivityRepository::insert_activity`, so that the record is rolled back with the action.
+pub fn record_activity(activity: &Activity) {
+    let result = get_connection().and_then(|conn| SqliteRepository::new(&conn).insert_activity(activity));
+    if let Err(e) = result {
+        log::error!("Failed to record activity {:?}: {}", activity.kind, e);
+    }
+}
+
+pub fn read_activities(filter: &ActivityFilter) -> Vec<Activity> {
+    with_repository("read activities", |repo| repo.list_activities(filter))
+}
+
+/// Delete the oldest activities beyond `keep`, so that the history does not grow without limit
+pub fn prune_activity_history(keep: usize) {
+    let deleted = with_repository("prune activities", |repo| repo.prune_activities(keep));
+    if deleted > 0 {
+        log::info!("Pruned {} old activities", deleted);
+    }
+}
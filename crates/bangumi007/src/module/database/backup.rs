@@ -0,0 +1,144 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use rusqlite::backup::Backup;
+use rusqlite::Connection;
+
+use crate::module::config::{BackupConfig, CONFIG};
+use crate::module::config::data_dir::DATA_DIR;
+use crate::module::database::activity::prune_activity_history;
+use crate::module::database::get_connection;
+
+/// Scheduled backups are named `scheduled.{timestamp}.db`, backups made before migrations are left alone
+const SCHEDULED_BACKUP_PREFIX: &str = "scheduled.";
+const SCHEDULED_BACKUP_SUFFIX: &str = ".db";
+
+const BACKUP_CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Copy a database with SQLite's online backup API, pages are copied in steps,
+/// so that the copy is consistent while other connections keep writing.
+/// The copy is written to a temporary file first, an interrupted backup never looks complete.
+pub fn backup_database_to(conn: &Connection, path: &Path) -> Result<(), Box<dyn Error>> {
+    let temp_path = path.with_extension("db.tmp");
+    {
+        let mut dst = Connection::open(&temp_path)?;
+        let backup = Backup::new(conn, &mut dst)?;
+        backup.run_to_completion(256, Duration::from_millis(50), None)?;
+    }
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Back up the database of the current profile now, and delete the oldest scheduled backups beyond `keep`
+pub fn create_scheduled_backup(keep: usize) -> Result<PathBuf, Box<dyn Error>> {
+    let backup_dir = DATA_DIR.database_backup_dir();
+    fs::create_dir_all(&backup_dir)?;
+    let path = backup_dir.join(format!("{}{}{}",
+                                       SCHEDULED_BACKUP_PREFIX, chrono::Local::now().format("%Y%m%d-%H%M%S"), SCHEDULED_BACKUP_SUFFIX));
+    let conn = get_connection()?;
+    backup_database_to(&conn, &path)?;
+    log::info!("Backed up database to {}", path.display());
+    rotate_backups(&backup_dir, keep)?;
+    Ok(path)
+}
+
+/// Scheduled backups in a directory, oldest first
+fn list_scheduled_backups(backup_dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    if !backup_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut backups: Vec<PathBuf> = fs::read_dir(backup_dir)?
+        .filter_map(|x| x.ok())
+        .map(|x| x.path())
+        .filter(|x| x.file_name()
+            .and_then(|x| x.to_str())
+            .map_or(false, |x| x.starts_with(SCHEDULED_BACKUP_PREFIX) && x.ends_with(SCHEDULED_BACKUP_SUFFIX)))
+        .collect();
+    // The timestamp in the name sorts chronologically
+    backups.sort();
+    Ok(backups)
+}
+
+/// Delete the oldest scheduled backups, keeping the newest `keep` ones
+///
+/// ## Output
+///
+/// Number of deleted backups : `usize`
+pub fn rotate_backups(backup_dir: &Path, keep: usize) -> Result<usize, Box<dyn Error>> {
+    let backups = list_scheduled_backups(backup_dir)?;
+    let outdated = backups.len().saturating_sub(keep);
+    for path in backups.iter().take(outdated) {
+        log::info!("Deleting old backup {}", path.display());
+        fs::remove_file(path)?;
+    }
+    Ok(outdated)
+}
+
+/// Whether the newest scheduled backup is older than the interval
+fn is_backup_due(backup_dir: &Path, config: &BackupConfig) -> Result<bool, Box<dyn Error>> {
+    let newest = match list_scheduled_backups(backup_dir)?.pop() {
+        Some(newest) => newest,
+        None => return Ok(true),
+    };
+    let age = SystemTime::now().duration_since(fs::metadata(newest)?.modified()?).unwrap_or_default();
+    Ok(age >= Duration::from_secs(config.interval_hours.max(1) as u64 * 3600))
+}
+
+/// Back up the database and prune the activity history in the background while the app is running, following the backup config
+pub fn start_backup_scheduler() {
+    thread::spawn(|| {
+        loop {
+            let config = CONFIG.read().unwrap().backup_config.clone();
+            if config.enabled {
+                match is_backup_due(&DATA_DIR.database_backup_dir(), &config) {
+                    Ok(true) => {
+                        if let Err(e) = create_scheduled_backup(config.keep) {
+                            log::error!("Failed to back up database: {}", e);
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(e) => log::error!("Failed to check database backups: {}", e),
+                }
+            }
+            prune_activity_history(config.activity_keep);
+            thread::sleep(BACKUP_CHECK_INTERVAL);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backup_and_rotate() {
+        let dir = std::env::temp_dir().join(format!("bangumi007-test-backup-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("create table t (x integer); insert into t values (42);").unwrap();
+        for ts in ["20240101-000000", "20240102-000000", "20240103-000000"] {
+            backup_database_to(&conn, &dir.join(format!("scheduled.{}.db", ts))).unwrap();
+        }
+        fs::write(dir.join("database.v2.20240101-000000.db"), "").unwrap();
+
+        let copy = Connection::open(dir.join("scheduled.20240103-000000.db")).unwrap();
+        let x: i32 = copy.query_row("select x from t", [], |row| row.get(0)).unwrap();
+        assert_eq!(x, 42);
+        drop(copy);
+
+        let config = BackupConfig { enabled: true, interval_hours: 24, keep: 2, ..Default::default() };
+        assert!(!is_backup_due(&dir, &config).unwrap());
+        assert_eq!(rotate_backups(&dir, 2).unwrap(), 1);
+        let names: Vec<String> = list_scheduled_backups(&dir).unwrap().iter()
+            .map(|x| x.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(names, vec!["scheduled.20240102-000000.db", "scheduled.20240103-000000.db"]);
+        assert!(dir.join("database.v2.20240101-000000.db").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,57 +1,120 @@
-use std::sync::RwLock;
 use std::fs;
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::Duration;
 
 use lazy_static::lazy_static;
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::Connection;
 
-use crate::module::database::cache::rss::{init_cache_bangumi_episode_table, init_cache_mikan_item_table, init_cache_mikan_subject_table};
-use crate::module::database::library::{init_cache_library_anime_season_item_table, init_cache_library_anime_season_table};
+use crate::module::config::data_dir::DATA_DIR;
+use crate::module::database::migration::{get_user_version, migrate_database, schema_version};
+use crate::module::utils::error::new_err;
 
-const DATABASE_PATH: &str = "data/database/database.db";
+const POOL_MAX_SIZE: u32 = 8;
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
 
-#[derive(Debug)]
-pub struct InitedDb {
-    inited: bool,
-}
+pub type DbConnection = PooledConnection<SqliteConnectionManager>;
 
-impl InitedDb {
-    fn new() -> InitedDb {
-        InitedDb {
-            inited: false,
-        }
-    }
-
-    pub fn set_inited(&mut self) {
-        self.inited = true;
-    }
+lazy_static! {
+    /// Shared by the UI thread, the update threads and the scrobbler server, `None` before `init_database`
+    static ref POOL: RwLock<Option<Pool<SqliteConnectionManager>>> = RwLock::new(None);
 }
 
-lazy_static! {
-    pub static ref INITED_DB: RwLock<InitedDb> = RwLock::new(InitedDb::new());
+/// Connection pool of a database file, every connection waits on locks instead of failing with `SQLITE_BUSY`,
+/// and the database is in WAL mode so that readers do not block the writer.
+fn new_pool(path: &Path) -> Result<Pool<SqliteConnectionManager>, Box<dyn std::error::Error>> {
+    let manager = SqliteConnectionManager::file(path).with_init(|conn| {
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.set_prepared_statement_cache_capacity(64);
+        conn.pragma_update_and_check(None, "journal_mode", "wal", |row| row.get::<_, String>(0))?;
+        conn.pragma_update(None, "synchronous", "normal")?;
+        Ok(())
+    });
+    let pool = Pool::builder()
+        .max_size(POOL_MAX_SIZE)
+        .connection_timeout(BUSY_TIMEOUT)
+        .build(manager)?;
+    Ok(pool)
 }
 
 #[deny(dead_code)]
 pub fn init_database() -> Result<(), Box<dyn std::error::Error>> {
-    let database_dir = std::path::Path::new("data/database");
+    let database_dir = DATA_DIR.database_dir();
     if !database_dir.exists() {
-        fs::create_dir_all(database_dir)?;
+        fs::create_dir_all(&database_dir)?;
     }
 
-    let conn = Connection::open(DATABASE_PATH)?;
-    init_cache_mikan_item_table(&conn)?;
-    init_cache_mikan_subject_table(&conn)?;
-    init_cache_library_anime_season_table(&conn)?;
-    init_cache_library_anime_season_item_table(&conn)?;
-    init_cache_bangumi_episode_table(&conn)?;
-    INITED_DB.write().unwrap().set_inited();
+    let mut pool = POOL.write().unwrap();
+    if pool.is_some() {
+        return Ok(());
+    }
+    let new_pool = new_pool(&DATA_DIR.database_file())?;
+    let mut conn = new_pool.get()?;
+    let current_version = get_user_version(&conn)?;
+    if current_version < schema_version() && has_tables(&conn)? {
+        backup_database(&conn, current_version)?;
+    }
+    migrate_database(&mut conn)?;
+    *pool = Some(new_pool);
     Ok(())
 }
 
-pub fn get_connection() -> Result<Connection, Box<dyn std::error::Error>> {
-    if !INITED_DB.read().unwrap().inited {
+/// Get a connection from the shared pool, initializing the database on first use.
+/// The connection returns to the pool when dropped.
+pub fn get_connection() -> Result<DbConnection, Box<dyn std::error::Error>> {
+    if POOL.read().unwrap().is_none() {
         init_database()?;
-        // panic!("Database not inited");
     }
-    let conn = Connection::open(DATABASE_PATH)?;
+    let pool = POOL.read().unwrap();
+    let conn = pool.as_ref().ok_or_else(|| new_err("Database not inited"))?.get()?;
     Ok(conn)
-}
\ No newline at end of file
+}
+
+/// Placeholders of a batched `in (...)` query, e.g. `?1, ?2, ?3`
+pub fn sql_placeholders(count: usize) -> String {
+    (1..=count).map(|x| format!("?{}", x)).collect::<Vec<_>>().join(", ")
+}
+
+/// Number of values bound in one batched query, well below `SQLITE_MAX_VARIABLE_NUMBER`
+pub const SQL_BATCH_SIZE: usize = 500;
+
+fn has_tables(conn: &Connection) -> Result<bool, Box<dyn std::error::Error>> {
+    let count: i64 = conn.query_row("select count(*) from sqlite_master where type = 'table'", [], |row| row.get(0))?;
+    Ok(count > 0)
+}
+
+/// Copy the database before upgrading it, e.g. `{profile}/database/backup/database.v2.20240101-120000.db`
+fn backup_database(conn: &Connection, version: i32) -> Result<(), Box<dyn std::error::Error>> {
+    let backup_dir = DATA_DIR.database_backup_dir();
+    if !backup_dir.exists() {
+        fs::create_dir_all(&backup_dir)?;
+    }
+    let backup_path = backup_dir.join(format!("database.v{}.{}.db", version, chrono::Local::now().format("%Y%m%d-%H%M%S")));
+    log::info!("Backing up database to {}", backup_path.display());
+    conn.execute("vacuum into ?1", [backup_path.to_string_lossy()])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_pool() {
+        let path = std::env::temp_dir().join(format!("bangumi007-test-pool-{}.db", std::process::id()));
+        let pool = new_pool(&path).unwrap();
+        let conn = pool.get().unwrap();
+        let journal_mode: String = conn.pragma_query_value(None, "journal_mode", |row| row.get(0)).unwrap();
+        assert_eq!(journal_mode, "wal");
+        let busy_timeout: i64 = conn.pragma_query_value(None, "busy_timeout", |row| row.get(0)).unwrap();
+        assert_eq!(busy_timeout, BUSY_TIMEOUT.as_millis() as i64);
+        assert_eq!(sql_placeholders(3), "?1, ?2, ?3");
+        drop(conn);
+        drop(pool);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
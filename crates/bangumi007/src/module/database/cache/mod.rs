@@ -1,2 +1,4 @@
 pub mod rss;
-
+pub mod tmdb;
+pub mod title;
+pub mod xref;
\ No newline at end of file
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use rusqlite::Connection;
+
+use crate::module::config::TitleLanguage;
+use crate::module::database::get_connection;
+
+// Titles of a Mikan subject in every language, keyed by `TitleLanguage::key`
+pub const TITLE_KIND_SERIES: &str = "series";
+pub const TITLE_KIND_SEASON: &str = "season";
+// Aliases of the Bangumi subject, keyed by "bangumi:alias:{i}", only searched and never displayed
+pub const TITLE_KIND_ALIAS: &str = "alias";
+// Name of the series folder a season was imported from, keyed by "local:folder", see `find_series_by_name`
+pub const TITLE_KIND_FOLDER: &str = "folder";
+// Empty title keyed by "local:failed" once the titles of a subject failed to be backfilled, see `auto_subject_title_backfill`
+pub const TITLE_KIND_BACKFILL_FAILED: &str = "backfill_failed";
+
+#[deny(dead_code)]
+pub fn init_cache_mikan_subject_title_table(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    conn.execute(
+        "create table if not exists cache_mikan_subject_title (
+            mikan_subject_id integer,
+            title_kind text,
+            language text,
+            title text,
+            primary key(mikan_subject_id,title_kind,language) on conflict replace
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Replace the stored titles of a kind, empty titles are skipped.
+pub fn save_subject_titles(mikan_subject_id: i32, title_kind: &str, titles: &HashMap<String, String>) -> Result<(), Box<dyn Error>> {
+    let conn = get_connection()?;
+    conn.execute(
+        "delete from cache_mikan_subject_title where mikan_subject_id = ?1 and title_kind = ?2",
+        rusqlite::params![mikan_subject_id, title_kind],
+    )?;
+    let mut stmt = conn.prepare_cached(
+        "insert or replace into cache_mikan_subject_title (mikan_subject_id, title_kind, language, title) values (?1, ?2, ?3, ?4)"
+    )?;
+    for (language, title) in titles.iter().filter(|(_, title)| !title.is_empty()) {
+        stmt.execute(rusqlite::params![mikan_subject_id, title_kind, language, title])?;
+    }
+    Ok(())
+}
+
+/// Mark a subject whose titles failed to be backfilled, it is not tried again until its metadata is refreshed
+pub fn mark_title_backfill_failed(mikan_subject_id: i32) -> Result<(), Box<dyn Error>> {
+    let conn = get_connection()?;
+    conn.execute(
+        "insert or replace into cache_mikan_subject_title (mikan_subject_id, title_kind, language, title) values (?1, ?2, 'local:failed', '')",
+        rusqlite::params![mikan_subject_id, TITLE_KIND_BACKFILL_FAILED],
+    )?;
+    Ok(())
+}
+
+pub fn is_title_backfill_failed(mikan_subject_id: i32) -> bool {
+    let conn = match get_connection() {
+        Ok(conn) => conn,
+        Err(_) => return false,
+    };
+    conn.prepare_cached("select 1 from cache_mikan_subject_title where mikan_subject_id = ?1 and title_kind = ?2")
+        .and_then(|mut stmt| stmt.exists(rusqlite::params![mikan_subject_id, TITLE_KIND_BACKFILL_FAILED]))
+        .unwrap_or(false)
+}
+
+/// Let the titles of a subject be backfilled again
+pub fn clear_title_backfill_failed(mikan_subject_id: i32) -> Result<(), Box<dyn Error>> {
+    save_subject_titles(mikan_subject_id, TITLE_KIND_BACKFILL_FAILED, &HashMap::new())
+}
+
+pub fn read_subject_titles(mikan_subject_id: i32, title_kind: &str) -> HashMap<String, String> {
+    let conn = match get_connection() {
+        Ok(conn) => conn,
+        Err(_) => return HashMap::new(),
+    };
+    let mut stmt = conn.prepare_cached("select language, title from cache_mikan_subject_title where mikan_subject_id = ?1 and title_kind = ?2").unwrap();
+    let title_iter = stmt.query_map(rusqlite::params![mikan_subject_id, title_kind], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    }).unwrap();
+
+    title_iter.filter_map(|x| x.ok()).collect()
+}
+
+/// The first available title in the order of the preferred languages.
+pub fn select_title(languages: &[TitleLanguage], titles: &HashMap<String, String>) -> Option<String> {
+    languages.iter()
+        .filter_map(|language| titles.get(language.key()))
+        .find(|title| !title.is_empty())
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_title() {
+        let titles: HashMap<String, String> = [
+            ("tmdb:zh-CN", "间谍过家家"),
+            ("tmdb:ja", "スパイファミリー"),
+            ("tmdb:zh-TW", ""),
+            ("bangumi:name", "SPY×FAMILY"),
+        ].iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+
+        assert_eq!(select_title(&[TitleLanguage::TMDBZhCN, TitleLanguage::TMDBJa], &titles), Some("间谍过家家".to_string()));
+        assert_eq!(select_title(&[TitleLanguage::TMDBZhTW, TitleLanguage::TMDBJa], &titles), Some("スパイファミリー".to_string()));
+        assert_eq!(select_title(&[TitleLanguage::TMDBEnUS, TitleLanguage::BangumiNameCn], &titles), None);
+        assert_eq!(select_title(&[], &titles), None);
+    }
+}
@@ -0,0 +1,118 @@
+use std::error::Error;
+
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::module::database::get_connection;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TMDBCandidate {
+    pub bangumi_subject_id: i32,
+    pub tmdb_series_id: i64,
+    pub tmdb_series_name: String,
+    pub tmdb_original_name: String,
+    pub tmdb_first_air_date: String,
+    pub score: f64,
+}
+
+impl TMDBCandidate {
+    pub fn disp_name(&self) -> String {
+        let year = self.tmdb_first_air_date.split('-').next().unwrap_or("");
+        if year.is_empty() {
+            self.tmdb_series_name.clone()
+        } else {
+            format!("{} ({})", self.tmdb_series_name, year)
+        }
+    }
+}
+
+#[deny(dead_code)]
+pub fn init_cache_tmdb_candidate_table(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    conn.execute(
+        "create table if not exists cache_tmdb_candidate (
+            bangumi_subject_id integer,
+            tmdb_series_id integer,
+            tmdb_series_name text,
+            tmdb_original_name text,
+            tmdb_first_air_date text,
+            score real,
+            primary key(bangumi_subject_id,tmdb_series_id) on conflict replace
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Replace the stored candidates of a Bangumi subject, only low-confidence matches are stored.
+pub fn save_tmdb_candidates(bangumi_subject_id: i32, candidates: &[TMDBCandidate]) -> Result<(), Box<dyn Error>> {
+    let conn = get_connection()?;
+    conn.execute("delete from cache_tmdb_candidate where bangumi_subject_id = ?1", [bangumi_subject_id])?;
+    let mut stmt = conn.prepare_cached(
+        "insert or replace into cache_tmdb_candidate (
+            bangumi_subject_id,
+            tmdb_series_id,
+            tmdb_series_name,
+            tmdb_original_name,
+            tmdb_first_air_date,
+            score
+        ) values (?1, ?2, ?3, ?4, ?5, ?6)"
+    )?;
+    for candidate in candidates {
+        stmt.execute(rusqlite::params![
+            candidate.bangumi_subject_id,
+            candidate.tmdb_series_id,
+            candidate.tmdb_series_name,
+            candidate.tmdb_original_name,
+            candidate.tmdb_first_air_date,
+            candidate.score,
+        ])?;
+    }
+    Ok(())
+}
+
+pub fn read_tmdb_candidates(bangumi_subject_id: i32) -> Vec<TMDBCandidate> {
+    let conn = match get_connection() {
+        Ok(conn) => conn,
+        Err(_) => return Vec::new(),
+    };
+    let mut stmt = conn.prepare_cached("select * from cache_tmdb_candidate where bangumi_subject_id = ?1 order by score desc, tmdb_series_id").unwrap();
+    let candidate_iter = stmt.query_map([bangumi_subject_id], |row| {
+        Ok(TMDBCandidate {
+            bangumi_subject_id: row.get(0)?,
+            tmdb_series_id: row.get(1)?,
+            tmdb_series_name: row.get(2)?,
+            tmdb_original_name: row.get(3)?,
+            tmdb_first_air_date: row.get(4)?,
+            score: row.get(5)?,
+        })
+    }).unwrap();
+
+    candidate_iter.filter_map(|x| x.ok()).collect()
+}
+
+#[deny(dead_code)]
+pub fn init_conf_tmdb_series_choice_table(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    conn.execute(
+        "create table if not exists conf_tmdb_series_choice (
+            bangumi_subject_id integer primary key,
+            tmdb_series_id integer
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Persist the TMDB series picked by the user for a Bangumi subject.
+pub fn set_tmdb_series_choice(bangumi_subject_id: i32, tmdb_series_id: i64) -> Result<(), Box<dyn Error>> {
+    let conn = get_connection()?;
+    conn.execute(
+        "insert or replace into conf_tmdb_series_choice (bangumi_subject_id, tmdb_series_id) values (?1, ?2)",
+        rusqlite::params![bangumi_subject_id, tmdb_series_id],
+    )?;
+    Ok(())
+}
+
+pub fn get_tmdb_series_choice(bangumi_subject_id: i32) -> Option<i64> {
+    let conn = get_connection().ok()?;
+    let mut stmt = conn.prepare_cached("select tmdb_series_id from conf_tmdb_series_choice where bangumi_subject_id = ?1").ok()?;
+    stmt.query_row([bangumi_subject_id], |row| row.get(0)).optional().ok().flatten()
+}
@@ -0,0 +1,378 @@
+use std::error::Error;
+use std::fs;
+use std::time::UNIX_EPOCH;
+
+use rusqlite::Connection;
+
+use crate::module::config::{CONFIG, MetadataSource};
+use crate::module::database::get_connection;
+use crate::module::utils::error::new_err;
+
+/// Ids of one anime across the metadata sources, -1 if unknown.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnimeXref {
+    pub bangumi_id: i32,
+    pub tmdb_id: i32,
+    pub tmdb_season: i32,
+    pub anilist_id: i32,
+    pub mal_id: i32,
+    pub anidb_id: i32,
+}
+
+impl Default for AnimeXref {
+    fn default() -> Self {
+        AnimeXref {
+            bangumi_id: -1,
+            tmdb_id: -1,
+            tmdb_season: -1,
+            anilist_id: -1,
+            mal_id: -1,
+            anidb_id: -1,
+        }
+    }
+}
+
+impl AnimeXref {
+    pub fn get(&self, source: MetadataSource) -> i32 {
+        match source {
+            MetadataSource::Bangumi => self.bangumi_id,
+            MetadataSource::TMDB => self.tmdb_id,
+            MetadataSource::AniList => self.anilist_id,
+            MetadataSource::MyAnimeList => self.mal_id,
+            MetadataSource::AniDB => self.anidb_id,
+        }
+    }
+
+    pub fn set(&mut self, source: MetadataSource, id: i32) {
+        match source {
+            MetadataSource::Bangumi => self.bangumi_id = id,
+            MetadataSource::TMDB => self.tmdb_id = id,
+            MetadataSource::AniList => self.anilist_id = id,
+            MetadataSource::MyAnimeList => self.mal_id = id,
+            MetadataSource::AniDB => self.anidb_id = id,
+        }
+    }
+
+    /// Number of sources with a known id, an entry is only useful with two or more.
+    pub fn known_count(&self) -> usize {
+        MetadataSource::ALL.iter().filter(|x| self.get(**x) > 0).count()
+    }
+
+    /// Fill the unknown ids from another entry, returns whether anything was filled.
+    /// The TMDB season is only taken along with the same TMDB series.
+    pub fn merge(&mut self, other: &AnimeXref) -> bool {
+        let mut changed = false;
+        for source in MetadataSource::ALL {
+            if self.get(source) <= 0 && other.get(source) > 0 {
+                self.set(source, other.get(source));
+                changed = true;
+            }
+        }
+        if self.tmdb_season < 0 && other.tmdb_season >= 0 && self.tmdb_id == other.tmdb_id {
+            self.tmdb_season = other.tmdb_season;
+            changed = true;
+        }
+        changed
+    }
+}
+
+fn xref_column(source: MetadataSource) -> &'static str {
+    match source {
+        MetadataSource::Bangumi => "bangumi_id",
+        MetadataSource::TMDB => "tmdb_id",
+        MetadataSource::AniList => "anilist_id",
+        MetadataSource::MyAnimeList => "mal_id",
+        MetadataSource::AniDB => "anidb_id",
+    }
+}
+
+#[deny(dead_code)]
+pub fn init_cache_anime_xref_table(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    conn.execute(
+        "create table if not exists cache_anime_xref (
+            dataset text,
+            priority integer,
+            bangumi_id integer,
+            tmdb_id integer,
+            tmdb_season integer,
+            anilist_id integer,
+            mal_id integer,
+            anidb_id integer
+        )",
+        [],
+    )?;
+    for source in MetadataSource::ALL {
+        let column = xref_column(source);
+        conn.execute(
+            &format!("create index if not exists idx_cache_anime_xref_{} on cache_anime_xref({})", column, column),
+            [],
+        )?;
+    }
+    conn.execute(
+        "create table if not exists cache_anime_xref_dataset (
+            dataset text primary key,
+            priority integer,
+            modified integer,
+            entry_count integer,
+            imported_at text
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Parse the id of a source from its page url, e.g. `https://anidb.net/anime/4563`
+fn parse_source_url(url: &str) -> Option<(MetadataSource, i32)> {
+    let url = url.trim_end_matches('/');
+    let url = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://")).unwrap_or(url);
+    let url = url.strip_prefix("www.").unwrap_or(url);
+    let (prefix, id) = url.rsplit_once('/')?;
+    let source = match prefix {
+        "bgm.tv/subject" | "bangumi.tv/subject" | "chii.in/subject" => MetadataSource::Bangumi,
+        "themoviedb.org/tv" => MetadataSource::TMDB,
+        "anilist.co/anime" => MetadataSource::AniList,
+        "myanimelist.net/anime" => MetadataSource::MyAnimeList,
+        "anidb.net/anime" => MetadataSource::AniDB,
+        _ => return None,
+    };
+    Some((source, id.parse().ok()?))
+}
+
+/// Parse one entry of a dataset, both the anime-offline-database `sources` urls
+/// and the id fields of mapping files (e.g. `"bgm_id": 400602, "mal_id": 52991`) are read.
+fn parse_anime_xref_entry(entry: &serde_json::Value) -> AnimeXref {
+    let mut xref = AnimeXref::default();
+    if let Some(sources) = entry.get("sources").and_then(|x| x.as_array()) {
+        for (source, id) in sources.iter().filter_map(|x| x.as_str()).filter_map(parse_source_url) {
+            if xref.get(source) <= 0 {
+                xref.set(source, id);
+            }
+        }
+    }
+
+    // Ids are numbers or numeric strings
+    let get_id = |keys: &[&str]| keys.iter()
+        .filter_map(|key| entry.get(*key))
+        .find_map(|x| x.as_i64().or_else(|| x.as_str().and_then(|x| x.parse().ok())))
+        .map(|x| x as i32);
+    let keys: [(MetadataSource, &[&str]); 5] = [
+        (MetadataSource::Bangumi, &["bangumi_id", "bgm_id", "bangumi"]),
+        (MetadataSource::TMDB, &["themoviedb_id", "tmdb_id", "tmdb"]),
+        (MetadataSource::AniList, &["anilist_id", "anilist"]),
+        (MetadataSource::MyAnimeList, &["mal_id", "myanimelist_id", "mal"]),
+        (MetadataSource::AniDB, &["anidb_id", "anidb"]),
+    ];
+    for (source, keys) in keys {
+        if let Some(id) = get_id(keys) {
+            if xref.get(source) <= 0 && id > 0 {
+                xref.set(source, id);
+            }
+        }
+    }
+    // TMDB tv ids only, movie ids share another id space
+    let is_movie = entry.get("type").and_then(|x| x.as_str()).map_or(false, |x| x.eq_ignore_ascii_case("movie"));
+    if is_movie {
+        xref.tmdb_id = -1;
+    } else if xref.tmdb_id > 0 {
+        xref.tmdb_season = get_id(&["tmdb_season", "themoviedb_season"]).unwrap_or(-1);
+    }
+    xref
+}
+
+/// Parse a dataset, either an anime-offline-database file (`{"data": [...]}`) or a mapping file (`[...]`).
+/// Entries knowing less than two ids are skipped.
+pub fn parse_anime_xref_json(json: &serde_json::Value) -> Result<Vec<AnimeXref>, Box<dyn Error>> {
+    let entries = json.get("data")
+        .and_then(|x| x.as_array())
+        .or_else(|| json.as_array())
+        .ok_or_else(|| new_err("Failed to get xref entries as array"))?;
+    Ok(entries.iter()
+        .map(parse_anime_xref_entry)
+        .filter(|x| x.known_count() >= 2)
+        .collect())
+}
+
+fn import_anime_xref_dataset(conn: &mut Connection, dataset: &str, priority: usize, modified: i64) -> Result<usize, Box<dyn Error>> {
+    let content = fs::read_to_string(dataset)?;
+    let json: serde_json::Value = serde_json::from_str(&content)
+        .map_err(|_| new_err(format!("Failed to parse xref dataset {}", dataset).as_str()))?;
+    let entries = parse_anime_xref_json(&json)?;
+
+    let tx = conn.transaction()?;
+    tx.execute("delete from cache_anime_xref where dataset = ?1", &[dataset])?;
+    {
+        let mut stmt = tx.prepare(
+            "insert into cache_anime_xref (
+                dataset,
+                priority,
+                bangumi_id,
+                tmdb_id,
+                tmdb_season,
+                anilist_id,
+                mal_id,
+                anidb_id
+            ) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"
+        )?;
+        for entry in entries.iter() {
+            stmt.execute(rusqlite::params![
+                dataset,
+                priority as i64,
+                entry.bangumi_id,
+                entry.tmdb_id,
+                entry.tmdb_season,
+                entry.anilist_id,
+                entry.mal_id,
+                entry.anidb_id,
+            ])?;
+        }
+    }
+    tx.execute(
+        "insert or replace into cache_anime_xref_dataset (dataset, priority, modified, entry_count, imported_at) values (?1, ?2, ?3, ?4, ?5)",
+        rusqlite::params![dataset, priority as i64, modified, entries.len() as i64, chrono::Local::now().to_rfc3339()],
+    )?;
+    tx.commit()?;
+    Ok(entries.len())
+}
+
+/// Import the configured xref datasets into the database
+///
+/// ## Input
+///
+/// force : `bool`, re-import unchanged datasets
+///
+/// ## Procedure
+///
+/// 1. Drop the datasets no longer configured
+/// 2. Re-import the datasets whose file or position in the config changed since the last import
+/// 3. Missing or broken files keep their previous import, so lookups still work offline
+///
+/// ## Output
+///
+/// Number of imported entries : `usize`
+pub fn refresh_anime_xref(force: bool) -> Result<usize, Box<dyn Error>> {
+    let datasets = CONFIG.read().unwrap().parser_config.xref_paths.clone();
+    let mut conn = get_connection()?;
+
+    let imported: Vec<(String, i64, i64)> = {
+        let mut stmt = conn.prepare_cached("select dataset, priority, modified from cache_anime_xref_dataset")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        rows.filter_map(|x| x.ok()).collect()
+    };
+    for (dataset, _, _) in imported.iter().filter(|(dataset, _, _)| !datasets.contains(dataset)) {
+        log::info!("Removing xref dataset {}", dataset);
+        conn.execute("delete from cache_anime_xref where dataset = ?1", &[dataset])?;
+        conn.execute("delete from cache_anime_xref_dataset where dataset = ?1", &[dataset])?;
+    }
+
+    let mut count = 0;
+    for (priority, dataset) in datasets.iter().enumerate() {
+        let modified = match fs::metadata(dataset).and_then(|x| x.modified()) {
+            Ok(modified) => modified.duration_since(UNIX_EPOCH).map_or(0, |x| x.as_secs() as i64),
+            Err(e) => {
+                log::warn!("Failed to read xref dataset {}: {}", dataset, e);
+                continue;
+            }
+        };
+        let unchanged = imported.iter().any(|x| x.0 == *dataset && x.1 == priority as i64 && x.2 == modified);
+        if unchanged && !force {
+            continue;
+        }
+        match import_anime_xref_dataset(&mut conn, dataset, priority, modified) {
+            Ok(n) => {
+                log::info!("Imported {} entries from xref dataset {}", n, dataset);
+                count += n;
+            }
+            Err(e) => log::warn!("Failed to import xref dataset {}: {}", dataset, e),
+        }
+    }
+    Ok(count)
+}
+
+/// Look up the ids of an anime in the imported datasets
+///
+/// Entries are chained through any shared id, e.g. a Bangumi-MAL mapping and an
+/// anime-offline-database entry of the same MAL id. When datasets disagree, the
+/// earlier one in the config wins, then the earlier entry in the file.
+///
+/// `None` if no other id is known.
+pub fn lookup_anime_xref(source: MetadataSource, id: i32) -> Option<AnimeXref> {
+    if id <= 0 {
+        return None;
+    }
+    let conn = get_connection().ok()?;
+    let mut xref = AnimeXref::default();
+    xref.set(source, id);
+
+    // A few rounds suffice, every round adds at least one id
+    for _ in 0..MetadataSource::ALL.len() {
+        let mut changed = false;
+        for source in MetadataSource::ALL {
+            let id = xref.get(source);
+            if id <= 0 {
+                continue;
+            }
+            let mut stmt = conn.prepare_cached(&format!(
+                "select bangumi_id, tmdb_id, tmdb_season, anilist_id, mal_id, anidb_id from cache_anime_xref where {} = ?1 order by priority, rowid",
+                xref_column(source)
+            )).ok()?;
+            let rows = stmt.query_map(&[&id], |row| {
+                Ok(AnimeXref {
+                    bangumi_id: row.get(0)?,
+                    tmdb_id: row.get(1)?,
+                    tmdb_season: row.get(2)?,
+                    anilist_id: row.get(3)?,
+                    mal_id: row.get(4)?,
+                    anidb_id: row.get(5)?,
+                })
+            }).ok()?;
+            for row in rows.filter_map(|x| x.ok()) {
+                changed |= xref.merge(&row);
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    if xref.known_count() >= 2 {
+        Some(xref)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_anime_xref_json() {
+        let offline_database: serde_json::Value = serde_json::from_str(r#"{"data": [
+            {"sources": ["https://anidb.net/anime/17617", "https://anilist.co/anime/154587", "https://myanimelist.net/anime/52991", "https://kitsu.app/anime/46474"],
+             "title": "Sousou no Frieren", "type": "TV", "episodes": 28},
+            {"sources": ["https://myanimelist.net/anime/56885"], "title": "Sousou no Frieren: ●● no Mahou", "type": "ONA"}
+        ]}"#).unwrap();
+        let entries = parse_anime_xref_json(&offline_database).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].anidb_id, 17617);
+        assert_eq!(entries[0].anilist_id, 154587);
+        assert_eq!(entries[0].mal_id, 52991);
+        assert_eq!(entries[0].bangumi_id, -1);
+
+        let mapping: serde_json::Value = serde_json::from_str(r#"[
+            {"bgm_id": "400602", "mal_id": 52991, "tmdb_id": 209867, "tmdb_season": 1},
+            {"anidb_id": 4563, "themoviedb_id": 129, "type": "MOVIE"}
+        ]"#).unwrap();
+        let entries = parse_anime_xref_json(&mapping).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].bangumi_id, 400602);
+        assert_eq!(entries[0].tmdb_id, 209867);
+        assert_eq!(entries[0].tmdb_season, 1);
+
+        // Chained through the MAL id
+        let mut xref = entries[0];
+        assert!(xref.merge(&parse_anime_xref_json(&offline_database).unwrap()[0]));
+        assert_eq!(xref.anilist_id, 154587);
+        assert_eq!(xref.known_count(), 5);
+    }
+}
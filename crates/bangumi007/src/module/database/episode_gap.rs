@@ -0,0 +1,54 @@
+use std::error::Error;
+
+use rusqlite::Connection;
+
+use crate::module::database::repository::{EpisodeGapRepository, with_repository};
+
+/// A main-story episode aired on Bangumi but missing in a season, see `find_episode_gaps`.
+/// Detected again on every feed update, keyed by Mikan subject, subgroup and Bangumi episode sort.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EpisodeGap {
+    pub mikan_subject_id: i32,
+    pub mikan_subgroup_id: i32,
+    pub bangumi_episode_sort: i32,
+    pub mikan_parsed_episode_num: i32,  // episode number the subgroups would use, i.e. the sort without the bangumi offset
+    pub disp_episode_num: i32,
+    pub episode_name: String,
+    pub episode_airdate: String,
+    pub replacement_uuid: String,       // release of another subgroup to fill the gap with, empty if none is found
+    pub replacement_title: String,
+}
+
+impl EpisodeGap {
+    pub fn has_replacement(&self) -> bool {
+        !self.replacement_uuid.is_empty()
+    }
+}
+
+#[deny(dead_code)]
+pub fn init_library_episode_gap_table(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    conn.execute(
+        "create table if not exists library_episode_gap (
+            mikan_subject_id integer,
+            mikan_subgroup_id integer,
+            bangumi_episode_sort integer,
+            mikan_parsed_episode_num integer,
+            disp_episode_num integer,
+            episode_name text default '',
+            episode_airdate text default '',
+            replacement_uuid text default '',
+            replacement_title text default '',
+            primary key(mikan_subject_id,mikan_subgroup_id,bangumi_episode_sort) on conflict replace
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+pub fn read_episode_gaps() -> Vec<EpisodeGap> {
+    with_repository("read episode gaps", |repo| repo.list_episode_gaps())
+}
+
+pub fn read_season_gaps(mikan_subject_id: i32, mikan_subgroup_id: i32) -> Vec<EpisodeGap> {
+    with_repository("read season gaps", |repo| repo.list_season_gaps(mikan_subject_id, mikan_subgroup_id))
+}
@@ -0,0 +1,303 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::module::config::{CONFIG, RSSItem};
+use crate::module::database::library::{AnimeSeason, AnimeSeasonItem, renumber_items_in};
+use crate::module::database::repository::{SeasonItemRepository, SeasonRepository, SeriesRepository, SubjectOverrideRepository, with_transaction};
+use crate::module::database::series::link_season_series_in;
+use crate::module::database::subject_override::MikanSubjectOverride;
+use crate::module::utils::error::new_err;
+
+/// Version of the export format, bumped on incompatible changes.
+/// Files of older versions are still imported, files of newer versions are refused.
+pub const LIBRARY_EXPORT_VERSION: i32 = 1;
+
+/// The user's library and its tuning: seasons with their configs, items, feed subscriptions and subject overrides
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LibraryExport {
+    pub version: i32,
+    pub exported_at: String,
+    pub seasons: Vec<AnimeSeason>,
+    pub items: Vec<AnimeSeasonItem>,
+    #[serde(default)]
+    pub subscriptions: Vec<RSSItem>,
+    #[serde(default)]
+    pub subject_overrides: Vec<MikanSubjectOverride>,
+}
+
+/// Which side wins when an imported entry differs from the local one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportConflictPolicy {
+    KeepLocal,
+    UseImported,
+}
+
+/// An entry that exists on both sides with different values
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportConflict {
+    pub entry: String,      // e.g. "season 3141-382 葬送的芙莉莲"
+    pub field: String,
+    pub local: String,
+    pub imported: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportReport {
+    pub seasons_added: usize,
+    pub items_added: usize,
+    pub subscriptions_added: usize,
+    pub subject_overrides_added: usize,
+    pub conflicts: Vec<ImportConflict>,
+}
+
+impl ImportReport {
+    fn conflict(&mut self, entry: &str, field: &str, local: impl ToString, imported: impl ToString) {
+        self.conflicts.push(ImportConflict {
+            entry: entry.to_string(),
+            field: field.to_string(),
+            local: local.to_string(),
+            imported: imported.to_string(),
+        });
+    }
+}
+
+/// Export the library of the current profile as a json file
+///
+/// ## Output
+///
+/// Number of exported seasons : `usize`
+pub fn export_library(path: &Path) -> Result<usize, Box<dyn Error>> {
+    let export = LibraryExport {
+        subscriptions: CONFIG.read().unwrap().rss_config.list.clone(),
+        ..with_transaction(|repo| read_library_export(repo))?
+    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(&export)?)?;
+    Ok(export.seasons.len())
+}
+
+/// Read the library in one transaction, so that seasons and items are consistent, the subscriptions are left to the caller
+pub fn read_library_export<R: SeasonRepository + SeasonItemRepository + SubjectOverrideRepository>(repo: &R) -> Result<LibraryExport, Box<dyn Error>> {
+    Ok(LibraryExport {
+        version: LIBRARY_EXPORT_VERSION,
+        exported_at: chrono::Local::now().to_rfc3339(),
+        seasons: repo.list_seasons()?,
+        items: repo.list_all_items()?,
+        subscriptions: Vec::new(),
+        subject_overrides: repo.list_subject_overrides()?,
+    })
+}
+
+pub fn parse_library_export(content: &str) -> Result<LibraryExport, Box<dyn Error>> {
+    let export: LibraryExport = serde_json::from_str(content)?;
+    if export.version > LIBRARY_EXPORT_VERSION {
+        return Err(new_err(format!(
+            "Library export version {} is newer than version {} supported by this build",
+            export.version, LIBRARY_EXPORT_VERSION).as_str()));
+    }
+    Ok(export)
+}
+
+/// Merge a json file written by `export_library` into the library of the current profile
+///
+/// ## Input
+///
+/// path : `&Path`
+/// policy : `ImportConflictPolicy`, which side wins for entries that differ
+///
+/// ## Procedure
+///
+/// 1. Merge seasons, items and subject overrides in one transaction, see `import_library_in`
+/// 2. Merge the feed subscriptions into the config by url, once the transaction is committed
+///
+/// ## Output
+///
+/// ImportReport : `ImportReport`, conflicts are reported for either policy
+pub fn import_library(path: &Path, policy: ImportConflictPolicy) -> Result<ImportReport, Box<dyn Error>> {
+    let export = parse_library_export(&fs::read_to_string(path)?)?;
+    let mut report = with_transaction(|repo| import_library_in(repo, &export, policy))?;
+
+    let mut config = CONFIG.write().unwrap();
+    let subscriptions_before = config.rss_config.list.clone();
+    merge_subscriptions(&mut config.rss_config.list, &export.subscriptions, policy, &mut report);
+    if config.rss_config.list != subscriptions_before {
+        config.save();
+    }
+    Ok(report)
+}
+
+/// Merge seasons, items and subject overrides, new entries are added and differing entries are resolved by `policy`.
+/// Items are renumbered by the resulting episode offset of their season, new seasons are linked to the local series.
+pub fn import_library_in<R: SeasonRepository + SeasonItemRepository + SeriesRepository + SubjectOverrideRepository>(repo: &R, export: &LibraryExport, policy: ImportConflictPolicy) -> Result<ImportReport, Box<dyn Error>> {
+    let mut report = ImportReport::default();
+    let mut touched_seasons = HashSet::new();
+
+    for season in export.seasons.iter() {
+        let key = (season.mikan_subject_id, season.mikan_subgroup_id);
+        let local = match repo.get_season(key.0, key.1)? {
+            Some(local) => local,
+            None => {
+                repo.upsert_season(&AnimeSeason { series_id: -1, ..season.clone() })?;
+                link_season_series_in(repo, key.0, key.1)?;
+                report.seasons_added += 1;
+                touched_seasons.insert(key);
+                continue;
+            }
+        };
+        let entry = format!("season {}-{} {}", key.0, key.1, local.disp_series_name);
+        let conflicts_before = report.conflicts.len();
+        if local.conf_tmdb_episode_offset != season.conf_tmdb_episode_offset {
+            report.conflict(&entry, "conf_tmdb_episode_offset", local.conf_tmdb_episode_offset, season.conf_tmdb_episode_offset);
+        }
+        if local.conf_bangumi_episode_offset != season.conf_bangumi_episode_offset {
+            report.conflict(&entry, "conf_bangumi_episode_offset", local.conf_bangumi_episode_offset, season.conf_bangumi_episode_offset);
+        }
+        if local.conf_season_num != season.conf_season_num {
+            report.conflict(&entry, "conf_season_num", local.conf_season_num, season.conf_season_num);
+        }
+        if local.conf_language != season.conf_language {
+            report.conflict(&entry, "conf_language", &local.conf_language, &season.conf_language);
+        }
+        if local.conf_codec != season.conf_codec {
+            report.conflict(&entry, "conf_codec", &local.conf_codec, &season.conf_codec);
+        }
+        if report.conflicts.len() > conflicts_before && policy == ImportConflictPolicy::UseImported {
+            repo.update_season_conf(&AnimeSeason {
+                conf_tmdb_episode_offset: season.conf_tmdb_episode_offset,
+                conf_bangumi_episode_offset: season.conf_bangumi_episode_offset,
+                conf_season_num: season.conf_season_num,
+                conf_language: season.conf_language.clone(),
+                conf_codec: season.conf_codec.clone(),
+                ..local
+            })?;
+            if season.conf_season_num != -1 {
+                repo.set_season_disp_season_num(key.0, key.1, season.conf_season_num)?;
+            }
+            touched_seasons.insert(key);
+        }
+    }
+
+    let local_items: HashSet<String> = repo.list_all_items()?.into_iter().map(|x| x.mikan_item_uuid).collect();
+    for item in export.items.iter().filter(|x| !local_items.contains(&x.mikan_item_uuid)) {
+        if repo.get_season(item.mikan_subject_id, item.mikan_subgroup_id)?.is_none() {
+            log::warn!("Skip importing item {} without season", item.mikan_item_title);
+            continue;
+        }
+        repo.upsert_season_item(item)?;
+        report.items_added += 1;
+        touched_seasons.insert((item.mikan_subject_id, item.mikan_subgroup_id));
+    }
+
+    for (mikan_subject_id, mikan_subgroup_id) in touched_seasons {
+        renumber_items_in(repo, mikan_subject_id, mikan_subgroup_id)?;
+    }
+
+    for subject_override in export.subject_overrides.iter() {
+        match repo.get_subject_override(subject_override.mikan_subject_id)? {
+            None => {
+                repo.upsert_subject_override(subject_override)?;
+                report.subject_overrides_added += 1;
+            }
+            Some(local) if local != *subject_override => {
+                report.conflict(&format!("subject override {}", local.mikan_subject_id), "override",
+                                format!("{:?}", local), format!("{:?}", subject_override));
+                if policy == ImportConflictPolicy::UseImported {
+                    repo.upsert_subject_override(subject_override)?;
+                }
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(report)
+}
+
+/// Merge feed subscriptions by url
+pub fn merge_subscriptions(local: &mut Vec<RSSItem>, imported: &[RSSItem], policy: ImportConflictPolicy, report: &mut ImportReport) {
+    let index: HashMap<String, usize> = local.iter().enumerate().map(|(i, x)| (x.url.clone(), i)).collect();
+    for item in imported {
+        match index.get(&item.url) {
+            None => {
+                local.push(item.clone());
+                report.subscriptions_added += 1;
+            }
+            Some(&i) => {
+                let entry = format!("subscription {}", item.url);
+                if local[i].name != item.name {
+                    report.conflict(&entry, "name", &local[i].name, &item.name);
+                }
+                if local[i].active != item.active {
+                    report.conflict(&entry, "active", local[i].active, item.active);
+                }
+                if policy == ImportConflictPolicy::UseImported {
+                    local[i] = item.clone();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::module::database::repository::{open_in_memory_database, SqliteRepository, test_season, test_season_item};
+
+    use super::*;
+
+    #[test]
+    fn test_import_library() {
+        let conn = open_in_memory_database().unwrap();
+        let repo = SqliteRepository::new(&conn);
+        repo.upsert_season(&test_season(3141, 1)).unwrap();
+        repo.upsert_season_item(&test_season_item("a", 3141, 1)).unwrap();
+
+        let export = LibraryExport {
+            version: LIBRARY_EXPORT_VERSION,
+            seasons: vec![AnimeSeason { conf_tmdb_episode_offset: 12, conf_language: "简日".to_string(), ..test_season(3141, 1) }, AnimeSeason { mikan_subgroup_id: 583, ..test_season(3141, 1) }],
+            items: vec![test_season_item("a", 3141, 1), test_season_item("b", 3141, 2)],
+            subject_overrides: vec![MikanSubjectOverride { mikan_subject_id: 3141, tmdb_season_num: Some(1), ..Default::default() }],
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&export).unwrap();
+        assert_eq!(parse_library_export(&json).unwrap(), export);
+        assert!(parse_library_export(&json.replace("\"version\":1", "\"version\":99")).is_err());
+
+        // Local configs are kept, the differences are reported
+        let report = import_library_in(&repo, &export, ImportConflictPolicy::KeepLocal).unwrap();
+        assert_eq!((report.seasons_added, report.items_added, report.subject_overrides_added), (1, 1, 1));
+        assert_eq!(report.conflicts.iter().map(|x| x.field.as_str()).collect::<Vec<_>>(), vec!["conf_tmdb_episode_offset", "conf_language"]);
+        assert_eq!(repo.get_season(3141, 382).unwrap().unwrap().conf_tmdb_episode_offset, 0);
+
+        // Imported configs win, and the items are renumbered
+        let report = import_library_in(&repo, &export, ImportConflictPolicy::UseImported).unwrap();
+        assert_eq!((report.seasons_added, report.items_added, report.conflicts.len()), (0, 0, 2));
+        assert_eq!(repo.get_season(3141, 382).unwrap().unwrap().conf_language, "简日");
+        let items = repo.list_season_items(3141, 382).unwrap();
+        assert_eq!(items.iter().map(|x| x.disp_episode_num).collect::<Vec<_>>(), vec![13, 14]);
+
+        // Everything is in sync now
+        let exported = read_library_export(&repo).unwrap();
+        let report = import_library_in(&repo, &exported, ImportConflictPolicy::KeepLocal).unwrap();
+        assert_eq!(report, ImportReport::default());
+    }
+
+    #[test]
+    fn test_merge_subscriptions() {
+        let rss_item = |name: &str, url: &str, active: bool| RSSItem { name: name.to_string(), url: url.to_string(), active, retired: false };
+        let mut local = vec![rss_item("我的番组", "https://mikanime.tv/RSS/MyBangumi?token=a", true)];
+        let imported = vec![
+            rss_item("我的番组", "https://mikanime.tv/RSS/MyBangumi?token=a", false),
+            rss_item("芙莉莲", "https://mikanime.tv/RSS/Bangumi?bangumiId=3141", true),
+        ];
+        let mut report = ImportReport::default();
+        merge_subscriptions(&mut local, &imported, ImportConflictPolicy::KeepLocal, &mut report);
+        assert_eq!(local.len(), 2);
+        assert!(local[0].active);
+        assert_eq!(report.subscriptions_added, 1);
+        assert_eq!(report.conflicts.len(), 1);
+    }
+}
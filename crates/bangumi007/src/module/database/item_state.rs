@@ -0,0 +1,230 @@
+use std::error::Error;
+
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+
+use crate::module::database::repository::{SeasonItemRepository, with_transaction};
+
+/// Lifecycle of an item in the library, stored by key
+///
+/// discovered → filtered / accepted → queued → downloading → completed → organized → watched,
+/// an item fails or is ignored on the way. See `ItemState::on` for the transitions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemState {
+    #[default]
+    Discovered,     // found in the feed
+    Filtered,       // rejected by the language and codec restriction of its season
+    Accepted,       // to be downloaded
+    Queued,         // added to the downloader
+    Downloading,
+    Completed,      // downloaded, file not renamed yet
+    Organized,      // file renamed for the media server
+    Watched,        // scrobbled from the media server
+    Failed,         // a step failed, the download is retried on the next update
+    Ignored,        // skipped by the user
+}
+
+/// What happened to an item, reported by the feed, downloader and scrobbler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItemEvent {
+    Accept,
+    Filter,
+    Queue,
+    StartDownload,
+    Complete,
+    Organize,
+    Watch,
+    Fail,
+    Ignore,
+    Reset,
+}
+
+impl ItemState {
+    pub const ALL: [ItemState; 10] = [
+        ItemState::Discovered,
+        ItemState::Filtered,
+        ItemState::Accepted,
+        ItemState::Queued,
+        ItemState::Downloading,
+        ItemState::Completed,
+        ItemState::Organized,
+        ItemState::Watched,
+        ItemState::Failed,
+        ItemState::Ignored,
+    ];
+
+    pub fn key(&self) -> &'static str {
+        match self {
+            ItemState::Discovered => "discovered",
+            ItemState::Filtered => "filtered",
+            ItemState::Accepted => "accepted",
+            ItemState::Queued => "queued",
+            ItemState::Downloading => "downloading",
+            ItemState::Completed => "completed",
+            ItemState::Organized => "organized",
+            ItemState::Watched => "watched",
+            ItemState::Failed => "failed",
+            ItemState::Ignored => "ignored",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<ItemState> {
+        ItemState::ALL.iter().find(|x| x.key() == key).copied()
+    }
+
+    pub fn disp_name(&self) -> &'static str {
+        match self {
+            ItemState::Discovered => "已发现",
+            ItemState::Filtered => "已过滤",
+            ItemState::Accepted => "待下载",
+            ItemState::Queued => "已添加",
+            ItemState::Downloading => "下载中",
+            ItemState::Completed => "已下载",
+            ItemState::Organized => "已整理",
+            ItemState::Watched => "已观看",
+            ItemState::Failed => "失败",
+            ItemState::Ignored => "已忽略",
+        }
+    }
+
+    /// Whether the item belongs to the library, i.e. is shown, downloaded and renamed
+    pub fn is_active(&self) -> bool {
+        !matches!(self, ItemState::Discovered | ItemState::Filtered | ItemState::Ignored)
+    }
+
+    /// Whether the torrent of the item is in the downloader
+    pub fn in_downloader(&self) -> bool {
+        matches!(self, ItemState::Queued | ItemState::Downloading | ItemState::Completed | ItemState::Organized | ItemState::Watched)
+    }
+
+    /// State after an event, `None` if the event is not defined in this state
+    ///
+    /// Download progress may be reported late or skipped, e.g. a torrent completed between two syncs,
+    /// so a later step is accepted from any earlier step of the download.
+    pub fn on(&self, event: ItemEvent) -> Option<ItemState> {
+        use ItemEvent::*;
+        use ItemState::*;
+        match (self, event) {
+            (Discovered | Filtered | Failed, Accept) => Some(Accepted),
+            (Discovered | Accepted | Failed, Filter) => Some(Filtered),
+            (Accepted | Failed, Queue) => Some(Queued),
+            (Queued, StartDownload) => Some(Downloading),
+            (Queued | Downloading, Complete) => Some(Completed),
+            (Completed, Organize) => Some(Organized),
+            (Completed | Organized, Watch) => Some(Watched),
+            (Accepted | Queued | Downloading | Completed, Fail) => Some(Failed),
+            (Discovered | Filtered | Accepted | Failed, Ignore) => Some(Ignored),
+            (Failed | Ignored, Reset) => Some(Accepted),
+            _ => None,
+        }
+    }
+}
+
+impl ToSql for ItemState {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.key()))
+    }
+}
+
+impl FromSql for ItemState {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let key = value.as_str()?;
+        ItemState::from_key(key).ok_or_else(|| FromSqlError::Other(format!("Unknown item state {}", key).into()))
+    }
+}
+
+/// Apply an event to an item in the library
+///
+/// ## Output
+///
+/// New state, `None` if the item is not found or the event is not defined in its state : `Option<ItemState>`
+pub fn apply_item_event_in<R: SeasonItemRepository>(repo: &R, mikan_item_uuid: &str, event: ItemEvent) -> Result<Option<ItemState>, Box<dyn Error>> {
+    let item = match repo.get_season_item(mikan_item_uuid)? {
+        Some(item) => item,
+        None => return Ok(None),
+    };
+    match item.state.on(event) {
+        Some(state) => {
+            if state != item.state {
+                log::debug!("Item {}: {:?} -> {:?}", item.mikan_item_title, item.state, state);
+                repo.set_item_state(mikan_item_uuid, state)?;
+            }
+            Ok(Some(state))
+        }
+        None => {
+            log::debug!("Item {}: {:?} ignored in state {:?}", item.mikan_item_title, event, item.state);
+            Ok(None)
+        }
+    }
+}
+
+/// Apply an event to an item outside of a transaction, a failure is logged.
+pub fn apply_item_event(mikan_item_uuid: &str, event: ItemEvent) -> Option<ItemState> {
+    with_transaction(|repo| apply_item_event_in(repo, mikan_item_uuid, event)).unwrap_or_else(|e| {
+        log::error!("Failed to update state of item {}: {}", mikan_item_uuid, e);
+        None
+    })
+}
+
+/// Mark the items of an episode watched, reported by the scrobbler, see `find_shown_episode`
+pub fn mark_items_watched(mikan_item_uuids: &[String]) -> Result<(), Box<dyn Error>> {
+    with_transaction(|repo| {
+        for mikan_item_uuid in mikan_item_uuids {
+            apply_item_event_in(repo, mikan_item_uuid, ItemEvent::Watch)?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::module::database::cache::rss::MikanItem;
+    use crate::module::database::library::{AnimeSeason, create_item_in};
+    use crate::module::database::repository::{open_in_memory_database, SeasonRepository, SqliteRepository};
+
+    use super::*;
+
+    #[test]
+    fn test_item_state_transitions() {
+        let run = |events: &[ItemEvent]| events.iter()
+            .try_fold(ItemState::Discovered, |state, event| state.on(*event));
+
+        use ItemEvent::*;
+        assert_eq!(run(&[Accept, Queue, StartDownload, Complete, Organize, Watch]), Some(ItemState::Watched));
+        // A torrent completed between two syncs
+        assert_eq!(run(&[Accept, Queue, Complete]), Some(ItemState::Completed));
+        assert_eq!(run(&[Accept, Queue, Fail, Queue]), Some(ItemState::Queued));
+        assert_eq!(run(&[Filter, Accept]), Some(ItemState::Accepted));
+        assert_eq!(run(&[Ignore, Reset]), Some(ItemState::Accepted));
+        // Undefined transitions
+        assert_eq!(run(&[Queue]), None);
+        assert_eq!(run(&[Accept, Queue, Filter]), None);
+        assert_eq!(run(&[Accept, Queue, StartDownload, Organize]), None);
+
+        for state in ItemState::ALL {
+            assert_eq!(ItemState::from_key(state.key()), Some(state));
+        }
+    }
+
+    #[test]
+    fn test_apply_item_event() {
+        let conn = open_in_memory_database().unwrap();
+        let repo = SqliteRepository::new(&conn);
+        repo.upsert_season(&AnimeSeason { mikan_subject_id: 3141, mikan_subgroup_id: 382, ..Default::default() }).unwrap();
+        let item = MikanItem { mikan_item_uuid: "a".to_string(), mikan_subject_id: 3141, mikan_subgroup_id: 382, ..Default::default() };
+        create_item_in(&repo, &item).unwrap();
+        let state = || repo.get_season_item("a").unwrap().unwrap().state;
+        assert_eq!(state(), ItemState::Discovered);
+
+        assert_eq!(apply_item_event_in(&repo, "a", ItemEvent::Accept).unwrap(), Some(ItemState::Accepted));
+        assert_eq!(apply_item_event_in(&repo, "a", ItemEvent::Queue).unwrap(), Some(ItemState::Queued));
+        assert_eq!(apply_item_event_in(&repo, "a", ItemEvent::Organize).unwrap(), None);
+        assert_eq!(state(), ItemState::Queued);
+        assert_eq!(apply_item_event_in(&repo, "x", ItemEvent::Accept).unwrap(), None);
+
+        // Seeing the item in the feed again keeps its state
+        create_item_in(&repo, &item).unwrap();
+        assert_eq!(state(), ItemState::Queued);
+    }
+}
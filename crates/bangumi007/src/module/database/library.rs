@@ -1,10 +1,15 @@
 use std::error::Error;
 
 use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
 
-use crate::module::database::get_connection;
+use crate::module::database::cache::rss::MikanItem;
+use crate::module::database::item_state::ItemState;
+use crate::module::database::season_status::SeasonStatus;
+use crate::module::database::repository::{EpisodeOffsetProposalRepository, SeasonItemRepository, SeasonRepository, with_repository, with_transaction};
+use crate::module::utils::error::new_err;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct AnimeSeason {
     pub mikan_subject_id: i32,
     pub mikan_subgroup_id: i32,
@@ -28,6 +33,70 @@ pub struct AnimeSeason {
     pub conf_codec: String,
     pub conf_season_num: i32,
     pub conf_bangumi_episode_offset: i32,
+    pub anilist_id: i32,
+    pub mal_id: i32,
+    pub anidb_id: i32,
+    #[serde(default)]
+    pub series_id: i32,     // see `AnimeSeries`, relinked on import
+    pub merged_subject_id: Option<i32>,     // see `AnimeSeason::merged_into`
+    pub merged_subgroup_id: Option<i32>,
+    pub merged_episode_offset: Option<i32>,
+    pub split_episode_num: Option<i32>,     // see `AnimeSeason::split_at`
+    pub split_season_num: Option<i32>,
+    #[serde(default)]
+    pub status: SeasonStatus,               // see `auto_season_status_update`
+}
+
+impl AnimeSeason {
+    /// (mikan_subject_id, mikan_subgroup_id) of the season this one is shown as a part of, e.g. the second cour
+    /// published as another Mikan subject
+    pub fn merged_into(&self) -> Option<(i32, i32)> {
+        self.merged_subject_id.zip(self.merged_subgroup_id)
+    }
+
+    /// (split_episode_num, split_season_num), the episodes from `split_episode_num` on are shown as another season,
+    /// e.g. a Mikan subject spanning two TMDB seasons
+    pub fn split_at(&self) -> Option<(i32, i32)> {
+        self.split_episode_num.zip(self.split_season_num)
+    }
+
+    /// The season split from this one, shown under the split season number, `None` if not split
+    pub fn split_part(&self) -> Option<AnimeSeason> {
+        self.split_season_num.map(|split_season_num| AnimeSeason {
+            disp_season_num: split_season_num,
+            disp_season_name: format!("第 {} 季", split_season_num),
+            conf_season_num: -1,
+            ..self.clone()
+        })
+    }
+
+    /// Whether an item of this season is shown in the season split from it, see `split_at`
+    pub fn in_split_part(&self, item: &AnimeSeasonItem) -> bool {
+        match self.split_episode_num {
+            Some(split_episode_num) => item.conf_placement().is_none() && item.conf_disp_episode_num.is_none()
+                && item.mikan_parsed_episode_num + self.conf_tmdb_episode_offset >= split_episode_num,
+            None => false,
+        }
+    }
+
+    /// Display episode number of an item of this season
+    ///
+    /// ## Procedure
+    ///
+    /// 1. The number set on the item, if any
+    /// 2. The parsed number shifted by the TMDB episode offset
+    /// 3. Counted from 1 again in the split part, or shifted by the offset of the merge
+    pub fn item_disp_episode_num(&self, item: &AnimeSeasonItem) -> i32 {
+        if let Some(disp_episode_num) = item.conf_disp_episode_num {
+            return disp_episode_num;
+        }
+        let disp_episode_num = item.mikan_parsed_episode_num + self.conf_tmdb_episode_offset;
+        match (self.split_episode_num, self.merged_episode_offset) {
+            (Some(split_episode_num), _) if self.in_split_part(item) => disp_episode_num - split_episode_num + 1,
+            (_, Some(merged_episode_offset)) if item.conf_placement().is_none() => disp_episode_num + merged_episode_offset,
+            _ => disp_episode_num,
+        }
+    }
 }
 
 #[deny(dead_code)]
@@ -56,6 +125,16 @@ pub fn init_cache_library_anime_season_table(conn: &Connection) -> Result<(), Bo
             conf_codec text,
             conf_season_num integer default -1,
             conf_bangumi_episode_offset integer default 0,
+            anilist_id integer default -1,
+            mal_id integer default -1,
+            anidb_id integer default -1,
+            series_id integer default -1,
+            merged_subject_id integer,
+            merged_subgroup_id integer,
+            merged_episode_offset integer,
+            split_episode_num integer,
+            split_season_num integer,
+            status text default 'airing',
             primary key(mikan_subject_id,mikan_subgroup_id) on conflict replace
         )",
         [],
@@ -64,261 +143,57 @@ pub fn init_cache_library_anime_season_table(conn: &Connection) -> Result<(), Bo
 }
 
 
-pub fn read_season_info(mikan_subject_id: i32, mikan_subgroup_id: i32) -> Option<AnimeSeason> {
-    let conn = get_connection().unwrap();
-    let mut stmt = conn.prepare("select * from library_anime_season where mikan_subject_id = ?1 and mikan_subgroup_id = ?2").unwrap();
-    let season_iter = stmt.query_map(&[&mikan_subject_id, &mikan_subgroup_id], |row| {
-        Ok(AnimeSeason {
-            mikan_subject_id: row.get(0)?,
-            mikan_subgroup_id: row.get(1)?,
-            mikan_subject_name: row.get(2)?,
-            mikan_subject_image: row.get(3)?,
-            bangumi_subject_id: row.get(4)?,
-            bangumi_subject_name: row.get(5)?,
-            bangumi_season_num: row.get(6)?,
-            bangumi_subject_image: row.get(7)?,
-            tmdb_series_id: row.get(8)?,
-            tmdb_series_name: row.get(9)?,
-            tmdb_season_num: row.get(10)?,
-            tmdb_season_name: row.get(11)?,
-            bangumi_to_tmdb_episode_offset: row.get(12)?,
-            disp_series_name: row.get(13)?,
-            disp_season_name: row.get(14)?,
-            disp_subgroup_name: row.get(15)?,
-            disp_season_num: row.get(16)?,
-            conf_tmdb_episode_offset: row.get(17)?,
-            conf_language: row.get(18)?,
-            conf_codec: row.get(19)?,
-            conf_season_num: row.get(20)?,
-            conf_bangumi_episode_offset: row.get(21)?,
-        })
-    }).unwrap();
-
-    for season in season_iter {
-        return season.ok();
-    }
-
-    None
+/// A season removed by the user, see `remove_season_in`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SeasonTombstone {
+    pub mikan_subject_id: i32,
+    pub mikan_subgroup_id: i32,
+    pub disp_series_name: String,
+    pub removed_at: String,
+    pub feed_active: bool,      // a subscription of the season was active when last checked, see `restore_resubscribed_seasons`
 }
 
-pub fn create_season(season: &AnimeSeason) {
-    let conn = get_connection().unwrap();
+/// Seasons removed by the user, so that their feed items are not added again.
+/// Keyed by Mikan subject and subgroup like the seasons.
+#[deny(dead_code)]
+pub fn init_library_season_tombstone_table(conn: &Connection) -> Result<(), Box<dyn Error>> {
     conn.execute(
-        "insert or replace into library_anime_season (
-            mikan_subject_id,
-            mikan_subgroup_id,
-            mikan_subject_name,
-            mikan_subject_image,
-            bangumi_subject_id,
-            bangumi_subject_name,
-            bangumi_season_num,
-            bangumi_subject_image,
-            tmdb_series_id,
-            tmdb_series_name,
-            tmdb_season_num,
-            tmdb_season_name,
-            bangumi_to_tmdb_episode_offset,
-            disp_series_name,
-            disp_season_name,
-            disp_subgroup_name,
-            disp_season_num,
-            conf_tmdb_episode_offset,
-            conf_language,
-            conf_codec,
-            conf_season_num,
-            conf_bangumi_episode_offset
-        ) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22)",
-        &[
-            &season.mikan_subject_id.to_string(),
-            &season.mikan_subgroup_id.to_string(),
-            &season.mikan_subject_name,
-            &season.mikan_subject_image,
-            &season.bangumi_subject_id.to_string(),
-            &season.bangumi_subject_name,
-            &season.bangumi_season_num.to_string(),
-            &season.bangumi_subject_image,
-            &season.tmdb_series_id.to_string(),
-            &season.tmdb_series_name,
-            &season.tmdb_season_num.to_string(),
-            &season.tmdb_season_name,
-            &season.bangumi_to_tmdb_episode_offset.to_string(),
-            &season.disp_series_name,
-            &season.disp_season_name,
-            &season.disp_subgroup_name,
-            &season.disp_season_num.to_string(),
-            &season.conf_tmdb_episode_offset.to_string(),
-            &season.conf_language,
-            &season.conf_codec,
-            &season.conf_season_num.to_string(),
-            &season.conf_bangumi_episode_offset.to_string(),
-        ],
-    ).unwrap();
+        "create table if not exists library_season_tombstone (
+            mikan_subject_id integer,
+            mikan_subgroup_id integer,
+            disp_series_name text default '',
+            removed_at text default '',
+            feed_active integer default 1,
+            primary key(mikan_subject_id,mikan_subgroup_id) on conflict replace
+        )",
+        [],
+    )?;
+    Ok(())
 }
 
-#[allow(dead_code)]
-pub fn delete_season(mikan_subject_id: i32, mikan_subgroup_id: i32) {
-    let conn = get_connection().unwrap();
-    conn.execute(
-        "delete from library_anime_season where mikan_subject_id = ?1 and mikan_subgroup_id = ?2",
-        &[&mikan_subject_id, &mikan_subgroup_id],
-    ).unwrap();
+pub fn read_season_info(mikan_subject_id: i32, mikan_subgroup_id: i32) -> Option<AnimeSeason> {
+    with_repository("read season", |repo| repo.get_season(mikan_subject_id, mikan_subgroup_id))
 }
 
 pub fn read_seasons() -> Vec<AnimeSeason> {
-    let conn = get_connection().unwrap();
-    let mut stmt = conn.prepare("select * from library_anime_season").unwrap();
-    let season_iter = stmt.query_map([], |row| {
-        Ok(AnimeSeason {
-            mikan_subject_id: row.get(0)?,
-            mikan_subgroup_id: row.get(1)?,
-            mikan_subject_name: row.get(2)?,
-            mikan_subject_image: row.get(3)?,
-            bangumi_subject_id: row.get(4)?,
-            bangumi_subject_name: row.get(5)?,
-            bangumi_season_num: row.get(6)?,
-            bangumi_subject_image: row.get(7)?,
-            tmdb_series_id: row.get(8)?,
-            tmdb_series_name: row.get(9)?,
-            tmdb_season_num: row.get(10)?,
-            tmdb_season_name: row.get(11)?,
-            bangumi_to_tmdb_episode_offset: row.get(12)?,
-            disp_series_name: row.get(13)?,
-            disp_season_name: row.get(14)?,
-            disp_subgroup_name: row.get(15)?,
-            disp_season_num: row.get(16)?,
-            conf_tmdb_episode_offset: row.get(17)?,
-            conf_language: row.get(18)?,
-            conf_codec: row.get(19)?,
-            conf_season_num: row.get(20)?,
-            conf_bangumi_episode_offset: row.get(21)?,
-        })
-    }).unwrap();
-
-    let mut seasons = Vec::new();
-    for season in season_iter {
-        seasons.push(season.unwrap());
-    }
-
-    seasons
+    with_repository("read seasons", |repo| repo.list_seasons())
 }
 
 pub fn get_season_tmdb_episode_offset(mikan_subject_id: i32, mikan_subgroup_id: i32) -> i32 {
-    let conn = get_connection().unwrap();
-    let mut stmt = conn.prepare("select conf_tmdb_episode_offset from library_anime_season where mikan_subject_id = ?1 and mikan_subgroup_id = ?2").unwrap();
-    let offset_iter = stmt.query_map(&[&mikan_subject_id, &mikan_subgroup_id], |row| {
-        Ok(row.get(0)?)
-    }).unwrap();
-
-    for offset in offset_iter {
-        return offset.unwrap();
-    }
-
-    0
-}
-
-pub fn set_season_tmdb_episode_offset(mikan_subject_id: i32, mikan_subgroup_id: i32, new_offset: i32) {
-    let conn = get_connection().unwrap();
-    conn.execute(
-        "update library_anime_season set conf_tmdb_episode_offset = ?1 where mikan_subject_id = ?2 and mikan_subgroup_id = ?3",
-        &[&new_offset, &mikan_subject_id, &mikan_subgroup_id],
-    ).unwrap();
-    conn.execute(
-        "update library_anime_season_item set disp_episode_num = mikan_parsed_episode_num + ?1 where mikan_subject_id = ?2 and mikan_subgroup_id = ?3",
-        &[&new_offset, &mikan_subject_id, &mikan_subgroup_id],
-    ).unwrap();
+    read_season_info(mikan_subject_id, mikan_subgroup_id).map_or(0, |x| x.conf_tmdb_episode_offset)
 }
 
 pub fn get_season_bangumi_episode_offset(mikan_subject_id: i32, mikan_subgroup_id: i32) -> i32 {
-    let conn = get_connection().unwrap();
-    let mut stmt = conn.prepare("select conf_bangumi_episode_offset from library_anime_season where mikan_subject_id = ?1 and mikan_subgroup_id = ?2").unwrap();
-    let offset_iter = stmt.query_map(&[&mikan_subject_id, &mikan_subgroup_id], |row| {
-        Ok(row.get(0)?)
-    }).unwrap();
-
-    for offset in offset_iter {
-        return offset.unwrap();
-    }
-
-    0
-}
-
-pub fn set_season_bangumi_episode_offset(mikan_subject_id: i32, mikan_subgroup_id: i32, new_offset: i32) {
-    let conn = get_connection().unwrap();
-    conn.execute(
-        "update library_anime_season set conf_bangumi_episode_offset = ?1 where mikan_subject_id = ?2 and mikan_subgroup_id = ?3",
-        &[&new_offset, &mikan_subject_id, &mikan_subgroup_id],
-    ).unwrap();
+    read_season_info(mikan_subject_id, mikan_subgroup_id).map_or(0, |x| x.conf_bangumi_episode_offset)
 }
 
 pub fn get_season_conf_season_num(mikan_subject_id: i32, mikan_subgroup_id: i32) -> i32 {
-    let conn = get_connection().unwrap();
-    let mut stmt = conn.prepare("select conf_season_num from library_anime_season where mikan_subject_id = ?1 and mikan_subgroup_id = ?2").unwrap();
-    let conf_season_num_iter = stmt.query_map(&[&mikan_subject_id, &mikan_subgroup_id], |row| {
-        Ok(row.get(0)?)
-    }).unwrap();
-
-    for conf_season_num in conf_season_num_iter {
-        return conf_season_num.unwrap();
-    }
-
-    -1
-}
-
-pub fn set_season_conf_season_num(mikan_subject_id: i32, mikan_subgroup_id: i32, new_conf_season_num: i32) {
-    let conn = get_connection().unwrap();
-    conn.execute(
-        "update library_anime_season set conf_season_num = ?1 where mikan_subject_id = ?2 and mikan_subgroup_id = ?3",
-        &[&new_conf_season_num, &mikan_subject_id, &mikan_subgroup_id],
-    ).unwrap();
+    read_season_info(mikan_subject_id, mikan_subgroup_id).map_or(-1, |x| x.conf_season_num)
 }
 
-pub fn set_season_disp_season_num(mikan_subject_id: i32, mikan_subgroup_id: i32, new_disp_season_num: i32) {
-    let conn = get_connection().unwrap();
-    conn.execute(
-        "update library_anime_season set disp_season_num = ?1 where mikan_subject_id = ?2 and mikan_subgroup_id = ?3",
-        &[&new_disp_season_num, &mikan_subject_id, &mikan_subgroup_id],
-    ).unwrap();
-}
 
-pub fn find_season_by_disp(disp_series_name: String, disp_season_num: i32) -> Option<AnimeSeason> {
-    let conn = get_connection().unwrap();
-    let mut stmt = conn.prepare("select * from library_anime_season where disp_series_name = ?1 and disp_season_num = ?2").unwrap();
-    let season_iter = stmt.query_map(&[&disp_series_name, &disp_season_num.to_string()], |row| {
-        Ok(AnimeSeason {
-            mikan_subject_id: row.get(0)?,
-            mikan_subgroup_id: row.get(1)?,
-            mikan_subject_name: row.get(2)?,
-            mikan_subject_image: row.get(3)?,
-            bangumi_subject_id: row.get(4)?,
-            bangumi_subject_name: row.get(5)?,
-            bangumi_season_num: row.get(6)?,
-            bangumi_subject_image: row.get(7)?,
-            tmdb_series_id: row.get(8)?,
-            tmdb_series_name: row.get(9)?,
-            tmdb_season_num: row.get(10)?,
-            tmdb_season_name: row.get(11)?,
-            bangumi_to_tmdb_episode_offset: row.get(12)?,
-            disp_series_name: row.get(13)?,
-            disp_season_name: row.get(14)?,
-            disp_subgroup_name: row.get(15)?,
-            disp_season_num: row.get(16)?,
-            conf_tmdb_episode_offset: row.get(17)?,
-            conf_language: row.get(18)?,
-            conf_codec: row.get(19)?,
-            conf_season_num: row.get(20)?,
-            conf_bangumi_episode_offset: row.get(21)?,
-        })
-    }).unwrap();
 
-    for season in season_iter {
-        return season.ok();
-    }
-
-    None
-}
-
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct AnimeSeasonItem {
     pub mikan_item_uuid: String,
     pub mikan_subject_id: i32,
@@ -335,10 +210,26 @@ pub struct AnimeSeasonItem {
     pub mikan_parsed_language: String,
     pub mikan_parsed_codec: String,
     pub disp_episode_num: i32,
-    pub bangumi_parsed_episode_id: i32,
-    pub bangumi_parsed_episode_ep: i32,
-    pub bangumi_parsed_episode_sort: String,
     pub bangumi_episode_type: i32,
+    #[serde(default = "default_imported_item_state")]
+    pub state: ItemState,
+    #[serde(default)]
+    pub state_updated_at: String,   // rfc3339, local time
+    pub conf_placement_subject_id: Option<i32>,     // see `AnimeSeasonItem::conf_placement`
+    pub conf_placement_subgroup_id: Option<i32>,
+    pub conf_disp_episode_num: Option<i32>,
+}
+
+impl AnimeSeasonItem {
+    /// (mikan_subject_id, mikan_subgroup_id) of the season the item is moved to by the user, e.g. a special released in the feed of the main season
+    pub fn conf_placement(&self) -> Option<(i32, i32)> {
+        self.conf_placement_subject_id.zip(self.conf_placement_subgroup_id)
+    }
+}
+
+/// Items exported by older builds carry no state, they were all accepted by the feed filter
+fn default_imported_item_state() -> ItemState {
+    ItemState::Accepted
 }
 
 #[deny(dead_code)]
@@ -360,145 +251,170 @@ pub fn init_cache_library_anime_season_item_table(conn: &Connection) -> Result<(
             mikan_parsed_language text,
             mikan_parsed_codec text,
             disp_episode_num integer,
-            bangumi_parsed_episode_id integer,
-            bangumi_parsed_episode_ep integer,
-            bangumi_parsed_episode_sort text,
-            bangumi_episode_type integer
+            bangumi_episode_type integer,
+            state text default 'discovered',
+            state_updated_at text default '',
+            conf_placement_subject_id integer,
+            conf_placement_subgroup_id integer,
+            conf_disp_episode_num integer
         )",
-        // TODO: bangumi_parsed_episode_id, bangumi_parsed_episode_ep, bangumi_parsed_episode_sort deprecated
         [],
     )?;
     Ok(())
 }
 
-pub fn create_item(item: &crate::module::database::cache::rss::MikanItem) {
-    let conn = get_connection().unwrap();
-    let season = read_season_info(item.mikan_subject_id, item.mikan_subgroup_id).unwrap();
-    let disp_episode_num_offseted = item.mikan_parsed_episode_num + season.conf_tmdb_episode_offset;
+/// Add an item to its season in the library, numbered by the season, see `AnimeSeason::item_disp_episode_num`.
+/// A new item is discovered, an existing one keeps its state and where the user moved it.
+pub fn create_item_in<R: SeasonRepository + SeasonItemRepository>(repo: &R, item: &MikanItem) -> Result<(), Box<dyn Error>> {
+    let season = repo.get_season(item.mikan_subject_id, item.mikan_subgroup_id)?
+        .ok_or_else(|| new_err(format!("Season of item {} not found", item.mikan_item_uuid).as_str()))?;
+    let mut season_item = new_season_item(item, repo.get_season_item(&item.mikan_item_uuid)?);
+    season_item.disp_episode_num = season.item_disp_episode_num(&season_item);
+    repo.upsert_season_item(&season_item)
+}
 
-    conn.execute(
-        "insert or replace into library_anime_season_item (
-            mikan_item_uuid,
-            mikan_subject_id,
-            mikan_subgroup_id,
-            mikan_subject_name,
-            mikan_item_title,
-            mikan_item_magnet_link,
-            mikan_item_pub_date,
-            tmdb_series_name,
-            tmdb_season_name,
-            tmdb_parsed_season_num,
-            bangumi_parsed_season_num,
-            mikan_parsed_episode_num,
-            mikan_parsed_language,
-            mikan_parsed_codec,
-            disp_episode_num,
-            bangumi_parsed_episode_id,
-            bangumi_parsed_episode_ep,
-            bangumi_parsed_episode_sort,
-            bangumi_episode_type
-        ) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19)",
-        &[
-            &item.mikan_item_uuid,
-            &item.mikan_subject_id.to_string(),
-            &item.mikan_subgroup_id.to_string(),
-            &item.mikan_subject_name,
-            &item.mikan_item_title,
-            &item.mikan_item_magnet_link,
-            &item.mikan_item_pub_date,
-            &item.tmdb_series_name,
-            &item.tmdb_season_name,
-            &item.tmdb_parsed_season_num.to_string(),
-            &item.bangumi_parsed_season_num.to_string(),
-            &item.mikan_parsed_episode_num.to_string(),
-            &item.mikan_parsed_language,
-            &item.mikan_parsed_codec,
-            &disp_episode_num_offseted.to_string(),
-            &item.bangumi_parsed_episode_id.to_string(),
-            &item.bangumi_parsed_episode_ep.to_string(),
-            &item.bangumi_parsed_episode_sort.to_string(),
-            &0.to_string(),     // TODO: P0 bangumi_episode_type from parser
-        ],
-    ).unwrap();
+/// Add an item shown in another season as the given episode, e.g. the release of another subgroup filling a missing episode.
+/// Its own season need not be in the library, see `item_placement`.
+pub fn create_placed_item_in<R: SeasonRepository + SeasonItemRepository>(repo: &R, item: &MikanItem, placement: (i32, i32), disp_episode_num: i32) -> Result<(), Box<dyn Error>> {
+    if repo.get_season(placement.0, placement.1)?.is_none() {
+        return Err(new_err(format!("Season {}-{} not found", placement.0, placement.1).as_str()));
+    }
+    let season_item = AnimeSeasonItem {
+        disp_episode_num,
+        conf_placement_subject_id: Some(placement.0),
+        conf_placement_subgroup_id: Some(placement.1),
+        conf_disp_episode_num: Some(disp_episode_num),
+        ..new_season_item(item, repo.get_season_item(&item.mikan_item_uuid)?)
+    };
+    repo.upsert_season_item(&season_item)
 }
 
-pub fn delete_item(item_uuid: &str) {
-    let conn = get_connection().unwrap();
-    conn.execute(
-        "delete from library_anime_season_item where mikan_item_uuid = ?1",
-        &[item_uuid],
-    ).unwrap();
+/// A new item is discovered, an existing one keeps its state and placement, the episode number is left to the caller
+fn new_season_item(item: &MikanItem, existing: Option<AnimeSeasonItem>) -> AnimeSeasonItem {
+    let (state, state_updated_at) = match &existing {
+        Some(existing) => (existing.state, existing.state_updated_at.clone()),
+        None => (ItemState::Discovered, chrono::Local::now().to_rfc3339()),
+    };
+    AnimeSeasonItem {
+        mikan_item_uuid: item.mikan_item_uuid.clone(),
+        mikan_subject_id: item.mikan_subject_id,
+        mikan_subject_name: item.mikan_subject_name.clone(),
+        mikan_subgroup_id: item.mikan_subgroup_id,
+        mikan_item_title: item.mikan_item_title.clone(),
+        mikan_item_magnet_link: item.mikan_item_magnet_link.clone(),
+        mikan_item_pub_date: item.mikan_item_pub_date.clone(),
+        tmdb_series_name: item.tmdb_series_name.clone(),
+        tmdb_season_name: item.tmdb_season_name.clone(),
+        tmdb_parsed_season_num: item.tmdb_parsed_season_num,
+        bangumi_parsed_season_num: item.bangumi_parsed_season_num,
+        mikan_parsed_episode_num: item.mikan_parsed_episode_num,
+        mikan_parsed_language: item.mikan_parsed_language.clone(),
+        mikan_parsed_codec: item.mikan_parsed_codec.clone(),
+        disp_episode_num: -1,
+        bangumi_episode_type: 0,    // TODO: P0 bangumi_episode_type from parser
+        state,
+        state_updated_at,
+        conf_placement_subject_id: existing.as_ref().and_then(|x| x.conf_placement_subject_id),
+        conf_placement_subgroup_id: existing.as_ref().and_then(|x| x.conf_placement_subgroup_id),
+        conf_disp_episode_num: existing.as_ref().and_then(|x| x.conf_disp_episode_num),
+    }
+}
+
+/// Renumber the items of a season after its offset, merge or split changed, see `AnimeSeason::item_disp_episode_num`
+pub fn renumber_items_in<R: SeasonRepository + SeasonItemRepository>(repo: &R, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<(), Box<dyn Error>> {
+    let season = match repo.get_season(mikan_subject_id, mikan_subgroup_id)? {
+        Some(season) => season,
+        None => return Ok(()),
+    };
+    for item in repo.list_season_items(mikan_subject_id, mikan_subgroup_id)? {
+        let disp_episode_num = season.item_disp_episode_num(&item);
+        if disp_episode_num != item.disp_episode_num {
+            repo.set_item_disp_episode_num(&item.mikan_item_uuid, disp_episode_num)?;
+        }
+    }
+    Ok(())
+}
+
+#[allow(dead_code)]
+pub fn delete_item(item_uuid: &str) -> Result<(), Box<dyn Error>> {
+    with_transaction(|repo| repo.delete_season_item(item_uuid))
 }
 
 
 // TODO: rename to read_subject_items
+/// Items of a season in the library, filtered and ignored ones are left out, see `ItemState::is_active`
 pub fn read_season_items(mikan_subject_id: i32, mikan_subgroup_id: i32) -> Vec<AnimeSeasonItem> {
-    let conn = get_connection().unwrap();
-    let mut stmt = conn.prepare("select * from library_anime_season_item where mikan_subject_id = ?1 and mikan_subgroup_id = ?2").unwrap();
-    let item_iter = stmt.query_map(&[&mikan_subject_id, &mikan_subgroup_id], |row| {
-        Ok(AnimeSeasonItem {
-            mikan_item_uuid: row.get(0)?,
-            mikan_subject_id: row.get(1)?,
-            mikan_subgroup_id: row.get(2)?,
-            mikan_subject_name: row.get(3)?,
-            mikan_item_title: row.get(4)?,
-            mikan_item_magnet_link: row.get(5)?,
-            mikan_item_pub_date: row.get(6)?,
-            tmdb_series_name: row.get(7)?,
-            tmdb_season_name: row.get(8)?,
-            tmdb_parsed_season_num: row.get(9)?,
-            bangumi_parsed_season_num: row.get(10)?,
-            mikan_parsed_episode_num: row.get(11)?,
-            mikan_parsed_language: row.get(12)?,
-            mikan_parsed_codec: row.get(13)?,
-            disp_episode_num: row.get(14)?,
-            bangumi_parsed_episode_id: row.get(15)?,
-            bangumi_parsed_episode_ep: row.get(16)?,
-            bangumi_parsed_episode_sort: row.get(17)?,
-            bangumi_episode_type: row.get(18)?,
-        })
-    }).unwrap();
+    with_repository("read season items", |repo| repo.list_season_items(mikan_subject_id, mikan_subgroup_id))
+        .into_iter()
+        .filter(|x| x.state.is_active())
+        .collect()
+}
 
-    let mut items = Vec::new();
-    for item in item_iter {
-        items.push(item.unwrap());
-    }
 
-    items
+/// Items of other seasons moved into a season, filtered and ignored ones are left out
+pub fn read_placed_items(mikan_subject_id: i32, mikan_subgroup_id: i32) -> Vec<AnimeSeasonItem> {
+    with_repository("read placed items", |repo| repo.list_placed_items(mikan_subject_id, mikan_subgroup_id))
+        .into_iter()
+        .filter(|x| x.state.is_active())
+        .collect()
 }
 
 
+/// Items of all seasons in the library, filtered and ignored ones are left out
 pub fn read_all_items() -> Vec<AnimeSeasonItem> {
-    let conn = get_connection().unwrap();
-    let mut stmt = conn.prepare("select * from library_anime_season_item").unwrap();
-    let item_iter = stmt.query_map([], |row| {
-        Ok(AnimeSeasonItem {
-            mikan_item_uuid: row.get(0)?,
-            mikan_subject_id: row.get(1)?,
-            mikan_subgroup_id: row.get(2)?,
-            mikan_subject_name: row.get(3)?,
-            mikan_item_title: row.get(4)?,
-            mikan_item_magnet_link: row.get(5)?,
-            mikan_item_pub_date: row.get(6)?,
-            tmdb_series_name: row.get(7)?,
-            tmdb_season_name: row.get(8)?,
-            tmdb_parsed_season_num: row.get(9)?,
-            bangumi_parsed_season_num: row.get(10)?,
-            mikan_parsed_episode_num: row.get(11)?,
-            mikan_parsed_language: row.get(12)?,
-            mikan_parsed_codec: row.get(13)?,
-            disp_episode_num: row.get(14)?,
-            bangumi_parsed_episode_id: row.get(15)?,
-            bangumi_parsed_episode_ep: row.get(16)?,
-            bangumi_parsed_episode_sort: row.get(17)?,
-            bangumi_episode_type: row.get(18)?,
-        })
-    }).unwrap();
+    with_repository("read items", |repo| repo.list_all_items())
+        .into_iter()
+        .filter(|x| x.state.is_active())
+        .collect()
+}
 
-    let mut items = Vec::new();
-    for item in item_iter {
-        items.push(item.unwrap());
+#[derive(Debug, Clone)]
+pub struct EpisodeOffsetProposalRecord {
+    pub mikan_subject_id: i32,
+    pub mikan_subgroup_id: i32,
+    pub ep_num_min: i32,
+    pub ep_num_max: i32,
+    pub tmdb_episode_offset: i32,
+    pub bangumi_episode_offset: i32,
+    pub confidence: i32,
+    pub explanation: String,
+    pub applied: bool,
+}
+
+#[deny(dead_code)]
+pub fn init_library_episode_offset_proposal_table(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    conn.execute(
+        "create table if not exists library_episode_offset_proposal (
+            mikan_subject_id integer,
+            mikan_subgroup_id integer,
+            ep_num_min integer,
+            ep_num_max integer,
+            tmdb_episode_offset integer,
+            bangumi_episode_offset integer,
+            confidence integer,
+            explanation text,
+            applied integer default 0,
+            primary key(mikan_subject_id,mikan_subgroup_id)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Save an inferred offset proposal. The `applied` flag is kept as it is, so that a proposal is
+/// only applied automatically once per season.
+pub fn save_episode_offset_proposal(proposal: &EpisodeOffsetProposalRecord) {
+    if let Err(e) = with_transaction(|repo| repo.save_episode_offset_proposal(proposal)) {
+        log::error!("Failed to save the episode offset proposal of {}-{}: {}", proposal.mikan_subject_id, proposal.mikan_subgroup_id, e);
     }
+}
 
-    items
+pub fn read_episode_offset_proposal(mikan_subject_id: i32, mikan_subgroup_id: i32) -> Option<EpisodeOffsetProposalRecord> {
+    with_repository("read episode offset proposal", |repo| repo.get_episode_offset_proposal(mikan_subject_id, mikan_subgroup_id))
+}
+
+pub fn set_episode_offset_proposal_applied(mikan_subject_id: i32, mikan_subgroup_id: i32) {
+    if let Err(e) = with_transaction(|repo| repo.set_episode_offset_proposal_applied(mikan_subject_id, mikan_subgroup_id)) {
+        log::error!("Failed to mark the episode offset proposal of {}-{} applied: {}", mikan_subject_id, mikan_subgroup_id, e);
+    }
 }
@@ -0,0 +1,56 @@
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use lazy_static::lazy_static;
+
+use crate::module::database::item_state::ItemState;
+
+/// A change of the media library, published by the repository once it is committed, see `transact`.
+/// Seasons are keyed by (mikan_subject_id, mikan_subgroup_id), items by their own season.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LibraryEvent {
+    SeasonAdded { season: (i32, i32) },
+    SeasonUpdated { season: (i32, i32) },   // metadata, config, arrangement, status, series or gaps
+    SeasonRemoved { season: (i32, i32) },
+    ItemAdded { season: (i32, i32), mikan_item_uuid: String },
+    ItemUpdated { season: (i32, i32), mikan_item_uuid: String },  // number or placement
+    ItemRemoved { season: (i32, i32), mikan_item_uuid: String },
+    ItemStateChanged { season: (i32, i32), mikan_item_uuid: String, state: ItemState },
+    WatchStatusChanged { bangumi_subject_id: i32 },  // collection status updated on Bangumi by the scrobbler
+}
+
+impl LibraryEvent {
+    /// Season the change is about, `None` if it is not about the library itself
+    pub fn season(&self) -> Option<(i32, i32)> {
+        match self {
+            LibraryEvent::SeasonAdded { season }
+            | LibraryEvent::SeasonUpdated { season }
+            | LibraryEvent::SeasonRemoved { season }
+            | LibraryEvent::ItemAdded { season, .. }
+            | LibraryEvent::ItemUpdated { season, .. }
+            | LibraryEvent::ItemRemoved { season, .. }
+            | LibraryEvent::ItemStateChanged { season, .. } => Some(*season),
+            LibraryEvent::WatchStatusChanged { .. } => None,
+        }
+    }
+}
+
+lazy_static! {
+    static ref SUBSCRIBERS: Mutex<Vec<Sender<LibraryEvent>>> = Mutex::new(Vec::new());
+}
+
+/// Receive every library event published from now on
+pub fn subscribe_library_events() -> Receiver<LibraryEvent> {
+    let (tx, rx) = channel();
+    SUBSCRIBERS.lock().unwrap().push(tx);
+    rx
+}
+
+/// Send events to every subscriber, those whose receiver is dropped are forgotten
+pub fn publish_library_events(events: Vec<LibraryEvent>) {
+    if events.is_empty() {
+        return;
+    }
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    subscribers.retain(|subscriber| events.iter().all(|event| subscriber.send(event.clone()).is_ok()));
+}
\ No newline at end of file
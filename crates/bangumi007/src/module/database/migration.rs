@@ -0,0 +1,333 @@
+use std::error::Error;
+
+use rusqlite::{Connection, Transaction};
+
+use crate::module::database::activity::init_activity_table;
+use crate::module::database::cache::rss::{init_cache_bangumi_episode_table, init_cache_mikan_item_table, init_cache_mikan_subject_table};
+use crate::module::database::cache::title::init_cache_mikan_subject_title_table;
+use crate::module::database::cache::tmdb::{init_cache_tmdb_candidate_table, init_conf_tmdb_series_choice_table};
+use crate::module::database::cache::xref::init_cache_anime_xref_table;
+use crate::module::database::episode_gap::init_library_episode_gap_table;
+use crate::module::database::search::init_search_index;
+use crate::module::database::series::{init_library_series_table, link_existing_seasons};
+use crate::module::database::library::{init_cache_library_anime_season_item_table, init_cache_library_anime_season_table, init_library_episode_offset_proposal_table, init_library_season_tombstone_table};
+use crate::module::database::subject_override::init_conf_mikan_subject_override_table;
+use crate::module::utils::error::new_err;
+
+/// A schema change, applied once in a transaction when `PRAGMA user_version` is below `version`.
+pub struct Migration {
+    pub version: i32,
+    pub description: &'static str,
+    pub up: fn(&Transaction) -> Result<(), Box<dyn Error>>,
+}
+
+/// Ordered by version, never edit or reorder an applied migration, append a new one instead.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create tables",
+        up: migrate_create_tables,
+    },
+    Migration {
+        version: 2,
+        description: "add columns missing in databases created by older versions",
+        up: migrate_add_missing_columns,
+    },
+    Migration {
+        version: 3,
+        description: "drop deprecated bangumi_parsed_episode_* columns",
+        up: migrate_drop_bangumi_parsed_episode_columns,
+    },
+    Migration {
+        version: 4,
+        description: "create activity table",
+        up: migrate_create_activity_table,
+    },
+    Migration {
+        version: 5,
+        description: "add item state columns",
+        up: migrate_add_item_state_columns,
+    },
+    Migration {
+        version: 6,
+        description: "create full-text search index",
+        up: migrate_create_search_index,
+    },
+    Migration {
+        version: 7,
+        description: "create series table and link seasons to series",
+        up: migrate_create_series_table,
+    },
+    Migration {
+        version: 8,
+        description: "add season merge and split, item placement columns",
+        up: migrate_add_arrangement_columns,
+    },
+    Migration {
+        version: 9,
+        description: "create season tombstone table",
+        up: migrate_create_season_tombstone_table,
+    },
+    Migration {
+        version: 10,
+        description: "create episode gap table",
+        up: migrate_create_episode_gap_table,
+    },
+    Migration {
+        version: 11,
+        description: "add season status column",
+        up: migrate_add_season_status_column,
+    },
+    Migration {
+        version: 12,
+        description: "add season tombstone feed column",
+        up: migrate_add_season_tombstone_feed_column,
+    },
+    Migration {
+        version: 13,
+        description: "add mikan item subgroup name column",
+        up: migrate_add_mikan_item_subgroup_name_column,
+    },
+];
+
+/// Schema version of this build, i.e. the version of the last migration.
+pub fn schema_version() -> i32 {
+    MIGRATIONS.last().map_or(0, |x| x.version)
+}
+
+#[deny(dead_code)]
+fn migrate_create_tables(tx: &Transaction) -> Result<(), Box<dyn Error>> {
+    init_cache_mikan_item_table(tx)?;
+    init_cache_mikan_subject_table(tx)?;
+    init_cache_library_anime_season_table(tx)?;
+    init_cache_library_anime_season_item_table(tx)?;
+    init_cache_bangumi_episode_table(tx)?;
+    init_library_episode_offset_proposal_table(tx)?;
+    init_cache_tmdb_candidate_table(tx)?;
+    init_conf_tmdb_series_choice_table(tx)?;
+    init_conf_mikan_subject_override_table(tx)?;
+    init_cache_mikan_subject_title_table(tx)?;
+    init_cache_anime_xref_table(tx)?;
+    Ok(())
+}
+
+fn migrate_add_missing_columns(tx: &Transaction) -> Result<(), Box<dyn Error>> {
+    add_column_if_missing(tx, "cache_mikan_subject", "bangumi_to_tmdb_episode_offset", "integer default 0")?;
+    add_column_if_missing(tx, "cache_mikan_subject", "anilist_id", "integer default -1")?;
+    add_column_if_missing(tx, "cache_mikan_subject", "mal_id", "integer default -1")?;
+    add_column_if_missing(tx, "cache_mikan_subject", "anidb_id", "integer default -1")?;
+    add_column_if_missing(tx, "library_anime_season", "conf_season_num", "integer default -1")?;
+    add_column_if_missing(tx, "library_anime_season", "conf_bangumi_episode_offset", "integer default 0")?;
+    add_column_if_missing(tx, "library_anime_season", "anilist_id", "integer default -1")?;
+    add_column_if_missing(tx, "library_anime_season", "mal_id", "integer default -1")?;
+    add_column_if_missing(tx, "library_anime_season", "anidb_id", "integer default -1")?;
+    Ok(())
+}
+
+fn migrate_drop_bangumi_parsed_episode_columns(tx: &Transaction) -> Result<(), Box<dyn Error>> {
+    for table in ["cache_mikan_item", "library_anime_season_item"] {
+        for column in ["bangumi_parsed_episode_id", "bangumi_parsed_episode_ep", "bangumi_parsed_episode_sort"] {
+            drop_column_if_exists(tx, table, column)?;
+        }
+    }
+    Ok(())
+}
+
+fn migrate_create_activity_table(tx: &Transaction) -> Result<(), Box<dyn Error>> {
+    init_activity_table(tx)
+}
+
+/// Items of older builds were already accepted by the feed filter, the downloader sync moves them on
+fn migrate_add_item_state_columns(tx: &Transaction) -> Result<(), Box<dyn Error>> {
+    add_column_if_missing(tx, "library_anime_season_item", "state", "text default 'accepted'")?;
+    add_column_if_missing(tx, "library_anime_season_item", "state_updated_at", "text default ''")?;
+    Ok(())
+}
+
+fn migrate_create_search_index(tx: &Transaction) -> Result<(), Box<dyn Error>> {
+    init_search_index(tx)
+}
+
+/// Seasons were grouped by display name before, they are linked to series once
+fn migrate_create_series_table(tx: &Transaction) -> Result<(), Box<dyn Error>> {
+    init_library_series_table(tx)?;
+    add_column_if_missing(tx, "library_anime_season", "series_id", "integer default -1")?;
+    link_existing_seasons(tx)
+}
+
+/// Nothing is merged, split or moved in older databases, the columns are left null
+fn migrate_add_arrangement_columns(tx: &Transaction) -> Result<(), Box<dyn Error>> {
+    for column in ["merged_subject_id", "merged_subgroup_id", "merged_episode_offset", "split_episode_num", "split_season_num"] {
+        add_column_if_missing(tx, "library_anime_season", column, "integer")?;
+    }
+    for column in ["conf_placement_subject_id", "conf_placement_subgroup_id", "conf_disp_episode_num"] {
+        add_column_if_missing(tx, "library_anime_season_item", column, "integer")?;
+    }
+    Ok(())
+}
+
+fn migrate_create_season_tombstone_table(tx: &Transaction) -> Result<(), Box<dyn Error>> {
+    init_library_season_tombstone_table(tx)
+}
+
+fn migrate_create_episode_gap_table(tx: &Transaction) -> Result<(), Box<dyn Error>> {
+    init_library_episode_gap_table(tx)
+}
+
+/// Seasons of older builds are airing until the next feed update computes their status
+fn migrate_add_season_status_column(tx: &Transaction) -> Result<(), Box<dyn Error>> {
+    add_column_if_missing(tx, "library_anime_season", "status", "text default 'airing'")
+}
+
+/// Tombstones of older builds are only restored after their subscription is seen inactive once
+fn migrate_add_season_tombstone_feed_column(tx: &Transaction) -> Result<(), Box<dyn Error>> {
+    add_column_if_missing(tx, "library_season_tombstone", "feed_active", "integer default 1")
+}
+
+/// Older builds named every season by a placeholder, it is dropped so that search does not match it,
+/// the next item of the season names it, see `update_library`
+fn migrate_add_mikan_item_subgroup_name_column(tx: &Transaction) -> Result<(), Box<dyn Error>> {
+    add_column_if_missing(tx, "cache_mikan_item", "mikan_subgroup_name", "text default ''")?;
+    tx.execute("update library_anime_season set disp_subgroup_name = '' where disp_subgroup_name = '字幕组名称'", [])?;
+    Ok(())
+}
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> Result<bool, Box<dyn Error>> {
+    let mut stmt = conn.prepare(&format!("pragma table_info({})", table))?;
+    let exists = stmt.query_map([], |row| row.get::<_, String>(1))?
+        .filter_map(|x| x.ok())
+        .any(|x| x == column);
+    Ok(exists)
+}
+
+/// Add a column to an existing table, for tables created before the column was introduced.
+/// `definition` is the column type with constraints, e.g. `integer default -1`.
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<(), Box<dyn Error>> {
+    if !column_exists(conn, table, column)? {
+        log::info!("Adding column {}.{}", table, column);
+        conn.execute(&format!("alter table {} add column {} {}", table, column, definition), [])?;
+    }
+    Ok(())
+}
+
+fn drop_column_if_exists(conn: &Connection, table: &str, column: &str) -> Result<(), Box<dyn Error>> {
+    if column_exists(conn, table, column)? {
+        log::info!("Dropping column {}.{}", table, column);
+        conn.execute(&format!("alter table {} drop column {}", table, column), [])?;
+    }
+    Ok(())
+}
+
+pub fn get_user_version(conn: &Connection) -> Result<i32, Box<dyn Error>> {
+    Ok(conn.pragma_query_value(None, "user_version", |row| row.get(0))?)
+}
+
+/// Bring a database to the schema version of this build
+///
+/// ## Input
+///
+/// conn : `&mut Connection`
+///
+/// ## Procedure
+///
+/// 1. Read `PRAGMA user_version`, refuse a database written by a newer build
+/// 2. Apply every migration above the version in its own transaction, bumping `user_version` in the same transaction,
+///    so that a failed migration leaves the database at the previous version
+///
+/// ## Output
+///
+/// Version before migrating : `i32`
+pub fn migrate_database(conn: &mut Connection) -> Result<i32, Box<dyn Error>> {
+    let current_version = get_user_version(conn)?;
+    if current_version > schema_version() {
+        return Err(new_err(format!(
+            "Database schema version {} is newer than version {} supported by this build, please upgrade Bangumi007",
+            current_version, schema_version()).as_str()));
+    }
+
+    for migration in MIGRATIONS.iter().filter(|x| x.version > current_version) {
+        log::info!("Migrating database to version {}: {}", migration.version, migration.description);
+        let tx = conn.transaction()?;
+        (migration.up)(&tx)
+            .map_err(|e| new_err(format!("Failed to migrate database to version {}: {}", migration.version, e).as_str()))?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+    }
+    Ok(current_version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_database() {
+        // Fresh database
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(migrate_database(&mut conn).unwrap(), 0);
+        assert_eq!(get_user_version(&conn).unwrap(), schema_version());
+        assert!(column_exists(&conn, "library_anime_season", "anidb_id").unwrap());
+        assert!(column_exists(&conn, "activity", "kind").unwrap());
+        assert!(column_exists(&conn, "library_season_tombstone", "feed_active").unwrap());
+        assert!(column_exists(&conn, "library_episode_gap", "replacement_uuid").unwrap());
+        // Migrating again is a no-op
+        assert_eq!(migrate_database(&mut conn).unwrap(), schema_version());
+
+        // Unversioned database of an older build
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "create table library_anime_season_item (
+                mikan_item_uuid text primary key,
+                disp_episode_num integer,
+                bangumi_parsed_episode_id integer,
+                bangumi_parsed_episode_ep integer,
+                bangumi_parsed_episode_sort text,
+                bangumi_episode_type integer
+            );
+            insert into library_anime_season_item values ('uuid', 3, -1, -1, '', 0);
+            create table library_anime_season (
+                mikan_subject_id integer,
+                mikan_subgroup_id integer,
+                mikan_subject_name text,
+                bangumi_subject_id integer,
+                bangumi_subject_name text,
+                tmdb_series_id integer,
+                tmdb_series_name text,
+                tmdb_season_name text,
+                disp_series_name text,
+                disp_season_name text,
+                disp_subgroup_name text,
+                disp_season_num integer
+            );
+            insert into library_anime_season (mikan_subject_id, mikan_subgroup_id, tmdb_series_id, disp_series_name, disp_subgroup_name, disp_season_num)
+            values (3141, 382, 209867, '葬送的芙莉莲', '字幕组名称', 1), (3310, 382, 209867, 'Frieren', '字幕组名称', 2), (2968, 382, -1, 'SPY×FAMILY', '字幕组名称', 1);"
+        ).unwrap();
+        migrate_database(&mut conn).unwrap();
+        assert!(!column_exists(&conn, "library_anime_season_item", "bangumi_parsed_episode_sort").unwrap());
+        assert!(column_exists(&conn, "library_anime_season", "conf_bangumi_episode_offset").unwrap());
+        assert!(column_exists(&conn, "library_anime_season_item", "conf_disp_episode_num").unwrap());
+        let status: String = conn.query_row("select status from library_anime_season where mikan_subject_id = 3141", [], |row| row.get(0)).unwrap();
+        assert_eq!(status, "airing");
+        // The placeholder subgroup name is not searched
+        let found: i64 = conn.query_row("select count(*) from search_season where search_season match '字幕组'", [], |row| row.get(0)).unwrap();
+        assert_eq!(found, 0);
+        let disp_episode_num: i32 = conn.query_row("select disp_episode_num from library_anime_season_item", [], |row| row.get(0)).unwrap();
+        assert_eq!(disp_episode_num, 3);
+        let state: String = conn.query_row("select state from library_anime_season_item", [], |row| row.get(0)).unwrap();
+        assert_eq!(state, "accepted");
+        // Existing rows are indexed for search
+        let found: i64 = conn.query_row("select count(*) from search_season where search_season match '芙莉莲'", [], |row| row.get(0)).unwrap();
+        assert_eq!(found, 1);
+        // Seasons of the same TMDB series are linked to one series
+        let series: Vec<i32> = conn.prepare("select series_id from library_anime_season order by mikan_subject_id").unwrap()
+            .query_map([], |row| row.get(0)).unwrap().map(|x| x.unwrap()).collect();
+        assert!(series[0] > 0 && series[1] != series[0] && series[1] == series[2]);
+        let name: String = conn.query_row("select disp_series_name from library_series where series_id = ?1", [series[1]], |row| row.get(0)).unwrap();
+        assert_eq!(name, "葬送的芙莉莲");
+
+        // Database of a newer build
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", schema_version() + 1).unwrap();
+        assert!(migrate_database(&mut conn).is_err());
+    }
+}
@@ -1,6 +1,17 @@
 pub use base::*;
 
+pub mod activity;
+pub mod backup;
 pub mod base;
 pub mod cache;
+pub mod episode_gap;
+pub mod export;
+pub mod item_state;
 pub mod library;
-
+pub mod library_event;
+pub mod migration;
+pub mod repository;
+pub mod search;
+pub mod season_status;
+pub mod series;
+pub mod subject_override;
\ No newline at end of file
@@ -0,0 +1,1735 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+
+use rusqlite::{Connection, named_params, params_from_iter, Row, ToSql, TransactionBehavior};
+
+use crate::module::database::{get_connection, sql_placeholders, SQL_BATCH_SIZE};
+use crate::module::database::activity::{Activity, ActivityFilter};
+use crate::module::database::cache::rss::{MikanItem, MikanSubject};
+use crate::module::database::cache::title::TITLE_KIND_FOLDER;
+use crate::module::database::episode_gap::EpisodeGap;
+use crate::module::database::item_state::ItemState;
+use crate::module::database::library::{AnimeSeason, AnimeSeasonItem, EpisodeOffsetProposalRecord, SeasonTombstone};
+use crate::module::database::library_event::{LibraryEvent, publish_library_events};
+use crate::module::database::search::{ReleaseSearchResult, search_condition};
+use crate::module::database::season_status::SeasonStatus;
+use crate::module::database::series::AnimeSeries;
+use crate::module::database::subject_override::MikanSubjectOverride;
+
+/// Build an entity from a `select *` row by column names, so that the column order does not matter.
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> rusqlite::Result<Self>;
+}
+
+impl FromRow for AnimeSeason {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(AnimeSeason {
+            mikan_subject_id: row.get("mikan_subject_id")?,
+            mikan_subgroup_id: row.get("mikan_subgroup_id")?,
+            mikan_subject_name: row.get("mikan_subject_name")?,
+            mikan_subject_image: row.get("mikan_subject_image")?,
+            bangumi_subject_id: row.get("bangumi_subject_id")?,
+            bangumi_subject_name: row.get("bangumi_subject_name")?,
+            bangumi_season_num: row.get("bangumi_season_num")?,
+            bangumi_subject_image: row.get("bangumi_subject_image")?,
+            tmdb_series_id: row.get("tmdb_series_id")?,
+            tmdb_series_name: row.get("tmdb_series_name")?,
+            tmdb_season_num: row.get("tmdb_season_num")?,
+            tmdb_season_name: row.get("tmdb_season_name")?,
+            bangumi_to_tmdb_episode_offset: row.get("bangumi_to_tmdb_episode_offset")?,
+            disp_series_name: row.get("disp_series_name")?,
+            disp_season_name: row.get("disp_season_name")?,
+            disp_subgroup_name: row.get("disp_subgroup_name")?,
+            disp_season_num: row.get("disp_season_num")?,
+            conf_tmdb_episode_offset: row.get("conf_tmdb_episode_offset")?,
+            conf_language: row.get("conf_language")?,
+            conf_codec: row.get("conf_codec")?,
+            conf_season_num: row.get("conf_season_num")?,
+            conf_bangumi_episode_offset: row.get("conf_bangumi_episode_offset")?,
+            anilist_id: row.get("anilist_id")?,
+            mal_id: row.get("mal_id")?,
+            anidb_id: row.get("anidb_id")?,
+            series_id: row.get("series_id")?,
+            merged_subject_id: row.get("merged_subject_id")?,
+            merged_subgroup_id: row.get("merged_subgroup_id")?,
+            merged_episode_offset: row.get("merged_episode_offset")?,
+            split_episode_num: row.get("split_episode_num")?,
+            split_season_num: row.get("split_season_num")?,
+            status: row.get("status")?,
+        })
+    }
+}
+
+impl FromRow for AnimeSeries {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(AnimeSeries {
+            series_id: row.get("series_id")?,
+            disp_series_name: row.get("disp_series_name")?,
+            bangumi_subject_id: row.get("bangumi_subject_id")?,
+            bangumi_subject_name: row.get("bangumi_subject_name")?,
+            tmdb_series_id: row.get("tmdb_series_id")?,
+            tmdb_series_name: row.get("tmdb_series_name")?,
+            anilist_id: row.get("anilist_id")?,
+            mal_id: row.get("mal_id")?,
+            anidb_id: row.get("anidb_id")?,
+        })
+    }
+}
+
+impl FromRow for AnimeSeasonItem {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(AnimeSeasonItem {
+            mikan_item_uuid: row.get("mikan_item_uuid")?,
+            mikan_subject_id: row.get("mikan_subject_id")?,
+            mikan_subject_name: row.get("mikan_subject_name")?,
+            mikan_subgroup_id: row.get("mikan_subgroup_id")?,
+            mikan_item_title: row.get("mikan_item_title")?,
+            mikan_item_magnet_link: row.get("mikan_item_magnet_link")?,
+            mikan_item_pub_date: row.get("mikan_item_pub_date")?,
+            tmdb_series_name: row.get("tmdb_series_name")?,
+            tmdb_season_name: row.get("tmdb_season_name")?,
+            tmdb_parsed_season_num: row.get("tmdb_parsed_season_num")?,
+            bangumi_parsed_season_num: row.get("bangumi_parsed_season_num")?,
+            mikan_parsed_episode_num: row.get("mikan_parsed_episode_num")?,
+            mikan_parsed_language: row.get("mikan_parsed_language")?,
+            mikan_parsed_codec: row.get("mikan_parsed_codec")?,
+            disp_episode_num: row.get("disp_episode_num")?,
+            bangumi_episode_type: row.get("bangumi_episode_type")?,
+            state: row.get("state")?,
+            state_updated_at: row.get("state_updated_at")?,
+            conf_placement_subject_id: row.get("conf_placement_subject_id")?,
+            conf_placement_subgroup_id: row.get("conf_placement_subgroup_id")?,
+            conf_disp_episode_num: row.get("conf_disp_episode_num")?,
+        })
+    }
+}
+
+impl FromRow for MikanItem {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(MikanItem {
+            mikan_item_uuid: row.get("mikan_item_uuid")?,
+            mikan_subject_id: row.get("mikan_subject_id")?,
+            mikan_subject_name: row.get("mikan_subject_name")?,
+            mikan_subgroup_id: row.get("mikan_subgroup_id")?,
+            mikan_subgroup_name: row.get("mikan_subgroup_name")?,
+            mikan_item_title: row.get("mikan_item_title")?,
+            mikan_item_magnet_link: row.get("mikan_item_magnet_link")?,
+            mikan_item_pub_date: row.get("mikan_item_pub_date")?,
+            tmdb_series_name: row.get("tmdb_series_name")?,
+            tmdb_season_name: row.get("tmdb_season_name")?,
+            tmdb_parsed_season_num: row.get("tmdb_parsed_season_num")?,
+            bangumi_parsed_season_num: row.get("bangumi_parsed_season_num")?,
+            mikan_parsed_episode_num: row.get("mikan_parsed_episode_num")?,
+            mikan_parsed_language: row.get("mikan_parsed_language")?,
+            mikan_parsed_codec: row.get("mikan_parsed_codec")?,
+        })
+    }
+}
+
+impl FromRow for MikanSubject {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(MikanSubject {
+            mikan_subject_id: row.get("mikan_subject_id")?,
+            mikan_subject_image_url: row.get("mikan_subject_image_url")?,
+            bangumi_subject_id: row.get("bangumi_subject_id")?,
+            bangumi_subject_name: row.get("bangumi_subject_name")?,
+            bangumi_season_num: row.get("bangumi_season_num")?,
+            bangumi_subject_image_url: row.get("bangumi_subject_image_url")?,
+            tmdb_series_id: row.get("tmdb_series_id")?,
+            tmdb_series_name: row.get("tmdb_series_name")?,
+            tmdb_season_num: row.get("tmdb_season_num")?,
+            tmdb_season_name: row.get("tmdb_season_name")?,
+            bangumi_to_tmdb_episode_offset: row.get("bangumi_to_tmdb_episode_offset")?,
+            anilist_id: row.get("anilist_id")?,
+            mal_id: row.get("mal_id")?,
+            anidb_id: row.get("anidb_id")?,
+        })
+    }
+}
+
+impl FromRow for MikanSubjectOverride {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(MikanSubjectOverride {
+            mikan_subject_id: row.get("mikan_subject_id")?,
+            bangumi_subject_id: row.get("bangumi_subject_id")?,
+            tmdb_series_id: row.get("tmdb_series_id")?,
+            tmdb_season_num: row.get("tmdb_season_num")?,
+            disp_series_name: row.get("disp_series_name")?,
+            disp_season_name: row.get("disp_season_name")?,
+        })
+    }
+}
+
+impl FromRow for ReleaseSearchResult {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(ReleaseSearchResult {
+            item: MikanItem::from_row(row)?,
+            state: row.get("library_state")?,
+        })
+    }
+}
+
+impl FromRow for EpisodeGap {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(EpisodeGap {
+            mikan_subject_id: row.get("mikan_subject_id")?,
+            mikan_subgroup_id: row.get("mikan_subgroup_id")?,
+            bangumi_episode_sort: row.get("bangumi_episode_sort")?,
+            mikan_parsed_episode_num: row.get("mikan_parsed_episode_num")?,
+            disp_episode_num: row.get("disp_episode_num")?,
+            episode_name: row.get("episode_name")?,
+            episode_airdate: row.get("episode_airdate")?,
+            replacement_uuid: row.get("replacement_uuid")?,
+            replacement_title: row.get("replacement_title")?,
+        })
+    }
+}
+
+impl FromRow for SeasonTombstone {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(SeasonTombstone {
+            mikan_subject_id: row.get("mikan_subject_id")?,
+            mikan_subgroup_id: row.get("mikan_subgroup_id")?,
+            disp_series_name: row.get("disp_series_name")?,
+            removed_at: row.get("removed_at")?,
+            feed_active: row.get("feed_active")?,
+        })
+    }
+}
+
+impl FromRow for EpisodeOffsetProposalRecord {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(EpisodeOffsetProposalRecord {
+            mikan_subject_id: row.get("mikan_subject_id")?,
+            mikan_subgroup_id: row.get("mikan_subgroup_id")?,
+            ep_num_min: row.get("ep_num_min")?,
+            ep_num_max: row.get("ep_num_max")?,
+            tmdb_episode_offset: row.get("tmdb_episode_offset")?,
+            bangumi_episode_offset: row.get("bangumi_episode_offset")?,
+            confidence: row.get("confidence")?,
+            explanation: row.get("explanation")?,
+            applied: row.get("applied")?,
+        })
+    }
+}
+
+impl FromRow for Activity {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Activity {
+            id: row.get("id")?,
+            timestamp: row.get("timestamp")?,
+            kind: row.get("kind")?,
+            mikan_subject_id: row.get("mikan_subject_id")?,
+            mikan_subgroup_id: row.get("mikan_subgroup_id")?,
+            episode_num: row.get("episode_num")?,
+            title: row.get("title")?,
+            before_value: row.get("before_value")?,
+            after_value: row.get("after_value")?,
+            success: row.get("success")?,
+            error: row.get("error")?,
+        })
+    }
+}
+
+/// Seasons of the media library, keyed by Mikan subject and subgroup
+pub trait SeasonRepository {
+    fn get_season(&self, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<Option<AnimeSeason>, Box<dyn Error>>;
+
+    fn list_seasons(&self) -> Result<Vec<AnimeSeason>, Box<dyn Error>>;
+
+    fn upsert_season(&self, season: &AnimeSeason) -> Result<(), Box<dyn Error>>;
+
+    fn delete_season(&self, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<(), Box<dyn Error>>;
+
+    /// Save the `conf_*` fields of a season, other fields are left untouched.
+    fn update_season_conf(&self, season: &AnimeSeason) -> Result<(), Box<dyn Error>>;
+
+    fn set_season_disp_season_num(&self, mikan_subject_id: i32, mikan_subgroup_id: i32, disp_season_num: i32) -> Result<(), Box<dyn Error>>;
+
+    /// Save the merge and the split of a season, see `AnimeSeason::merged_into` and `AnimeSeason::split_at`
+    fn update_season_arrangement(&self, season: &AnimeSeason) -> Result<(), Box<dyn Error>>;
+
+    fn set_season_status(&self, mikan_subject_id: i32, mikan_subgroup_id: i32, status: SeasonStatus) -> Result<(), Box<dyn Error>>;
+}
+
+/// Series grouping the seasons of the media library, see `link_season_series_in`
+pub trait SeriesRepository {
+    fn get_series(&self, series_id: i32) -> Result<Option<AnimeSeries>, Box<dyn Error>>;
+
+    fn list_series(&self) -> Result<Vec<AnimeSeries>, Box<dyn Error>>;
+
+    fn find_series_by_tmdb_id(&self, tmdb_series_id: i32) -> Result<Option<AnimeSeries>, Box<dyn Error>>;
+
+    /// Series whose display, TMDB or Bangumi name is the given one, or with a season imported from a folder of the name
+    fn find_series_by_name(&self, name: &str) -> Result<Option<AnimeSeries>, Box<dyn Error>>;
+
+    /// Remember the name of the series folder a subject was imported from
+    fn save_series_folder_name(&self, mikan_subject_id: i32, folder_name: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Insert a series, the id of the series is ignored
+    ///
+    /// ## Output
+    ///
+    /// series_id of the new series : `i32`
+    fn insert_series(&self, series: &AnimeSeries) -> Result<i32, Box<dyn Error>>;
+
+    /// Copy the names and external ids of the representative season, the first one with TMDB metadata
+    /// by season number, and delete the series if it has no season left.
+    fn sync_series(&self, series_id: i32) -> Result<(), Box<dyn Error>>;
+
+    fn set_season_series_id(&self, mikan_subject_id: i32, mikan_subgroup_id: i32, series_id: i32) -> Result<(), Box<dyn Error>>;
+
+    fn find_season_in_series(&self, series_id: i32, disp_season_num: i32) -> Result<Option<AnimeSeason>, Box<dyn Error>>;
+}
+
+/// Episodes of the seasons in the media library
+pub trait SeasonItemRepository {
+    fn get_season_item(&self, mikan_item_uuid: &str) -> Result<Option<AnimeSeasonItem>, Box<dyn Error>>;
+
+    fn list_season_items(&self, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<Vec<AnimeSeasonItem>, Box<dyn Error>>;
+
+    fn list_all_items(&self) -> Result<Vec<AnimeSeasonItem>, Box<dyn Error>>;
+
+    /// Items of other seasons moved into a season, see `AnimeSeasonItem::conf_placement`
+    fn list_placed_items(&self, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<Vec<AnimeSeasonItem>, Box<dyn Error>>;
+
+    fn upsert_season_item(&self, item: &AnimeSeasonItem) -> Result<(), Box<dyn Error>>;
+
+    fn delete_season_item(&self, mikan_item_uuid: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Save the state of an item, see `ItemState::on` for the transitions.
+    fn set_item_state(&self, mikan_item_uuid: &str, state: ItemState) -> Result<(), Box<dyn Error>>;
+
+    /// See `renumber_items_in`
+    fn set_item_disp_episode_num(&self, mikan_item_uuid: &str, disp_episode_num: i32) -> Result<(), Box<dyn Error>>;
+
+    /// Save where the user moved an item, `None` puts it back to its own season and numbering
+    fn set_item_placement(&self, mikan_item_uuid: &str, placement: Option<(i32, i32)>, disp_episode_num: Option<i32>) -> Result<(), Box<dyn Error>>;
+}
+
+/// Seasons removed by the user, see `remove_season_in`
+pub trait SeasonTombstoneRepository {
+    fn list_season_tombstones(&self) -> Result<Vec<SeasonTombstone>, Box<dyn Error>>;
+
+    /// `feed_active`: whether a subscription of the season is still active after the removal
+    fn insert_season_tombstone(&self, season: &AnimeSeason, feed_active: bool) -> Result<(), Box<dyn Error>>;
+
+    fn set_season_tombstone_feed_active(&self, mikan_subject_id: i32, mikan_subgroup_id: i32, feed_active: bool) -> Result<(), Box<dyn Error>>;
+
+    /// Let the feed items of the season be added again
+    fn delete_season_tombstone(&self, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<(), Box<dyn Error>>;
+
+    fn is_season_tombstoned(&self, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<bool, Box<dyn Error>>;
+}
+
+/// Episode offsets inferred for the seasons, see `auto_episode_offset_infer`
+pub trait EpisodeOffsetProposalRepository {
+    fn get_episode_offset_proposal(&self, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<Option<EpisodeOffsetProposalRecord>, Box<dyn Error>>;
+
+    /// Save an inferred proposal, its `applied` flag is kept as saved
+    fn save_episode_offset_proposal(&self, proposal: &EpisodeOffsetProposalRecord) -> Result<(), Box<dyn Error>>;
+
+    fn set_episode_offset_proposal_applied(&self, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<(), Box<dyn Error>>;
+}
+
+/// Missing episodes of the seasons, see `auto_episode_gap_detect`
+pub trait EpisodeGapRepository {
+    fn list_episode_gaps(&self) -> Result<Vec<EpisodeGap>, Box<dyn Error>>;
+
+    fn list_season_gaps(&self, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<Vec<EpisodeGap>, Box<dyn Error>>;
+
+    fn get_episode_gap(&self, mikan_subject_id: i32, mikan_subgroup_id: i32, bangumi_episode_sort: i32) -> Result<Option<EpisodeGap>, Box<dyn Error>>;
+
+    /// Replace the gaps of a season by the ones just detected
+    fn replace_season_gaps(&self, mikan_subject_id: i32, mikan_subgroup_id: i32, gaps: &[EpisodeGap]) -> Result<(), Box<dyn Error>>;
+
+    fn delete_episode_gap(&self, mikan_subject_id: i32, mikan_subgroup_id: i32, bangumi_episode_sort: i32) -> Result<(), Box<dyn Error>>;
+}
+
+/// Parsed items of the RSS feeds
+pub trait MikanItemRepository {
+    fn insert_mikan_item(&self, item: &MikanItem) -> Result<(), Box<dyn Error>>;
+
+    /// Uuids among the given ones that are cached.
+    fn select_cached_uuids(&self, uuids: &[&str]) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// Cached items of the given uuids, in the order of the given uuids, uncached ones are skipped.
+    fn fetch_mikan_items(&self, uuids: &[&str]) -> Result<Vec<MikanItem>, Box<dyn Error>>;
+
+    /// Cached items of a subject from every subgroup, the newest first
+    fn list_subject_mikan_items(&self, mikan_subject_id: i32) -> Result<Vec<MikanItem>, Box<dyn Error>>;
+}
+
+/// Parsed metadata of the Mikan subjects
+pub trait MikanSubjectRepository {
+    fn insert_mikan_subject(&self, subject: &MikanSubject) -> Result<(), Box<dyn Error>>;
+
+    fn get_mikan_subject(&self, mikan_subject_id: i32) -> Result<Option<MikanSubject>, Box<dyn Error>>;
+}
+
+/// Metadata overrides of the Mikan subjects, set by the user
+pub trait SubjectOverrideRepository {
+    fn get_subject_override(&self, mikan_subject_id: i32) -> Result<Option<MikanSubjectOverride>, Box<dyn Error>>;
+
+    fn list_subject_overrides(&self) -> Result<Vec<MikanSubjectOverride>, Box<dyn Error>>;
+
+    /// Save the override of a Mikan subject, an empty override is deleted.
+    fn upsert_subject_override(&self, subject_override: &MikanSubjectOverride) -> Result<(), Box<dyn Error>>;
+}
+
+/// History of what the app did, shown in the timeline
+pub trait ActivityRepository {
+    /// Append an activity, the id of the activity is ignored
+    fn insert_activity(&self, activity: &Activity) -> Result<(), Box<dyn Error>>;
+
+    fn list_activities(&self, filter: &ActivityFilter) -> Result<Vec<Activity>, Box<dyn Error>>;
+
+    /// Delete all but the newest `keep` activities, returns the number deleted
+    fn prune_activities(&self, keep: usize) -> Result<usize, Box<dyn Error>>;
+}
+
+/// Full-text search of the library and the cached feeds, see `search_condition` for the query syntax
+pub trait SearchRepository {
+    /// Seasons whose names, or titles and aliases of their subject, match the query
+    fn search_seasons(&self, query: &str, limit: usize) -> Result<Vec<AnimeSeason>, Box<dyn Error>>;
+
+    /// Cached releases whose titles match the query, the newest first
+    fn search_releases(&self, query: &str, limit: usize) -> Result<Vec<ReleaseSearchResult>, Box<dyn Error>>;
+}
+
+/// Repositories on a SQLite connection, a pooled one or an in-memory one in tests.
+/// Changes of the library are collected as `LibraryEvent`s, published by `with_repository` and `transact`.
+pub struct SqliteRepository<'a> {
+    conn: &'a Connection,
+    events: RefCell<Vec<LibraryEvent>>,
+}
+
+impl<'a> SqliteRepository<'a> {
+    pub fn new(conn: &'a Connection) -> Self {
+        SqliteRepository { conn, events: RefCell::new(Vec::new()) }
+    }
+
+    fn emit(&self, event: LibraryEvent) {
+        self.events.borrow_mut().push(event);
+    }
+
+    /// Events collected so far
+    pub fn take_events(&self) -> Vec<LibraryEvent> {
+        self.events.take()
+    }
+
+    /// (mikan_subject_id, mikan_subgroup_id) of the season of an item, for the events of the item
+    fn item_season(&self, mikan_item_uuid: &str) -> Result<Option<(i32, i32)>, Box<dyn Error>> {
+        Ok(self.get_season_item(mikan_item_uuid)?.map(|x| (x.mikan_subject_id, x.mikan_subgroup_id)))
+    }
+
+    fn query_one<T: FromRow>(&self, sql: &str, params: impl rusqlite::Params) -> Result<Option<T>, Box<dyn Error>> {
+        let mut stmt = self.conn.prepare_cached(sql)?;
+        let mut rows = stmt.query(params)?;
+        match rows.next()? {
+            Some(row) => Ok(Some(T::from_row(row)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn query_all<T: FromRow>(&self, sql: &str, params: impl rusqlite::Params) -> Result<Vec<T>, Box<dyn Error>> {
+        let mut stmt = self.conn.prepare_cached(sql)?;
+        let rows = stmt.query_map(params, |row| T::from_row(row))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<T>>>()?)
+    }
+}
+
+/// Run a query on a pooled connection, errors are logged and replaced by the default value,
+/// e.g. an empty library when the database is unavailable.
+/// Each statement commits on its own, so the changes made before an error are published as well.
+pub fn with_repository<T: Default>(action: &str, f: impl FnOnce(&SqliteRepository) -> Result<T, Box<dyn Error>>) -> T {
+    let result = get_connection().and_then(|conn| {
+        let repo = SqliteRepository::new(&conn);
+        let result = f(&repo);
+        publish_library_events(repo.take_events());
+        result
+    });
+    result.unwrap_or_else(|e| {
+        log::error!("Failed to {}: {}", action, e);
+        T::default()
+    })
+}
+
+/// Run a multi-step mutation in one transaction on a pooled connection, see `transact`.
+pub fn with_transaction<T>(f: impl FnOnce(&SqliteRepository) -> Result<T, Box<dyn Error>>) -> Result<T, Box<dyn Error>> {
+    let mut conn = get_connection()?;
+    transact(&mut conn, f)
+}
+
+/// Run a multi-step mutation in one transaction
+///
+/// ## Procedure
+///
+/// 1. Begin an immediate transaction, taking the write lock up front so that the steps never fail halfway on a busy database
+/// 2. Run `f` on the repositories of the transaction
+/// 3. Commit if `f` succeeds, otherwise the transaction is rolled back when dropped, also when `f` panics
+/// 4. Publish the changes of the library once committed, nothing is published for a rolled back transaction
+pub fn transact<T>(conn: &mut Connection, f: impl FnOnce(&SqliteRepository) -> Result<T, Box<dyn Error>>) -> Result<T, Box<dyn Error>> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let repo = SqliteRepository::new(&tx);
+    let result = f(&repo)?;
+    let events = repo.take_events();
+    tx.commit()?;
+    publish_library_events(events);
+    Ok(result)
+}
+
+/// An empty database in memory with the current schema, for unit tests of the library logic.
+#[cfg(test)]
+pub fn open_in_memory_database() -> Result<Connection, Box<dyn Error>> {
+    let mut conn = Connection::open_in_memory()?;
+    crate::module::database::migration::migrate_database(&mut conn)?;
+    Ok(conn)
+}
+
+/// A season of 葬送的芙莉莲 by subgroup 382, for unit tests of the library logic.
+/// Tests set the fields they are about with the struct update syntax.
+#[cfg(test)]
+pub fn test_season(mikan_subject_id: i32, disp_season_num: i32) -> AnimeSeason {
+    AnimeSeason {
+        mikan_subject_id,
+        mikan_subgroup_id: 382,
+        mikan_subject_name: "葬送的芙莉莲".to_string(),
+        disp_series_name: "葬送的芙莉莲".to_string(),
+        disp_season_name: format!("第 {} 季", disp_season_num),
+        disp_season_num,
+        conf_season_num: -1,
+        anilist_id: -1,
+        mal_id: -1,
+        anidb_id: -1,
+        ..Default::default()
+    }
+}
+
+/// A release of subgroup 382 titled by its uuid, for unit tests of the library logic.
+#[cfg(test)]
+pub fn test_item(uuid: &str, mikan_subject_id: i32, episode_num: i32) -> MikanItem {
+    MikanItem {
+        mikan_item_uuid: uuid.to_string(),
+        mikan_subject_id,
+        mikan_subgroup_id: 382,
+        mikan_subject_name: "葬送的芙莉莲".to_string(),
+        mikan_item_title: uuid.to_string(),
+        mikan_parsed_episode_num: episode_num,
+        ..Default::default()
+    }
+}
+
+/// `test_item` as it is stored in the library, numbered as released.
+#[cfg(test)]
+pub fn test_season_item(uuid: &str, mikan_subject_id: i32, episode_num: i32) -> AnimeSeasonItem {
+    AnimeSeasonItem {
+        mikan_item_uuid: uuid.to_string(),
+        mikan_subject_id,
+        mikan_subgroup_id: 382,
+        mikan_subject_name: "葬送的芙莉莲".to_string(),
+        mikan_item_title: uuid.to_string(),
+        mikan_parsed_episode_num: episode_num,
+        disp_episode_num: episode_num,
+        ..Default::default()
+    }
+}
+
+impl SeasonRepository for SqliteRepository<'_> {
+    fn get_season(&self, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<Option<AnimeSeason>, Box<dyn Error>> {
+        self.query_one(
+            "select * from library_anime_season where mikan_subject_id = :mikan_subject_id and mikan_subgroup_id = :mikan_subgroup_id",
+            named_params! {":mikan_subject_id": mikan_subject_id, ":mikan_subgroup_id": mikan_subgroup_id},
+        )
+    }
+
+    fn list_seasons(&self) -> Result<Vec<AnimeSeason>, Box<dyn Error>> {
+        self.query_all("select * from library_anime_season", [])
+    }
+
+    fn upsert_season(&self, season: &AnimeSeason) -> Result<(), Box<dyn Error>> {
+        let existing = self.get_season(season.mikan_subject_id, season.mikan_subgroup_id)?;
+        self.conn.prepare_cached(
+            "insert or replace into library_anime_season (
+                mikan_subject_id,
+                mikan_subgroup_id,
+                mikan_subject_name,
+                mikan_subject_image,
+                bangumi_subject_id,
+                bangumi_subject_name,
+                bangumi_season_num,
+                bangumi_subject_image,
+                tmdb_series_id,
+                tmdb_series_name,
+                tmdb_season_num,
+                tmdb_season_name,
+                bangumi_to_tmdb_episode_offset,
+                disp_series_name,
+                disp_season_name,
+                disp_subgroup_name,
+                disp_season_num,
+                conf_tmdb_episode_offset,
+                conf_language,
+                conf_codec,
+                conf_season_num,
+                conf_bangumi_episode_offset,
+                anilist_id,
+                mal_id,
+                anidb_id,
+                series_id,
+                merged_subject_id,
+                merged_subgroup_id,
+                merged_episode_offset,
+                split_episode_num,
+                split_season_num,
+                status
+            ) values (
+                :mikan_subject_id,
+                :mikan_subgroup_id,
+                :mikan_subject_name,
+                :mikan_subject_image,
+                :bangumi_subject_id,
+                :bangumi_subject_name,
+                :bangumi_season_num,
+                :bangumi_subject_image,
+                :tmdb_series_id,
+                :tmdb_series_name,
+                :tmdb_season_num,
+                :tmdb_season_name,
+                :bangumi_to_tmdb_episode_offset,
+                :disp_series_name,
+                :disp_season_name,
+                :disp_subgroup_name,
+                :disp_season_num,
+                :conf_tmdb_episode_offset,
+                :conf_language,
+                :conf_codec,
+                :conf_season_num,
+                :conf_bangumi_episode_offset,
+                :anilist_id,
+                :mal_id,
+                :anidb_id,
+                :series_id,
+                :merged_subject_id,
+                :merged_subgroup_id,
+                :merged_episode_offset,
+                :split_episode_num,
+                :split_season_num,
+                :status
+            )"
+        )?.execute(named_params! {
+            ":mikan_subject_id": season.mikan_subject_id,
+            ":mikan_subgroup_id": season.mikan_subgroup_id,
+            ":mikan_subject_name": season.mikan_subject_name,
+            ":mikan_subject_image": season.mikan_subject_image,
+            ":bangumi_subject_id": season.bangumi_subject_id,
+            ":bangumi_subject_name": season.bangumi_subject_name,
+            ":bangumi_season_num": season.bangumi_season_num,
+            ":bangumi_subject_image": season.bangumi_subject_image,
+            ":tmdb_series_id": season.tmdb_series_id,
+            ":tmdb_series_name": season.tmdb_series_name,
+            ":tmdb_season_num": season.tmdb_season_num,
+            ":tmdb_season_name": season.tmdb_season_name,
+            ":bangumi_to_tmdb_episode_offset": season.bangumi_to_tmdb_episode_offset,
+            ":disp_series_name": season.disp_series_name,
+            ":disp_season_name": season.disp_season_name,
+            ":disp_subgroup_name": season.disp_subgroup_name,
+            ":disp_season_num": season.disp_season_num,
+            ":conf_tmdb_episode_offset": season.conf_tmdb_episode_offset,
+            ":conf_language": season.conf_language,
+            ":conf_codec": season.conf_codec,
+            ":conf_season_num": season.conf_season_num,
+            ":conf_bangumi_episode_offset": season.conf_bangumi_episode_offset,
+            ":anilist_id": season.anilist_id,
+            ":mal_id": season.mal_id,
+            ":anidb_id": season.anidb_id,
+            ":series_id": season.series_id,
+            ":merged_subject_id": season.merged_subject_id,
+            ":merged_subgroup_id": season.merged_subgroup_id,
+            ":merged_episode_offset": season.merged_episode_offset,
+            ":split_episode_num": season.split_episode_num,
+            ":split_season_num": season.split_season_num,
+            ":status": season.status,
+        })?;
+        let key = (season.mikan_subject_id, season.mikan_subgroup_id);
+        match existing {
+            None => self.emit(LibraryEvent::SeasonAdded { season: key }),
+            Some(existing) if existing != *season => self.emit(LibraryEvent::SeasonUpdated { season: key }),
+            Some(_) => {}
+        }
+        Ok(())
+    }
+
+    fn delete_season(&self, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<(), Box<dyn Error>> {
+        self.conn.prepare_cached(
+            "delete from library_anime_season where mikan_subject_id = :mikan_subject_id and mikan_subgroup_id = :mikan_subgroup_id"
+        )?.execute(named_params! {":mikan_subject_id": mikan_subject_id, ":mikan_subgroup_id": mikan_subgroup_id})?;
+        self.emit(LibraryEvent::SeasonRemoved { season: (mikan_subject_id, mikan_subgroup_id) });
+        Ok(())
+    }
+
+    fn update_season_conf(&self, season: &AnimeSeason) -> Result<(), Box<dyn Error>> {
+        self.conn.prepare_cached(
+            "update library_anime_season set
+                conf_tmdb_episode_offset = :conf_tmdb_episode_offset,
+                conf_language = :conf_language,
+                conf_codec = :conf_codec,
+                conf_season_num = :conf_season_num,
+                conf_bangumi_episode_offset = :conf_bangumi_episode_offset
+            where mikan_subject_id = :mikan_subject_id and mikan_subgroup_id = :mikan_subgroup_id"
+        )?.execute(named_params! {
+            ":conf_tmdb_episode_offset": season.conf_tmdb_episode_offset,
+            ":conf_language": season.conf_language,
+            ":conf_codec": season.conf_codec,
+            ":conf_season_num": season.conf_season_num,
+            ":conf_bangumi_episode_offset": season.conf_bangumi_episode_offset,
+            ":mikan_subject_id": season.mikan_subject_id,
+            ":mikan_subgroup_id": season.mikan_subgroup_id,
+        })?;
+        self.emit(LibraryEvent::SeasonUpdated { season: (season.mikan_subject_id, season.mikan_subgroup_id) });
+        Ok(())
+    }
+
+    fn set_season_disp_season_num(&self, mikan_subject_id: i32, mikan_subgroup_id: i32, disp_season_num: i32) -> Result<(), Box<dyn Error>> {
+        self.conn.prepare_cached(
+            "update library_anime_season set disp_season_num = :disp_season_num where mikan_subject_id = :mikan_subject_id and mikan_subgroup_id = :mikan_subgroup_id"
+        )?.execute(named_params! {
+            ":disp_season_num": disp_season_num,
+            ":mikan_subject_id": mikan_subject_id,
+            ":mikan_subgroup_id": mikan_subgroup_id,
+        })?;
+        self.emit(LibraryEvent::SeasonUpdated { season: (mikan_subject_id, mikan_subgroup_id) });
+        Ok(())
+    }
+
+    fn update_season_arrangement(&self, season: &AnimeSeason) -> Result<(), Box<dyn Error>> {
+        self.conn.prepare_cached(
+            "update library_anime_season set
+                merged_subject_id = :merged_subject_id,
+                merged_subgroup_id = :merged_subgroup_id,
+                merged_episode_offset = :merged_episode_offset,
+                split_episode_num = :split_episode_num,
+                split_season_num = :split_season_num
+            where mikan_subject_id = :mikan_subject_id and mikan_subgroup_id = :mikan_subgroup_id"
+        )?.execute(named_params! {
+            ":merged_subject_id": season.merged_subject_id,
+            ":merged_subgroup_id": season.merged_subgroup_id,
+            ":merged_episode_offset": season.merged_episode_offset,
+            ":split_episode_num": season.split_episode_num,
+            ":split_season_num": season.split_season_num,
+            ":mikan_subject_id": season.mikan_subject_id,
+            ":mikan_subgroup_id": season.mikan_subgroup_id,
+        })?;
+        self.emit(LibraryEvent::SeasonUpdated { season: (season.mikan_subject_id, season.mikan_subgroup_id) });
+        Ok(())
+    }
+
+    fn set_season_status(&self, mikan_subject_id: i32, mikan_subgroup_id: i32, status: SeasonStatus) -> Result<(), Box<dyn Error>> {
+        self.conn.prepare_cached(
+            "update library_anime_season set status = :status where mikan_subject_id = :mikan_subject_id and mikan_subgroup_id = :mikan_subgroup_id"
+        )?.execute(named_params! {
+            ":status": status,
+            ":mikan_subject_id": mikan_subject_id,
+            ":mikan_subgroup_id": mikan_subgroup_id,
+        })?;
+        self.emit(LibraryEvent::SeasonUpdated { season: (mikan_subject_id, mikan_subgroup_id) });
+        Ok(())
+    }
+}
+
+impl SeriesRepository for SqliteRepository<'_> {
+    fn get_series(&self, series_id: i32) -> Result<Option<AnimeSeries>, Box<dyn Error>> {
+        self.query_one("select * from library_series where series_id = :series_id", named_params! {":series_id": series_id})
+    }
+
+    fn list_series(&self) -> Result<Vec<AnimeSeries>, Box<dyn Error>> {
+        self.query_all("select * from library_series order by series_id", [])
+    }
+
+    fn find_series_by_tmdb_id(&self, tmdb_series_id: i32) -> Result<Option<AnimeSeries>, Box<dyn Error>> {
+        self.query_one(
+            "select * from library_series where tmdb_series_id = :tmdb_series_id order by series_id",
+            named_params! {":tmdb_series_id": tmdb_series_id},
+        )
+    }
+
+    fn find_series_by_name(&self, name: &str) -> Result<Option<AnimeSeries>, Box<dyn Error>> {
+        self.query_one(
+            "select * from library_series
+            where disp_series_name = :name or tmdb_series_name = :name or bangumi_subject_name = :name
+                or series_id in (
+                    select season.series_id from library_anime_season season
+                    join cache_mikan_subject_title title on title.mikan_subject_id = season.mikan_subject_id
+                    where title.title_kind = :title_kind and title.title = :name
+                )
+            order by disp_series_name = :name desc, series_id",
+            named_params! {":name": name, ":title_kind": TITLE_KIND_FOLDER},
+        )
+    }
+
+    fn save_series_folder_name(&self, mikan_subject_id: i32, folder_name: &str) -> Result<(), Box<dyn Error>> {
+        self.conn.prepare_cached(
+            "insert or replace into cache_mikan_subject_title (mikan_subject_id, title_kind, language, title)
+            values (:mikan_subject_id, :title_kind, 'local:folder', :title)"
+        )?.execute(named_params! {":mikan_subject_id": mikan_subject_id, ":title_kind": TITLE_KIND_FOLDER, ":title": folder_name})?;
+        Ok(())
+    }
+
+    fn insert_series(&self, series: &AnimeSeries) -> Result<i32, Box<dyn Error>> {
+        self.conn.prepare_cached(
+            "insert into library_series (
+                disp_series_name,
+                bangumi_subject_id,
+                bangumi_subject_name,
+                tmdb_series_id,
+                tmdb_series_name,
+                anilist_id,
+                mal_id,
+                anidb_id
+            ) values (
+                :disp_series_name,
+                :bangumi_subject_id,
+                :bangumi_subject_name,
+                :tmdb_series_id,
+                :tmdb_series_name,
+                :anilist_id,
+                :mal_id,
+                :anidb_id
+            )"
+        )?.execute(named_params! {
+            ":disp_series_name": series.disp_series_name,
+            ":bangumi_subject_id": series.bangumi_subject_id,
+            ":bangumi_subject_name": series.bangumi_subject_name,
+            ":tmdb_series_id": series.tmdb_series_id,
+            ":tmdb_series_name": series.tmdb_series_name,
+            ":anilist_id": series.anilist_id,
+            ":mal_id": series.mal_id,
+            ":anidb_id": series.anidb_id,
+        })?;
+        Ok(self.conn.last_insert_rowid() as i32)
+    }
+
+    fn sync_series(&self, series_id: i32) -> Result<(), Box<dyn Error>> {
+        // Columns are listed, so that the migration linking older seasons can use it
+        self.conn.prepare_cached(
+            "update library_series set (
+                disp_series_name,
+                bangumi_subject_id,
+                bangumi_subject_name,
+                tmdb_series_id,
+                tmdb_series_name,
+                anilist_id,
+                mal_id,
+                anidb_id
+            ) = (
+                select
+                    coalesce(disp_series_name, ''),
+                    coalesce(bangumi_subject_id, -1),
+                    coalesce(bangumi_subject_name, ''),
+                    coalesce(tmdb_series_id, -1),
+                    coalesce(tmdb_series_name, ''),
+                    coalesce(anilist_id, -1),
+                    coalesce(mal_id, -1),
+                    coalesce(anidb_id, -1)
+                from library_anime_season where library_anime_season.series_id = library_series.series_id
+                order by coalesce(tmdb_series_name, '') = '', disp_season_num, mikan_subject_id, mikan_subgroup_id limit 1
+            )
+            where series_id = :series_id and exists (select 1 from library_anime_season where series_id = :series_id)"
+        )?.execute(named_params! {":series_id": series_id})?;
+        self.conn.prepare_cached(
+            "delete from library_series where series_id = :series_id and not exists (select 1 from library_anime_season where series_id = :series_id)"
+        )?.execute(named_params! {":series_id": series_id})?;
+        Ok(())
+    }
+
+    fn set_season_series_id(&self, mikan_subject_id: i32, mikan_subgroup_id: i32, series_id: i32) -> Result<(), Box<dyn Error>> {
+        self.conn.prepare_cached(
+            "update library_anime_season set series_id = :series_id where mikan_subject_id = :mikan_subject_id and mikan_subgroup_id = :mikan_subgroup_id"
+        )?.execute(named_params! {
+            ":series_id": series_id,
+            ":mikan_subject_id": mikan_subject_id,
+            ":mikan_subgroup_id": mikan_subgroup_id,
+        })?;
+        self.emit(LibraryEvent::SeasonUpdated { season: (mikan_subject_id, mikan_subgroup_id) });
+        Ok(())
+    }
+
+    fn find_season_in_series(&self, series_id: i32, disp_season_num: i32) -> Result<Option<AnimeSeason>, Box<dyn Error>> {
+        self.query_one(
+            "select * from library_anime_season where series_id = :series_id and disp_season_num = :disp_season_num",
+            named_params! {":series_id": series_id, ":disp_season_num": disp_season_num},
+        )
+    }
+}
+
+impl SeasonItemRepository for SqliteRepository<'_> {
+    fn get_season_item(&self, mikan_item_uuid: &str) -> Result<Option<AnimeSeasonItem>, Box<dyn Error>> {
+        self.query_one(
+            "select * from library_anime_season_item where mikan_item_uuid = :mikan_item_uuid",
+            named_params! {":mikan_item_uuid": mikan_item_uuid},
+        )
+    }
+
+    fn list_season_items(&self, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<Vec<AnimeSeasonItem>, Box<dyn Error>> {
+        self.query_all(
+            "select * from library_anime_season_item where mikan_subject_id = :mikan_subject_id and mikan_subgroup_id = :mikan_subgroup_id",
+            named_params! {":mikan_subject_id": mikan_subject_id, ":mikan_subgroup_id": mikan_subgroup_id},
+        )
+    }
+
+    fn list_all_items(&self) -> Result<Vec<AnimeSeasonItem>, Box<dyn Error>> {
+        self.query_all("select * from library_anime_season_item", [])
+    }
+
+    fn list_placed_items(&self, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<Vec<AnimeSeasonItem>, Box<dyn Error>> {
+        self.query_all(
+            "select * from library_anime_season_item
+            where conf_placement_subject_id = :mikan_subject_id and conf_placement_subgroup_id = :mikan_subgroup_id
+            and not (mikan_subject_id = :mikan_subject_id and mikan_subgroup_id = :mikan_subgroup_id)",
+            named_params! {":mikan_subject_id": mikan_subject_id, ":mikan_subgroup_id": mikan_subgroup_id},
+        )
+    }
+
+    fn upsert_season_item(&self, item: &AnimeSeasonItem) -> Result<(), Box<dyn Error>> {
+        let existing = self.get_season_item(&item.mikan_item_uuid)?;
+        self.conn.prepare_cached(
+            "insert or replace into library_anime_season_item (
+                mikan_item_uuid,
+                mikan_subject_id,
+                mikan_subgroup_id,
+                mikan_subject_name,
+                mikan_item_title,
+                mikan_item_magnet_link,
+                mikan_item_pub_date,
+                tmdb_series_name,
+                tmdb_season_name,
+                tmdb_parsed_season_num,
+                bangumi_parsed_season_num,
+                mikan_parsed_episode_num,
+                mikan_parsed_language,
+                mikan_parsed_codec,
+                disp_episode_num,
+                bangumi_episode_type,
+                state,
+                state_updated_at,
+                conf_placement_subject_id,
+                conf_placement_subgroup_id,
+                conf_disp_episode_num
+            ) values (
+                :mikan_item_uuid,
+                :mikan_subject_id,
+                :mikan_subgroup_id,
+                :mikan_subject_name,
+                :mikan_item_title,
+                :mikan_item_magnet_link,
+                :mikan_item_pub_date,
+                :tmdb_series_name,
+                :tmdb_season_name,
+                :tmdb_parsed_season_num,
+                :bangumi_parsed_season_num,
+                :mikan_parsed_episode_num,
+                :mikan_parsed_language,
+                :mikan_parsed_codec,
+                :disp_episode_num,
+                :bangumi_episode_type,
+                :state,
+                :state_updated_at,
+                :conf_placement_subject_id,
+                :conf_placement_subgroup_id,
+                :conf_disp_episode_num
+            )"
+        )?.execute(named_params! {
+            ":mikan_item_uuid": item.mikan_item_uuid,
+            ":mikan_subject_id": item.mikan_subject_id,
+            ":mikan_subgroup_id": item.mikan_subgroup_id,
+            ":mikan_subject_name": item.mikan_subject_name,
+            ":mikan_item_title": item.mikan_item_title,
+            ":mikan_item_magnet_link": item.mikan_item_magnet_link,
+            ":mikan_item_pub_date": item.mikan_item_pub_date,
+            ":tmdb_series_name": item.tmdb_series_name,
+            ":tmdb_season_name": item.tmdb_season_name,
+            ":tmdb_parsed_season_num": item.tmdb_parsed_season_num,
+            ":bangumi_parsed_season_num": item.bangumi_parsed_season_num,
+            ":mikan_parsed_episode_num": item.mikan_parsed_episode_num,
+            ":mikan_parsed_language": item.mikan_parsed_language,
+            ":mikan_parsed_codec": item.mikan_parsed_codec,
+            ":disp_episode_num": item.disp_episode_num,
+            ":bangumi_episode_type": item.bangumi_episode_type,
+            ":state": item.state,
+            ":state_updated_at": item.state_updated_at,
+            ":conf_placement_subject_id": item.conf_placement_subject_id,
+            ":conf_placement_subgroup_id": item.conf_placement_subgroup_id,
+            ":conf_disp_episode_num": item.conf_disp_episode_num,
+        })?;
+        let (season, mikan_item_uuid) = ((item.mikan_subject_id, item.mikan_subgroup_id), item.mikan_item_uuid.clone());
+        match existing {
+            None => self.emit(LibraryEvent::ItemAdded { season, mikan_item_uuid }),
+            // An item moved to another season by the feed is removed from the one it was in
+            Some(existing) if (existing.mikan_subject_id, existing.mikan_subgroup_id) != season => {
+                self.emit(LibraryEvent::ItemRemoved { season: (existing.mikan_subject_id, existing.mikan_subgroup_id), mikan_item_uuid: mikan_item_uuid.clone() });
+                self.emit(LibraryEvent::ItemAdded { season, mikan_item_uuid });
+            }
+            Some(existing) if existing != *item => self.emit(LibraryEvent::ItemUpdated { season, mikan_item_uuid }),
+            Some(_) => {}
+        }
+        Ok(())
+    }
+
+    fn delete_season_item(&self, mikan_item_uuid: &str) -> Result<(), Box<dyn Error>> {
+        let season = self.item_season(mikan_item_uuid)?;
+        self.conn.prepare_cached("delete from library_anime_season_item where mikan_item_uuid = :mikan_item_uuid")?
+            .execute(named_params! {":mikan_item_uuid": mikan_item_uuid})?;
+        if let Some(season) = season {
+            self.emit(LibraryEvent::ItemRemoved { season, mikan_item_uuid: mikan_item_uuid.to_string() });
+        }
+        Ok(())
+    }
+
+    fn set_item_state(&self, mikan_item_uuid: &str, state: ItemState) -> Result<(), Box<dyn Error>> {
+        let season = self.item_season(mikan_item_uuid)?;
+        self.conn.prepare_cached(
+            "update library_anime_season_item set state = :state, state_updated_at = :state_updated_at
+            where mikan_item_uuid = :mikan_item_uuid"
+        )?.execute(named_params! {
+            ":state": state,
+            ":state_updated_at": chrono::Local::now().to_rfc3339(),
+            ":mikan_item_uuid": mikan_item_uuid,
+        })?;
+        if let Some(season) = season {
+            self.emit(LibraryEvent::ItemStateChanged { season, mikan_item_uuid: mikan_item_uuid.to_string(), state });
+        }
+        Ok(())
+    }
+
+    fn set_item_disp_episode_num(&self, mikan_item_uuid: &str, disp_episode_num: i32) -> Result<(), Box<dyn Error>> {
+        self.conn.prepare_cached("update library_anime_season_item set disp_episode_num = :disp_episode_num where mikan_item_uuid = :mikan_item_uuid")?
+            .execute(named_params! {":disp_episode_num": disp_episode_num, ":mikan_item_uuid": mikan_item_uuid})?;
+        if let Some(season) = self.item_season(mikan_item_uuid)? {
+            self.emit(LibraryEvent::ItemUpdated { season, mikan_item_uuid: mikan_item_uuid.to_string() });
+        }
+        Ok(())
+    }
+
+    fn set_item_placement(&self, mikan_item_uuid: &str, placement: Option<(i32, i32)>, disp_episode_num: Option<i32>) -> Result<(), Box<dyn Error>> {
+        if let Some(season) = self.item_season(mikan_item_uuid)? {
+            self.emit(LibraryEvent::ItemUpdated { season, mikan_item_uuid: mikan_item_uuid.to_string() });
+        }
+        self.conn.prepare_cached(
+            "update library_anime_season_item set
+                conf_placement_subject_id = :conf_placement_subject_id,
+                conf_placement_subgroup_id = :conf_placement_subgroup_id,
+                conf_disp_episode_num = :conf_disp_episode_num
+            where mikan_item_uuid = :mikan_item_uuid"
+        )?.execute(named_params! {
+            ":conf_placement_subject_id": placement.map(|x| x.0),
+            ":conf_placement_subgroup_id": placement.map(|x| x.1),
+            ":conf_disp_episode_num": disp_episode_num,
+            ":mikan_item_uuid": mikan_item_uuid,
+        })?;
+        Ok(())
+    }
+}
+
+impl SeasonTombstoneRepository for SqliteRepository<'_> {
+    fn list_season_tombstones(&self) -> Result<Vec<SeasonTombstone>, Box<dyn Error>> {
+        self.query_all("select * from library_season_tombstone order by removed_at", [])
+    }
+
+    fn insert_season_tombstone(&self, season: &AnimeSeason, feed_active: bool) -> Result<(), Box<dyn Error>> {
+        self.conn.prepare_cached(
+            "insert into library_season_tombstone (mikan_subject_id, mikan_subgroup_id, disp_series_name, removed_at, feed_active)
+            values (:mikan_subject_id, :mikan_subgroup_id, :disp_series_name, :removed_at, :feed_active)"
+        )?.execute(named_params! {
+            ":mikan_subject_id": season.mikan_subject_id,
+            ":mikan_subgroup_id": season.mikan_subgroup_id,
+            ":disp_series_name": season.disp_series_name,
+            ":removed_at": chrono::Local::now().to_rfc3339(),
+            ":feed_active": feed_active,
+        })?;
+        Ok(())
+    }
+
+    fn set_season_tombstone_feed_active(&self, mikan_subject_id: i32, mikan_subgroup_id: i32, feed_active: bool) -> Result<(), Box<dyn Error>> {
+        self.conn.prepare_cached(
+            "update library_season_tombstone set feed_active = :feed_active
+            where mikan_subject_id = :mikan_subject_id and mikan_subgroup_id = :mikan_subgroup_id"
+        )?.execute(named_params! {":feed_active": feed_active, ":mikan_subject_id": mikan_subject_id, ":mikan_subgroup_id": mikan_subgroup_id})?;
+        Ok(())
+    }
+
+    fn delete_season_tombstone(&self, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<(), Box<dyn Error>> {
+        self.conn.prepare_cached(
+            "delete from library_season_tombstone where mikan_subject_id = :mikan_subject_id and mikan_subgroup_id = :mikan_subgroup_id"
+        )?.execute(named_params! {":mikan_subject_id": mikan_subject_id, ":mikan_subgroup_id": mikan_subgroup_id})?;
+        Ok(())
+    }
+
+    fn is_season_tombstoned(&self, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<bool, Box<dyn Error>> {
+        Ok(self.conn.prepare_cached(
+            "select 1 from library_season_tombstone where mikan_subject_id = :mikan_subject_id and mikan_subgroup_id = :mikan_subgroup_id"
+        )?.exists(named_params! {":mikan_subject_id": mikan_subject_id, ":mikan_subgroup_id": mikan_subgroup_id})?)
+    }
+}
+
+impl EpisodeOffsetProposalRepository for SqliteRepository<'_> {
+    fn get_episode_offset_proposal(&self, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<Option<EpisodeOffsetProposalRecord>, Box<dyn Error>> {
+        self.query_one(
+            "select * from library_episode_offset_proposal where mikan_subject_id = :mikan_subject_id and mikan_subgroup_id = :mikan_subgroup_id",
+            named_params! {":mikan_subject_id": mikan_subject_id, ":mikan_subgroup_id": mikan_subgroup_id},
+        )
+    }
+
+    fn save_episode_offset_proposal(&self, proposal: &EpisodeOffsetProposalRecord) -> Result<(), Box<dyn Error>> {
+        self.conn.prepare_cached(
+            "insert into library_episode_offset_proposal (
+                mikan_subject_id,
+                mikan_subgroup_id,
+                ep_num_min,
+                ep_num_max,
+                tmdb_episode_offset,
+                bangumi_episode_offset,
+                confidence,
+                explanation,
+                applied
+            ) values (
+                :mikan_subject_id,
+                :mikan_subgroup_id,
+                :ep_num_min,
+                :ep_num_max,
+                :tmdb_episode_offset,
+                :bangumi_episode_offset,
+                :confidence,
+                :explanation,
+                :applied
+            )
+            on conflict(mikan_subject_id, mikan_subgroup_id) do update set
+                ep_num_min = excluded.ep_num_min,
+                ep_num_max = excluded.ep_num_max,
+                tmdb_episode_offset = excluded.tmdb_episode_offset,
+                bangumi_episode_offset = excluded.bangumi_episode_offset,
+                confidence = excluded.confidence,
+                explanation = excluded.explanation"
+        )?.execute(named_params! {
+            ":mikan_subject_id": proposal.mikan_subject_id,
+            ":mikan_subgroup_id": proposal.mikan_subgroup_id,
+            ":ep_num_min": proposal.ep_num_min,
+            ":ep_num_max": proposal.ep_num_max,
+            ":tmdb_episode_offset": proposal.tmdb_episode_offset,
+            ":bangumi_episode_offset": proposal.bangumi_episode_offset,
+            ":confidence": proposal.confidence,
+            ":explanation": proposal.explanation,
+            ":applied": proposal.applied,
+        })?;
+        Ok(())
+    }
+
+    fn set_episode_offset_proposal_applied(&self, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<(), Box<dyn Error>> {
+        self.conn.prepare_cached(
+            "update library_episode_offset_proposal set applied = 1 where mikan_subject_id = :mikan_subject_id and mikan_subgroup_id = :mikan_subgroup_id"
+        )?.execute(named_params! {":mikan_subject_id": mikan_subject_id, ":mikan_subgroup_id": mikan_subgroup_id})?;
+        Ok(())
+    }
+}
+
+impl EpisodeGapRepository for SqliteRepository<'_> {
+    fn list_episode_gaps(&self) -> Result<Vec<EpisodeGap>, Box<dyn Error>> {
+        self.query_all("select * from library_episode_gap order by mikan_subject_id, mikan_subgroup_id, bangumi_episode_sort", [])
+    }
+
+    fn get_episode_gap(&self, mikan_subject_id: i32, mikan_subgroup_id: i32, bangumi_episode_sort: i32) -> Result<Option<EpisodeGap>, Box<dyn Error>> {
+        self.query_one(
+            "select * from library_episode_gap
+            where mikan_subject_id = :mikan_subject_id and mikan_subgroup_id = :mikan_subgroup_id and bangumi_episode_sort = :bangumi_episode_sort",
+            named_params! {":mikan_subject_id": mikan_subject_id, ":mikan_subgroup_id": mikan_subgroup_id, ":bangumi_episode_sort": bangumi_episode_sort},
+        )
+    }
+
+    fn list_season_gaps(&self, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<Vec<EpisodeGap>, Box<dyn Error>> {
+        self.query_all(
+            "select * from library_episode_gap where mikan_subject_id = :mikan_subject_id and mikan_subgroup_id = :mikan_subgroup_id order by bangumi_episode_sort",
+            named_params! {":mikan_subject_id": mikan_subject_id, ":mikan_subgroup_id": mikan_subgroup_id},
+        )
+    }
+
+    fn replace_season_gaps(&self, mikan_subject_id: i32, mikan_subgroup_id: i32, gaps: &[EpisodeGap]) -> Result<(), Box<dyn Error>> {
+        let existing = self.list_season_gaps(mikan_subject_id, mikan_subgroup_id)?;
+        if existing != gaps {
+            self.emit(LibraryEvent::SeasonUpdated { season: (mikan_subject_id, mikan_subgroup_id) });
+        }
+        self.conn.prepare_cached(
+            "delete from library_episode_gap where mikan_subject_id = :mikan_subject_id and mikan_subgroup_id = :mikan_subgroup_id"
+        )?.execute(named_params! {":mikan_subject_id": mikan_subject_id, ":mikan_subgroup_id": mikan_subgroup_id})?;
+        for gap in gaps {
+            self.conn.prepare_cached(
+                "insert into library_episode_gap (
+                    mikan_subject_id,
+                    mikan_subgroup_id,
+                    bangumi_episode_sort,
+                    mikan_parsed_episode_num,
+                    disp_episode_num,
+                    episode_name,
+                    episode_airdate,
+                    replacement_uuid,
+                    replacement_title
+                ) values (
+                    :mikan_subject_id,
+                    :mikan_subgroup_id,
+                    :bangumi_episode_sort,
+                    :mikan_parsed_episode_num,
+                    :disp_episode_num,
+                    :episode_name,
+                    :episode_airdate,
+                    :replacement_uuid,
+                    :replacement_title
+                )"
+            )?.execute(named_params! {
+                ":mikan_subject_id": mikan_subject_id,
+                ":mikan_subgroup_id": mikan_subgroup_id,
+                ":bangumi_episode_sort": gap.bangumi_episode_sort,
+                ":mikan_parsed_episode_num": gap.mikan_parsed_episode_num,
+                ":disp_episode_num": gap.disp_episode_num,
+                ":episode_name": gap.episode_name,
+                ":episode_airdate": gap.episode_airdate,
+                ":replacement_uuid": gap.replacement_uuid,
+                ":replacement_title": gap.replacement_title,
+            })?;
+        }
+        Ok(())
+    }
+
+    fn delete_episode_gap(&self, mikan_subject_id: i32, mikan_subgroup_id: i32, bangumi_episode_sort: i32) -> Result<(), Box<dyn Error>> {
+        self.conn.prepare_cached(
+            "delete from library_episode_gap
+            where mikan_subject_id = :mikan_subject_id and mikan_subgroup_id = :mikan_subgroup_id and bangumi_episode_sort = :bangumi_episode_sort"
+        )?.execute(named_params! {":mikan_subject_id": mikan_subject_id, ":mikan_subgroup_id": mikan_subgroup_id, ":bangumi_episode_sort": bangumi_episode_sort})?;
+        self.emit(LibraryEvent::SeasonUpdated { season: (mikan_subject_id, mikan_subgroup_id) });
+        Ok(())
+    }
+}
+
+impl MikanItemRepository for SqliteRepository<'_> {
+    fn insert_mikan_item(&self, item: &MikanItem) -> Result<(), Box<dyn Error>> {
+        self.conn.prepare_cached(
+            "insert or replace into cache_mikan_item (
+                mikan_item_uuid,
+                mikan_subject_id,
+                mikan_subgroup_id,
+                mikan_subgroup_name,
+                mikan_subject_name,
+                mikan_item_title,
+                mikan_item_magnet_link,
+                mikan_item_pub_date,
+                tmdb_series_name,
+                tmdb_season_name,
+                tmdb_parsed_season_num,
+                bangumi_parsed_season_num,
+                mikan_parsed_episode_num,
+                mikan_parsed_language,
+                mikan_parsed_codec
+            ) values (
+                :mikan_item_uuid,
+                :mikan_subject_id,
+                :mikan_subgroup_id,
+                :mikan_subgroup_name,
+                :mikan_subject_name,
+                :mikan_item_title,
+                :mikan_item_magnet_link,
+                :mikan_item_pub_date,
+                :tmdb_series_name,
+                :tmdb_season_name,
+                :tmdb_parsed_season_num,
+                :bangumi_parsed_season_num,
+                :mikan_parsed_episode_num,
+                :mikan_parsed_language,
+                :mikan_parsed_codec
+            )"
+        )?.execute(named_params! {
+            ":mikan_item_uuid": item.mikan_item_uuid,
+            ":mikan_subject_id": item.mikan_subject_id,
+            ":mikan_subgroup_id": item.mikan_subgroup_id,
+            ":mikan_subgroup_name": item.mikan_subgroup_name,
+            ":mikan_subject_name": item.mikan_subject_name,
+            ":mikan_item_title": item.mikan_item_title,
+            ":mikan_item_magnet_link": item.mikan_item_magnet_link,
+            ":mikan_item_pub_date": item.mikan_item_pub_date,
+            ":tmdb_series_name": item.tmdb_series_name,
+            ":tmdb_season_name": item.tmdb_season_name,
+            ":tmdb_parsed_season_num": item.tmdb_parsed_season_num,
+            ":bangumi_parsed_season_num": item.bangumi_parsed_season_num,
+            ":mikan_parsed_episode_num": item.mikan_parsed_episode_num,
+            ":mikan_parsed_language": item.mikan_parsed_language,
+            ":mikan_parsed_codec": item.mikan_parsed_codec,
+        })?;
+        Ok(())
+    }
+
+    fn select_cached_uuids(&self, uuids: &[&str]) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut result = Vec::new();
+        for chunk in uuids.chunks(SQL_BATCH_SIZE) {
+            let mut stmt = self.conn.prepare_cached(&format!(
+                "select mikan_item_uuid from cache_mikan_item where mikan_item_uuid in ({})",
+                sql_placeholders(chunk.len())
+            ))?;
+            let rows = stmt.query_map(params_from_iter(chunk.iter()), |row| row.get::<_, String>(0))?;
+            for row in rows {
+                result.push(row?);
+            }
+        }
+        Ok(result)
+    }
+
+    fn fetch_mikan_items(&self, uuids: &[&str]) -> Result<Vec<MikanItem>, Box<dyn Error>> {
+        let mut cached: HashMap<String, MikanItem> = HashMap::new();
+        for chunk in uuids.chunks(SQL_BATCH_SIZE) {
+            let items: Vec<MikanItem> = self.query_all(
+                &format!("select * from cache_mikan_item where mikan_item_uuid in ({})", sql_placeholders(chunk.len())),
+                params_from_iter(chunk.iter()),
+            )?;
+            for item in items {
+                cached.insert(item.mikan_item_uuid.clone(), item);
+            }
+        }
+        Ok(uuids.iter().filter_map(|x| cached.remove(*x)).collect())
+    }
+
+    fn list_subject_mikan_items(&self, mikan_subject_id: i32) -> Result<Vec<MikanItem>, Box<dyn Error>> {
+        self.query_all(
+            "select * from cache_mikan_item where mikan_subject_id = :mikan_subject_id order by mikan_item_pub_date desc",
+            named_params! {":mikan_subject_id": mikan_subject_id},
+        )
+    }
+}
+
+impl MikanSubjectRepository for SqliteRepository<'_> {
+    fn insert_mikan_subject(&self, subject: &MikanSubject) -> Result<(), Box<dyn Error>> {
+        self.conn.prepare_cached(
+            "insert or replace into cache_mikan_subject (
+                mikan_subject_id,
+                mikan_subject_image_url,
+                bangumi_subject_id,
+                bangumi_subject_name,
+                bangumi_season_num,
+                bangumi_subject_image_url,
+                tmdb_series_id,
+                tmdb_series_name,
+                tmdb_season_num,
+                tmdb_season_name,
+                bangumi_to_tmdb_episode_offset,
+                anilist_id,
+                mal_id,
+                anidb_id
+            ) values (
+                :mikan_subject_id,
+                :mikan_subject_image_url,
+                :bangumi_subject_id,
+                :bangumi_subject_name,
+                :bangumi_season_num,
+                :bangumi_subject_image_url,
+                :tmdb_series_id,
+                :tmdb_series_name,
+                :tmdb_season_num,
+                :tmdb_season_name,
+                :bangumi_to_tmdb_episode_offset,
+                :anilist_id,
+                :mal_id,
+                :anidb_id
+            )"
+        )?.execute(named_params! {
+            ":mikan_subject_id": subject.mikan_subject_id,
+            ":mikan_subject_image_url": subject.mikan_subject_image_url,
+            ":bangumi_subject_id": subject.bangumi_subject_id,
+            ":bangumi_subject_name": subject.bangumi_subject_name,
+            ":bangumi_season_num": subject.bangumi_season_num,
+            ":bangumi_subject_image_url": subject.bangumi_subject_image_url,
+            ":tmdb_series_id": subject.tmdb_series_id,
+            ":tmdb_series_name": subject.tmdb_series_name,
+            ":tmdb_season_num": subject.tmdb_season_num,
+            ":tmdb_season_name": subject.tmdb_season_name,
+            ":bangumi_to_tmdb_episode_offset": subject.bangumi_to_tmdb_episode_offset,
+            ":anilist_id": subject.anilist_id,
+            ":mal_id": subject.mal_id,
+            ":anidb_id": subject.anidb_id,
+        })?;
+        Ok(())
+    }
+
+    fn get_mikan_subject(&self, mikan_subject_id: i32) -> Result<Option<MikanSubject>, Box<dyn Error>> {
+        self.query_one(
+            "select * from cache_mikan_subject where mikan_subject_id = :mikan_subject_id",
+            named_params! {":mikan_subject_id": mikan_subject_id},
+        )
+    }
+}
+
+impl SubjectOverrideRepository for SqliteRepository<'_> {
+    fn get_subject_override(&self, mikan_subject_id: i32) -> Result<Option<MikanSubjectOverride>, Box<dyn Error>> {
+        self.query_one(
+            "select * from conf_mikan_subject_override where mikan_subject_id = :mikan_subject_id",
+            named_params! {":mikan_subject_id": mikan_subject_id},
+        )
+    }
+
+    fn list_subject_overrides(&self) -> Result<Vec<MikanSubjectOverride>, Box<dyn Error>> {
+        self.query_all("select * from conf_mikan_subject_override order by mikan_subject_id", [])
+    }
+
+    fn upsert_subject_override(&self, subject_override: &MikanSubjectOverride) -> Result<(), Box<dyn Error>> {
+        if subject_override.is_empty() {
+            self.conn.prepare_cached("delete from conf_mikan_subject_override where mikan_subject_id = :mikan_subject_id")?
+                .execute(named_params! {":mikan_subject_id": subject_override.mikan_subject_id})?;
+            return Ok(());
+        }
+        self.conn.prepare_cached(
+            "insert or replace into conf_mikan_subject_override (
+                mikan_subject_id,
+                bangumi_subject_id,
+                tmdb_series_id,
+                tmdb_season_num,
+                disp_series_name,
+                disp_season_name
+            ) values (
+                :mikan_subject_id,
+                :bangumi_subject_id,
+                :tmdb_series_id,
+                :tmdb_season_num,
+                :disp_series_name,
+                :disp_season_name
+            )"
+        )?.execute(named_params! {
+            ":mikan_subject_id": subject_override.mikan_subject_id,
+            ":bangumi_subject_id": subject_override.bangumi_subject_id,
+            ":tmdb_series_id": subject_override.tmdb_series_id,
+            ":tmdb_season_num": subject_override.tmdb_season_num,
+            ":disp_series_name": subject_override.disp_series_name,
+            ":disp_season_name": subject_override.disp_season_name,
+        })?;
+        Ok(())
+    }
+}
+
+impl ActivityRepository for SqliteRepository<'_> {
+    fn insert_activity(&self, activity: &Activity) -> Result<(), Box<dyn Error>> {
+        self.conn.prepare_cached(
+            "insert into activity (
+                timestamp,
+                kind,
+                mikan_subject_id,
+                mikan_subgroup_id,
+                episode_num,
+                title,
+                before_value,
+                after_value,
+                success,
+                error
+            ) values (
+                :timestamp,
+                :kind,
+                :mikan_subject_id,
+                :mikan_subgroup_id,
+                :episode_num,
+                :title,
+                :before_value,
+                :after_value,
+                :success,
+                :error
+            )"
+        )?.execute(named_params! {
+            ":timestamp": activity.timestamp,
+            ":kind": activity.kind,
+            ":mikan_subject_id": activity.mikan_subject_id,
+            ":mikan_subgroup_id": activity.mikan_subgroup_id,
+            ":episode_num": activity.episode_num,
+            ":title": activity.title,
+            ":before_value": activity.before_value,
+            ":after_value": activity.after_value,
+            ":success": activity.success,
+            ":error": activity.error,
+        })?;
+        Ok(())
+    }
+
+    fn list_activities(&self, filter: &ActivityFilter) -> Result<Vec<Activity>, Box<dyn Error>> {
+        let limit = filter.limit as i64;
+        let mut conditions = vec!["1 = 1"];
+        let mut params: Vec<(&str, &dyn ToSql)> = vec![(":limit", &limit)];
+        if let Some(kind) = &filter.kind {
+            conditions.push("kind = :kind");
+            params.push((":kind", kind));
+        }
+        if let Some((mikan_subject_id, mikan_subgroup_id)) = &filter.season {
+            conditions.push("mikan_subject_id = :mikan_subject_id and mikan_subgroup_id = :mikan_subgroup_id");
+            params.push((":mikan_subject_id", mikan_subject_id));
+            params.push((":mikan_subgroup_id", mikan_subgroup_id));
+        }
+        if filter.failed_only {
+            conditions.push("success = 0");
+        }
+        if !filter.keyword.is_empty() {
+            conditions.push("instr(title, :keyword) > 0");
+            params.push((":keyword", &filter.keyword));
+        }
+        self.query_all(
+            &format!("select * from activity where {} order by id desc limit :limit", conditions.join(" and ")),
+            params.as_slice(),
+        )
+    }
+
+    fn prune_activities(&self, keep: usize) -> Result<usize, Box<dyn Error>> {
+        let deleted = self.conn.execute(
+            "delete from activity where id <= (select id from activity order by id desc limit 1 offset ?1)",
+            [keep as i64],
+        )?;
+        Ok(deleted)
+    }
+}
+
+impl SearchRepository for SqliteRepository<'_> {
+    fn search_seasons(&self, query: &str, limit: usize) -> Result<Vec<AnimeSeason>, Box<dyn Error>> {
+        let (season_condition, params) = match search_condition("search_season", query) {
+            Some(condition) => condition,
+            None => return Ok(Vec::new()),
+        };
+        // The parameters are the same for every index, only the table name in `match` differs
+        let (title_condition, _) = search_condition("search_subject_title", query).unwrap_or_default();
+        let limit = limit as i64;
+        let mut params: Vec<(&str, &dyn ToSql)> = params.iter().map(|(k, v)| (k.as_str(), v as &dyn ToSql)).collect();
+        params.push((":limit", &limit));
+        self.query_all(
+            &format!(
+                "select * from library_anime_season
+                where rowid in (select rowid from search_season where {})
+                    or mikan_subject_id in (select mikan_subject_id from cache_mikan_subject_title
+                        where rowid in (select rowid from search_subject_title where {}))
+                order by disp_series_name, disp_season_num limit :limit",
+                season_condition, title_condition,
+            ),
+            params.as_slice(),
+        )
+    }
+
+    fn search_releases(&self, query: &str, limit: usize) -> Result<Vec<ReleaseSearchResult>, Box<dyn Error>> {
+        let (condition, params) = match search_condition("search_release", query) {
+            Some(condition) => condition,
+            None => return Ok(Vec::new()),
+        };
+        let limit = limit as i64;
+        let mut params: Vec<(&str, &dyn ToSql)> = params.iter().map(|(k, v)| (k.as_str(), v as &dyn ToSql)).collect();
+        params.push((":limit", &limit));
+        self.query_all(
+            &format!(
+                "select cache_mikan_item.*, library_anime_season_item.state as library_state from cache_mikan_item
+                left join library_anime_season_item using (mikan_item_uuid)
+                where cache_mikan_item.rowid in (select rowid from search_release where {})
+                order by cache_mikan_item.mikan_item_pub_date desc limit :limit",
+                condition,
+            ),
+            params.as_slice(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::module::database::activity::ActivityKind;
+    use crate::module::database::library::create_item_in;
+    use crate::module::database::library_event::subscribe_library_events;
+    use crate::module::library::media_library::update_season_config_in;
+    use crate::module::utils::error::new_err;
+
+    use super::*;
+
+    #[test]
+    fn test_season_repository() {
+        let conn = open_in_memory_database().unwrap();
+        let repo = SqliteRepository::new(&conn);
+        repo.upsert_season(&test_season(3141, 1)).unwrap();
+
+        let season = repo.get_season(3141, 382).unwrap().unwrap();
+        assert_eq!(season.mikan_subject_name, "葬送的芙莉莲");
+        assert_eq!(season.conf_season_num, -1);
+        assert!(repo.get_season(3141, 0).unwrap().is_none());
+        repo.set_season_series_id(3141, 382, 7).unwrap();
+        assert_eq!(repo.find_season_in_series(7, 1).unwrap().unwrap().mikan_subgroup_id, 382);
+        assert!(repo.find_season_in_series(7, 2).unwrap().is_none());
+
+        repo.set_season_disp_season_num(3141, 382, 2).unwrap();
+        assert_eq!(repo.list_seasons().unwrap()[0].disp_season_num, 2);
+
+        repo.delete_season(3141, 382).unwrap();
+        assert!(repo.list_seasons().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_episode_offset_proposal_repository() {
+        let conn = open_in_memory_database().unwrap();
+        let repo = SqliteRepository::new(&conn);
+        let proposal = EpisodeOffsetProposalRecord {
+            mikan_subject_id: 3141,
+            mikan_subgroup_id: 382,
+            ep_num_min: 13,
+            ep_num_max: 24,
+            tmdb_episode_offset: -12,
+            bangumi_episode_offset: 0,
+            confidence: 90,
+            explanation: "13-24".to_string(),
+            applied: false,
+        };
+        repo.save_episode_offset_proposal(&proposal).unwrap();
+        assert!(repo.get_episode_offset_proposal(3141, 583).unwrap().is_none());
+        repo.set_episode_offset_proposal_applied(3141, 382).unwrap();
+
+        // A proposal inferred again keeps its applied flag
+        repo.save_episode_offset_proposal(&EpisodeOffsetProposalRecord { confidence: 95, ..proposal }).unwrap();
+        let saved = repo.get_episode_offset_proposal(3141, 382).unwrap().unwrap();
+        assert_eq!((saved.tmdb_episode_offset, saved.confidence, saved.applied), (-12, 95, true));
+    }
+
+    #[test]
+    fn test_update_season_config() {
+        let conn = open_in_memory_database().unwrap();
+        let repo = SqliteRepository::new(&conn);
+        let mut season = test_season(3141, 1);
+        season.conf_tmdb_episode_offset = 12;
+        repo.upsert_season(&season).unwrap();
+
+        // Items are numbered by the offset of their season
+        let item = |uuid: &str, episode_num: i32, language: &str| MikanItem { mikan_parsed_language: language.to_string(), ..test_item(uuid, 3141, episode_num) };
+        create_item_in(&repo, &item("a", 1, "简日")).unwrap();
+        create_item_in(&repo, &item("b", 2, "繁日")).unwrap();
+        assert!(create_item_in(&repo, &MikanItem { mikan_subgroup_id: 0, ..item("c", 1, "简日") }).is_err());
+        let items = repo.list_season_items(3141, 382).unwrap();
+        assert_eq!(items.iter().map(|x| x.disp_episode_num).collect::<Vec<_>>(), vec![13, 14]);
+
+        // Restricting the language filters the other items, and the items are renumbered
+        season.conf_language = "简日".to_string();
+        season.conf_tmdb_episode_offset = 0;
+        update_season_config_in(&repo, &season, true).unwrap();
+        let items = repo.list_all_items().unwrap();
+        assert_eq!(items.iter().map(|x| (x.mikan_item_uuid.as_str(), x.state)).collect::<Vec<_>>(),
+                   vec![("a", ItemState::Accepted), ("b", ItemState::Filtered)]);
+        assert_eq!(items[0].disp_episode_num, 1);
+        assert_eq!(repo.get_season(3141, 382).unwrap().unwrap().conf_language, "简日");
+
+        // Lifting the restriction accepts the filtered items again
+        season.conf_language = "".to_string();
+        update_season_config_in(&repo, &season, true).unwrap();
+        assert!(repo.list_all_items().unwrap().iter().all(|x| x.state == ItemState::Accepted));
+    }
+
+    #[test]
+    fn test_transact() {
+        let mut conn = open_in_memory_database().unwrap();
+        let count_seasons = |conn: &Connection| SqliteRepository::new(conn).list_seasons().unwrap().len();
+
+        // Failed steps roll back the earlier ones
+        let result: Result<(), Box<dyn Error>> = transact(&mut conn, |repo| {
+            repo.upsert_season(&test_season(3141, 1))?;
+            Err(new_err("failed"))
+        });
+        assert!(result.is_err());
+        assert_eq!(count_seasons(&conn), 0);
+
+        // So does a panic
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            transact(&mut conn, |repo| -> Result<(), Box<dyn Error>> {
+                repo.upsert_season(&test_season(3141, 1))?;
+                panic!("panicked halfway");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(count_seasons(&conn), 0);
+
+        transact(&mut conn, |repo| repo.upsert_season(&test_season(3141, 1))).unwrap();
+        assert_eq!(count_seasons(&conn), 1);
+    }
+
+    #[test]
+    fn test_library_events() {
+        let mut conn = open_in_memory_database().unwrap();
+        let events = subscribe_library_events();
+        // Other tests publish too, only look at a season of our own
+        let season = AnimeSeason { mikan_subject_id: 271828, ..test_season(3141, 1) };
+        let key = (season.mikan_subject_id, season.mikan_subgroup_id);
+        let received = || events.try_iter().filter(|x| x.season() == Some(key)).collect::<Vec<_>>();
+
+        // Nothing is published for a rolled back transaction
+        let result: Result<(), Box<dyn Error>> = transact(&mut conn, |repo| {
+            repo.upsert_season(&season)?;
+            Err(new_err("failed"))
+        });
+        assert!(result.is_err());
+        assert!(received().is_empty());
+
+        transact(&mut conn, |repo| repo.upsert_season(&season)).unwrap();
+        assert_eq!(received(), vec![LibraryEvent::SeasonAdded { season: key }]);
+
+        // Saving the same season again changes nothing
+        transact(&mut conn, |repo| repo.upsert_season(&season)).unwrap();
+        assert!(received().is_empty());
+
+        let renamed = AnimeSeason { disp_series_name: "Frieren".to_string(), ..season.clone() };
+        transact(&mut conn, |repo| repo.upsert_season(&renamed)).unwrap();
+        assert_eq!(received(), vec![LibraryEvent::SeasonUpdated { season: key }]);
+
+        transact(&mut conn, |repo| repo.delete_season(key.0, key.1)).unwrap();
+        assert_eq!(received(), vec![LibraryEvent::SeasonRemoved { season: key }]);
+    }
+
+    #[test]
+    fn test_activity_repository() {
+        let conn = open_in_memory_database().unwrap();
+        let repo = SqliteRepository::new(&conn);
+        let season = test_season(3141, 1);
+        repo.insert_activity(&Activity::of_season(ActivityKind::SeasonAdded, &season)).unwrap();
+        let failed: Result<(), Box<dyn Error>> = Err(new_err("connection refused"));
+        let item = AnimeSeasonItem { mikan_item_title: "[LoliHouse] Sousou no Frieren - 01".to_string(), ..Default::default() };
+        repo.insert_activity(&Activity::of_item(ActivityKind::FileRenamed, &item).change("a.mkv", "b.mkv").result(&failed)).unwrap();
+
+        let activities = repo.list_activities(&ActivityFilter::default()).unwrap();
+        assert_eq!(activities.iter().map(|x| x.kind).collect::<Vec<_>>(), vec![ActivityKind::FileRenamed, ActivityKind::SeasonAdded]);
+        assert_eq!(activities[0].error, "connection refused");
+        assert_eq!(activities[0].after_value, "b.mkv");
+
+        let filter = |filter: ActivityFilter| repo.list_activities(&filter).unwrap().len();
+        assert_eq!(filter(ActivityFilter { failed_only: true, ..Default::default() }), 1);
+        assert_eq!(filter(ActivityFilter { kind: Some(ActivityKind::SeasonAdded), ..Default::default() }), 1);
+        assert_eq!(filter(ActivityFilter { season: Some((3141, 382)), ..Default::default() }), 1);
+        assert_eq!(filter(ActivityFilter { keyword: "Frieren".to_string(), ..Default::default() }), 1);
+        assert_eq!(filter(ActivityFilter { limit: 1, ..Default::default() }), 1);
+
+        // Only the newest are kept
+        repo.insert_activity(&Activity::of_season(ActivityKind::SeasonStatusChanged, &season)).unwrap();
+        assert_eq!(repo.prune_activities(2).unwrap(), 1);
+        assert_eq!(repo.list_activities(&ActivityFilter::default()).unwrap().iter().map(|x| x.kind).collect::<Vec<_>>(),
+                   vec![ActivityKind::SeasonStatusChanged, ActivityKind::FileRenamed]);
+        assert_eq!(repo.prune_activities(2).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_mikan_cache_repository() {
+        let conn = open_in_memory_database().unwrap();
+        let repo = SqliteRepository::new(&conn);
+        for (uuid, episode_num) in [("a", 1), ("b", 2), ("c", 3)] {
+            repo.insert_mikan_item(&test_item(uuid, 3141, episode_num)).unwrap();
+        }
+
+        let mut cached = repo.select_cached_uuids(&["c", "x", "a"]).unwrap();
+        cached.sort();
+        assert_eq!(cached, vec!["a", "c"]);
+        let items = repo.fetch_mikan_items(&["c", "x", "a"]).unwrap();
+        assert_eq!(items.iter().map(|x| x.mikan_parsed_episode_num).collect::<Vec<_>>(), vec![3, 1]);
+
+        repo.insert_mikan_subject(&MikanSubject {
+            mikan_subject_id: 3141,
+            bangumi_subject_id: 400602,
+            tmdb_series_id: 209867,
+            anilist_id: 154587,
+            mal_id: 52991,
+            anidb_id: -1,
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(repo.get_mikan_subject(3141).unwrap().unwrap().mal_id, 52991);
+        assert!(repo.get_mikan_subject(0).unwrap().is_none());
+    }
+}
@@ -0,0 +1,197 @@
+use std::error::Error;
+
+use rusqlite::Connection;
+
+use crate::module::database::cache::rss::MikanItem;
+use crate::module::database::item_state::ItemState;
+use crate::module::database::library::AnimeSeason;
+use crate::module::database::repository::{SearchRepository, with_repository};
+
+/// Number of seasons and of releases shown for a query
+pub const SEARCH_LIMIT: usize = 100;
+
+/// Queries shorter than a trigram cannot use `match`, they are answered by `like` on the same index
+const TRIGRAM_LEN: usize = 3;
+
+/// A full-text index of a source table, an index row has the rowid of its source row.
+/// Triggers keep the index in sync, also when a source row is replaced by `insert or replace`.
+struct SearchIndex {
+    name: &'static str,
+    source: &'static str,
+    key: &'static [&'static str],       // unique key of the source table
+    columns: &'static [&'static str],   // indexed columns, joined into one text
+}
+
+const SEARCH_INDEXES: &[SearchIndex] = &[
+    // Raw releases of the feeds, including the ones rejected by the filters
+    SearchIndex {
+        name: "search_release",
+        source: "cache_mikan_item",
+        key: &["mikan_item_uuid"],
+        columns: &["mikan_item_title"],
+    },
+    SearchIndex {
+        name: "search_season",
+        source: "library_anime_season",
+        key: &["mikan_subject_id", "mikan_subgroup_id"],
+        columns: &["disp_series_name", "disp_season_name", "disp_subgroup_name", "mikan_subject_name",
+            "bangumi_subject_name", "tmdb_series_name", "tmdb_season_name"],
+    },
+    // Titles in every language and Bangumi aliases of the subjects
+    SearchIndex {
+        name: "search_subject_title",
+        source: "cache_mikan_subject_title",
+        key: &["mikan_subject_id", "title_kind", "language"],
+        columns: &["title"],
+    },
+];
+
+impl SearchIndex {
+    fn text(&self, row: &str) -> String {
+        let columns: Vec<String> = self.columns.iter().map(|x| format!("{}.{}", row, x)).collect();
+        format!("concat_ws(' ', {})", columns.join(", "))
+    }
+
+    fn create_sql(&self) -> String {
+        let key: Vec<String> = self.key.iter().map(|x| format!("{} = new.{}", x, x)).collect();
+        format!(
+            "create virtual table if not exists {name} using fts5(text, tokenize = 'trigram');
+            create trigger if not exists {name}_before_insert before insert on {source} begin
+                delete from {name} where rowid in (select rowid from {source} where {key});
+            end;
+            create trigger if not exists {name}_after_insert after insert on {source} begin
+                insert into {name} (rowid, text) values (new.rowid, {new_text});
+            end;
+            create trigger if not exists {name}_after_update after update on {source} begin
+                delete from {name} where rowid = old.rowid;
+                insert into {name} (rowid, text) values (new.rowid, {new_text});
+            end;
+            create trigger if not exists {name}_after_delete after delete on {source} begin
+                delete from {name} where rowid = old.rowid;
+            end;
+            delete from {name};
+            insert into {name} (rowid, text) select rowid, {source_text} from {source};",
+            name = self.name,
+            source = self.source,
+            key = key.join(" and "),
+            new_text = self.text("new"),
+            source_text = self.text(self.source),
+        )
+    }
+}
+
+/// Create the search indexes and index the existing rows
+#[deny(dead_code)]
+pub fn init_search_index(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    for index in SEARCH_INDEXES {
+        conn.execute_batch(&index.create_sql())?;
+    }
+    Ok(())
+}
+
+/// Condition on a search index matching every term of a query, with its named parameters
+///
+/// Terms of at least three characters are matched as phrases by `match`,
+/// shorter ones (e.g. a two-character Chinese title) by `like`. `None` if the query is empty.
+pub fn search_condition(index: &str, query: &str) -> Option<(String, Vec<(String, String)>)> {
+    let terms: Vec<&str> = query.split_whitespace().collect();
+    if terms.is_empty() {
+        return None;
+    }
+    let mut conditions = Vec::new();
+    let mut params = Vec::new();
+    let phrases: Vec<String> = terms.iter()
+        .filter(|x| x.chars().count() >= TRIGRAM_LEN)
+        .map(|x| format!("\"{}\"", x.replace('"', "\"\"")))
+        .collect();
+    if !phrases.is_empty() {
+        conditions.push(format!("{} match :match", index));
+        params.push((":match".to_string(), phrases.join(" ")));
+    }
+    for (i, term) in terms.iter().filter(|x| x.chars().count() < TRIGRAM_LEN).enumerate() {
+        let escaped = term.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+        conditions.push(format!("text like :like{} escape '\\'", i));
+        params.push((format!(":like{}", i), format!("%{}%", escaped)));
+    }
+    Some((conditions.join(" and "), params))
+}
+
+/// A release matching a query, with the state of its item if it is in the library
+#[derive(Debug, Clone, Default)]
+pub struct ReleaseSearchResult {
+    pub item: MikanItem,
+    pub state: Option<ItemState>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SearchResult {
+    pub seasons: Vec<AnimeSeason>,
+    pub releases: Vec<ReleaseSearchResult>,
+}
+
+pub fn search_library(query: &str) -> SearchResult {
+    with_repository("search library", |repo| Ok(SearchResult {
+        seasons: repo.search_seasons(query, SEARCH_LIMIT)?,
+        releases: repo.search_releases(query, SEARCH_LIMIT)?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::module::database::repository::{MikanItemRepository, open_in_memory_database, SeasonRepository, SqliteRepository};
+
+    use super::*;
+
+    #[test]
+    fn test_search_condition() {
+        assert!(search_condition("search_release", "  ").is_none());
+        let (condition, params) = search_condition("search_release", "Frieren 芙莉 1080\"p").unwrap();
+        assert_eq!(condition, "search_release match :match and text like :like0 escape '\\'");
+        assert_eq!(params, vec![
+            (":match".to_string(), "\"Frieren\" \"1080\"\"p\"".to_string()),
+            (":like0".to_string(), "%芙莉%".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_search_library() {
+        let conn = open_in_memory_database().unwrap();
+        let repo = SqliteRepository::new(&conn);
+        repo.upsert_season(&AnimeSeason {
+            mikan_subject_id: 3141,
+            mikan_subgroup_id: 382,
+            disp_series_name: "葬送的芙莉莲".to_string(),
+            tmdb_series_name: "Frieren: Beyond Journey's End".to_string(),
+            ..Default::default()
+        }).unwrap();
+        conn.execute("insert into cache_mikan_subject_title values (3141, 'alias', 'bangumi:alias:0', 'Sousou no Frieren')", []).unwrap();
+        let release = |uuid: &str, title: &str| MikanItem {
+            mikan_item_uuid: uuid.to_string(),
+            mikan_subject_id: 3141,
+            mikan_subgroup_id: 382,
+            mikan_item_title: title.to_string(),
+            ..Default::default()
+        };
+        repo.insert_mikan_item(&release("a", "[LoliHouse] Sousou no Frieren - 01 [1080p]")).unwrap();
+        repo.insert_mikan_item(&release("b", "[ANi] 葬送的芙莉蓮 - 01 [1080P]")).unwrap();
+
+        let seasons = |query: &str| repo.search_seasons(query, SEARCH_LIMIT).unwrap().len();
+        assert_eq!(seasons("芙莉莲"), 1);
+        assert_eq!(seasons("beyond"), 1);
+        assert_eq!(seasons("Sousou"), 1);    // by the alias
+        assert_eq!(seasons("葬送"), 1);
+        assert_eq!(seasons("Spy"), 0);
+
+        let releases = |query: &str| repo.search_releases(query, SEARCH_LIMIT).unwrap()
+            .iter().map(|x| x.item.mikan_item_uuid.clone()).collect::<Vec<_>>();
+        assert_eq!(releases("frieren 1080p"), vec!["a"]);
+        assert_eq!(releases("01 [1080").len(), 2);
+
+        // Replaced and deleted rows leave the index
+        repo.insert_mikan_item(&release("a", "[LoliHouse] Sousou no Frieren - 02 [1080p]")).unwrap();
+        assert_eq!(releases("Frieren"), vec!["a"]);
+        assert_eq!(releases("01"), vec!["b"]);
+        repo.delete_season(3141, 382).unwrap();
+        assert_eq!(seasons("芙莉莲"), 0);
+    }
+}
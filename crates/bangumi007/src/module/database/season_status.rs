@@ -0,0 +1,70 @@
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+
+/// Progress of a season, stored by key
+///
+/// airing → finished airing → complete in library, computed by `season_status` on every feed update;
+/// only airing seasons are polled; archived is set by the user, or on completion if `auto_archive_complete` is configured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SeasonStatus {
+    #[default]
+    Airing,         // main-story episodes still to air, or unknown
+    Finished,       // every main-story episode aired, some not downloaded yet
+    Complete,       // every main-story episode downloaded
+    Archived,       // hidden from the library and no longer polled
+}
+
+impl SeasonStatus {
+    pub const ALL: [SeasonStatus; 4] = [
+        SeasonStatus::Airing,
+        SeasonStatus::Finished,
+        SeasonStatus::Complete,
+        SeasonStatus::Archived,
+    ];
+
+    pub fn key(&self) -> &'static str {
+        match self {
+            SeasonStatus::Airing => "airing",
+            SeasonStatus::Finished => "finished",
+            SeasonStatus::Complete => "complete",
+            SeasonStatus::Archived => "archived",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<SeasonStatus> {
+        SeasonStatus::ALL.iter().find(|x| x.key() == key).copied()
+    }
+
+    pub fn disp_name(&self) -> &'static str {
+        match self {
+            SeasonStatus::Airing => "连载中",
+            SeasonStatus::Finished => "已完结",
+            SeasonStatus::Complete => "已下载全部",
+            SeasonStatus::Archived => "已归档",
+        }
+    }
+
+    /// Whether the feeds of the season are still fetched
+    pub fn is_polled(&self) -> bool {
+        matches!(self, SeasonStatus::Airing)
+    }
+
+    /// Whether the season may miss episodes, a finished season still gets them filled, see `auto_episode_gap_detect`
+    pub fn may_have_gaps(&self) -> bool {
+        matches!(self, SeasonStatus::Airing | SeasonStatus::Finished)
+    }
+}
+
+impl ToSql for SeasonStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.key()))
+    }
+}
+
+impl FromSql for SeasonStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let key = value.as_str()?;
+        SeasonStatus::from_key(key).ok_or_else(|| FromSqlError::Other(format!("Unknown season status {}", key).into()))
+    }
+}
\ No newline at end of file
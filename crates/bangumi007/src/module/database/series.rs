@@ -0,0 +1,222 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+use crate::module::database::library::AnimeSeason;
+use crate::module::database::repository::{SeasonRepository, SeriesRepository, SqliteRepository, with_repository};
+use crate::module::utils::error::new_err;
+
+/// A show in the library, grouping the seasons of every subject and subgroup under a stable id.
+/// Names and external ids are those of its representative season, see `SeriesRepository::sync_series`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AnimeSeries {
+    pub series_id: i32,
+    pub disp_series_name: String,
+    pub bangumi_subject_id: i32,
+    pub bangumi_subject_name: String,
+    pub tmdb_series_id: i32,
+    pub tmdb_series_name: String,
+    pub anilist_id: i32,
+    pub mal_id: i32,
+    pub anidb_id: i32,
+}
+
+#[deny(dead_code)]
+pub fn init_library_series_table(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    conn.execute(
+        "create table if not exists library_series (
+            series_id integer primary key autoincrement,
+            disp_series_name text default '',
+            bangumi_subject_id integer default -1,
+            bangumi_subject_name text default '',
+            tmdb_series_id integer default -1,
+            tmdb_series_name text default '',
+            anilist_id integer default -1,
+            mal_id integer default -1,
+            anidb_id integer default -1
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Link the seasons of an older database to series, grouped by TMDB series, then by subject, then by display name.
+/// Only the columns of this schema version are read, later migrations may add columns to the season table.
+pub fn link_existing_seasons(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let seasons = conn.prepare(
+        "select mikan_subject_id, mikan_subgroup_id, coalesce(tmdb_series_id, -1), coalesce(disp_series_name, '')
+        from library_anime_season where series_id <= 0 order by mikan_subject_id, mikan_subgroup_id"
+    )?.query_map([], |row| Ok((row.get::<_, i32>(0)?, row.get::<_, i32>(1)?, row.get::<_, i32>(2)?, row.get::<_, String>(3)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let repo = SqliteRepository::new(conn);
+    let mut series_ids: HashMap<String, i32> = HashMap::new();
+    let mut linked = HashSet::new();
+    for (mikan_subject_id, mikan_subgroup_id, tmdb_series_id, disp_series_name) in seasons {
+        let mut keys = vec![format!("subject:{}", mikan_subject_id), format!("name:{}", disp_series_name)];
+        if tmdb_series_id > 0 {
+            keys.insert(0, format!("tmdb:{}", tmdb_series_id));
+        }
+        let series_id = match keys.iter().find_map(|x| series_ids.get(x)) {
+            Some(series_id) => *series_id,
+            None => repo.insert_series(&AnimeSeries { disp_series_name: disp_series_name.clone(), ..Default::default() })?,
+        };
+        for key in keys {
+            series_ids.entry(key).or_insert(series_id);
+        }
+        repo.set_season_series_id(mikan_subject_id, mikan_subgroup_id, series_id)?;
+        linked.insert(series_id);
+    }
+    for series_id in linked {
+        repo.sync_series(series_id)?;
+    }
+    Ok(())
+}
+
+/// Series a season belongs to, `None` if a new series is needed
+///
+/// ## Procedure
+///
+/// 1. The series of the same TMDB series, so that a season named by a Bangumi fallback is not split from the show
+/// 2. The series the season is linked to
+/// 3. The series of another subgroup of the same subject
+/// 4. A series with the same name, e.g. seasons only known to Bangumi
+fn find_season_series_in<R: SeasonRepository + SeriesRepository>(repo: &R, season: &AnimeSeason) -> Result<Option<i32>, Box<dyn Error>> {
+    if season.tmdb_series_id > 0 {
+        if let Some(series) = repo.find_series_by_tmdb_id(season.tmdb_series_id)? {
+            return Ok(Some(series.series_id));
+        }
+    }
+    if season.series_id > 0 && repo.get_series(season.series_id)?.is_some() {
+        return Ok(Some(season.series_id));
+    }
+    let sibling = repo.list_seasons()?.into_iter()
+        .find(|x| x.mikan_subject_id == season.mikan_subject_id && x.mikan_subgroup_id != season.mikan_subgroup_id && x.series_id > 0);
+    if let Some(sibling) = sibling {
+        return Ok(Some(sibling.series_id));
+    }
+    Ok(repo.find_series_by_name(&season.disp_series_name)?.map(|x| x.series_id))
+}
+
+/// Link a season to its series, creating the series if none matches, and refresh the names of the series.
+/// A series left without seasons is deleted.
+///
+/// ## Output
+///
+/// series_id : `i32`
+pub fn link_season_series_in<R: SeasonRepository + SeriesRepository>(repo: &R, mikan_subject_id: i32, mikan_subgroup_id: i32) -> Result<i32, Box<dyn Error>> {
+    let season = repo.get_season(mikan_subject_id, mikan_subgroup_id)?
+        .ok_or_else(|| new_err(format!("Season {}-{} not found", mikan_subject_id, mikan_subgroup_id).as_str()))?;
+    let series_id = match find_season_series_in(repo, &season)? {
+        Some(series_id) => series_id,
+        None => repo.insert_series(&AnimeSeries { disp_series_name: season.disp_series_name.clone(), ..Default::default() })?,
+    };
+    if series_id != season.series_id {
+        repo.set_season_series_id(mikan_subject_id, mikan_subgroup_id, series_id)?;
+        if season.series_id > 0 {
+            repo.sync_series(season.series_id)?;
+        }
+    }
+    repo.sync_series(series_id)?;
+    Ok(series_id)
+}
+
+pub fn read_series_info(series_id: i32) -> Option<AnimeSeries> {
+    with_repository("read series", |repo| repo.get_series(series_id))
+}
+
+pub fn read_series_list() -> Vec<AnimeSeries> {
+    with_repository("read series", |repo| repo.list_series())
+}
+
+/// Season reported by the media server, by the name of its series and the display season number.
+/// The name is matched against every name of the series, the id tag of the folder name (e.g. ` [tmdbid-209867]`) is ignored.
+pub fn find_season_by_series_name(series_name: &str, disp_season_num: i32) -> Option<AnimeSeason> {
+    let series_name = strip_folder_id_tag(series_name);
+    with_repository("find season", |repo| {
+        match repo.find_series_by_name(series_name)? {
+            Some(series) => repo.find_season_in_series(series.series_id, disp_season_num),
+            None => Ok(None),
+        }
+    })
+}
+
+/// "葬送的芙莉莲 [tmdbid-209867]" -> "葬送的芙莉莲"
+pub(crate) fn strip_folder_id_tag(series_name: &str) -> &str {
+    let series_name = series_name.trim_end();
+    match series_name.rsplit_once(" [") {
+        Some((name, tag)) if tag.ends_with(']') && tag.contains("id-") => name,
+        _ => series_name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::module::database::repository::{open_in_memory_database, test_season};
+
+    use super::*;
+
+    #[test]
+    fn test_link_season_series() {
+        let conn = open_in_memory_database().unwrap();
+        let repo = SqliteRepository::new(&conn);
+        let tmdb_season = |mikan_subject_id: i32, tmdb_series_id: i32, name: &str, disp_season_num: i32| AnimeSeason {
+            tmdb_series_id,
+            tmdb_series_name: name.to_string(),
+            disp_series_name: name.to_string(),
+            ..test_season(mikan_subject_id, disp_season_num)
+        };
+        let link = |season: AnimeSeason| {
+            repo.upsert_season(&season).unwrap();
+            link_season_series_in(&repo, season.mikan_subject_id, season.mikan_subgroup_id).unwrap()
+        };
+
+        let frieren = link(tmdb_season(3141, 209867, "Frieren", 1));
+        // Same TMDB series under another name, another subgroup of the subject, a Bangumi-only season of the same name
+        assert_eq!(link(tmdb_season(3310, 209867, "葬送的芙莉莲", 2)), frieren);
+        assert_eq!(link(AnimeSeason { mikan_subgroup_id: 583, disp_series_name: "葬送のフリーレン".to_string(), ..test_season(3141, 1) }), frieren);
+        assert_eq!(link(AnimeSeason { disp_series_name: "Frieren".to_string(), ..test_season(3500, 0) }), frieren);
+        let spy = link(tmdb_season(2968, 120089, "SPY×FAMILY", 1));
+        assert_ne!(spy, frieren);
+        // The series is named after its season with TMDB metadata
+        assert_eq!(repo.get_series(frieren).unwrap().unwrap().disp_series_name, "Frieren");
+        assert_eq!(repo.find_season_in_series(frieren, 2).unwrap().unwrap().mikan_subject_id, 3310);
+
+        // Renaming keeps the series
+        let mut renamed = repo.get_season(3141, 382).unwrap().unwrap();
+        renamed.disp_series_name = "芙莉莲".to_string();
+        renamed.tmdb_series_name = "芙莉莲".to_string();
+        assert_eq!(link(renamed), frieren);
+        assert_eq!(repo.get_series(frieren).unwrap().unwrap().disp_series_name, "芙莉莲");
+
+        // Choosing another TMDB series moves the season, the empty series is deleted
+        let alone = link(AnimeSeason { mikan_subgroup_id: 1, disp_series_name: "Misparsed".to_string(), ..test_season(4000, 1) });
+        let mut moved = repo.get_season(4000, 1).unwrap().unwrap();
+        moved.tmdb_series_id = 120089;
+        assert_eq!(link(moved), spy);
+        assert!(repo.get_series(alone).unwrap().is_none());
+        assert_eq!(repo.list_series().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_find_series_by_name() {
+        let conn = open_in_memory_database().unwrap();
+        let repo = SqliteRepository::new(&conn);
+        repo.upsert_season(&AnimeSeason {
+            bangumi_subject_name: "葬送のフリーレン".to_string(),
+            tmdb_series_id: 209867,
+            tmdb_series_name: "Frieren".to_string(),
+            disp_series_name: "Frieren".to_string(),
+            ..test_season(3141, 1)
+        }).unwrap();
+        let series_id = link_season_series_in(&repo, 3141, 382).unwrap();
+        let find = |name: &str| repo.find_series_by_name(strip_folder_id_tag(name)).unwrap().map(|x| x.series_id);
+        assert_eq!(find("Frieren"), Some(series_id));
+        assert_eq!(find("Frieren [tmdbid-209867]"), Some(series_id));
+        assert_eq!(find("葬送のフリーレン"), Some(series_id));
+        assert_eq!(find("Spy"), None);
+        assert_eq!(strip_folder_id_tag("Re:Zero [Director's Cut]"), "Re:Zero [Director's Cut]");
+    }
+}
@@ -0,0 +1,135 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+use crate::module::database::cache::rss::MikanSubject;
+use crate::module::database::get_connection;
+use crate::module::database::repository::{SqliteRepository, SubjectOverrideRepository, with_repository};
+
+/// Manual metadata of a Mikan subject, set by the user and kept across re-parsing.
+/// `None` fields are left to the parsers.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MikanSubjectOverride {
+    pub mikan_subject_id: i32,
+    pub bangumi_subject_id: Option<i32>,
+    pub tmdb_series_id: Option<i64>,
+    pub tmdb_season_num: Option<i32>,
+    pub disp_series_name: Option<String>,
+    pub disp_season_name: Option<String>,
+}
+
+impl MikanSubjectOverride {
+    pub fn is_empty(&self) -> bool {
+        self.bangumi_subject_id.is_none()
+            && self.tmdb_series_id.is_none()
+            && self.tmdb_season_num.is_none()
+            && self.disp_series_name.is_none()
+            && self.disp_season_name.is_none()
+    }
+
+    /// Whether a parsed subject already uses the pinned ids, i.e. no re-parse is needed.
+    pub fn matches(&self, subject: &MikanSubject) -> bool {
+        self.bangumi_subject_id.map_or(true, |x| x == subject.bangumi_subject_id)
+            && self.tmdb_series_id.map_or(true, |x| x == subject.tmdb_series_id as i64)
+            && self.tmdb_season_num.map_or(true, |x| x == subject.tmdb_season_num)
+    }
+}
+
+#[deny(dead_code)]
+pub fn init_conf_mikan_subject_override_table(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    conn.execute(
+        "create table if not exists conf_mikan_subject_override (
+            mikan_subject_id integer primary key,
+            bangumi_subject_id integer,
+            tmdb_series_id integer,
+            tmdb_season_num integer,
+            disp_series_name text,
+            disp_season_name text
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Save the override of a Mikan subject, an empty override is deleted.
+pub fn set_subject_override(subject_override: &MikanSubjectOverride) -> Result<(), Box<dyn Error>> {
+    let conn = get_connection()?;
+    SqliteRepository::new(&conn).upsert_subject_override(subject_override)
+}
+
+pub fn get_subject_override(mikan_subject_id: i32) -> Option<MikanSubjectOverride> {
+    with_repository("read subject override", |repo| repo.get_subject_override(mikan_subject_id))
+}
+
+pub fn read_subject_overrides() -> Vec<MikanSubjectOverride> {
+    with_repository("read subject overrides", |repo| repo.list_subject_overrides())
+}
+
+/// Apply the display names of the override (if any) on a parsed subject, the cache is kept as parsed.
+pub fn apply_subject_override(subject: &mut MikanSubject) {
+    if let Some(subject_override) = get_subject_override(subject.mikan_subject_id) {
+        if let Some(name) = subject_override.disp_series_name {
+            subject.tmdb_series_name = name;
+        }
+        if let Some(name) = subject_override.disp_season_name {
+            subject.tmdb_season_name = name;
+        }
+    }
+}
+
+/// Export all overrides as a json file.
+pub fn export_subject_overrides(path: &Path) -> Result<usize, Box<dyn Error>> {
+    let overrides = read_subject_overrides();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(&overrides)?)?;
+    Ok(overrides.len())
+}
+
+/// Import overrides from a json file written by `export_subject_overrides`, existing overrides of the same subjects are replaced.
+pub fn import_subject_overrides(path: &Path) -> Result<usize, Box<dyn Error>> {
+    let overrides: Vec<MikanSubjectOverride> = serde_json::from_str(&fs::read_to_string(path)?)?;
+    for subject_override in overrides.iter() {
+        set_subject_override(subject_override)?;
+    }
+    Ok(overrides.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subject_override_matches() {
+        let subject = MikanSubject {
+            mikan_subject_id: 3141,
+            mikan_subject_image_url: "".to_string(),
+            bangumi_subject_id: 400602,
+            bangumi_subject_name: "葬送のフリーレン".to_string(),
+            bangumi_season_num: 1,
+            bangumi_subject_image_url: "".to_string(),
+            tmdb_series_id: 209867,
+            tmdb_series_name: "葬送的芙莉莲".to_string(),
+            tmdb_season_num: 1,
+            tmdb_season_name: "第 1 季".to_string(),
+            bangumi_to_tmdb_episode_offset: 0,
+            anilist_id: -1,
+            mal_id: -1,
+            anidb_id: -1,
+        };
+        let mut subject_override = MikanSubjectOverride { mikan_subject_id: 3141, ..Default::default() };
+        assert!(subject_override.is_empty());
+        assert!(subject_override.matches(&subject));
+
+        subject_override.disp_series_name = Some("芙莉莲".to_string());
+        assert!(!subject_override.is_empty());
+        assert!(subject_override.matches(&subject));
+
+        subject_override.tmdb_season_num = Some(2);
+        assert!(!subject_override.matches(&subject));
+    }
+}
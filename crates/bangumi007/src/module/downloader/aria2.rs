@@ -0,0 +1,367 @@
+use std::error::Error;
+use std::path::{Component, Path};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use reqwest::blocking::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::module::config::{DownloaderConfig, DownloaderKind};
+use crate::module::downloader::{Downloader, DownloaderFile, DownloaderTorrent, TorrentSource};
+use crate::module::utils::error::new_err;
+
+/// aria2 through its JSON-RPC at `/jsonrpc`, `password` being the RPC secret
+///
+/// aria2 has no categories or tags and cannot rename the files of a torrent once added,
+/// nor move the files already downloaded. Every download is listed, they are told apart by their hash.
+#[derive(Debug)]
+pub struct Aria2 {
+    config: DownloaderConfig,
+    client: Client,
+}
+
+/// Numbers come as strings in aria2 replies
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct Aria2Download {
+    gid: String,
+    info_hash: String,
+    status: String,             // active, waiting, paused, error, complete or removed
+    dir: String,
+    completed_length: String,
+    total_length: String,
+    files: Vec<Aria2File>,
+    bittorrent: Option<Aria2Bittorrent>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Aria2Bittorrent {
+    info: Option<Aria2BittorrentInfo>,     // missing while the metadata of a magnet link is fetched
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Aria2BittorrentInfo {
+    name: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct Aria2File {
+    path: String,
+    length: String,
+    completed_length: String,
+}
+
+const DOWNLOAD_KEYS: [&str; 8] = ["gid", "infoHash", "status", "dir", "completedLength", "totalLength", "files", "bittorrent"];
+
+/*
+$ curl http://localhost:6800/jsonrpc --data '{"jsonrpc":"2.0","id":"bangumi007","method":"aria2.tellActive","params":["token:secret",["gid","infoHash"]]}'
+{"id":"bangumi007","jsonrpc":"2.0","result":[{"gid":"2089b05ecca3d829","infoHash":"bc5fe73ecf6667dcefabdbdeb0f47fd985cc776e"}]}
+ */
+
+impl Aria2 {
+    pub fn new(config: DownloaderConfig) -> Aria2 {
+        Aria2 {
+            config,
+            client: Client::new(),
+        }
+    }
+
+    /// Call a method with the secret token put first, errors come in the body whatever the status
+    fn call(&self, method: &str, params: Vec<Value>) -> Result<Value, Box<dyn Error>> {
+        log::debug!("aria2 {}", method);
+        let url = format!("{}/jsonrpc", self.config.base_url());
+        let mut all_params = Vec::new();
+        if !self.config.password.is_empty() {
+            all_params.push(json!(format!("token:{}", self.config.password)));
+        }
+        all_params.extend(params);
+        let body = json!({ "jsonrpc": "2.0", "id": "bangumi007", "method": method, "params": all_params });
+        let resp: Value = self.client.post(&url).json(&body).send()?.json()?;
+        if let Some(error) = resp.get("error") {
+            return Err(new_err(format!("aria2 {} failed: {}", method, error["message"].as_str().unwrap_or("unknown error")).as_str()));
+        }
+        Ok(resp["result"].clone())
+    }
+
+    /// Torrent downloads, active, waiting and stopped ones
+    fn downloads(&self) -> Result<Vec<Aria2Download>, Box<dyn Error>> {
+        let mut downloads: Vec<Aria2Download> = serde_json::from_value(self.call("aria2.tellActive", vec![json!(DOWNLOAD_KEYS)])?)?;
+        for method in ["aria2.tellWaiting", "aria2.tellStopped"] {
+            let result = self.call(method, vec![json!(0), json!(1000), json!(DOWNLOAD_KEYS)])?;
+            downloads.extend(serde_json::from_value::<Vec<Aria2Download>>(result)?);
+        }
+        Ok(downloads.into_iter().filter(|x| !x.info_hash.is_empty()).collect())
+    }
+
+    /// Downloads of a torrent, a magnet link also has the download of its metadata
+    fn downloads_of(&self, hash: &str) -> Result<Vec<Aria2Download>, Box<dyn Error>> {
+        let downloads: Vec<Aria2Download> = self.downloads()?.into_iter().filter(|x| x.info_hash == hash).collect();
+        if downloads.is_empty() {
+            return Err(new_err(format!("Torrent {} not found in aria2", hash).as_str()));
+        }
+        Ok(downloads)
+    }
+
+    /// The download of the torrent itself rather than of its metadata, when there is one
+    fn download_of(&self, hash: &str) -> Result<Aria2Download, Box<dyn Error>> {
+        let mut downloads = self.downloads_of(hash)?;
+        let index = downloads.iter().position(|x| x.has_info()).unwrap_or(0);
+        Ok(downloads.swap_remove(index))
+    }
+}
+
+impl Aria2Download {
+    fn has_info(&self) -> bool {
+        self.bittorrent.as_ref().is_some_and(|x| x.info.is_some())
+    }
+
+    /// Path of a file relative to the download folder
+    fn relative_path(&self, path: &str) -> String {
+        path.strip_prefix(&self.dir).map_or(path, |x| x.trim_start_matches('/')).to_string()
+    }
+}
+
+/// Whether a file of a removed download can be deleted here: it is in `download_dir` on this machine,
+/// so that the files of an aria2 on another machine, or outside the library, are left alone
+fn is_local_download_file(path: &str, download_dir: &str) -> bool {
+    let path = Path::new(path);
+    !download_dir.is_empty()
+        && path.starts_with(download_dir)
+        && !path.components().any(|x| x == Component::ParentDir)
+        && path.is_file()
+}
+
+fn parse_length(length: &str) -> i64 {
+    length.parse().unwrap_or(0)
+}
+
+fn progress(completed_length: &str, total_length: &str) -> f32 {
+    match parse_length(total_length) {
+        0 => 0.,
+        total => parse_length(completed_length) as f32 / total as f32,
+    }
+}
+
+impl Downloader for Aria2 {
+    fn kind(&self) -> DownloaderKind {
+        DownloaderKind::Aria2
+    }
+
+    fn add_torrent(&self, source: &TorrentSource, save_path: &str) -> Result<(), Box<dyn Error>> {
+        let mut options = json!({
+            "dir": save_path,
+            "pause": if self.config.paused_after_add { "true" } else { "false" },
+        });
+        if self.config.first_last_piece_prio {
+            options["bt-prioritize-piece"] = json!("head,tail");
+        }
+        match source {
+            TorrentSource::Magnet(link) => self.call("aria2.addUri", vec![json!([link]), options])?,
+            TorrentSource::File(content) => self.call("aria2.addTorrent", vec![json!(BASE64.encode(content)), json!([]), options])?,
+        };
+        Ok(())
+    }
+
+    fn list_torrents(&self) -> Result<Vec<DownloaderTorrent>, Box<dyn Error>> {
+        let mut torrents: Vec<DownloaderTorrent> = Vec::new();
+        for download in self.downloads()? {
+            // The lengths of a download without info are those of the metadata, the torrent is not started yet
+            let (completed_length, total_length) = match download.has_info() {
+                true => (download.completed_length.as_str(), download.total_length.as_str()),
+                false => ("0", "0"),
+            };
+            let torrent = DownloaderTorrent {
+                hash: download.info_hash.clone(),
+                name: download.bittorrent.as_ref().and_then(|x| x.info.as_ref()).map_or("".to_string(), |x| x.name.clone()),
+                save_path: download.dir.clone(),
+                progress: progress(completed_length, total_length),
+                downloaded: parse_length(completed_length),
+                size: parse_length(total_length),
+            };
+            match torrents.iter_mut().find(|x| x.hash == torrent.hash) {
+                // The metadata of a magnet link is done before the torrent is
+                Some(existing) if download.has_info() => *existing = torrent,
+                Some(_) => {}
+                None => torrents.push(torrent),
+            }
+        }
+        Ok(torrents)
+    }
+
+    fn torrent_files(&self, hash: &str) -> Result<Vec<DownloaderFile>, Box<dyn Error>> {
+        let download = self.download_of(hash)?;
+        if !download.has_info() {
+            return Ok(vec![]);
+        }
+        Ok(download.files.iter().map(|x| DownloaderFile {
+            name: download.relative_path(&x.path),
+            size: parse_length(&x.length),
+            progress: progress(&x.completed_length, &x.length),
+        }).collect())
+    }
+
+    fn rename_file(&self, _hash: &str, _old_path: &str, _new_path: &str) -> Result<(), Box<dyn Error>> {
+        Err(new_err("aria2 cannot rename the files of a torrent"))
+    }
+
+    /// Only a download not started yet can be moved, aria2 leaves downloaded files where they are
+    fn move_torrent(&self, hash: &str, save_path: &str) -> Result<(), Box<dyn Error>> {
+        let download = self.download_of(hash)?;
+        if download.dir == save_path {
+            return Ok(());
+        }
+        if parse_length(&download.completed_length) > 0 {
+            return Err(new_err(format!("aria2 cannot move the downloaded files of {}", hash).as_str()));
+        }
+        self.call("aria2.changeOption", vec![json!(download.gid), json!({ "dir": save_path })])?;
+        Ok(())
+    }
+
+    fn set_labels(&self, _hash: &str, _category: &str, _tags: &str) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// aria2 keeps the files of a removed download, they are deleted here if `delete_files`,
+    /// only those found under `download_dir` on this machine, see `is_local_download_file`
+    fn remove_torrents(&self, hashes: &[String], delete_files: bool) -> Result<(), Box<dyn Error>> {
+        let downloads = self.downloads()?;
+        for download in downloads.iter().filter(|x| hashes.contains(&x.info_hash)) {
+            match download.status.as_str() {
+                "active" | "waiting" | "paused" => self.call("aria2.forceRemove", vec![json!(download.gid)])?,
+                _ => self.call("aria2.removeDownloadResult", vec![json!(download.gid)])?,
+            };
+            if delete_files && download.has_info() {
+                for file in download.files.iter() {
+                    if !is_local_download_file(&file.path, &self.config.download_dir) {
+                        log::debug!("Not deleting {}, not found in {}", file.path, self.config.download_dir);
+                        continue;
+                    }
+                    log::debug!("Deleting {}", file.path);
+                    std::fs::remove_file(&file.path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::module::downloader::fake_server::FakeServer;
+
+    use super::*;
+
+    const HASH: &str = "bc5fe73ecf6667dcefabdbdeb0f47fd985cc776e";
+    const MAGNET_HASH: &str = "0b5fe73ecf6667dcefabdbdeb0f47fd985cc776f";
+
+    fn fake_aria2() -> (FakeServer, Aria2) {
+        let server = FakeServer::start(|request| {
+            let body: Value = serde_json::from_str(&request.body).unwrap();
+            if body["params"][0] != json!("token:secret") {
+                return (400, vec![], json!({ "id": "bangumi007", "jsonrpc": "2.0", "error": { "code": 1, "message": "Unauthorized" } }).to_string());
+            }
+            let result = match body["method"].as_str().unwrap() {
+                "aria2.tellActive" => json!([{
+                    "gid": "2089b05ecca3d829", "infoHash": HASH, "status": "active", "dir": "/downloads",
+                    "completedLength": "0", "totalLength": "2048",
+                    "files": [{ "path": "/downloads/a.mkv", "length": "2048", "completedLength": "0" }],
+                    "bittorrent": { "info": { "name": "a.mkv" } },
+                }]),
+                // Magnet link whose metadata is being fetched
+                "aria2.tellWaiting" => json!([{
+                    "gid": "9a3d80d2a89b05ec", "infoHash": MAGNET_HASH, "status": "waiting", "dir": "/downloads",
+                    "completedLength": "256", "totalLength": "256",
+                    "files": [{ "path": format!("[METADATA]{}", MAGNET_HASH), "length": "256", "completedLength": "256" }],
+                    "bittorrent": {},
+                }]),
+                // Metadata of the magnet link, done before the torrent
+                "aria2.tellStopped" => json!([{
+                    "gid": "d2a89b05ecca3d80", "infoHash": HASH, "status": "complete", "dir": "/downloads",
+                    "completedLength": "512", "totalLength": "512",
+                    "files": [{ "path": format!("[METADATA]{}", HASH), "length": "512", "completedLength": "512" }],
+                    "bittorrent": {},
+                }]),
+                _ => json!("OK"),
+            };
+            (200, vec![], json!({ "id": "bangumi007", "jsonrpc": "2.0", "result": result }).to_string())
+        });
+        let config = DownloaderConfig {
+            host: "127.0.0.1".to_string(),
+            port: server.port as i64,
+            password: "secret".to_string(),
+            ..Default::default()
+        };
+        (server, Aria2::new(config))
+    }
+
+    #[test]
+    fn test_fake_aria2() {
+        let (server, aria2) = fake_aria2();
+        assert_eq!(aria2.list_torrents().unwrap(), vec![DownloaderTorrent {
+            hash: HASH.to_string(),
+            name: "a.mkv".to_string(),
+            save_path: "/downloads".to_string(),
+            progress: 0.,
+            downloaded: 0,
+            size: 2048,
+        }, DownloaderTorrent {
+            hash: MAGNET_HASH.to_string(),
+            name: "".to_string(),
+            save_path: "/downloads".to_string(),
+            progress: 0.,
+            downloaded: 0,
+            size: 0,
+        }]);
+        assert_eq!(aria2.torrent_files(HASH).unwrap(), vec![DownloaderFile { name: "a.mkv".to_string(), size: 2048, progress: 0. }]);
+        assert!(aria2.rename_file(HASH, "a.mkv", "Frieren S01E01.mkv").is_err());
+        aria2.set_labels(HASH, "Anime", "Bangumi007").unwrap();
+
+        let requests = |method: &str| -> Vec<Value> {
+            server.requests().iter()
+                .map(|x| serde_json::from_str::<Value>(&x.body).unwrap())
+                .filter(|x| x["method"] == json!(method))
+                .collect()
+        };
+        aria2.add_torrent(&TorrentSource::Magnet(format!("magnet:?xt=urn:btih:{}", HASH)), "/downloads/Frieren/Season 1").unwrap();
+        let add = requests("aria2.addUri");
+        assert_eq!(add[0]["params"][1], json!([format!("magnet:?xt=urn:btih:{}", HASH)]));
+        assert_eq!(add[0]["params"][2]["dir"], json!("/downloads/Frieren/Season 1"));
+
+        // Not started yet, so it can still be moved
+        aria2.move_torrent(HASH, "/downloads/Frieren/Season 1").unwrap();
+        assert_eq!(requests("aria2.changeOption")[0]["params"][1], json!("2089b05ecca3d829"));
+
+        aria2.remove_torrents(&[HASH.to_string()], false).unwrap();
+        assert_eq!(requests("aria2.forceRemove")[0]["params"][1], json!("2089b05ecca3d829"));
+        assert_eq!(requests("aria2.removeDownloadResult")[0]["params"][1], json!("d2a89b05ecca3d80"));
+        // JSON-RPC calls are all posted
+        assert!(server.requests().iter().all(|x| x.method == "POST"));
+    }
+
+    #[test]
+    fn test_is_local_download_file() {
+        let download_dir = std::env::temp_dir().join("bangumi007_aria2_test");
+        std::fs::create_dir_all(download_dir.join("Frieren")).unwrap();
+        let file = download_dir.join("Frieren").join("a.mkv");
+        std::fs::write(&file, b"").unwrap();
+        let download_dir_str = download_dir.to_str().unwrap();
+        assert!(is_local_download_file(file.to_str().unwrap(), download_dir_str));
+        // Not downloaded here, or outside the download folder
+        assert!(!is_local_download_file(download_dir.join("Frieren").join("b.mkv").to_str().unwrap(), download_dir_str));
+        assert!(!is_local_download_file(file.to_str().unwrap(), download_dir.join("Frieren").join("Season 1").to_str().unwrap()));
+        assert!(!is_local_download_file(download_dir.join("..").join("bangumi007_aria2_test").join("Frieren").join("a.mkv").to_str().unwrap(), download_dir_str));
+        assert!(!is_local_download_file(file.to_str().unwrap(), ""));
+        std::fs::remove_dir_all(&download_dir).unwrap();
+    }
+
+    #[test]
+    fn test_fake_aria2_error() {
+        let (_server, aria2) = fake_aria2();
+        let aria2 = Aria2::new(DownloaderConfig { password: "wrong".to_string(), ..aria2.config.clone() });
+        assert!(aria2.list_torrents().unwrap_err().to_string().contains("Unauthorized"));
+    }
+}
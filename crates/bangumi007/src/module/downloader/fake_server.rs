@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A request received by `FakeServer`, header names are lowercase
+#[derive(Debug, Clone)]
+pub struct FakeRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+/// Status, extra headers and body of a reply
+pub type FakeResponse = (u16, Vec<(&'static str, String)>, String);
+
+/// Local HTTP server standing in for a downloader in tests, one request per connection
+pub struct FakeServer {
+    pub port: u16,
+    requests: Arc<Mutex<Vec<FakeRequest>>>,
+}
+
+impl FakeServer {
+    /// Listen on a free local port and answer every request with `handler` from a background thread
+    pub fn start<F>(handler: F) -> FakeServer
+    where
+        F: Fn(&FakeRequest) -> FakeResponse + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let request = match read_request(&stream) {
+                    Some(request) => request,
+                    None => continue,
+                };
+                let (status, headers, body) = handler(&request);
+                received.lock().unwrap().push(request);
+                let mut response = format!("HTTP/1.1 {} Fake\r\nContent-Length: {}\r\nConnection: close\r\n", status, body.len());
+                for (name, value) in headers {
+                    response.push_str(&format!("{}: {}\r\n", name, value));
+                }
+                response.push_str("\r\n");
+                response.push_str(&body);
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        FakeServer { port, requests }
+    }
+
+    /// Requests received so far, in order
+    pub fn requests(&self) -> Vec<FakeRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Option<FakeRequest> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    let length = headers.get("content-length").and_then(|x| x.parse::<usize>().ok()).unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    Some(FakeRequest { method, path, headers, body: String::from_utf8_lossy(&body).to_string() })
+}
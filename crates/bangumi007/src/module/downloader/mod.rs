@@ -1,2 +1,391 @@
 pub mod qbittorrent;
+pub mod transmission;
+pub mod aria2;
+#[cfg(test)]
+mod fake_server;
 
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+
+use crate::module::config::{CONFIG, DownloaderConfig, DownloaderKind, MetadataSource};
+use crate::module::database::activity::{Activity, ActivityKind, record_activity};
+use crate::module::database::item_state::{apply_item_event, ItemEvent, ItemState};
+use crate::module::database::library::{AnimeSeason, AnimeSeasonItem};
+use crate::module::database::series::{AnimeSeries, read_series_info};
+use crate::module::downloader::aria2::Aria2;
+use crate::module::downloader::qbittorrent::QBittorrent;
+use crate::module::downloader::transmission::Transmission;
+use crate::module::library::arrangement::read_item_placement;
+use crate::module::utils::error::new_err;
+
+/// A torrent in the downloader
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloaderTorrent {
+    pub hash: String,           // info hash in hex
+    pub name: String,
+    pub save_path: String,
+    pub progress: f32,          // in [0, 1]
+    pub downloaded: i64,        // bytes
+    pub size: i64,              // bytes
+}
+
+/// A file of a torrent in the downloader
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloaderFile {
+    pub name: String,           // path relative to the save path of the torrent
+    pub size: i64,
+    pub progress: f32,
+}
+
+/// What a torrent is added from
+#[derive(Debug, Clone, PartialEq)]
+pub enum TorrentSource {
+    Magnet(String),
+    File(Vec<u8>),              // content of a .torrent file
+}
+
+/// A BitTorrent client torrents are sent to, see `DownloaderKind`
+///
+/// Torrents are identified by their info hash. Options of new torrents, e.g. paused or sequential download,
+/// are taken from the `DownloaderConfig` the client is created with, and ignored where the client has no such option.
+pub trait Downloader: Send + Sync {
+    fn kind(&self) -> DownloaderKind;
+
+    /// Add a torrent saved under `save_path`, with the configured category and tags
+    fn add_torrent(&self, source: &TorrentSource, save_path: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Torrents of the client, only those of the configured category and tags if the client filters them
+    fn list_torrents(&self) -> Result<Vec<DownloaderTorrent>, Box<dyn Error>>;
+
+    fn torrent_files(&self, hash: &str) -> Result<Vec<DownloaderFile>, Box<dyn Error>>;
+
+    /// Rename a file of a torrent, paths are relative to the save path, see `DownloaderKind::can_rename`
+    fn rename_file(&self, hash: &str, old_path: &str, new_path: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Move a torrent and its downloaded files to `save_path`
+    fn move_torrent(&self, hash: &str, save_path: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Set the category and add the comma-separated tags of a torrent
+    fn set_labels(&self, hash: &str, category: &str, tags: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Remove torrents, with their downloaded files if `delete_files`
+    fn remove_torrents(&self, hashes: &[String], delete_files: bool) -> Result<(), Box<dyn Error>>;
+}
+
+lazy_static! {
+    static ref DOWNLOADER: Mutex<Option<(DownloaderConfig, Arc<dyn Downloader>)>> = Mutex::new(None);
+}
+
+fn get_config() -> DownloaderConfig {
+    CONFIG.read().unwrap().downloader_config.clone()
+}
+
+/// Client of a downloader config, sessions are not shared between clients
+pub fn new_downloader(config: DownloaderConfig) -> Arc<dyn Downloader> {
+    match config.kind {
+        DownloaderKind::QBittorrent => Arc::new(QBittorrent::new(config)),
+        DownloaderKind::Transmission => Arc::new(Transmission::new(config)),
+        DownloaderKind::Aria2 => Arc::new(Aria2::new(config)),
+    }
+}
+
+/// Client of the configured downloader, kept with its session until the config changes
+pub fn get_downloader() -> Arc<dyn Downloader> {
+    let config = get_config();
+    let mut downloader = DOWNLOADER.lock().unwrap();
+    match downloader.as_ref() {
+        Some((cached_config, cached)) if *cached_config == config => cached.clone(),
+        _ => {
+            log::debug!("Connecting to {} at {}:{}", config.kind.disp_name(), config.host, config.port);
+            let client = new_downloader(config.clone());
+            *downloader = Some((config, client.clone()));
+            client
+        }
+    }
+}
+
+/// A magnet link as is, any other link is fetched as a .torrent file
+fn torrent_source(link: &str) -> Result<TorrentSource, Box<dyn Error>> {
+    if link.starts_with("magnet:") {
+        return Ok(TorrentSource::Magnet(link.to_string()));
+    }
+    let resp = reqwest::blocking::get(link)?;
+    if !resp.status().is_success() {
+        return Err(new_err(format!("Failed to fetch torrent {}: {}", link, resp.status()).as_str()));
+    }
+    Ok(TorrentSource::File(resp.bytes()?.to_vec()))
+}
+
+/// Season the item is shown in, which may be another season than its own, see `item_placement`
+fn item_to_series_info(item: &AnimeSeasonItem) -> Option<AnimeSeason> {
+    read_item_placement(item)
+}
+
+/// Series name of a season, the seasons of a series share one folder whatever their own display names
+fn season_series_name(season: &AnimeSeason) -> String {
+    read_series_info(season.series_id).map_or(season.disp_series_name.clone(), |x| x.disp_series_name)
+}
+
+/// Id tag of the configured source for media server agents, e.g. ` [anilistid-154587]`, empty if unknown.
+fn series_id_tag(series: &AnimeSeries) -> String {
+    let source = match CONFIG.read().unwrap().parser_config.folder_id_source {
+        Some(source) => source,
+        None => return "".to_string(),
+    };
+    let id = match source {
+        MetadataSource::Bangumi => series.bangumi_subject_id,
+        MetadataSource::TMDB => series.tmdb_series_id,
+        MetadataSource::AniList => series.anilist_id,
+        MetadataSource::MyAnimeList => series.mal_id,
+        MetadataSource::AniDB => series.anidb_id,
+    };
+    if id > 0 {
+        format!(" [{}-{}]", source.id_tag(), id)
+    } else {
+        "".to_string()
+    }
+}
+
+fn item_to_savepath(item: &AnimeSeasonItem) -> String {
+    let config = get_config();
+    let season_info = item_to_series_info(item);
+    let id_tag = season_info.as_ref()
+        .and_then(|x| read_series_info(x.series_id))
+        .map_or("".to_string(), |x| series_id_tag(&x));
+    let series_name = match &season_info {
+        Some(season_info) => season_series_name(season_info),
+        None => match item.tmdb_series_name.as_str() {
+            "" => item.mikan_subject_name.clone(),
+            _ => item.tmdb_series_name.clone(),
+        }
+    };
+    let season_num = match season_info {
+        Some(season_info) => {
+            season_info.disp_season_num
+        }
+        None => match item.tmdb_parsed_season_num {
+            -1 => item.bangumi_parsed_season_num,
+            _ => item.tmdb_parsed_season_num,
+        }
+    };
+    // replace \ / : * ? " < > |
+    let series_name = series_name
+        .replace("\\", "")
+        .replace("/", "")
+        .replace(":", "")
+        .replace("*", "")
+        .replace("?", "")
+        .replace("\"", "")
+        .replace("<", "")
+        .replace(">", "")
+        .replace("|", "");
+    let savepath = format!("{}/{}{}/Season {}", config.download_dir, series_name, id_tag, season_num);
+    savepath
+}
+
+fn maglink_to_hash(magnet_link: &str) -> String {
+    let hash = magnet_link.split("btih:").last().unwrap();
+    let hash = hash.split("&").next().unwrap();
+    hash.to_string()
+}
+
+/// Move the items in the downloader along their lifecycle by the progress of their torrents
+fn sync_item_states(hash_to_item: &HashMap<String, AnimeSeasonItem>, torrents: &[DownloaderTorrent]) {
+    for torrent in torrents {
+        let item = match hash_to_item.get(&torrent.hash) {
+            Some(item) => item,
+            None => continue,
+        };
+        // e.g. added by an older build, or by hand
+        if !item.state.in_downloader() {
+            apply_item_event(&item.mikan_item_uuid, ItemEvent::Queue);
+        }
+        if torrent.progress >= 1.0 {
+            apply_item_event(&item.mikan_item_uuid, ItemEvent::Complete);
+        } else if torrent.downloaded > 0 {
+            apply_item_event(&item.mikan_item_uuid, ItemEvent::StartDownload);
+        }
+    }
+}
+
+/// Add the accepted items to the downloader, a failed one is added again
+pub fn download_items(items: &[AnimeSeasonItem], move_existing: bool) -> Result<(), Box<dyn Error>> {
+    let config = get_config();
+    let downloader = get_downloader();
+    let downloader_torrents = downloader.list_torrents()?;
+    let downloader_hash: HashSet<String> = downloader_torrents.iter().map(|x| x.hash.clone()).collect();
+    let mut library_hash: HashSet<String> = HashSet::new();
+    let mut library_hash_to_item: HashMap<String, AnimeSeasonItem> = HashMap::new();
+    for item in items {
+        let hash = maglink_to_hash(&item.mikan_item_magnet_link);
+        library_hash.insert(hash.clone());
+        library_hash_to_item.insert(hash, item.clone());
+    }
+    sync_item_states(&library_hash_to_item, &downloader_torrents);
+    let hash_to_download = library_hash.difference(&downloader_hash).collect::<HashSet<&String>>();
+    let hash_to_move = downloader_hash.intersection(&library_hash).collect::<HashSet<&String>>();
+    if move_existing {
+        for hash in hash_to_move {
+            let item = library_hash_to_item.get(hash).unwrap();
+            let result = downloader.move_torrent(hash, &item_to_savepath(item))
+                .and_then(|_| downloader.set_labels(hash, &config.category, &config.tags))
+                .map_err(|e| new_err(format!("Failed to move torrent: {}", e).as_str()));
+            // ignore error, continue to next torrent
+            record_activity(&Activity::of_item(ActivityKind::TorrentMoved, item).change("", item_to_savepath(item)).result(&result));
+        }
+    }
+    let items_to_download = hash_to_download.iter()
+        .map(|x| library_hash_to_item.get(*x).unwrap())
+        .filter(|x| matches!(x.state, ItemState::Accepted | ItemState::Failed))
+        .collect::<Vec<&AnimeSeasonItem>>();
+    for item in items_to_download {
+        log::debug!("Adding torrent");
+        // ignore error, continue to next
+        let result = torrent_source(&item.mikan_item_magnet_link)
+            .and_then(|source| downloader.add_torrent(&source, &item_to_savepath(item)));
+        apply_item_event(&item.mikan_item_uuid, if result.is_ok() { ItemEvent::Queue } else { ItemEvent::Fail });
+        record_activity(&Activity::of_item(ActivityKind::TorrentAdded, item).change("", item_to_savepath(item)).result(&result));
+    }
+
+    Ok(())
+}
+
+/// Delete the torrents of the items from the downloader, with their downloaded files if `delete_files`
+pub fn delete_torrents(items: &[AnimeSeasonItem], delete_files: bool) -> Result<(), Box<dyn Error>> {
+    let hashes: Vec<String> = items.iter().map(|x| maglink_to_hash(&x.mikan_item_magnet_link)).collect();
+    if hashes.is_empty() {
+        return Ok(());
+    }
+    log::debug!("Deleting {} torrents, delete files: {}", hashes.len(), delete_files);
+    get_downloader().remove_torrents(&hashes, delete_files)
+}
+
+pub fn rename_torrents_files(items: &[AnimeSeasonItem]) -> Result<(), Box<dyn Error>> {
+    let hash_to_item: HashMap<String, AnimeSeasonItem> = items.iter().map(|x| {
+        let hash = maglink_to_hash(&x.mikan_item_magnet_link);
+        (hash.to_string(), x.clone())
+    }).collect();
+    let downloader = get_downloader();
+    let downloader_torrents = downloader.list_torrents()?;
+    sync_item_states(&hash_to_item, &downloader_torrents);
+    let mut downloader_torrents_file_info: HashMap<String, Vec<DownloaderFile>> = HashMap::new();
+    for torrent in downloader_torrents.iter() {
+        downloader_torrents_file_info.insert(torrent.hash.clone(), downloader.torrent_files(&torrent.hash)?);
+    }
+
+    // Assert all torrents in downloader_torrents_file_info have only one file
+    // Otherwide pop it out
+    let mut to_remove = Vec::new();
+    for (hash, files) in downloader_torrents_file_info.iter() {
+        if files.len() != 1 {
+            log::warn!("Torrent {} has more than one file, pop it out", hash);
+            to_remove.push(hash.clone());
+        }
+    }
+    for hash in to_remove {
+        downloader_torrents_file_info.remove(&hash);
+    }
+    // Leave only file name
+    let downloader_torrents_file_name = downloader_torrents_file_info.iter().map(|(hash, files)| {
+        (hash.clone(), files[0].name.clone())
+    }).collect::<HashMap<String, String>>();
+
+    // For each torrent, get item info from hash_to_item and construct an ideal filename
+    // (If not appear in hash_to_item, skip and ignore it)
+    // Then compare with the filename in downloader_torrents_file_info
+    // If not match, rename it
+    for (hash, old_name) in downloader_torrents_file_name.iter() {
+        let item = match hash_to_item.get(hash) {
+            Some(item) => item,
+            None => continue,
+        };
+        let series_info = item_to_series_info(item);
+
+        let series_name = match &series_info {
+            Some(series_info) => season_series_name(series_info),
+            None => match item.tmdb_series_name.as_str() {
+                "" => item.mikan_subject_name.clone(),
+                _ => item.tmdb_series_name.clone(),
+            }
+        };
+        let season_num = match series_info {
+            Some(series_info) => {
+                series_info.disp_season_num
+            },
+            None => match item.tmdb_parsed_season_num {
+                -1 => item.bangumi_parsed_season_num,
+                _ => item.tmdb_parsed_season_num,
+            }
+        };
+
+        let new_name = format!(
+            "{} S{:02}E{:02}.{}",
+            series_name,
+            season_num,
+            item.disp_episode_num,
+            old_name.split(".").last().unwrap(),
+        );
+        let result = if *old_name != new_name && downloader.kind().can_rename() {
+            log::debug!("Renaming file: {} -> {}", old_name, new_name);
+            let result = downloader.rename_file(hash, old_name, &new_name);
+            record_activity(&Activity::of_item(ActivityKind::FileRenamed, item).change(old_name, &new_name).result(&result));
+            result
+        } else {
+            Ok(())
+        };
+        // Organized once the file of a completed download is renamed, or kept as is by a downloader that cannot rename
+        apply_item_event(&item.mikan_item_uuid, if result.is_ok() { ItemEvent::Organize } else { ItemEvent::Fail });
+    }
+
+    Ok(())
+}
+
+pub fn clean_empty_folders(path: String) {
+    let path = match path.is_empty() {
+        true => get_config().download_dir.clone(),
+        false => path,
+    };
+    // If path not exists, return
+    if !std::path::Path::new(&path).exists() {
+        return;
+    }
+    // If path is not folder, return
+    if !std::path::Path::new(&path).is_dir() {
+        return;
+    }
+    // If path is empty, remove it
+    if std::fs::read_dir(&path).unwrap().count() == 0 {
+        std::fs::remove_dir(&path).unwrap();
+        return;
+    }
+    // Else, walk into and clean subfolders
+    for entry in std::fs::read_dir(&path).unwrap() {
+        let entry = entry.unwrap();
+        let path = entry.path();
+        if path.is_dir() {
+            clean_empty_folders(path.to_str().unwrap().to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_maglink_to_hash() {
+        assert_eq!(maglink_to_hash("magnet:?xt=urn:btih:bc5fe73ecf6667dcefabdbdeb0f47fd985cc776e&tr=http%3a%2f%2ft.acg.rip%3a6699%2fannounce"),
+                   "bc5fe73ecf6667dcefabdbdeb0f47fd985cc776e");
+    }
+
+    #[test]
+    fn test_new_downloader() {
+        for kind in DownloaderKind::ALL {
+            let config = DownloaderConfig { kind, ..Default::default() };
+            assert_eq!(new_downloader(config).kind(), kind);
+        }
+    }
+}
@@ -1,24 +1,106 @@
-use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::fmt;
 use std::sync::RwLock;
 
-use lazy_static::lazy_static;
-use reqwest::blocking::{Client, multipart};
+use reqwest::blocking::{Client, multipart, RequestBuilder};
 use serde::Deserialize;
 
-use crate::module::config::{CONFIG, DownloaderConfig};
-use crate::module::database::library::{AnimeSeason, AnimeSeasonItem, read_season_info};
-use crate::module::utils::error::new_err;
+use crate::module::config::{DownloaderConfig, DownloaderKind};
+use crate::module::downloader::{Downloader, DownloaderFile, DownloaderTorrent, TorrentSource};
 
+/// qBittorrent through its WebUI API, over https and under a reverse-proxy base path if configured
+///
+/// Logged in on the first request and again whenever the WebUI refuses the session,
+/// requests and replies follow the API version found at login, see `ApiVersion`.
 #[derive(Debug)]
-struct Downloader {
+pub struct QBittorrent {
+    config: DownloaderConfig,
     client: Client,
-    cookie: String,
-    last_login: i64,
+    session: RwLock<Session>,
 }
 
+#[derive(Debug, Default)]
+struct Session {
+    cookie: String,                         // e.g. `SID=hBc7TxF76ERhvIw0jQQ4LZ7Z1jQUV0tQ`, empty if the WebUI needs no login
+    api_version: Option<ApiVersion>,        // `None` until logged in
+}
+
+/// Version of the WebUI API, e.g. 2.11.2 for qBittorrent 5.0
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ApiVersion(pub u32, pub u32, pub u32);
+
+impl ApiVersion {
+    /// `app/webapiVersion` only exists since 2.0.0 (qBittorrent 4.1)
+    const OLDEST: ApiVersion = ApiVersion(2, 0, 0);
+    /// `renameFile` takes `oldPath` and `newPath` rather than a file id and name
+    const RENAME_BY_PATH: ApiVersion = ApiVersion(2, 7, 0);
+    /// `torrents/info` filters by `tag`
+    const TAG_FILTER: ApiVersion = ApiVersion(2, 8, 3);
+    /// `paused` is renamed `stopped` in qBittorrent 5.0
+    const STOPPED: ApiVersion = ApiVersion(2, 11, 0);
+
+    fn parse(text: &str) -> Option<ApiVersion> {
+        let mut parts = text.trim().split('.').map(|x| x.parse::<u32>());
+        let major = parts.next()?.ok()?;
+        let minor = parts.next().unwrap_or(Ok(0)).ok()?;
+        let patch = parts.next().unwrap_or(Ok(0)).ok()?;
+        Some(ApiVersion(major, minor, patch))
+    }
+}
+
+impl fmt::Display for ApiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// Failure of a WebUI request, `api` being e.g. `torrents/add`
+#[derive(Debug)]
+pub enum QBittorrentError {
+    Request(reqwest::Error),                            // WebUI not reached, or the reply not read
+    Banned,                                             // too many failed logins from this address
+    LoginFailed,                                        // wrong username or password
+    Forbidden { api: String },                          // session refused even after logging in again
+    NotFound { api: String },                           // e.g. unknown torrent hash, or wrong base path
+    Rejected { api: String, reason: String },           // `Fails.` or a 400, 409 or 415 reply, e.g. invalid torrent or path
+    Status { api: String, status: u16 },                // any other unexpected status
+    InvalidResponse { api: String, reason: String },    // reply not of the shape expected for the API version
+}
+
+impl fmt::Display for QBittorrentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QBittorrentError::Request(e) => write!(f, "qBittorrent request failed: {}", e),
+            QBittorrentError::Banned => write!(f, "qBittorrent login failed, banned by WebUI"),
+            QBittorrentError::LoginFailed => write!(f, "qBittorrent login failed, wrong username or password"),
+            QBittorrentError::Forbidden { api } => write!(f, "qBittorrent {} forbidden", api),
+            QBittorrentError::NotFound { api } => write!(f, "qBittorrent {} not found", api),
+            QBittorrentError::Rejected { api, reason } => write!(f, "qBittorrent {} rejected: {}", api, reason),
+            QBittorrentError::Status { api, status } => write!(f, "qBittorrent {} failed, status code: {}", api, status),
+            QBittorrentError::InvalidResponse { api, reason } => write!(f, "qBittorrent {} returned an invalid response: {}", api, reason),
+        }
+    }
+}
+
+impl Error for QBittorrentError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            QBittorrentError::Request(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for QBittorrentError {
+    fn from(e: reqwest::Error) -> Self {
+        QBittorrentError::Request(e)
+    }
+}
+
+/// Fields missing from older API versions are left default
 #[allow(non_snake_case)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
 struct TorrentInfo {
     added_on: i64,
     amount_left: i64,
@@ -36,7 +118,7 @@ struct TorrentInfo {
     f_l_piece_prio: bool,
     force_start: bool,
     hash: String,
-    // isPrivate: bool,     // TODO: added in 5.0.0
+    isPrivate: Option<bool>,    // added in 5.0.0
     last_activity: i64,
     magnet_uri: String,
     max_ratio: f32,
@@ -69,9 +151,10 @@ struct TorrentInfo {
 }
 
 #[allow(non_snake_case)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
 struct TorrentFile {
-    index: i64,
+    index: Option<i64>,         // added in 2.8.2, the position in the list before
     name: String,
     size: i64,
     progress: f32,
@@ -81,14 +164,6 @@ struct TorrentFile {
     availability: f32,
 }
 
-lazy_static! {
-    static ref DOWNLOADER: RwLock<Downloader> = RwLock::new(Downloader {
-        client: Client::new(),
-        cookie: String::new(),
-        last_login: 0,
-    });
-}
-
 /*
 Login authenticate
 
@@ -99,429 +174,279 @@ Content-Length: 3
 Content-Type: text/plain; charset=UTF-8
 Set-Cookie: SID=hBc7TxF76ERhvIw0jQQ4LZ7Z1jQUV0tQ; path=/
 $ curl http://localhost:8080/api/v2/torrents/info --cookie "SID=hBc7TxF76ERhvIw0jQQ4LZ7Z1jQUV0tQ"
+$ curl http://localhost:8080/api/v2/app/webapiVersion --cookie "SID=hBc7TxF76ERhvIw0jQQ4LZ7Z1jQUV0tQ"
+2.11.2
  */
 
-fn get_config() -> DownloaderConfig {
-    CONFIG.read().unwrap().downloader_config.clone()
+/// Body of a successful reply, the status and `Fails.` mapped to errors otherwise
+fn check_response(api: &str, status: u16, body: String) -> Result<String, QBittorrentError> {
+    let api = api.to_string();
+    match status {
+        200 if body == "Fails." => Err(QBittorrentError::Rejected { api, reason: body }),
+        200 => Ok(body),
+        403 => Err(QBittorrentError::Forbidden { api }),
+        404 => Err(QBittorrentError::NotFound { api }),
+        400 | 409 | 415 => Err(QBittorrentError::Rejected { api, reason: if body.is_empty() { status.to_string() } else { body } }),
+        _ => Err(QBittorrentError::Status { api, status }),
+    }
 }
 
-fn login() -> Result<(), Box<dyn Error>> {
-    log::debug!("Attempting to login");
-    let config = get_config();
-    let url = format!("http://{}:{}/api/v2/auth/login", config.host, config.port);
-    let resp = DOWNLOADER.write().unwrap().client.post(&url)
-        .header("Referer", format!("http://{}:{}", config.host, config.port))
-        .header("Content-Type", "application/x-www-form-urlencoded")
-        .body(format!("username={}&password={}", config.username, config.password))
-        .send()?;
-    let status = resp.status().as_u16();
-    let headers = resp.headers().clone();
-    let body = resp.text().unwrap().clone();
-    // assert code=200
-    if status != 200 {
-        // 403: Banned, others: unknown
-        match status {
-            403 => log::error!("Login failed, banned by WebUI."),
-            _ => log::error!("Login failed, unknown error, status code: {}", status),
-        }
-        return Err("Login failed".into());
-    }
-    // assert "Ok." in body
-    if body != "Ok." {
-        log::error!("Login failed, perhaps password error. Response: {}", body);
-        return Err("Login failed".into());
-    }
-    let cookie = headers.get("Set-Cookie").unwrap().to_str().unwrap().to_string();
-    DOWNLOADER.write().unwrap().cookie = cookie;
-    DOWNLOADER.write().unwrap().last_login = chrono::Local::now().timestamp();
-    log::debug!("Successfully logged in");
-    Ok(())
+fn parse_json<T: for<'de> Deserialize<'de>>(api: &str, body: &str) -> Result<T, QBittorrentError> {
+    serde_json::from_str(body).map_err(|e| QBittorrentError::InvalidResponse { api: api.to_string(), reason: e.to_string() })
 }
 
-fn relogin_if_needed() -> Result<(), Box<dyn Error>> {
-    let config = get_config();
-    if chrono::Local::now().timestamp() - DOWNLOADER.read().unwrap().last_login > config.ttl {
-        log::debug!("Session expired, login needed");
-        login()?;
-    }
-    Ok(())
+fn form_bool(value: bool) -> &'static str {
+    if value { "true" } else { "false" }
 }
 
-fn add_torrent_item(item: &AnimeSeasonItem) -> Result<(), Box<dyn Error>> {
-    log::debug!("Adding torrent");
-    let config = get_config();
-    relogin_if_needed()?;
-    let savepath = item_to_savepath(item);
-    let url = format!("http://{}:{}/api/v2/torrents/add", config.host, config.port);
-    let form = multipart::Form::new()
-        .text("urls", item.mikan_item_magnet_link.clone())
-        .text("savepath", savepath)
-        .text("category", config.category)
-        .text("tags", config.tags)
-        .text("paused", if config.paused_after_add { "true" } else { "false" })
-        .text("autoTMM", "false")
-        .text("sequentialDownload", if config.sequential_download { "true" } else { "false" })
-        .text("firstLastPiecePrio", if config.first_last_piece_prio { "true" } else { "false" });
-    let cookie = &DOWNLOADER.read().unwrap().cookie.clone();
-    let resp = DOWNLOADER.write().unwrap().client.post(&url)
-        .header("Referer", format!("http://{}:{}", config.host, config.port))
-        .header("Origin", format!("http://{}:{}", config.host, config.port))
-        .header("Cookie", cookie)
-        .multipart(form)
-        .send()?;
-    log::debug!("Add torrent response status: {}", resp.status());
-    let body = resp.text()?;
-    log::debug!("Add torrent response: {}", body);
-
-    Ok(())
-}
+impl QBittorrent {
+    pub fn new(config: DownloaderConfig) -> QBittorrent {
+        QBittorrent {
+            config,
+            client: Client::new(),
+            session: RwLock::new(Session::default()),
+        }
+    }
 
-fn item_to_series_info(item: &AnimeSeasonItem) -> Option<AnimeSeason> {
-    let season_info = read_season_info(item.mikan_subject_id, item.mikan_subgroup_id);
-    season_info
-}
+    fn url(&self, api: &str) -> String {
+        format!("{}/api/v2/{}", self.config.base_url(), api)
+    }
 
-fn item_to_savepath(item: &AnimeSeasonItem) -> String {
-    let config = get_config();
-    let season_info = read_season_info(item.mikan_subject_id, item.mikan_subgroup_id);
-    let series_name = match &season_info {
-        Some(season_info) => season_info.disp_series_name.clone(),
-        None => match item.tmdb_series_name.as_str() {
-            "" => item.mikan_subject_name.clone(),
-            _ => item.tmdb_series_name.clone(),
+    /// Headers the WebUI checks against cross-site requests, sent with the session cookie
+    fn with_headers(&self, request: RequestBuilder, cookie: &str) -> RequestBuilder {
+        let request = request
+            .header("Referer", format!("{}/", self.config.base_url()))
+            .header("Origin", self.config.origin());
+        match cookie {
+            "" => request,
+            cookie => request.header("Cookie", cookie),
         }
-    };
-    let season_num = match season_info {
-        Some(season_info) => {
-            season_info.disp_season_num
+    }
+
+    /// Log in and find the API version, the WebUI may hand out no cookie if it needs no login for this address
+    fn login(&self) -> Result<(), QBittorrentError> {
+        log::debug!("Attempting to login");
+        let api = "auth/login";
+        let resp = self.with_headers(self.client.post(self.url(api)), "")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(format!("username={}&password={}",
+                          urlencoding::encode(&self.config.username),
+                          urlencoding::encode(&self.config.password)))
+            .send()?;
+        let status = resp.status().as_u16();
+        let cookie = resp.headers().get("Set-Cookie")
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.split(';').next())
+            .unwrap_or("")
+            .to_string();
+        let body = resp.text()?;
+        match status {
+            403 => return Err(QBittorrentError::Banned),
+            200 if body == "Ok." => {}
+            200 => return Err(QBittorrentError::LoginFailed),
+            _ => return Err(QBittorrentError::Status { api: api.to_string(), status }),
         }
-        None => match item.tmdb_parsed_season_num {
-            -1 => item.bangumi_parsed_season_num,
-            _ => item.tmdb_parsed_season_num,
+        let api_version = self.detect_api_version(&cookie)?;
+        log::debug!("Successfully logged in, WebUI API {}", api_version);
+        *self.session.write().unwrap() = Session { cookie, api_version: Some(api_version) };
+        Ok(())
+    }
+
+    fn detect_api_version(&self, cookie: &str) -> Result<ApiVersion, QBittorrentError> {
+        let api = "app/webapiVersion";
+        let resp = self.with_headers(self.client.get(self.url(api)), cookie).send()?;
+        let status = resp.status().as_u16();
+        if status == 404 {
+            return Ok(ApiVersion::OLDEST);
         }
-    };
-    // replace \ / : * ? " < > |
-    let series_name = series_name
-        .replace("\\", "")
-        .replace("/", "")
-        .replace(":", "")
-        .replace("*", "")
-        .replace("?", "")
-        .replace("\"", "")
-        .replace("<", "")
-        .replace(">", "")
-        .replace("|", "");
-    let savepath = format!("{}/{}/Season {}", config.download_dir, series_name, season_num);
-    savepath
-}
+        let body = check_response(api, status, resp.text()?)?;
+        ApiVersion::parse(&body).ok_or_else(|| QBittorrentError::InvalidResponse { api: api.to_string(), reason: body })
+    }
 
-fn maglink_to_hash(magnet_link: &String) -> String {
-    let hash = magnet_link.split("btih:").last().unwrap();
-    let hash = hash.split("&").next().unwrap();
-    hash.to_string()
-}
+    /// API version of the session, logging in first if needed
+    fn api_version(&self) -> Result<ApiVersion, QBittorrentError> {
+        if let Some(api_version) = self.session.read().unwrap().api_version {
+            return Ok(api_version);
+        }
+        self.login()?;
+        Ok(self.session.read().unwrap().api_version.unwrap_or(ApiVersion::OLDEST))
+    }
 
-fn move_torrent_item(item: &AnimeSeasonItem) -> Result<(), Box<dyn Error>> {
-    // TODO: not implemented
-    log::debug!("Moving torrent");
-    let config = get_config();
-    relogin_if_needed()?;
-    let savepath = item_to_savepath(item);
-    let savepath = urlencoding::encode(&savepath);
-    let url = format!("http://{}:{}/api/v2/torrents/setLocation", config.host, config.port);
-    // application/x-www-form-urlencoded
-    let body = format!("hashes={}&location={}",
-                       maglink_to_hash(&item.mikan_item_magnet_link),
-                       savepath);
-    let cookie = &DOWNLOADER.read().unwrap().cookie.clone();
-    let resp = DOWNLOADER.write().unwrap().client.post(&url)
-        .header("Referer", format!("http://{}:{}", config.host, config.port))
-        .header("Origin", format!("http://{}:{}", config.host, config.port))
-        .header("Cookie", cookie)
-        .header("Content-Type", "application/x-www-form-urlencoded")
-        .body(body)
-        .send()?;
-    log::debug!("Move torrent response status: {}", resp.status());
-    let body = resp.text()?;
-    log::debug!("Move torrent response: {}", body);
-
-    Ok(())
-}
+    /// Send a request built by `build` from the url of an API, logging in again once if the session is refused
+    fn send<F>(&self, api: &str, build: F) -> Result<String, QBittorrentError>
+    where
+        F: Fn(&Client, String) -> RequestBuilder,
+    {
+        self.api_version()?;
+        let mut relogged = false;
+        loop {
+            let cookie = self.session.read().unwrap().cookie.clone();
+            let resp = self.with_headers(build(&self.client, self.url(api)), &cookie).send()?;
+            let status = resp.status().as_u16();
+            if status == 403 && !relogged {
+                log::debug!("Session refused by {}, login needed", api);
+                self.login()?;
+                relogged = true;
+                continue;
+            }
+            let body = resp.text()?;
+            log::debug!("{} response status: {}", api, status);
+            return check_response(api, status, body);
+        }
+    }
 
-fn set_torrent_category(item: &AnimeSeasonItem) -> Result<(), Box<dyn Error>> {
-    log::debug!("Setting torrent category");
-    let config = get_config();
-    relogin_if_needed()?;
-    let url = format!("http://{}:{}/api/v2/torrents/setCategory", config.host, config.port);
-    // application/x-www-form-urlencoded
-    let category = urlencoding::encode(&config.category);
-    let body = format!("hashes={}&category={}", maglink_to_hash(&item.mikan_item_magnet_link), category);
-    let cookie = &DOWNLOADER.read().unwrap().cookie.clone();
-    let resp = DOWNLOADER.write().unwrap().client.post(&url)
-        .header("Referer", format!("http://{}:{}", config.host, config.port))
-        .header("Origin", format!("http://{}:{}", config.host, config.port))
-        .header("Cookie", cookie)
-        .header("Content-Type", "application/x-www-form-urlencoded")
-        .body(body)
-        .send()?;
-    log::debug!("Set torrent category response status: {}", resp.status());
-    let body = resp.text()?;
-    log::debug!("Set torrent category response: {}", body);
-
-    Ok(())
-}
+    /// POST an application/x-www-form-urlencoded body to an API, e.g. `torrents/setLocation`
+    fn post_form(&self, api: &str, body: String) -> Result<String, QBittorrentError> {
+        self.send(api, |client, url| client.post(url)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body.clone()))
+    }
 
-fn add_torrent_tags(item: &AnimeSeasonItem) -> Result<(), Box<dyn Error>> {
-    log::debug!("Adding torrent tags");
-    let config = get_config();
-    relogin_if_needed()?;
-    let url = format!("http://{}:{}/api/v2/torrents/addTags", config.host, config.port);
-    // application/x-www-form-urlencoded
-    let tags = urlencoding::encode(&config.tags);
-    let body = format!("hashes={}&tags={}", maglink_to_hash(&item.mikan_item_magnet_link), tags);
-    let cookie = &DOWNLOADER.read().unwrap().cookie.clone();
-    let resp = DOWNLOADER.write().unwrap().client.post(&url)
-        .header("Referer", format!("http://{}:{}", config.host, config.port))
-        .header("Origin", format!("http://{}:{}", config.host, config.port))
-        .header("Cookie", cookie)
-        .header("Content-Type", "application/x-www-form-urlencoded")
-        .body(body)
-        .send()?;
-    log::debug!("Add torrent tags response status: {}", resp.status());
-    let body = resp.text()?;
-    log::debug!("Add torrent tags response: {}", body);
-
-    Ok(())
-}
+    /// GET an API with a query string, e.g. `torrents/files?hash=...`
+    fn get(&self, api: &str, query: &str) -> Result<String, QBittorrentError> {
+        self.send(api, |client, url| client.get(format!("{}?{}", url, query)))
+    }
 
-fn set_torrent_automatic_management(item: &AnimeSeasonItem, atm: bool) -> Result<(), Box<dyn Error>> {
-    log::debug!("Setting torrent automatic management");
-    let config = get_config();
-    relogin_if_needed()?;
-    let url = format!("http://{}:{}/api/v2/torrents/setAutoManagement", config.host, config.port);
-    // application/x-www-form-urlencoded
-    let atm = if atm { "true" } else { "false" };
-    let body = format!("hashes={}&enable={}", maglink_to_hash(&item.mikan_item_magnet_link), atm);
-    let cookie = &DOWNLOADER.read().unwrap().cookie.clone();
-    let resp = DOWNLOADER.write().unwrap().client.post(&url)
-        .header("Referer", format!("http://{}:{}", config.host, config.port))
-        .header("Origin", format!("http://{}:{}", config.host, config.port))
-        .header("Cookie", cookie)
-        .header("Content-Type", "application/x-www-form-urlencoded")
-        .body(body)
-        .send()?;
-    log::debug!("Set torrent automatic management response status: {}", resp.status());
-    let body = resp.text()?;
-    log::debug!("Set torrent automatic management response: {}", body);
-
-    Ok(())
-}
+    fn set_torrent_automatic_management(&self, hash: &str, atm: bool) -> Result<(), QBittorrentError> {
+        log::debug!("Setting torrent automatic management");
+        self.post_form("torrents/setAutoManagement", format!("hashes={}&enable={}", hash, form_bool(atm)))?;
+        Ok(())
+    }
 
-fn list_torrents() -> Result<Vec<TorrentInfo>, Box<dyn Error>> {
-    log::debug!("Listing torrents");
-    let config = get_config();
-    relogin_if_needed()?;
-    let url = format!("http://{}:{}/api/v2/torrents/info", config.host, config.port);
-    // ?filter=downloading&category=sample%20category&sort=ratio
-    // url encode
-    let category = urlencoding::encode(&config.category);
-    let tags = urlencoding::encode(&config.tags);
-    let url = format!("{}?category={}&tag={}", url, category, tags);
-    let cookie = &DOWNLOADER.read().unwrap().cookie.clone();
-    let resp = DOWNLOADER.write().unwrap().client.get(&url)
-        .header("Referer", format!("http://{}:{}", config.host, config.port))
-        .header("Origin", format!("http://{}:{}", config.host, config.port))
-        .header("Cookie", cookie)
-        .send()?;
-    log::debug!("List torrents response status: {}", resp.status());
-    let body = resp.text()?;
-    // log::debug!("List torrents response: {}", body);
-    // body is json
-    let list_torrents: Vec<TorrentInfo> = serde_json::from_str(&body)?;
-    Ok(list_torrents)
-}
+    fn set_torrent_category(&self, hash: &str, category: &str) -> Result<(), QBittorrentError> {
+        log::debug!("Setting torrent category");
+        self.post_form("torrents/setCategory", format!("hashes={}&category={}", hash, urlencoding::encode(category)))?;
+        Ok(())
+    }
 
-pub fn download_items(items: &Vec<AnimeSeasonItem>, move_existing: bool) -> Result<(), Box<dyn Error>> {
-    let downloader_torrents = list_torrents()?;
-    let downloader_hash: HashSet<String> = downloader_torrents.iter().map(|x| x.hash.clone()).collect();
-    let mut library_hash: HashSet<String> = HashSet::new();
-    let mut library_hash_to_item: HashMap<String, AnimeSeasonItem> = HashMap::new();
-    for item in items {
-        let hash = item.mikan_item_magnet_link.split("btih:").last().unwrap();
-        let hash = hash.split("&").next().unwrap();
-        library_hash.insert(hash.to_string());
-        library_hash_to_item.insert(hash.to_string(), item.clone());
-    }
-    let hash_to_download = library_hash.difference(&downloader_hash).collect::<HashSet<&String>>();
-    let hash_to_move = downloader_hash.intersection(&library_hash).collect::<HashSet<&String>>();
-    if move_existing {
-        for hash in hash_to_move {
-            let item = library_hash_to_item.get(hash).unwrap();
-            set_torrent_automatic_management(item, false)
-                .and_then(|_| move_torrent_item(item))
-                .and_then(|_| set_torrent_category(item))
-                .and_then(|_| add_torrent_tags(item))
-                .map_err(|e| new_err(format!("Failed to move torrent: {}", e).as_str()))
-                .unwrap_or(());  // ignore error, continue to next torrent
-        }
+    fn add_torrent_tags(&self, hash: &str, tags: &str) -> Result<(), QBittorrentError> {
+        log::debug!("Adding torrent tags");
+        self.post_form("torrents/addTags", format!("hashes={}&tags={}", hash, urlencoding::encode(tags)))?;
+        Ok(())
     }
-    let items_to_download = hash_to_download.iter().map(|x| library_hash_to_item.get(*x).unwrap()).collect::<Vec<&AnimeSeasonItem>>();
-    for item in items_to_download {
-        add_torrent_item(item).unwrap_or(());  // ignore error, continue to next
+
+    fn get_fileinfo(&self, hash: &str) -> Result<Vec<TorrentFile>, QBittorrentError> {
+        log::debug!("Get filename");
+        let api = "torrents/files";
+        // body is json array
+        parse_json(api, &self.get(api, &format!("hash={}", hash))?)
     }
 
-    Ok(())
+    fn add_form(&self, source: &TorrentSource, save_path: &str, api_version: ApiVersion) -> multipart::Form {
+        let config = &self.config;
+        let form = match source {
+            TorrentSource::Magnet(link) => multipart::Form::new().text("urls", link.clone()),
+            TorrentSource::File(content) => multipart::Form::new()
+                .part("torrents", multipart::Part::bytes(content.clone()).file_name("item.torrent")),
+        };
+        let paused = if api_version >= ApiVersion::STOPPED { "stopped" } else { "paused" };
+        form.text("savepath", save_path.to_string())
+            .text("category", config.category.clone())
+            .text("tags", config.tags.clone())
+            .text(paused, form_bool(config.paused_after_add))
+            .text("autoTMM", "false")
+            .text("sequentialDownload", form_bool(config.sequential_download))
+            .text("firstLastPiecePrio", form_bool(config.first_last_piece_prio))
+    }
 }
 
-fn get_fileinfo(hash: &String) -> Result<Vec<TorrentFile>, Box<dyn Error>> {
-    log::debug!("Get filename");
-    let config = get_config();
-    relogin_if_needed()?;
-    let url = format!("http://{}:{}/api/v2/torrents/files", config.host, config.port);
-    // ?hash={}
-    let url = format!("{}?hash={}", url, hash);
-    let cookie = &DOWNLOADER.read().unwrap().cookie.clone();
-    let resp = DOWNLOADER.write().unwrap().client.get(&url)
-        .header("Referer", format!("http://{}:{}", config.host, config.port))
-        .header("Origin", format!("http://{}:{}", config.host, config.port))
-        .header("Cookie", cookie)
-        .send()?;
-    log::debug!("List torrents response status: {}", resp.status());
-    let body = resp.text()?;
-    // log::debug!("List torrents response: {}", body);
-    // body is json array
-    let list_files: Vec<TorrentFile> = serde_json::from_str(&body)?;
-    Ok(list_files)
-}
+impl Downloader for QBittorrent {
+    fn kind(&self) -> DownloaderKind {
+        DownloaderKind::QBittorrent
+    }
 
-fn rename_file(hash: &String, old_path: &String, new_path: &String) -> Result<(), Box<dyn Error>> {
-    log::debug!("Renaming file");
-    let config = get_config();
-    relogin_if_needed()?;
-    let url = format!("http://{}:{}/api/v2/torrents/renameFile", config.host, config.port);
-    // application/x-www-form-urlencoded
-    let old_path = urlencoding::encode(&old_path);
-    let new_path = urlencoding::encode(&new_path);
-    let body = format!("hash={}&oldPath={}&newPath={}", hash, old_path, new_path);
-    let cookie = &DOWNLOADER.read().unwrap().cookie.clone();
-    let resp = DOWNLOADER.write().unwrap().client.post(&url)
-        .header("Referer", format!("http://{}:{}", config.host, config.port))
-        .header("Origin", format!("http://{}:{}", config.host, config.port))
-        .header("Cookie", cookie)
-        .header("Content-Type", "application/x-www-form-urlencoded")
-        .body(body)
-        .send()?;
-    log::debug!("Rename file response status: {}", resp.status());
-    let body = resp.text()?;
-    log::debug!("Rename file response: {}", body);
-
-    Ok(())
-}
+    fn add_torrent(&self, source: &TorrentSource, save_path: &str) -> Result<(), Box<dyn Error>> {
+        log::debug!("Adding torrent");
+        let api_version = self.api_version()?;
+        self.send("torrents/add", |client, url| client.post(url).multipart(self.add_form(source, save_path, api_version)))?;
+        Ok(())
+    }
 
-pub fn rename_torrents_files(items: &Vec<AnimeSeasonItem>) -> Result<(), Box<dyn Error>> {
-    let hash_to_item: HashMap<String, AnimeSeasonItem> = items.iter().map(|x| {
-        let hash = maglink_to_hash(&x.mikan_item_magnet_link);
-        (hash.to_string(), x.clone())
-    }).collect();
-    let downloader_torrents = list_torrents()?;
-    let mut downloader_torrents_file_info: HashMap<String, Vec<TorrentFile>> =
-        downloader_torrents.iter().map(|x| (x.hash.clone(), get_fileinfo(&x.hash).unwrap())).collect();
-
-    // Assert all torrents in downloader_torrents_file_info have only one file
-    // Otherwide pop it out
-    let mut to_remove = Vec::new();
-    for (hash, files) in downloader_torrents_file_info.iter() {
-        if files.len() != 1 {
-            log::warn!("Torrent {} has more than one file, pop it out", hash);
-            to_remove.push(hash.clone());
+    /// Torrents of the configured category and tag, the tag is filtered here by WebUIs that cannot
+    fn list_torrents(&self) -> Result<Vec<DownloaderTorrent>, Box<dyn Error>> {
+        log::debug!("Listing torrents");
+        let api = "torrents/info";
+        let tag_filter = self.api_version()? >= ApiVersion::TAG_FILTER;
+        // ?filter=downloading&category=sample%20category&sort=ratio
+        let mut query = format!("category={}", urlencoding::encode(&self.config.category));
+        if tag_filter {
+            query.push_str(&format!("&tag={}", urlencoding::encode(&self.config.tags)));
         }
+        let list_torrents: Vec<TorrentInfo> = parse_json(api, &self.get(api, &query)?)?;
+        Ok(list_torrents.into_iter()
+            .filter(|x| tag_filter || self.config.tags.is_empty() || x.tags.split(',').any(|tag| tag.trim() == self.config.tags))
+            .map(|x| DownloaderTorrent {
+                hash: x.hash,
+                name: x.name,
+                save_path: x.save_path,
+                progress: x.progress,
+                downloaded: x.downloaded,
+                size: x.size,
+            })
+            .collect())
     }
-    for hash in to_remove {
-        downloader_torrents_file_info.remove(&hash);
-    }
-    // Leave only file name
-    let downloader_torrents_file_name = downloader_torrents_file_info.iter().map(|(hash, files)| {
-        (hash.clone(), files[0].name.clone())
-    }).collect::<HashMap<String, String>>();
-
-    // For each torrent, get item info from hash_to_item and construct an ideal filename
-    // (If not appear in hash_to_item, skip and ignore it)
-    // Then compare with the filename in downloader_torrents_file_info
-    // If not match, rename it
-    for (hash, old_name) in downloader_torrents_file_name.iter() {
-        let item = match hash_to_item.get(hash) {
-            Some(item) => item,
-            None => continue,
-        };
-        let series_info = item_to_series_info(item);
 
-        let series_name = match &series_info {
-            Some(series_info) => series_info.disp_series_name.clone(),
-            None => match item.tmdb_series_name.as_str() {
-                "" => item.mikan_subject_name.clone(),
-                _ => item.tmdb_series_name.clone(),
-            }
-        };
-        let season_num = match series_info {
-            Some(series_info) => {
-                series_info.disp_season_num
-            },
-            None => match item.tmdb_parsed_season_num {
-                -1 => item.bangumi_parsed_season_num,
-                _ => item.tmdb_parsed_season_num,
-            }
+    fn torrent_files(&self, hash: &str) -> Result<Vec<DownloaderFile>, Box<dyn Error>> {
+        Ok(self.get_fileinfo(hash)?.into_iter().map(|x| DownloaderFile {
+            name: x.name,
+            size: x.size,
+            progress: x.progress,
+        }).collect())
+    }
+
+    /// Renamed by file id before API 2.7.0
+    fn rename_file(&self, hash: &str, old_path: &str, new_path: &str) -> Result<(), Box<dyn Error>> {
+        log::debug!("Renaming file");
+        let api = "torrents/renameFile";
+        let body = if self.api_version()? >= ApiVersion::RENAME_BY_PATH {
+            format!("hash={}&oldPath={}&newPath={}", hash, urlencoding::encode(old_path), urlencoding::encode(new_path))
+        } else {
+            let files = self.get_fileinfo(hash)?;
+            let id = files.iter().enumerate()
+                .find(|(_, x)| x.name == old_path)
+                .map(|(position, x)| x.index.unwrap_or(position as i64))
+                .ok_or_else(|| QBittorrentError::Rejected { api: api.to_string(), reason: format!("no file {}", old_path) })?;
+            format!("hash={}&id={}&name={}", hash, id, urlencoding::encode(new_path))
         };
+        self.post_form(api, body)?;
+        Ok(())
+    }
 
-        let new_name = format!(
-            "{} S{:02}E{:02}.{}",
-            series_name,
-            season_num,
-            item.disp_episode_num,
-            old_name.split(".").last().unwrap(),
-        );
-        if *old_name != new_name {
-            log::debug!("Renaming file: {} -> {}", old_name, new_name);
-            rename_file(&hash, old_name, &new_name).unwrap_or(());
-        }
+    /// Automatic torrent management is turned off first, otherwise the category decides the location
+    fn move_torrent(&self, hash: &str, save_path: &str) -> Result<(), Box<dyn Error>> {
+        self.set_torrent_automatic_management(hash, false)?;
+        log::debug!("Moving torrent");
+        self.post_form("torrents/setLocation", format!("hashes={}&location={}", hash, urlencoding::encode(save_path)))?;
+        Ok(())
     }
 
-    Ok(())
-}
+    fn set_labels(&self, hash: &str, category: &str, tags: &str) -> Result<(), Box<dyn Error>> {
+        self.set_torrent_category(hash, category)?;
+        self.add_torrent_tags(hash, tags)?;
+        Ok(())
+    }
 
-pub fn clean_empty_folders(path: String) {
-    let path = match path.is_empty() {
-        true => get_config().download_dir.clone(),
-        false => path,
-    };
-    // If path not exists, return
-    if !std::path::Path::new(&path).exists() {
-        return;
-    }
-    // If path is not folder, return
-    if !std::path::Path::new(&path).is_dir() {
-        return;
-    }
-    // If path is empty, remove it
-    if std::fs::read_dir(&path).unwrap().count() == 0 {
-        std::fs::remove_dir(&path).unwrap();
-        return;
-    }
-    // Else, walk into and clean subfolders
-    for entry in std::fs::read_dir(&path).unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        if path.is_dir() {
-            clean_empty_folders(path.to_str().unwrap().to_string());
-        }
+    fn remove_torrents(&self, hashes: &[String], delete_files: bool) -> Result<(), Box<dyn Error>> {
+        self.post_form("torrents/delete", format!("hashes={}&deleteFiles={}", hashes.join("|"), delete_files))?;
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::module::config::CONFIG;
+    use crate::module::downloader::fake_server::{FakeRequest, FakeResponse, FakeServer};
     use crate::module::logger;
 
     use super::*;
 
+    fn get_config() -> DownloaderConfig {
+        CONFIG.read().unwrap().downloader_config.clone()
+    }
+
     #[test]
     fn test_get_config() {
         get_config();
@@ -530,26 +455,171 @@ mod tests {
     #[test]
     fn test_login() {
         logger::init();
-        login().unwrap();
-        let cookie = DOWNLOADER.read().unwrap().cookie.clone();
+        let qbittorrent = QBittorrent::new(get_config());
+        qbittorrent.login().unwrap();
+        let cookie = qbittorrent.session.read().unwrap().cookie.clone();
         assert!(!cookie.is_empty());
     }
 
     #[test]
     fn test_get_fileinfo() {
         logger::init();
-        login().unwrap();
-        let files = get_fileinfo(&"007c84bc9bcb28fa779ef7567e4a17c8a896d51d".to_string()).unwrap();
+        let qbittorrent = QBittorrent::new(get_config());
+        qbittorrent.login().unwrap();
+        let files = qbittorrent.get_fileinfo("007c84bc9bcb28fa779ef7567e4a17c8a896d51d").unwrap();
         println!("{:?}", files);
     }
 
     #[test]
     fn test_rename_file() {
         logger::init();
-        login().unwrap();
-        rename_file(&"007c84bc9bcb28fa779ef7567e4a17c8a896d51d".to_string(),
-                    &"[ANi] 極速星舞 - 03 [1080P][Baha][WEB-DL][AAC AVC][CHT].mp4".to_string(),
-                    &"極速星舞 - S01E03.mp4".to_string()).unwrap();
+        let qbittorrent = QBittorrent::new(get_config());
+        qbittorrent.login().unwrap();
+        qbittorrent.rename_file("007c84bc9bcb28fa779ef7567e4a17c8a896d51d",
+                                "[ANi] 極速星舞 - 03 [1080P][Baha][WEB-DL][AAC AVC][CHT].mp4",
+                                "極速星舞 - S01E03.mp4").unwrap();
+    }
+
+    const HASH: &str = "bc5fe73ecf6667dcefabdbdeb0f47fd985cc776e";
+
+    /// A WebUI of API `version` served under `/qbt`, `other` answering the requests not about torrents or files
+    fn fake_qbittorrent<F>(version: &'static str, other: F) -> (FakeServer, QBittorrent)
+    where
+        F: Fn(&FakeRequest) -> FakeResponse + Send + 'static,
+    {
+        let server = FakeServer::start(move |request| {
+            match request.path.split('?').next().unwrap() {
+                "/qbt/api/v2/auth/login" => (200, vec![("Set-Cookie", "SID=fake; HttpOnly; path=/".to_string())], "Ok.".to_string()),
+                "/qbt/api/v2/app/webapiVersion" => (200, vec![], version.to_string()),
+                "/qbt/api/v2/torrents/info" => (200, vec![], format!(r#"[
+                    {{"hash":"{}","name":"a.mkv","save_path":"/downloads","progress":0.5,"downloaded":1024,"size":2048,"tags":"Anime, Bangumi007","isPrivate":false}},
+                    {{"hash":"0f83082453d63c3286a23d7f59faf38665d9a37b","name":"b.mkv","tags":"Other"}}
+                ]"#, HASH)),
+                "/qbt/api/v2/torrents/files" => (200, vec![], r#"[{"name":"a.mkv","size":2048,"progress":0.5,"priority":1}]"#.to_string()),
+                _ => other(request),
+            }
+        });
+        let config = DownloaderConfig {
+            host: "127.0.0.1".to_string(),
+            port: server.port as i64,
+            https: false,
+            base_path: "/qbt/".to_string(),
+            category: "Anime".to_string(),
+            tags: "Bangumi007".to_string(),
+            ..Default::default()
+        };
+        (server, QBittorrent::new(config))
+    }
+
+    fn paths(server: &FakeServer) -> Vec<String> {
+        server.requests().iter().map(|x| x.path.split('?').next().unwrap().trim_start_matches("/qbt/api/v2/").to_string()).collect()
+    }
+
+    #[test]
+    fn test_fake_qbittorrent() {
+        let (server, qbittorrent) = fake_qbittorrent("2.11.2", |_| (200, vec![], "Ok.".to_string()));
+        let torrents = qbittorrent.list_torrents().unwrap();
+        assert_eq!(torrents, vec![
+            DownloaderTorrent { hash: HASH.to_string(), name: "a.mkv".to_string(), save_path: "/downloads".to_string(), progress: 0.5, downloaded: 1024, size: 2048 },
+            DownloaderTorrent { hash: "0f83082453d63c3286a23d7f59faf38665d9a37b".to_string(), name: "b.mkv".to_string(), save_path: "".to_string(), progress: 0., downloaded: 0, size: 0 },
+        ]);
+        assert_eq!(qbittorrent.torrent_files(HASH).unwrap(), vec![DownloaderFile { name: "a.mkv".to_string(), size: 2048, progress: 0.5 }]);
+        qbittorrent.add_torrent(&TorrentSource::Magnet(format!("magnet:?xt=urn:btih:{}", HASH)), "/downloads/Frieren/Season 1").unwrap();
+        qbittorrent.rename_file(HASH, "a.mkv", "Frieren S01E01.mkv").unwrap();
+        qbittorrent.move_torrent(HASH, "/downloads/Frieren/Season 1").unwrap();
+        qbittorrent.set_labels(HASH, "Anime", "Bangumi007").unwrap();
+        qbittorrent.remove_torrents(&[HASH.to_string()], true).unwrap();
+
+        // Logged in once under the base path, then the session cookie is sent along
+        assert_eq!(paths(&server), vec!["auth/login", "app/webapiVersion", "torrents/info", "torrents/files", "torrents/add", "torrents/renameFile",
+                                        "torrents/setAutoManagement", "torrents/setLocation", "torrents/setCategory", "torrents/addTags", "torrents/delete"]);
+        let requests = server.requests();
+        assert!(requests[1..].iter().all(|x| x.headers.get("cookie").map(|x| x.as_str()) == Some("SID=fake")));
+        assert_eq!(requests[0].headers.get("referer").unwrap(), &format!("http://127.0.0.1:{}/qbt/", server.port));
+        // Only the lists are read with GET, the WebUI refuses to change anything but by POST
+        let methods: Vec<&str> = requests.iter().map(|x| x.method.as_str()).collect();
+        assert_eq!(methods, vec!["POST", "GET", "GET", "GET", "POST", "POST", "POST", "POST", "POST", "POST", "POST"]);
+        assert_eq!(requests[2].path, "/qbt/api/v2/torrents/info?category=Anime&tag=Bangumi007");
+        assert!(requests[4].body.contains(&format!("magnet:?xt=urn:btih:{}", HASH)));
+        assert!(requests[4].body.contains("name=\"stopped\""));
+        assert!(requests[4].body.contains("/downloads/Frieren/Season 1"));
+        assert_eq!(requests[5].body, format!("hash={}&oldPath=a.mkv&newPath=Frieren%20S01E01.mkv", HASH));
+        assert_eq!(requests[7].body, format!("hashes={}&location=%2Fdownloads%2FFrieren%2FSeason%201", HASH));
+        assert_eq!(requests[10].body, format!("hashes={}&deleteFiles=true", HASH));
+    }
+
+    #[test]
+    fn test_fake_qbittorrent_old_api() {
+        let (server, qbittorrent) = fake_qbittorrent("2.2.0", |_| (200, vec![], "".to_string()));
+        // No tag filter on the WebUI
+        let torrents = qbittorrent.list_torrents().unwrap();
+        assert_eq!(torrents.iter().map(|x| x.hash.as_str()).collect::<Vec<_>>(), vec![HASH]);
+        qbittorrent.add_torrent(&TorrentSource::File(b"d4:infod4:name5:a.mkvee".to_vec()), "/downloads").unwrap();
+        qbittorrent.rename_file(HASH, "a.mkv", "Frieren S01E01.mkv").unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests[2].path, "/qbt/api/v2/torrents/info?category=Anime");
+        assert!(requests[3].body.contains("name=\"paused\""));
+        assert!(requests[3].body.contains("filename=\"item.torrent\""));
+        assert_eq!(requests[5].body, format!("hash={}&id=0&name=Frieren%20S01E01.mkv", HASH));
+    }
+
+    #[test]
+    fn test_fake_qbittorrent_relogin() {
+        // The first session expires on the WebUI before its next request
+        let logins = Arc::new(AtomicUsize::new(0));
+        let server_logins = logins.clone();
+        let server = FakeServer::start(move |request| {
+            match request.path.as_str() {
+                "/api/v2/auth/login" => {
+                    let login = server_logins.fetch_add(1, Ordering::SeqCst) + 1;
+                    (200, vec![("Set-Cookie", format!("SID={}; path=/", login))], "Ok.".to_string())
+                }
+                "/api/v2/app/webapiVersion" => (200, vec![], "2.11.2".to_string()),
+                _ if request.headers.get("cookie").map(|x| x.as_str()) != Some("SID=2") => (403, vec![], "Forbidden".to_string()),
+                _ => (200, vec![], "[]".to_string()),
+            }
+        });
+        let config = DownloaderConfig { host: "127.0.0.1".to_string(), port: server.port as i64, base_path: "".to_string(), ..Default::default() };
+        let qbittorrent = QBittorrent::new(config);
+        assert!(qbittorrent.list_torrents().unwrap().is_empty());
+        assert_eq!(logins.load(Ordering::SeqCst), 2);
+
+        // Refused again after logging in again
+        logins.store(5, Ordering::SeqCst);
+        qbittorrent.session.write().unwrap().cookie = "SID=expired".to_string();
+        let error = qbittorrent.list_torrents().unwrap_err();
+        assert!(matches!(error.downcast_ref::<QBittorrentError>(), Some(QBittorrentError::Forbidden { .. })));
+    }
+
+    #[test]
+    fn test_fake_qbittorrent_errors() {
+        let (_server, qbittorrent) = fake_qbittorrent("2.11.2", |request| {
+            match request.path.as_str() {
+                "/qbt/api/v2/torrents/add" => (200, vec![], "Fails.".to_string()),
+                "/qbt/api/v2/torrents/renameFile" => (409, vec![], "Target file already exists".to_string()),
+                _ => (404, vec![], "".to_string()),
+            }
+        });
+        let error = |result: Result<(), Box<dyn Error>>| result.unwrap_err().downcast::<QBittorrentError>().unwrap();
+        assert!(matches!(*error(qbittorrent.add_torrent(&TorrentSource::Magnet("magnet:?xt=urn:btih:0".to_string()), "/downloads")),
+                         QBittorrentError::Rejected { ref reason, .. } if reason == "Fails."));
+        assert!(matches!(*error(qbittorrent.rename_file(HASH, "a.mkv", "b.mkv")),
+                         QBittorrentError::Rejected { ref reason, .. } if reason == "Target file already exists"));
+        assert!(matches!(*error(qbittorrent.remove_torrents(&[HASH.to_string()], false)), QBittorrentError::NotFound { .. }));
+
+        let server = FakeServer::start(|_| (200, vec![], "Fails.".to_string()));
+        let qbittorrent = QBittorrent::new(DownloaderConfig { host: "127.0.0.1".to_string(), port: server.port as i64, base_path: "".to_string(), ..Default::default() });
+        assert!(matches!(qbittorrent.login(), Err(QBittorrentError::LoginFailed)));
+    }
+
+    #[test]
+    fn test_api_version() {
+        assert_eq!(ApiVersion::parse("2.11.2\n"), Some(ApiVersion(2, 11, 2)));
+        assert_eq!(ApiVersion::parse("2.8"), Some(ApiVersion(2, 8, 0)));
+        assert_eq!(ApiVersion::parse("Forbidden"), None);
+        assert!(ApiVersion(2, 8, 19) > ApiVersion::TAG_FILTER);
+        assert!(ApiVersion(2, 11, 2) >= ApiVersion::STOPPED);
     }
 
     //
@@ -0,0 +1,250 @@
+use std::error::Error;
+use std::sync::RwLock;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use reqwest::blocking::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::module::config::{DownloaderConfig, DownloaderKind};
+use crate::module::downloader::{Downloader, DownloaderFile, DownloaderTorrent, TorrentSource};
+use crate::module::utils::error::new_err;
+
+/// Transmission through its RPC at `/transmission/rpc`
+///
+/// Transmission has no categories, the category and tags are both kept as labels (Transmission 4.0+).
+/// Every torrent is listed, they are told apart by their hash.
+#[derive(Debug)]
+pub struct Transmission {
+    config: DownloaderConfig,
+    client: Client,
+    session_id: RwLock<String>,     // X-Transmission-Session-Id, handed out on a 409 reply
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct TransmissionTorrent {
+    hash_string: String,
+    name: String,
+    download_dir: String,
+    percent_done: f32,
+    downloaded_ever: i64,
+    total_size: i64,
+    files: Vec<TransmissionFile>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct TransmissionFile {
+    name: String,
+    length: i64,
+    bytes_completed: i64,
+}
+
+/*
+$ curl -i http://localhost:9091/transmission/rpc --data '{"method":"session-get"}'
+HTTP/1.1 409 Conflict
+X-Transmission-Session-Id: 4tSMHNLMjcbEXA9q2J4hJfgAnNo23T1UG1rPV9qADpIbNRnC
+$ curl http://localhost:9091/transmission/rpc --header 'X-Transmission-Session-Id: 4tSMHNLMjcbEXA9q2J4hJfgAnNo23T1UG1rPV9qADpIbNRnC' \
+    --data '{"method":"torrent-get","arguments":{"fields":["hashString","name"]}}'
+{"arguments":{"torrents":[...]},"result":"success"}
+ */
+
+impl Transmission {
+    pub fn new(config: DownloaderConfig) -> Transmission {
+        Transmission {
+            config,
+            client: Client::new(),
+            session_id: RwLock::new(String::new()),
+        }
+    }
+
+    /// Call an RPC method, asking for a new session id once if the current one is refused
+    fn rpc(&self, method: &str, arguments: Value) -> Result<Value, Box<dyn Error>> {
+        log::debug!("Transmission {}", method);
+        let url = format!("{}/transmission/rpc", self.config.base_url());
+        let body = json!({ "method": method, "arguments": arguments });
+        for _ in 0..2 {
+            let session_id = self.session_id.read().unwrap().clone();
+            let mut request = self.client.post(&url)
+                .header("X-Transmission-Session-Id", session_id)
+                .json(&body);
+            if !self.config.username.is_empty() {
+                request = request.basic_auth(&self.config.username, Some(&self.config.password));
+            }
+            let resp = request.send()?;
+            if resp.status().as_u16() == 409 {
+                let session_id = resp.headers().get("X-Transmission-Session-Id")
+                    .and_then(|x| x.to_str().ok())
+                    .ok_or_else(|| new_err("Transmission refused the session without a new session id"))?;
+                log::debug!("Transmission session id renewed");
+                *self.session_id.write().unwrap() = session_id.to_string();
+                continue;
+            }
+            if !resp.status().is_success() {
+                return Err(new_err(format!("Transmission {} failed: {}", method, resp.status()).as_str()));
+            }
+            let resp: Value = resp.json()?;
+            return match resp["result"].as_str() {
+                Some("success") => Ok(resp["arguments"].clone()),
+                result => Err(new_err(format!("Transmission {} failed: {}", method, result.unwrap_or("no result")).as_str())),
+            };
+        }
+        Err(new_err("Transmission refused the session id"))
+    }
+
+    fn get_torrents(&self, ids: Option<&[&str]>, fields: &[&str]) -> Result<Vec<TransmissionTorrent>, Box<dyn Error>> {
+        let mut arguments = json!({ "fields": fields });
+        if let Some(ids) = ids {
+            arguments["ids"] = json!(ids);
+        }
+        let arguments = self.rpc("torrent-get", arguments)?;
+        Ok(serde_json::from_value(arguments["torrents"].clone())?)
+    }
+
+    /// Configured category and comma-separated tags as one list of labels
+    fn labels(category: &str, tags: &str) -> Vec<String> {
+        std::iter::once(category).chain(tags.split(','))
+            .map(|x| x.trim().to_string())
+            .filter(|x| !x.is_empty())
+            .collect()
+    }
+}
+
+impl Downloader for Transmission {
+    fn kind(&self) -> DownloaderKind {
+        DownloaderKind::Transmission
+    }
+
+    fn add_torrent(&self, source: &TorrentSource, save_path: &str) -> Result<(), Box<dyn Error>> {
+        let mut arguments = json!({
+            "download-dir": save_path,
+            "paused": self.config.paused_after_add,
+            "labels": Self::labels(&self.config.category, &self.config.tags),
+            "sequential_download": self.config.sequential_download,     // Transmission 4.1+
+        });
+        match source {
+            TorrentSource::Magnet(link) => arguments["filename"] = json!(link),
+            TorrentSource::File(content) => arguments["metainfo"] = json!(BASE64.encode(content)),
+        }
+        let arguments = self.rpc("torrent-add", arguments)?;
+        if arguments.get("torrent-duplicate").is_some() {
+            log::debug!("Torrent already in Transmission");
+        }
+        Ok(())
+    }
+
+    fn list_torrents(&self) -> Result<Vec<DownloaderTorrent>, Box<dyn Error>> {
+        let torrents = self.get_torrents(None, &["hashString", "name", "downloadDir", "percentDone", "downloadedEver", "totalSize"])?;
+        Ok(torrents.into_iter().map(|x| DownloaderTorrent {
+            hash: x.hash_string,
+            name: x.name,
+            save_path: x.download_dir,
+            progress: x.percent_done,
+            downloaded: x.downloaded_ever,
+            size: x.total_size,
+        }).collect())
+    }
+
+    fn torrent_files(&self, hash: &str) -> Result<Vec<DownloaderFile>, Box<dyn Error>> {
+        let torrent = self.get_torrents(Some(&[hash]), &["hashString", "files"])?.into_iter().next()
+            .ok_or_else(|| new_err(format!("Torrent {} not found in Transmission", hash).as_str()))?;
+        Ok(torrent.files.into_iter().map(|x| DownloaderFile {
+            progress: if x.length > 0 { x.bytes_completed as f32 / x.length as f32 } else { 0. },
+            name: x.name,
+            size: x.length,
+        }).collect())
+    }
+
+    /// Transmission renames the last component of a path, so only the file name of `new_path` is used
+    fn rename_file(&self, hash: &str, old_path: &str, new_path: &str) -> Result<(), Box<dyn Error>> {
+        let name = new_path.rsplit('/').next().unwrap_or(new_path);
+        self.rpc("torrent-rename-path", json!({ "ids": [hash], "path": old_path, "name": name }))?;
+        Ok(())
+    }
+
+    fn move_torrent(&self, hash: &str, save_path: &str) -> Result<(), Box<dyn Error>> {
+        self.rpc("torrent-set-location", json!({ "ids": [hash], "location": save_path, "move": true }))?;
+        Ok(())
+    }
+
+    fn set_labels(&self, hash: &str, category: &str, tags: &str) -> Result<(), Box<dyn Error>> {
+        self.rpc("torrent-set", json!({ "ids": [hash], "labels": Self::labels(category, tags) }))?;
+        Ok(())
+    }
+
+    fn remove_torrents(&self, hashes: &[String], delete_files: bool) -> Result<(), Box<dyn Error>> {
+        self.rpc("torrent-remove", json!({ "ids": hashes, "delete-local-data": delete_files }))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::module::downloader::fake_server::FakeServer;
+
+    use super::*;
+
+    #[test]
+    fn test_fake_transmission() {
+        let server = FakeServer::start(|request| {
+            if request.headers.get("x-transmission-session-id").map(|x| x.as_str()) != Some("fake-session") {
+                return (409, vec![("X-Transmission-Session-Id", "fake-session".to_string())], "".to_string());
+            }
+            let body: Value = serde_json::from_str(&request.body).unwrap();
+            let arguments = match body["method"].as_str().unwrap() {
+                "torrent-get" => json!({ "torrents": [{
+                    "hashString": "bc5fe73ecf6667dcefabdbdeb0f47fd985cc776e",
+                    "name": "a.mkv",
+                    "downloadDir": "/downloads",
+                    "percentDone": 0.5,
+                    "downloadedEver": 1024,
+                    "totalSize": 2048,
+                    "files": [{ "name": "a.mkv", "length": 2048, "bytesCompleted": 1024 }],
+                }] }),
+                _ => json!({}),
+            };
+            (200, vec![], json!({ "arguments": arguments, "result": "success" }).to_string())
+        });
+        let config = DownloaderConfig {
+            host: "127.0.0.1".to_string(),
+            port: server.port as i64,
+            username: "".to_string(),
+            category: "Anime".to_string(),
+            tags: "Bangumi007, Frieren".to_string(),
+            ..Default::default()
+        };
+        let transmission = Transmission::new(config);
+        let hash = "bc5fe73ecf6667dcefabdbdeb0f47fd985cc776e";
+
+        let torrents = transmission.list_torrents().unwrap();
+        assert_eq!(torrents.len(), 1);
+        assert_eq!((torrents[0].hash.as_str(), torrents[0].progress, torrents[0].downloaded), (hash, 0.5, 1024));
+        assert_eq!(transmission.torrent_files(hash).unwrap(), vec![DownloaderFile { name: "a.mkv".to_string(), size: 2048, progress: 0.5 }]);
+        transmission.add_torrent(&TorrentSource::File(b"d4:infod4:name5:a.mkvee".to_vec()), "/downloads/Frieren/Season 1").unwrap();
+        transmission.rename_file(hash, "a.mkv", "Frieren S01E01.mkv").unwrap();
+        transmission.move_torrent(hash, "/downloads/Frieren/Season 1").unwrap();
+        transmission.remove_torrents(&[hash.to_string()], false).unwrap();
+
+        // The session id is asked for once, then every call is sent once
+        assert!(server.requests().iter().all(|x| x.method == "POST"));
+        let requests: Vec<Value> = server.requests().iter().map(|x| serde_json::from_str(&x.body).unwrap()).collect();
+        let methods: Vec<&str> = requests.iter().map(|x| x["method"].as_str().unwrap()).collect();
+        assert_eq!(methods, vec!["torrent-get", "torrent-get", "torrent-get", "torrent-add", "torrent-rename-path", "torrent-set-location", "torrent-remove"]);
+        assert_eq!(requests[2]["arguments"]["ids"], json!([hash]));
+        assert_eq!(requests[3]["arguments"]["metainfo"], json!(BASE64.encode(b"d4:infod4:name5:a.mkvee")));
+        assert_eq!(requests[3]["arguments"]["download-dir"], json!("/downloads/Frieren/Season 1"));
+        assert_eq!(requests[3]["arguments"]["labels"], json!(["Anime", "Bangumi007", "Frieren"]));
+        assert_eq!(requests[4]["arguments"]["name"], json!("Frieren S01E01.mkv"));
+        assert_eq!(requests[6]["arguments"]["delete-local-data"], json!(false));
+    }
+
+    #[test]
+    fn test_fake_transmission_error() {
+        let server = FakeServer::start(|_| (200, vec![], json!({ "arguments": {}, "result": "invalid or corrupt torrent file" }).to_string()));
+        let config = DownloaderConfig { host: "127.0.0.1".to_string(), port: server.port as i64, ..Default::default() };
+        let result = Transmission::new(config).add_torrent(&TorrentSource::File(vec![]), "/downloads");
+        assert!(result.unwrap_err().to_string().contains("invalid or corrupt torrent file"));
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+use crate::module::database::activity::{Activity, ActivityKind};
+use crate::module::database::library::{AnimeSeason, AnimeSeasonItem, read_season_info, renumber_items_in};
+use crate::module::database::repository::{ActivityRepository, SeasonItemRepository, SeasonRepository, SeriesRepository, with_repository, with_transaction};
+use crate::module::database::series::strip_folder_id_tag;
+use crate::module::utils::error::new_err;
+
+/// A change of how the seasons and episodes of the library are shown, seasons are keyed by (mikan_subject_id, mikan_subgroup_id).
+/// Save paths and file names follow the change, see `item_placement`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arrangement {
+    /// Show a season as a part of another one, its episodes numbered after those of the target
+    MergeSeason { season: (i32, i32), target: (i32, i32) },
+    UnmergeSeason { season: (i32, i32) },
+    /// Show the episodes of a season from `split_episode_num` on as season `split_season_num`, numbered from 1
+    SplitSeason { season: (i32, i32), split_episode_num: i32, split_season_num: i32 },
+    UnsplitSeason { season: (i32, i32) },
+    /// Show an item in another season, under its own number unless `disp_episode_num` is set. `None` puts it back.
+    MoveItem { mikan_item_uuid: String, target: Option<(i32, i32)>, disp_episode_num: Option<i32> },
+}
+
+/// Season an item is shown in, `None` if its season is not in the library
+///
+/// ## Input
+///
+/// get_season : `(mikan_subject_id, mikan_subgroup_id) -> Option<AnimeSeason>`
+///
+/// ## Procedure
+///
+/// 1. The season the item is moved to
+/// 2. The split part of its season, under the split season number
+/// 3. Its season
+///
+/// A season merged into another one is shown as the other one.
+pub fn item_placement(item: &AnimeSeasonItem, get_season: impl Fn(i32, i32) -> Option<AnimeSeason>) -> Option<AnimeSeason> {
+    let merge_target = |season: AnimeSeason| match season.merged_into() {
+        Some((mikan_subject_id, mikan_subgroup_id)) => get_season(mikan_subject_id, mikan_subgroup_id).unwrap_or(season),
+        None => season,
+    };
+    if let Some((mikan_subject_id, mikan_subgroup_id)) = item.conf_placement() {
+        if let Some(target) = get_season(mikan_subject_id, mikan_subgroup_id) {
+            return Some(merge_target(target));
+        }
+    }
+    let season = get_season(item.mikan_subject_id, item.mikan_subgroup_id)?;
+    if season.in_split_part(item) {
+        return season.split_part();
+    }
+    Some(merge_target(season))
+}
+
+pub fn read_item_placement(item: &AnimeSeasonItem) -> Option<AnimeSeason> {
+    item_placement(item, read_season_info)
+}
+
+/// Items shown as an episode reported by the media server, each with the season it belongs to,
+/// whose Bangumi subject and episode offset the episode is scrobbled with.
+///
+/// ## Input
+///
+/// series_name : `&str`, any name of the series, the id tag of the folder name (e.g. ` [tmdbid-209867]`) is ignored
+/// disp_season_num, disp_episode_num : `i32`, numbers of the episode as shown, after merges, splits and moves, see `item_placement`
+///
+/// ## Output
+///
+/// (item, its own season) : `Vec<(AnimeSeasonItem, AnimeSeason)>`, empty if nothing is shown there
+pub fn find_shown_episode_in<R: SeasonRepository + SeasonItemRepository + SeriesRepository>(repo: &R, series_name: &str, disp_season_num: i32, disp_episode_num: i32) -> Result<Vec<(AnimeSeasonItem, AnimeSeason)>, Box<dyn Error>> {
+    let series = match repo.find_series_by_name(strip_folder_id_tag(series_name))? {
+        Some(series) => series,
+        None => return Ok(Vec::new()),
+    };
+    let seasons: HashMap<(i32, i32), AnimeSeason> = repo.list_seasons()?.into_iter()
+        .map(|x| ((x.mikan_subject_id, x.mikan_subgroup_id), x))
+        .collect();
+    let get_season = |mikan_subject_id, mikan_subgroup_id| seasons.get(&(mikan_subject_id, mikan_subgroup_id)).cloned();
+    Ok(repo.list_all_items()?.into_iter()
+        .filter(|x| x.state.is_active() && x.disp_episode_num == disp_episode_num)
+        .filter(|x| item_placement(x, get_season)
+            .map_or(false, |shown| shown.series_id == series.series_id && shown.disp_season_num == disp_season_num))
+        .filter_map(|x| {
+            // A release of another subgroup filling a missing episode is scrobbled as an episode of the season it fills
+            let season = get_season(x.mikan_subject_id, x.mikan_subgroup_id)
+                .or_else(|| x.conf_placement().and_then(|(subject_id, subgroup_id)| get_season(subject_id, subgroup_id))
+                    .filter(|season| season.mikan_subject_id == x.mikan_subject_id))?;
+            Some((x, season))
+        })
+        .collect())
+}
+
+pub fn find_shown_episode(series_name: &str, disp_season_num: i32, disp_episode_num: i32) -> Vec<(AnimeSeasonItem, AnimeSeason)> {
+    with_repository("find episode", |repo| find_shown_episode_in(repo, series_name, disp_season_num, disp_episode_num))
+}
+
+/// Apply an arrangement in one transaction
+///
+/// ## Output
+///
+/// Active items whose season or episode number may have changed, to be moved and renamed in the downloader : `Vec<AnimeSeasonItem>`
+pub fn arrange_library(arrangement: &Arrangement) -> Result<Vec<AnimeSeasonItem>, Box<dyn Error>> {
+    let touched_seasons = with_transaction(|repo| arrange_library_in(repo, arrangement))?;
+    let items = with_repository("read items", |repo| repo.list_all_items());
+    Ok(items.into_iter()
+        .filter(|x| x.state.is_active())
+        .filter(|x| touched_seasons.contains(&(x.mikan_subject_id, x.mikan_subgroup_id))
+            || x.conf_placement().map_or(false, |placement| touched_seasons.contains(&placement))
+            || matches!(arrangement, Arrangement::MoveItem { mikan_item_uuid, .. } if *mikan_item_uuid == x.mikan_item_uuid))
+        .collect())
+}
+
+/// ## Output
+///
+/// Seasons whose items are shown elsewhere after the arrangement : `HashSet<(i32, i32)>`
+pub fn arrange_library_in<R: SeasonRepository + SeasonItemRepository + ActivityRepository>(repo: &R, arrangement: &Arrangement) -> Result<HashSet<(i32, i32)>, Box<dyn Error>> {
+    let season_key = match arrangement {
+        Arrangement::MoveItem { mikan_item_uuid, target, disp_episode_num } => {
+            move_item_in(repo, mikan_item_uuid, *target, *disp_episode_num)?;
+            return Ok(HashSet::new());
+        }
+        Arrangement::MergeSeason { season, .. } | Arrangement::UnmergeSeason { season }
+        | Arrangement::SplitSeason { season, .. } | Arrangement::UnsplitSeason { season } => *season,
+    };
+    let season = repo.get_season(season_key.0, season_key.1)?
+        .ok_or_else(|| new_err(format!("Season {}-{} not found", season_key.0, season_key.1).as_str()))?;
+    let after = match arrangement {
+        Arrangement::MergeSeason { target, .. } => {
+            let merged_episode_offset = merge_episode_offset_in(repo, &season, *target)?;
+            AnimeSeason {
+                merged_subject_id: Some(target.0),
+                merged_subgroup_id: Some(target.1),
+                merged_episode_offset: Some(merged_episode_offset),
+                ..season.clone()
+            }
+        }
+        Arrangement::UnmergeSeason { .. } => AnimeSeason {
+            merged_subject_id: None,
+            merged_subgroup_id: None,
+            merged_episode_offset: None,
+            ..season.clone()
+        },
+        Arrangement::SplitSeason { split_episode_num, split_season_num, .. } => {
+            if *split_season_num < 0 || *split_season_num == season.disp_season_num
+                || taken_season_nums(&repo.list_seasons()?, &season).contains(split_season_num) {
+                return Err(new_err(format!("Cannot split season {}-{} as season {}", season_key.0, season_key.1, split_season_num).as_str()));
+            }
+            AnimeSeason {
+                split_episode_num: Some(*split_episode_num),
+                split_season_num: Some(*split_season_num),
+                ..season.clone()
+            }
+        }
+        Arrangement::UnsplitSeason { .. } => AnimeSeason {
+            split_episode_num: None,
+            split_season_num: None,
+            ..season.clone()
+        },
+        Arrangement::MoveItem { .. } => unreachable!(),
+    };
+    repo.update_season_arrangement(&after)?;
+    repo.insert_activity(&Activity::of_season(ActivityKind::LibraryArranged, &season)
+        .change(arrangement_summary(&season), arrangement_summary(&after)))?;
+    renumber_items_in(repo, season_key.0, season_key.1)?;
+    Ok(HashSet::from([season_key]))
+}
+
+/// Season numbers shown by the other Mikan subjects of the series of a season, which the season cannot be split as.
+/// Other subgroups of the same subject share its numbers.
+pub fn taken_season_nums(seasons: &[AnimeSeason], season: &AnimeSeason) -> HashSet<i32> {
+    seasons.iter()
+        .filter(|x| x.series_id > 0 && x.series_id == season.series_id && x.mikan_subject_id != season.mikan_subject_id)
+        .flat_map(|x| std::iter::once(x.disp_season_num).chain(x.split_season_num))
+        .collect()
+}
+
+/// Offset of the episodes of a season merged into `target`, so that they continue after the last episode shown in the target.
+/// Episodes already numbered after it, e.g. by the TMDB episode offset, are kept.
+fn merge_episode_offset_in<R: SeasonRepository + SeasonItemRepository>(repo: &R, season: &AnimeSeason, target: (i32, i32)) -> Result<i32, Box<dyn Error>> {
+    let season_key = (season.mikan_subject_id, season.mikan_subgroup_id);
+    if season_key == target {
+        return Err(new_err("Cannot merge a season into itself"));
+    }
+    let target_season = repo.get_season(target.0, target.1)?
+        .ok_or_else(|| new_err(format!("Season {}-{} not found", target.0, target.1).as_str()))?;
+    let seasons = repo.list_seasons()?;
+    // Only one level of merging, so that a merge is undone on its own
+    if target_season.merged_into().is_some() || seasons.iter().any(|x| x.merged_into() == Some(season_key)) {
+        return Err(new_err(format!("Cannot merge season {}-{} into {}-{}, unmerge the other seasons first",
+                                   season_key.0, season_key.1, target.0, target.1).as_str()));
+    }
+
+    let mut last_episode_num = None;
+    for part in seasons.iter().filter(|x| (x.mikan_subject_id, x.mikan_subgroup_id) == target || (x.merged_into() == Some(target) && (x.mikan_subject_id, x.mikan_subgroup_id) != season_key)) {
+        let episode_nums = repo.list_season_items(part.mikan_subject_id, part.mikan_subgroup_id)?.into_iter()
+            .filter(|x| x.conf_placement().is_none() && !part.in_split_part(x))
+            .map(|x| x.disp_episode_num);
+        last_episode_num = last_episode_num.into_iter().chain(episode_nums).max();
+    }
+    let first_episode_num = repo.list_season_items(season_key.0, season_key.1)?.into_iter()
+        .filter(|x| x.conf_placement().is_none() && x.conf_disp_episode_num.is_none() && !season.in_split_part(x))
+        .map(|x| x.mikan_parsed_episode_num + season.conf_tmdb_episode_offset)
+        .min();
+    Ok(match (last_episode_num, first_episode_num) {
+        (Some(last), Some(first)) if first <= last => last - first + 1,
+        _ => 0,
+    })
+}
+
+fn move_item_in<R: SeasonRepository + SeasonItemRepository + ActivityRepository>(repo: &R, mikan_item_uuid: &str, target: Option<(i32, i32)>, disp_episode_num: Option<i32>) -> Result<(), Box<dyn Error>> {
+    let item = repo.get_season_item(mikan_item_uuid)?
+        .ok_or_else(|| new_err(format!("Item {} not found", mikan_item_uuid).as_str()))?;
+    if let Some((mikan_subject_id, mikan_subgroup_id)) = target {
+        if repo.get_season(mikan_subject_id, mikan_subgroup_id)?.is_none() {
+            return Err(new_err(format!("Season {}-{} not found", mikan_subject_id, mikan_subgroup_id).as_str()));
+        }
+    }
+    repo.set_item_placement(mikan_item_uuid, target, disp_episode_num)?;
+    renumber_items_in(repo, item.mikan_subject_id, item.mikan_subgroup_id)?;
+    let after = repo.get_season_item(mikan_item_uuid)?.unwrap_or_default();
+    repo.insert_activity(&Activity::of_item(ActivityKind::LibraryArranged, &item)
+        .change(placement_summary(&item), placement_summary(&after)))
+}
+
+fn arrangement_summary(season: &AnimeSeason) -> String {
+    let mut summary = Vec::new();
+    if let Some((mikan_subject_id, mikan_subgroup_id)) = season.merged_into() {
+        summary.push(format!("并入 {}-{}，集数偏移 {}", mikan_subject_id, mikan_subgroup_id, season.merged_episode_offset.unwrap_or(0)));
+    }
+    if let Some((split_episode_num, split_season_num)) = season.split_at() {
+        summary.push(format!("第 {} 集起拆分为第 {} 季", split_episode_num, split_season_num));
+    }
+    if summary.is_empty() {
+        return "未调整".to_string();
+    }
+    summary.join("，")
+}
+
+fn placement_summary(item: &AnimeSeasonItem) -> String {
+    let (mikan_subject_id, mikan_subgroup_id) = item.conf_placement().unwrap_or((item.mikan_subject_id, item.mikan_subgroup_id));
+    format!("{}-{} 第 {} 集", mikan_subject_id, mikan_subgroup_id, item.disp_episode_num)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::module::database::item_state::{apply_item_event_in, ItemEvent};
+    use crate::module::database::library::create_item_in;
+    use crate::module::database::repository::{open_in_memory_database, SqliteRepository, test_item, test_season};
+    use crate::module::database::series::link_season_series_in;
+
+    use super::*;
+
+    fn add_items(repo: &SqliteRepository, mikan_subject_id: i32, episode_nums: impl Iterator<Item=i32>) {
+        for episode_num in episode_nums {
+            create_item_in(repo, &test_item(&format!("{}-{}", mikan_subject_id, episode_num), mikan_subject_id, episode_num)).unwrap();
+        }
+    }
+
+    /// (display season number, display episode number) of an item
+    fn shown_as(repo: &SqliteRepository, mikan_item_uuid: &str) -> (i32, i32) {
+        let item = repo.get_season_item(mikan_item_uuid).unwrap().unwrap();
+        let season = item_placement(&item, |subject, subgroup| repo.get_season(subject, subgroup).unwrap()).unwrap();
+        (season.disp_season_num, item.disp_episode_num)
+    }
+
+    #[test]
+    fn test_merge_seasons() {
+        let conn = open_in_memory_database().unwrap();
+        let repo = SqliteRepository::new(&conn);
+        repo.upsert_season(&test_season(3141, 1)).unwrap();
+        repo.upsert_season(&test_season(3500, 2)).unwrap();
+        add_items(&repo, 3141, 1..=12);
+        add_items(&repo, 3500, 1..=4);
+
+        // The second cour continues after the first one
+        arrange_library_in(&repo, &Arrangement::MergeSeason { season: (3500, 382), target: (3141, 382) }).unwrap();
+        assert_eq!(shown_as(&repo, "3500-1"), (1, 13));
+        assert_eq!(shown_as(&repo, "3500-4"), (1, 16));
+        // A new episode of the merged season is numbered alike
+        add_items(&repo, 3500, 5..=5);
+        assert_eq!(shown_as(&repo, "3500-5"), (1, 17));
+        // A merged season cannot be merged into
+        repo.upsert_season(&test_season(3600, 3)).unwrap();
+        assert!(arrange_library_in(&repo, &Arrangement::MergeSeason { season: (3600, 382), target: (3500, 382) }).is_err());
+
+        arrange_library_in(&repo, &Arrangement::UnmergeSeason { season: (3500, 382) }).unwrap();
+        assert_eq!(shown_as(&repo, "3500-1"), (2, 1));
+
+        // Episodes numbered after the target already keep their numbers
+        let mut numbered = repo.get_season(3500, 382).unwrap().unwrap();
+        numbered.conf_tmdb_episode_offset = 12;
+        repo.update_season_conf(&numbered).unwrap();
+        renumber_items_in(&repo, 3500, 382).unwrap();
+        arrange_library_in(&repo, &Arrangement::MergeSeason { season: (3500, 382), target: (3141, 382) }).unwrap();
+        assert_eq!(shown_as(&repo, "3500-1"), (1, 13));
+    }
+
+    #[test]
+    fn test_find_shown_episode() {
+        let conn = open_in_memory_database().unwrap();
+        let repo = SqliteRepository::new(&conn);
+        for (mikan_subject_id, disp_season_num) in [(3141, 1), (3500, 2), (3600, 3)] {
+            repo.upsert_season(&test_season(mikan_subject_id, disp_season_num)).unwrap();
+            link_season_series_in(&repo, mikan_subject_id, 382).unwrap();
+        }
+        add_items(&repo, 3141, 1..=12);
+        add_items(&repo, 3500, 1..=4);
+        add_items(&repo, 3600, 1..=24);
+        for item in repo.list_all_items().unwrap() {
+            apply_item_event_in(&repo, &item.mikan_item_uuid, ItemEvent::Accept).unwrap();
+        }
+        arrange_library_in(&repo, &Arrangement::MergeSeason { season: (3500, 382), target: (3141, 382) }).unwrap();
+        arrange_library_in(&repo, &Arrangement::SplitSeason { season: (3600, 382), split_episode_num: 13, split_season_num: 4 }).unwrap();
+        let find = |disp_season_num, disp_episode_num| find_shown_episode_in(&repo, "葬送的芙莉莲 [tmdbid-209867]", disp_season_num, disp_episode_num).unwrap().into_iter()
+            .map(|(item, season)| (item.mikan_item_uuid, season.mikan_subject_id))
+            .collect::<Vec<_>>();
+
+        // An episode of the merged season, scrobbled to its own subject
+        assert_eq!(find(1, 13), vec![("3500-1".to_string(), 3500)]);
+        assert_eq!(find(1, 12), vec![("3141-12".to_string(), 3141)]);
+        assert_eq!(find(2, 1), vec![]);
+        // An episode of the split part, which has no season of its own
+        assert_eq!(find(4, 1), vec![("3600-13".to_string(), 3600)]);
+        assert_eq!(find(3, 12), vec![("3600-12".to_string(), 3600)]);
+        assert_eq!(find(3, 13), vec![]);
+        // A moved item is found where it is shown
+        arrange_library_in(&repo, &Arrangement::MoveItem { mikan_item_uuid: "3600-24".to_string(), target: Some((3141, 382)), disp_episode_num: Some(0) }).unwrap();
+        assert_eq!(find(1, 0), vec![("3600-24".to_string(), 3600)]);
+        assert_eq!(find_shown_episode_in(&repo, "Unknown", 1, 1).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_split_season_and_move_item() {
+        let conn = open_in_memory_database().unwrap();
+        let repo = SqliteRepository::new(&conn);
+        for (mikan_subject_id, disp_season_num) in [(3141, 1), (3600, 2)] {
+            repo.upsert_season(&test_season(mikan_subject_id, disp_season_num)).unwrap();
+            link_season_series_in(&repo, mikan_subject_id, 382).unwrap();
+        }
+        add_items(&repo, 3141, 1..=24);
+
+        // Season 2 of the series is taken by another subject
+        assert!(arrange_library_in(&repo, &Arrangement::SplitSeason { season: (3141, 382), split_episode_num: 13, split_season_num: 2 }).is_err());
+        assert!(arrange_library_in(&repo, &Arrangement::SplitSeason { season: (3141, 382), split_episode_num: 13, split_season_num: 1 }).is_err());
+        arrange_library_in(&repo, &Arrangement::SplitSeason { season: (3141, 382), split_episode_num: 13, split_season_num: 3 }).unwrap();
+        assert_eq!(shown_as(&repo, "3141-12"), (1, 12));
+        assert_eq!(shown_as(&repo, "3141-13"), (3, 1));
+        assert_eq!(shown_as(&repo, "3141-24"), (3, 12));
+        // Nor can another subject be split as the split part
+        assert!(arrange_library_in(&repo, &Arrangement::SplitSeason { season: (3600, 382), split_episode_num: 7, split_season_num: 3 }).is_err());
+
+        // A moved item leaves the split part, numbered as set
+        arrange_library_in(&repo, &Arrangement::MoveItem { mikan_item_uuid: "3141-24".to_string(), target: Some((3600, 382)), disp_episode_num: Some(0) }).unwrap();
+        assert_eq!(shown_as(&repo, "3141-24"), (2, 0));
+        assert_eq!(repo.get_season_item("3141-24").unwrap().unwrap().conf_placement(), Some((3600, 382)));
+        let placed: Vec<String> = repo.list_placed_items(3600, 382).unwrap().into_iter().map(|x| x.mikan_item_uuid).collect();
+        assert_eq!(placed, vec!["3141-24".to_string()]);
+        assert!(repo.list_placed_items(3141, 382).unwrap().is_empty());
+        // The placement is kept when the feed lists the item again
+        add_items(&repo, 3141, 24..=24);
+        assert_eq!(shown_as(&repo, "3141-24"), (2, 0));
+        arrange_library_in(&repo, &Arrangement::MoveItem { mikan_item_uuid: "3141-24".to_string(), target: None, disp_episode_num: None }).unwrap();
+        assert_eq!(shown_as(&repo, "3141-24"), (3, 12));
+
+        arrange_library_in(&repo, &Arrangement::UnsplitSeason { season: (3141, 382) }).unwrap();
+        assert_eq!(shown_as(&repo, "3141-24"), (1, 24));
+    }
+}
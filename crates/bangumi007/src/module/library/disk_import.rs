@@ -0,0 +1,453 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::module::config::CONFIG;
+use crate::module::database::activity::{Activity, ActivityKind};
+use crate::module::database::cache::rss::{fetch_mikan_subject_info, MikanItem, MikanSubject};
+use crate::module::database::item_state::ItemState;
+use crate::module::database::library::create_item_in;
+use crate::module::database::repository::{ActivityRepository, SeasonItemRepository, SeasonRepository, SeasonTombstoneRepository, SeriesRepository, with_transaction};
+use crate::module::database::series::{find_season_by_series_name, link_season_series_in, strip_folder_id_tag};
+use crate::module::database::subject_override::{get_subject_override, MikanSubjectOverride, set_subject_override};
+use crate::module::library::media_library::new_season;
+use crate::module::parser::bangumi_parser::{bangumi_search_subjects, BangumiSubject, get_bangumi_subject};
+use crate::module::parser::mikan_parser::{parse_filename_to_codec, parse_filename_to_episode, parse_filename_to_language, parse_mikan_subject_info};
+use crate::module::parser::name_similarity::{name_similarity, parse_season_mark, strip_season_marks};
+use crate::module::utils::error::new_err;
+
+/// Subgroup of the seasons imported from disk, which have no release group
+pub const LOCAL_SUBGROUP_ID: i32 = 0;
+
+const VIDEO_EXTENSIONS: [&str; 10] = ["mkv", "mp4", "avi", "ts", "m2ts", "webm", "flv", "rmvb", "wmv", "mov"];
+
+lazy_static! {
+    static ref SEASON_FOLDER: Regex = Regex::new(r"(?i)^s(\d{1,2})$").unwrap();
+    static ref SEASON_EPISODE: Regex = Regex::new(r"(?i)S(\d{1,2})E(\d{1,4})").unwrap();
+}
+
+/// Subject id of a show imported from disk, which is not on Mikan.
+/// Negative so that it never collides with a Mikan subject, the Bangumi subject is pinned by a subject override.
+pub fn local_subject_id(bangumi_subject_id: i32) -> i32 {
+    -bangumi_subject_id
+}
+
+/// Whether a subject was imported from disk, i.e. has no Mikan feed
+pub fn is_local_subject(mikan_subject_id: i32) -> bool {
+    mikan_subject_id < 0
+}
+
+/// Item uuid of a video file imported from disk, there is no torrent hash
+fn local_item_uuid(path: &str) -> String {
+    format!("local:{}", path)
+}
+
+/// A season folder found on disk, e.g. `葬送的芙莉莲/Season 1`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScannedSeason {
+    pub folder: String,
+    pub series_name: String,    // name of the series folder without its id tag
+    pub season_num: i32,        // 0 for specials
+    pub episodes: Vec<ScannedEpisode>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScannedEpisode {
+    pub path: String,
+    pub file_name: String,
+    pub episode_num: i32,
+}
+
+/// Result of an import of the shows on disk
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LocalImportReport {
+    pub seasons_imported: usize,
+    pub episodes_imported: usize,
+    pub seasons_skipped: usize,         // already in the library
+    pub unmatched: Vec<String>,         // folders without a Bangumi subject
+}
+
+/// "Season 2", "S02", "第二季" -> 2, "Specials" -> 0
+fn parse_season_folder(name: &str) -> Option<i32> {
+    let name = name.trim();
+    if name.eq_ignore_ascii_case("specials") || name.eq_ignore_ascii_case("sp") {
+        return Some(0);
+    }
+    SEASON_FOLDER.captures(name)
+        .and_then(|caps| caps[1].parse::<i32>().ok())
+        .or_else(|| parse_season_mark(name))
+}
+
+/// (season number if written, episode number) of a video file name, e.g. "Frieren S01E05.mkv" -> (Some(1), 5)
+fn parse_episode_file(file_name: &str) -> Option<(Option<i32>, i32)> {
+    let stem = Path::new(file_name).file_stem()?.to_str()?;
+    if let Some(caps) = SEASON_EPISODE.captures(stem) {
+        return Some((caps[1].parse::<i32>().ok(), caps[2].parse::<i32>().ok()?));
+    }
+    parse_filename_to_episode(stem).map(|x| (None, x))
+}
+
+fn is_video_file(path: &Path) -> bool {
+    path.is_file() && path.extension()
+        .and_then(|x| x.to_str())
+        .map_or(false, |x| VIDEO_EXTENSIONS.iter().any(|ext| ext.eq_ignore_ascii_case(x)))
+}
+
+/// Video files of a folder with their episode numbers, files without an episode number are skipped
+fn scan_episode_files(folder: &Path) -> Vec<(Option<i32>, ScannedEpisode)> {
+    let entries = match fs::read_dir(folder) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::warn!("Failed to read folder {}: {}", folder.display(), e);
+            return vec![];
+        }
+    };
+    let mut episodes = Vec::new();
+    for path in entries.filter_map(|x| x.ok()).map(|x| x.path()).filter(|x| is_video_file(x)) {
+        let file_name = path.file_name().and_then(|x| x.to_str()).unwrap_or_default().to_string();
+        match parse_episode_file(&file_name) {
+            Some((season_num, episode_num)) => episodes.push((season_num, ScannedEpisode {
+                path: path.display().to_string(),
+                file_name,
+                episode_num,
+            })),
+            None => log::debug!("Skip file without episode number: {}", path.display()),
+        }
+    }
+    episodes.sort_by(|a, b| a.1.episode_num.cmp(&b.1.episode_num).then(a.1.path.cmp(&b.1.path)));
+    episodes
+}
+
+/// Seasons of the shows organized in a library root, laid out as media servers expect
+///
+/// ## Procedure
+///
+/// 1. Every folder of the root is a series, named after the show, an id tag such as ` [tmdbid-209867]` is ignored
+/// 2. Every season folder of a series (`Season 2`, `S02`, `第二季`, `Specials`) is a season
+/// 3. Video files right in a series folder belong to the season in their name (`S02E05`), otherwise to season 1
+///
+/// ## Output
+///
+/// Seasons with at least one episode, ordered by folder : `Vec<ScannedSeason>`
+pub fn scan_library_root(root: &Path) -> Vec<ScannedSeason> {
+    let series_folders = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::warn!("Failed to read library root {}: {}", root.display(), e);
+            return vec![];
+        }
+    };
+    let mut seasons = Vec::new();
+    for series_folder in series_folders.filter_map(|x| x.ok()).map(|x| x.path()).filter(|x| x.is_dir()) {
+        let series_name = match series_folder.file_name().and_then(|x| x.to_str()) {
+            Some(name) => strip_folder_id_tag(name).to_string(),
+            None => continue,
+        };
+        let scanned_season = |folder: &Path, season_num: i32, episodes: Vec<ScannedEpisode>| ScannedSeason {
+            folder: folder.display().to_string(),
+            series_name: series_name.clone(),
+            season_num,
+            episodes,
+        };
+
+        let mut loose_seasons: Vec<ScannedSeason> = Vec::new();
+        for (season_num, episode) in scan_episode_files(&series_folder) {
+            let season_num = season_num.unwrap_or(1);
+            match loose_seasons.iter_mut().find(|x| x.season_num == season_num) {
+                Some(season) => season.episodes.push(episode),
+                None => loose_seasons.push(scanned_season(&series_folder, season_num, vec![episode])),
+            }
+        }
+        seasons.extend(loose_seasons);
+
+        let season_folders = fs::read_dir(&series_folder).into_iter().flatten()
+            .filter_map(|x| x.ok())
+            .map(|x| x.path())
+            .filter(|x| x.is_dir());
+        for season_folder in season_folders {
+            let season_num = match season_folder.file_name().and_then(|x| x.to_str()).and_then(parse_season_folder) {
+                Some(season_num) => season_num,
+                None => continue,
+            };
+            let episodes: Vec<ScannedEpisode> = scan_episode_files(&season_folder).into_iter().map(|x| x.1).collect();
+            if !episodes.is_empty() {
+                seasons.push(scanned_season(&season_folder, season_num, episodes));
+            }
+        }
+    }
+    seasons.sort_by(|a, b| a.series_name.cmp(&b.series_name).then(a.season_num.cmp(&b.season_num)).then(a.folder.cmp(&b.folder)));
+    seasons
+}
+
+/// Bangumi subject of a season folder among the search results
+///
+/// The subject must be of the season number of the folder (a subject without a season mark is season 1),
+/// and one of its names without the season mark must be similar to the series name, the most similar one is chosen.
+pub fn pick_bangumi_subject<'a>(series_name: &str, season_num: i32, candidates: &'a [BangumiSubject]) -> Option<&'a BangumiSubject> {
+    let similarity = |subject: &BangumiSubject| subject.aliases.iter()
+        .map(|alias| name_similarity(&strip_season_marks(alias), series_name))
+        .fold(0.0, f64::max);
+    candidates.iter()
+        .filter(|x| (if x.season_num > 0 { x.season_num } else { 1 }) == season_num)
+        .map(|x| (similarity(x), x))
+        .filter(|(score, _)| *score >= 0.6)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, x)| x)
+}
+
+/// Match the shows of `download_dir` and the extra library roots that are not in the library yet to their subjects,
+/// the network part of the import, run without holding the library, see `import_matched_seasons`
+///
+/// ## Procedure
+///
+/// 1. Scan every root, see `scan_library_root`
+/// 2. Skip the seasons already in the library, e.g. downloaded by this app, matched by series name and season number
+/// 3. Search the series name on Bangumi and pick the subject of the season, see `pick_bangumi_subject`
+/// 4. Parse the subject through the usual parser chain under a local subject id, with the Bangumi subject pinned by a subject override
+///
+/// ## Output
+///
+/// (seasons with their subjects, report of the skipped and unmatched seasons) : `(Vec<(ScannedSeason, MikanSubject)>, LocalImportReport)`
+pub fn match_local_library() -> (Vec<(ScannedSeason, MikanSubject)>, LocalImportReport) {
+    let config = CONFIG.read().unwrap().downloader_config.clone();
+    let roots: Vec<String> = std::iter::once(config.download_dir).chain(config.library_roots)
+        .filter(|x| !x.trim().is_empty())
+        .collect();
+    let mut matched = Vec::new();
+    let mut report = LocalImportReport::default();
+    for root in roots {
+        for scanned in scan_library_root(Path::new(&root)) {
+            match match_scanned_season(&scanned) {
+                Ok(Some(subject)) => matched.push((scanned, subject)),
+                Ok(None) => report.seasons_skipped += 1,
+                Err(e) => {
+                    log::warn!("Failed to match {}: {}", scanned.folder, e);
+                    report.unmatched.push(scanned.folder.clone());
+                }
+            }
+        }
+    }
+    (matched, report)
+}
+
+/// Add the matched seasons and their episodes as already organized, see `import_scanned_season_in`
+pub fn import_matched_seasons(matched: Vec<(ScannedSeason, MikanSubject)>, report: &mut LocalImportReport) {
+    for (scanned, subject) in matched {
+        match with_transaction(|repo| import_scanned_season_in(repo, &scanned, subject.clone())) {
+            Ok(episodes_imported) => {
+                report.seasons_imported += 1;
+                report.episodes_imported += episodes_imported;
+            }
+            Err(e) => {
+                log::warn!("Failed to import {}: {}", scanned.folder, e);
+                report.unmatched.push(scanned.folder.clone());
+            }
+        }
+    }
+    log::info!("Imported {} seasons, {} episodes from disk", report.seasons_imported, report.episodes_imported);
+}
+
+/// ## Output
+///
+/// Subject of the season, `None` if the season is already in the library : `Option<MikanSubject>`
+fn match_scanned_season(scanned: &ScannedSeason) -> Result<Option<MikanSubject>, Box<dyn Error>> {
+    if scanned.season_num <= 0 {
+        return Err(new_err("Specials are not matched to a subject"));
+    }
+    if find_season_by_series_name(&scanned.series_name, scanned.season_num).is_some() {
+        return Ok(None);
+    }
+    let candidates: Vec<BangumiSubject> = bangumi_search_subjects(&scanned.series_name)?.into_iter()
+        .take(5)
+        .filter_map(|x| get_bangumi_subject(x).ok())
+        .collect();
+    let bangumi_subject = pick_bangumi_subject(&scanned.series_name, scanned.season_num, &candidates)
+        .ok_or_else(|| new_err(format!("No Bangumi subject of {} season {}", scanned.series_name, scanned.season_num).as_str()))?;
+
+    let mikan_subject_id = local_subject_id(bangumi_subject.bangumi_subject_id);
+    if get_subject_override(mikan_subject_id).is_none() {
+        set_subject_override(&MikanSubjectOverride {
+            mikan_subject_id,
+            bangumi_subject_id: Some(bangumi_subject.bangumi_subject_id),
+            ..Default::default()
+        })?;
+    }
+    let subject = match fetch_mikan_subject_info(mikan_subject_id) {
+        Some(subject) => subject,
+        None => parse_mikan_subject_info(mikan_subject_id, bangumi_subject.image_url.clone())?,
+    };
+    Ok(Some(subject))
+}
+
+/// Add a scanned season to the library under the local subgroup, and its episodes as already organized,
+/// so that they are neither downloaded nor renamed. Episodes imported before are skipped.
+/// The name of the series folder is kept, so that the media server reporting it finds the season.
+///
+/// ## Output
+///
+/// Number of episodes imported : `usize`
+pub fn import_scanned_season_in<R>(repo: &R, scanned: &ScannedSeason, subject: MikanSubject) -> Result<usize, Box<dyn Error>>
+where R: SeasonRepository + SeasonItemRepository + SeriesRepository + SeasonTombstoneRepository + ActivityRepository {
+    let mikan_subject_id = subject.mikan_subject_id;
+    if repo.is_season_tombstoned(mikan_subject_id, LOCAL_SUBGROUP_ID)? {
+        return Ok(0);
+    }
+    repo.save_series_folder_name(mikan_subject_id, &scanned.series_name)?;
+    let season = match repo.get_season(mikan_subject_id, LOCAL_SUBGROUP_ID)? {
+        Some(season) => season,
+        None => {
+            let mut season = new_season(subject, LOCAL_SUBGROUP_ID, "本地文件".to_string());
+            // Numbered as on disk, e.g. a sequel kept as season 1 by another tool
+            if season.disp_season_num != scanned.season_num {
+                season.conf_season_num = scanned.season_num;
+                season.disp_season_num = scanned.season_num;
+            }
+            repo.upsert_season(&season)?;
+            link_season_series_in(repo, mikan_subject_id, LOCAL_SUBGROUP_ID)?;
+            repo.insert_activity(&Activity::of_season(ActivityKind::SeasonAdded, &season))?;
+            season
+        }
+    };
+
+    let mut imported = 0;
+    for episode in scanned.episodes.iter() {
+        let mikan_item_uuid = local_item_uuid(&episode.path);
+        if repo.get_season_item(&mikan_item_uuid)?.is_some() {
+            continue;
+        }
+        create_item_in(repo, &MikanItem {
+            mikan_item_uuid: mikan_item_uuid.clone(),
+            mikan_subject_id,
+            mikan_subject_name: season.mikan_subject_name.clone(),
+            mikan_subgroup_id: LOCAL_SUBGROUP_ID,
+            mikan_item_title: episode.file_name.clone(),
+            tmdb_series_name: season.tmdb_series_name.clone(),
+            tmdb_season_name: season.tmdb_season_name.clone(),
+            tmdb_parsed_season_num: season.tmdb_season_num,
+            bangumi_parsed_season_num: season.bangumi_season_num,
+            mikan_parsed_episode_num: episode.episode_num,
+            mikan_parsed_language: parse_filename_to_language(&episode.file_name),
+            mikan_parsed_codec: parse_filename_to_codec(&episode.file_name),
+            ..Default::default()
+        })?;
+        repo.set_item_state(&mikan_item_uuid, ItemState::Organized)?;
+        imported += 1;
+    }
+    if imported > 0 {
+        repo.insert_activity(&Activity::of_season(ActivityKind::LibraryImported, &season)
+            .change(&scanned.folder, format!("{} 集", imported)))?;
+    }
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::module::database::repository::{open_in_memory_database, SqliteRepository};
+
+    use super::*;
+
+    fn bangumi_subject(bangumi_subject_id: i32, season_num: i32, aliases: &[&str]) -> BangumiSubject {
+        BangumiSubject {
+            bangumi_subject_id,
+            image_url: "".to_string(),
+            aliases: aliases.iter().map(|x| x.to_string()).collect(),
+            name: aliases[0].to_string(),
+            name_cn: "".to_string(),
+            media_type: "TV".to_string(),
+            season_num,
+            date: "".to_string(),
+            eps: 12,
+        }
+    }
+
+    #[test]
+    fn test_scan_library_root() {
+        let root = std::env::temp_dir().join(format!("bangumi007-test-scan-{}", std::process::id()));
+        let files = [
+            "SPY×FAMILY [tmdbid-120089]/Season 1/SPY×FAMILY S01E01.mkv",
+            "SPY×FAMILY [tmdbid-120089]/Season 1/SPY×FAMILY S01E02.mkv",
+            "SPY×FAMILY [tmdbid-120089]/Season 1/poster.jpg",
+            "SPY×FAMILY [tmdbid-120089]/S02/[Sub] SPY×FAMILY - 03 [1080p].mp4",
+            "SPY×FAMILY [tmdbid-120089]/Extras/Making of.mkv",
+            "葬送的芙莉莲/葬送的芙莉莲 S01E05.mkv",
+            "葬送的芙莉莲/葬送的芙莉莲 S02E01.mkv",
+        ];
+        for file in files {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+
+        let seasons = scan_library_root(&root);
+        let summary: Vec<(&str, i32, Vec<i32>)> = seasons.iter()
+            .map(|x| (x.series_name.as_str(), x.season_num, x.episodes.iter().map(|x| x.episode_num).collect()))
+            .collect();
+        assert_eq!(summary, vec![
+            ("SPY×FAMILY", 1, vec![1, 2]),
+            ("SPY×FAMILY", 2, vec![3]),
+            ("葬送的芙莉莲", 1, vec![5]),
+            ("葬送的芙莉莲", 2, vec![1]),
+        ]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_pick_bangumi_subject() {
+        let candidates = vec![
+            bangumi_subject(329906, -1, &["间谍过家家", "SPY×FAMILY"]),
+            bangumi_subject(371546, 2, &["间谍过家家 第二季", "SPY×FAMILY Season 2"]),
+            bangumi_subject(364450, -1, &["剧场版 间谍过家家 代号：白"]),
+        ];
+        assert_eq!(pick_bangumi_subject("SPY×FAMILY", 1, &candidates).map(|x| x.bangumi_subject_id), Some(329906));
+        assert_eq!(pick_bangumi_subject("SPY×FAMILY", 2, &candidates).map(|x| x.bangumi_subject_id), Some(371546));
+        assert!(pick_bangumi_subject("SPY×FAMILY", 3, &candidates).is_none());
+        assert!(pick_bangumi_subject("葬送的芙莉莲", 1, &candidates).is_none());
+    }
+
+    #[test]
+    fn test_import_scanned_season() {
+        let conn = open_in_memory_database().unwrap();
+        let repo = SqliteRepository::new(&conn);
+        let subject = MikanSubject {
+            mikan_subject_id: local_subject_id(329906),
+            bangumi_subject_id: 329906,
+            bangumi_subject_name: "间谍过家家".to_string(),
+            bangumi_season_num: 1,
+            tmdb_series_id: 120089,
+            tmdb_series_name: "SPY×FAMILY".to_string(),
+            tmdb_season_num: 1,
+            ..Default::default()
+        };
+        let episode = |episode_num: i32| ScannedEpisode {
+            path: format!("/anime/SPY×FAMILY/Season 2/SPY×FAMILY S02E{:02}.mkv", episode_num),
+            file_name: format!("SPY×FAMILY S02E{:02}.mkv", episode_num),
+            episode_num,
+        };
+        let scanned = ScannedSeason {
+            folder: "/anime/SPY×FAMILY/Season 2".to_string(),
+            series_name: "SPY×FAMILY".to_string(),
+            season_num: 2,
+            episodes: vec![episode(1), episode(2)],
+        };
+
+        assert_eq!(import_scanned_season_in(&repo, &scanned, subject.clone()).unwrap(), 2);
+        let season = repo.get_season(-329906, LOCAL_SUBGROUP_ID).unwrap().unwrap();
+        assert_eq!((season.disp_season_num, season.conf_season_num), (2, 2));
+        assert!(season.series_id > 0);
+        // Found by the folder name, which is none of the names of the series
+        let scanned = ScannedSeason { series_name: "Spy x Family".to_string(), ..scanned };
+        assert_eq!(import_scanned_season_in(&repo, &scanned, subject.clone()).unwrap(), 0);
+        assert_eq!(repo.find_series_by_name("Spy x Family").unwrap().map(|x| x.series_id), Some(season.series_id));
+        let items = repo.list_season_items(-329906, LOCAL_SUBGROUP_ID).unwrap();
+        assert!(items.iter().all(|x| x.state == ItemState::Organized));
+        assert_eq!(items.iter().map(|x| x.disp_episode_num).collect::<Vec<_>>(), vec![1, 2]);
+
+        // Imported again with a new file, the known ones are skipped
+        let scanned = ScannedSeason { episodes: vec![episode(1), episode(2), episode(3)], ..scanned };
+        assert_eq!(import_scanned_season_in(&repo, &scanned, subject).unwrap(), 1);
+        assert_eq!(repo.list_season_items(-329906, LOCAL_SUBGROUP_ID).unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+use chrono::NaiveDate;
+
+use crate::module::config::CONFIG;
+use crate::module::database::activity::{Activity, ActivityKind};
+use crate::module::database::cache::rss::{BangumiEpisode, MikanItem};
+use crate::module::database::episode_gap::EpisodeGap;
+use crate::module::database::item_state::{apply_item_event_in, ItemEvent, ItemState};
+use crate::module::database::library::{AnimeSeason, AnimeSeasonItem, create_placed_item_in, read_seasons};
+use crate::module::database::repository::{ActivityRepository, EpisodeGapRepository, MikanItemRepository, SeasonItemRepository, SeasonRepository, with_repository, with_transaction};
+use crate::module::library::disk_import::is_local_subject;
+use crate::module::library::media_library::obeys_season_conf;
+use crate::module::parser::mikan_parser::update_rss;
+use crate::module::utils::error::new_err;
+
+/// Main-story episodes of a season aired by `today` but missing in the library
+///
+/// ## Input
+///
+/// season : `AnimeSeason`
+/// items : `&[AnimeSeasonItem]`, every item of the season in any state
+/// bangumi_episodes : `&[BangumiEpisode]`, episodes of the Bangumi subject of the season
+/// today : `NaiveDate`
+///
+/// ## Procedure
+///
+/// 1. Number the items as Bangumi does, i.e. the parsed episode number plus the bangumi episode offset of the season;
+///    filtered and discovered items are missing, ignored items are skipped by the user and count as present
+/// 2. Every main-story episode with an integer sort and an airdate not after `today` is a gap if no item has its number;
+///    episodes without an airdate are not aired yet
+///
+/// ## Output
+///
+/// Gaps ordered by sort, without replacement : `Vec<EpisodeGap>`
+pub fn find_episode_gaps(season: &AnimeSeason, items: &[AnimeSeasonItem], bangumi_episodes: &[BangumiEpisode], today: NaiveDate) -> Vec<EpisodeGap> {
+    let present: HashSet<i32> = items.iter()
+        .filter(|x| x.state.is_active() || x.state == ItemState::Ignored)
+        .map(|x| x.mikan_parsed_episode_num + season.conf_bangumi_episode_offset)
+        .collect();
+
+    let mut gaps: Vec<EpisodeGap> = bangumi_episodes.iter()
+        .filter(|x| x.episode_type == 0)
+        .filter(|x| NaiveDate::parse_from_str(&x.episode_airdate, "%Y-%m-%d").map_or(false, |airdate| airdate <= today))
+        .filter_map(|x| x.episode_sort.parse::<i32>().ok().map(|sort| (sort, x)))
+        .filter(|(sort, _)| !present.contains(sort))
+        .map(|(sort, episode)| {
+            let mikan_parsed_episode_num = sort - season.conf_bangumi_episode_offset;
+            let placeholder = AnimeSeasonItem { mikan_parsed_episode_num, ..Default::default() };
+            EpisodeGap {
+                mikan_subject_id: season.mikan_subject_id,
+                mikan_subgroup_id: season.mikan_subgroup_id,
+                bangumi_episode_sort: sort,
+                mikan_parsed_episode_num,
+                disp_episode_num: season.item_disp_episode_num(&placeholder),
+                episode_name: if episode.episode_name_cn.is_empty() { episode.episode_name.clone() } else { episode.episode_name_cn.clone() },
+                episode_airdate: episode.episode_airdate.clone(),
+                ..Default::default()
+            }
+        })
+        .collect();
+    gaps.sort_by_key(|x| x.bangumi_episode_sort);
+    gaps.dedup_by_key(|x| x.bangumi_episode_sort);
+    gaps
+}
+
+/// Release of another subgroup of the same subject to fill a gap with
+///
+/// Releases with the episode number of the gap and not in the library yet are candidates,
+/// those obeying the language and codec restriction of the season first, then the newest.
+pub fn pick_replacement<'a>(season: &AnimeSeason, gap: &EpisodeGap, candidates: &'a [MikanItem], in_library: &HashSet<String>) -> Option<&'a MikanItem> {
+    candidates.iter()
+        .filter(|x| x.mikan_subject_id == season.mikan_subject_id && x.mikan_subgroup_id != season.mikan_subgroup_id)
+        .filter(|x| x.mikan_parsed_episode_num == gap.mikan_parsed_episode_num)
+        .filter(|x| !in_library.contains(&x.mikan_item_uuid))
+        .max_by_key(|x| (obeys_season_conf(season, &x.mikan_parsed_language, &x.mikan_parsed_codec), x.mikan_item_pub_date.clone()))
+}
+
+/// Fetch the feeds the gaps are filled from, without holding the library, see `auto_episode_gap_detect`
+///
+/// For a subject with a gap no cached release can fill, the feed of every subgroup of the subject is fetched once,
+/// so that its releases are cached.
+///
+/// ## Input
+///
+/// bangumi_episodes : `&HashMap<i32, Vec<BangumiEpisode>>`, episodes by Bangumi subject id, see `fetch_season_bangumi_episodes`
+pub fn fetch_episode_gap_releases(bangumi_episodes: &HashMap<i32, Vec<BangumiEpisode>>) {
+    let today = chrono::Local::now().date_naive();
+    let in_library: HashSet<String> = with_repository("read items", |repo| repo.list_all_items())
+        .into_iter()
+        .map(|x| x.mikan_item_uuid)
+        .collect();
+    let mut fetched_subjects = HashSet::new();
+    for season in read_seasons().into_iter().filter(|x| x.status.may_have_gaps()) {
+        let episodes = match bangumi_episodes.get(&season.bangumi_subject_id) {
+            Some(episodes) => episodes,
+            None => continue,
+        };
+        // Seasons imported from disk have no Mikan feed, only releases cached before are candidates
+        if is_local_subject(season.mikan_subject_id) || fetched_subjects.contains(&season.mikan_subject_id) {
+            continue;
+        }
+        let unfilled = with_repository("read episode gaps", |repo| {
+            let items = season_gap_items(repo, &season)?;
+            let candidates = repo.list_subject_mikan_items(season.mikan_subject_id)?;
+            Ok(find_episode_gaps(&season, &items, episodes, today).iter()
+                .any(|gap| pick_replacement(&season, gap, &candidates, &in_library).is_none()))
+        });
+        if unfilled {
+            fetched_subjects.insert(season.mikan_subject_id);
+            let url = format!("https://mikanime.tv/RSS/Bangumi?bangumiId={}", season.mikan_subject_id);
+            if let Err(e) = update_rss(&url) {
+                log::warn!("Failed to fetch releases of {}: {}", season.mikan_subject_name, e);
+            }
+        }
+    }
+}
+
+/// Detect the gaps of every season airing or finished with a Bangumi subject, look for replacements and save them
+///
+/// ## Input
+///
+/// bangumi_episodes : `&HashMap<i32, Vec<BangumiEpisode>>`, episodes by Bangumi subject id, see `fetch_season_bangumi_episodes`
+///
+/// ## Procedure
+///
+/// 1. Find the gaps of each season against the main-story episodes of its Bangumi subject,
+///    the saved gaps of a season whose episodes were not fetched are kept
+/// 2. Pick a replacement for each gap among the cached releases, and replace the saved gaps of the season
+/// 3. If `auto_backfill_gaps` is configured, add the replacements to the library at once, see `backfill_episode_gap_in`
+pub fn auto_episode_gap_detect(bangumi_episodes: &HashMap<i32, Vec<BangumiEpisode>>) {
+    let today = chrono::Local::now().date_naive();
+    let auto_backfill = CONFIG.read().unwrap().rss_config.auto_backfill_gaps;
+    // A complete season has no gap, an archived one is left as it is
+    for season in read_seasons().into_iter().filter(|x| x.status.may_have_gaps()) {
+        let episodes = match bangumi_episodes.get(&season.bangumi_subject_id) {
+            Some(episodes) => episodes,
+            None => continue,
+        };
+        let result = with_transaction(|repo| {
+            let items = season_gap_items(repo, &season)?;
+            let mut gaps = find_episode_gaps(&season, &items, episodes, today);
+            let candidates = repo.list_subject_mikan_items(season.mikan_subject_id)?;
+            let in_library: HashSet<String> = repo.list_all_items()?.into_iter().map(|x| x.mikan_item_uuid).collect();
+            for gap in gaps.iter_mut() {
+                if let Some(replacement) = pick_replacement(&season, gap, &candidates, &in_library) {
+                    gap.replacement_uuid = replacement.mikan_item_uuid.clone();
+                    gap.replacement_title = replacement.mikan_item_title.clone();
+                }
+            }
+            repo.replace_season_gaps(season.mikan_subject_id, season.mikan_subgroup_id, &gaps)?;
+            if auto_backfill {
+                for gap in gaps.iter().filter(|x| x.has_replacement()) {
+                    log::info!("Backfilling episode {} of {} with {}", gap.disp_episode_num, season.mikan_subject_name, gap.replacement_title);
+                    backfill_episode_gap_in(repo, gap.mikan_subject_id, gap.mikan_subgroup_id, gap.bangumi_episode_sort)?;
+                }
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            log::error!("Failed to detect episode gaps of {}: {}", season.mikan_subject_name, e);
+        }
+    }
+}
+
+/// Items a season has, its own ones and the releases of other subgroups of the subject placed in it, see `backfill_episode_gap_in`
+fn season_gap_items<R: SeasonItemRepository>(repo: &R, season: &AnimeSeason) -> Result<Vec<AnimeSeasonItem>, Box<dyn Error>> {
+    let season_key = (season.mikan_subject_id, season.mikan_subgroup_id);
+    let mut items = repo.list_season_items(season.mikan_subject_id, season.mikan_subgroup_id)?;
+    items.extend(repo.list_all_items()?.into_iter()
+        .filter(|x| x.mikan_subject_id == season.mikan_subject_id && x.mikan_subgroup_id != season.mikan_subgroup_id)
+        .filter(|x| x.conf_placement() == Some(season_key)));
+    Ok(items)
+}
+
+/// Fill a gap with its replacement, see `backfill_episode_gap_in`
+pub fn backfill_episode_gap(mikan_subject_id: i32, mikan_subgroup_id: i32, bangumi_episode_sort: i32) -> Result<AnimeSeasonItem, Box<dyn Error>> {
+    with_transaction(|repo| backfill_episode_gap_in(repo, mikan_subject_id, mikan_subgroup_id, bangumi_episode_sort))
+}
+
+/// Add the replacement of a gap, accepted, and forget the gap.
+/// The release keeps its own subgroup and is placed in the season of the gap as the missing episode, see `item_placement`.
+///
+/// ## Output
+///
+/// The added item : `AnimeSeasonItem`
+pub fn backfill_episode_gap_in<R>(repo: &R, mikan_subject_id: i32, mikan_subgroup_id: i32, bangumi_episode_sort: i32) -> Result<AnimeSeasonItem, Box<dyn Error>>
+where R: SeasonRepository + SeasonItemRepository + EpisodeGapRepository + MikanItemRepository + ActivityRepository {
+    let gap = repo.get_episode_gap(mikan_subject_id, mikan_subgroup_id, bangumi_episode_sort)?
+        .ok_or_else(|| new_err(format!("Episode gap {} of season {}-{} not found", bangumi_episode_sort, mikan_subject_id, mikan_subgroup_id).as_str()))?;
+    if !gap.has_replacement() {
+        return Err(new_err(format!("No replacement for episode {} of season {}-{}", gap.disp_episode_num, mikan_subject_id, mikan_subgroup_id).as_str()));
+    }
+    if repo.get_season_item(&gap.replacement_uuid)?.is_some() {
+        return Err(new_err(format!("Replacement {} is already in the library", gap.replacement_title).as_str()));
+    }
+    let replacement = repo.fetch_mikan_items(&[gap.replacement_uuid.as_str()])?.pop()
+        .ok_or_else(|| new_err(format!("Replacement {} not cached", gap.replacement_title).as_str()))?;
+
+    create_placed_item_in(repo, &replacement, (mikan_subject_id, mikan_subgroup_id), gap.disp_episode_num)?;
+    apply_item_event_in(repo, &gap.replacement_uuid, ItemEvent::Accept)?;
+    let item = repo.get_season_item(&gap.replacement_uuid)?
+        .ok_or_else(|| new_err(format!("Replacement {} not added", gap.replacement_title).as_str()))?;
+    repo.insert_activity(&Activity::of_item(ActivityKind::GapBackfilled, &item)
+        .change(format!("缺第 {} 集", gap.disp_episode_num), &gap.replacement_title))?;
+    repo.delete_episode_gap(mikan_subject_id, mikan_subgroup_id, bangumi_episode_sort)?;
+    Ok(item)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::module::database::cache::rss::bangumi_episode;
+    use crate::module::database::library::create_item_in;
+    use crate::module::database::repository::{open_in_memory_database, SqliteRepository, test_item, test_season};
+
+    use super::*;
+
+    #[test]
+    fn test_find_episode_gaps() {
+        // Second cour numbered 13.. on Bangumi, 1.. by the subgroup
+        let season = AnimeSeason { conf_bangumi_episode_offset: 12, conf_tmdb_episode_offset: 12, ..test_season(3141, 1) };
+        let item = |episode_num: i32, state: ItemState| AnimeSeasonItem { mikan_parsed_episode_num: episode_num, state, ..Default::default() };
+        let items = vec![item(1, ItemState::Organized), item(2, ItemState::Filtered), item(3, ItemState::Ignored)];
+        let episodes = vec![
+            bangumi_episode("13", 0, "2024-01-05"),
+            bangumi_episode("14", 0, "2024-01-12"),
+            bangumi_episode("15", 0, "2024-01-19"),
+            bangumi_episode("16", 0, "2024-01-26"),
+            bangumi_episode("17", 0, "2024-02-02"),
+            bangumi_episode("18", 0, ""),
+            bangumi_episode("1", 1, "2024-01-01"),
+            bangumi_episode("16.5", 0, "2024-01-27"),
+        ];
+        let today = NaiveDate::from_ymd_opt(2024, 1, 30).unwrap();
+        let gaps = find_episode_gaps(&season, &items, &episodes, today);
+        // Filtered episode 2 and missing episode 4 are gaps, ignored episode 3, specials and unaired episodes are not
+        assert_eq!(gaps.iter().map(|x| (x.bangumi_episode_sort, x.mikan_parsed_episode_num, x.disp_episode_num)).collect::<Vec<_>>(),
+                   vec![(14, 2, 14), (16, 4, 16)]);
+        assert_eq!(gaps[1].episode_airdate, "2024-01-26");
+    }
+
+    #[test]
+    fn test_backfill_episode_gap() {
+        let conn = open_in_memory_database().unwrap();
+        let repo = SqliteRepository::new(&conn);
+        let season = AnimeSeason { conf_language: "简体".to_string(), ..test_season(3141, 1) };
+        repo.upsert_season(&season).unwrap();
+        create_item_in(&repo, &MikanItem { mikan_parsed_language: "简体".to_string(), mikan_item_pub_date: "2024-01-05".to_string(), ..test_item("own", 3141, 1) }).unwrap();
+
+        let candidates = vec![
+            MikanItem { mikan_subgroup_id: 583, mikan_parsed_language: "繁体".to_string(), mikan_item_pub_date: "2024-01-14".to_string(), ..test_item("newer", 3141, 2) },
+            MikanItem { mikan_subgroup_id: 583, mikan_parsed_language: "简体".to_string(), mikan_item_pub_date: "2024-01-12".to_string(), ..test_item("obeying", 3141, 2) },
+            MikanItem { mikan_parsed_language: "简体".to_string(), mikan_item_pub_date: "2024-01-13".to_string(), ..test_item("in_library", 3141, 2) },
+            MikanItem { mikan_subgroup_id: 583, mikan_parsed_language: "简体".to_string(), mikan_item_pub_date: "2024-01-19".to_string(), ..test_item("other_episode", 3141, 3) },
+            MikanItem { mikan_parsed_language: "简体".to_string(), mikan_item_pub_date: "2024-01-05".to_string(), ..test_item("own", 3141, 1) },
+        ];
+        let gap = EpisodeGap { mikan_subject_id: 3141, mikan_subgroup_id: 382, bangumi_episode_sort: 2, mikan_parsed_episode_num: 2, disp_episode_num: 2, ..Default::default() };
+        let in_library = HashSet::from(["own".to_string()]);
+        // Same subgroup releases are not replacements, the language restriction of the season comes before the date
+        assert_eq!(pick_replacement(&season, &gap, &candidates, &in_library).unwrap().mikan_item_uuid, "obeying");
+
+        for candidate in candidates.iter() {
+            repo.insert_mikan_item(candidate).unwrap();
+        }
+        repo.replace_season_gaps(3141, 382, &[EpisodeGap { replacement_uuid: "obeying".to_string(), ..gap.clone() }]).unwrap();
+        let item = backfill_episode_gap_in(&repo, 3141, 382, 2).unwrap();
+        assert_eq!((item.mikan_subgroup_id, item.disp_episode_num, item.state), (583, 2, ItemState::Accepted));
+        assert_eq!(item.conf_placement(), Some((3141, 382)));
+        assert!(repo.list_episode_gaps().unwrap().is_empty());
+        // The replacement fills the gap of the season it is placed in
+        let items = season_gap_items(&repo, &season).unwrap();
+        assert_eq!(items.iter().map(|x| x.mikan_item_uuid.as_str()).collect::<Vec<_>>(), vec!["own", "obeying"]);
+        assert!(backfill_episode_gap_in(&repo, 3141, 382, 2).is_err());
+    }
+}
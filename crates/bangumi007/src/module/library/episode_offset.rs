@@ -0,0 +1,403 @@
+use std::collections::{BTreeSet, HashMap};
+
+use chrono::NaiveDate;
+
+use crate::module::database::cache::rss::BangumiEpisode;
+use crate::module::database::library::{AnimeSeason, EpisodeOffsetProposalRecord, read_episode_offset_proposal, read_season_items, read_seasons, save_episode_offset_proposal, set_episode_offset_proposal_applied};
+use crate::module::library::update_season_config;
+use crate::module::parser::bangumi_parser::get_bangumi_episodes;
+use crate::module::parser::tmdb_parser::{tmdb_get_season_episodes, TMDBEpisode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OffsetConfidence {
+    Low = 0,
+    Medium = 1,
+    High = 2,
+}
+
+impl From<i32> for OffsetConfidence {
+    fn from(value: i32) -> Self {
+        match value {
+            2 => OffsetConfidence::High,
+            1 => OffsetConfidence::Medium,
+            _ => OffsetConfidence::Low,
+        }
+    }
+}
+
+impl OffsetConfidence {
+    pub fn disp_name(&self) -> &'static str {
+        match self {
+            OffsetConfidence::High => "高",
+            OffsetConfidence::Medium => "中",
+            OffsetConfidence::Low => "低",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EpisodeOffsetProposal {
+    pub tmdb_episode_offset: i32,
+    pub bangumi_episode_offset: i32,
+    pub confidence: OffsetConfidence,
+    pub explanation: String,
+}
+
+#[derive(Debug, Clone)]
+struct OffsetCandidate {
+    offset: i32,
+    covered: usize,
+    date_compared: usize,
+    date_agreed: usize,
+}
+
+/// Order candidates by coverage, then by airdate agreement, then prefer no offset, then the
+/// smallest offset. The order is total, so the result never depends on the input order.
+fn pick_best_candidate(mut candidates: Vec<OffsetCandidate>) -> (OffsetCandidate, Option<OffsetCandidate>) {
+    candidates.sort_by(|a, b| {
+        b.covered.cmp(&a.covered)
+            .then(b.date_agreed.cmp(&a.date_agreed))
+            .then((b.offset == 0).cmp(&(a.offset == 0)))
+            .then(a.offset.abs().cmp(&b.offset.abs()))
+            .then(a.offset.cmp(&b.offset))
+    });
+    let mut candidates = candidates.into_iter();
+    let best = candidates.next().unwrap();
+    (best, candidates.next())
+}
+
+fn airdate_agrees(a: &str, b: &str) -> Option<bool> {
+    let a = NaiveDate::parse_from_str(a, "%Y-%m-%d").ok()?;
+    let b = NaiveDate::parse_from_str(b, "%Y-%m-%d").ok()?;
+    // Allow one day of difference, TMDB and Bangumi do not always agree on the timezone
+    Some((a - b).num_days().abs() <= 1)
+}
+
+fn format_range<'a>(numbers: impl Iterator<Item=&'a i32>) -> String {
+    let numbers: Vec<&i32> = numbers.collect();
+    match (numbers.iter().min(), numbers.iter().max()) {
+        (Some(min), Some(max)) if min == max => format!("{}", min),
+        (Some(min), Some(max)) => format!("{}-{}", min, max),
+        _ => "无".to_string(),
+    }
+}
+
+/// # Infer episode offsets
+///
+/// ## Input
+///
+/// Episode numbers parsed from the subgroup's releases, Bangumi episodes of the subject and
+/// TMDB episodes of the matched season (either list may be empty).
+///
+/// ## Procedure
+///
+/// 1. Try the Bangumi offsets that map the subgroup numbers onto the main story `sort` values,
+///    including the `sort - ep` shift of subjects that continue the numbering of a previous season
+/// 2. Try the TMDB offsets that map the subgroup numbers into the TMDB season, using the episodes
+///    whose TMDB airdate equals the Bangumi airdate as extra candidates
+/// 3. Rank the candidates by coverage and airdate agreement
+///
+/// ## Output
+///
+/// `EpisodeOffsetProposal` with both offsets, a confidence and a human readable explanation.
+pub fn infer_episode_offset(subgroup_episodes: &[i32], bangumi_episodes: &[BangumiEpisode], tmdb_episodes: &[TMDBEpisode]) -> EpisodeOffsetProposal {
+    let subgroup: BTreeSet<i32> = subgroup_episodes.iter().copied().filter(|x| *x >= 0).collect();
+    if subgroup.is_empty() {
+        return EpisodeOffsetProposal {
+            tmdb_episode_offset: 0,
+            bangumi_episode_offset: 0,
+            confidence: OffsetConfidence::Low,
+            explanation: "字幕组剧集为空，无法推断".to_string(),
+        };
+    }
+    let sub_min = *subgroup.iter().next().unwrap();
+
+    // Main story episodes only, sort -> airdate
+    let bangumi_main: Vec<&BangumiEpisode> = bangumi_episodes.iter().filter(|x| x.episode_type == 0).collect();
+    let bangumi_sorts: HashMap<i32, &str> = bangumi_main.iter()
+        .filter_map(|x| x.episode_sort.parse::<i32>().ok().map(|sort| (sort, x.episode_airdate.as_str())))
+        .collect();
+    let tmdb_numbers: HashMap<i32, &str> = tmdb_episodes.iter()
+        .map(|x| (x.episode_number, x.air_date.as_str()))
+        .collect();
+
+    // 1. Bangumi offset
+    let mut bangumi_offsets = BTreeSet::from([0]);
+    if let Some(min_sort) = bangumi_sorts.keys().min() {
+        bangumi_offsets.insert(min_sort - sub_min);
+    }
+    for episode in &bangumi_main {
+        if let Ok(sort) = episode.episode_sort.parse::<i32>() {
+            bangumi_offsets.insert(sort - episode.episode_ep);
+        }
+    }
+    let bangumi_candidates = bangumi_offsets.iter().map(|offset| OffsetCandidate {
+        offset: *offset,
+        covered: subgroup.iter().filter(|x| bangumi_sorts.contains_key(&(*x + offset))).count(),
+        date_compared: 0,
+        date_agreed: 0,
+    }).collect();
+    let (bangumi_best, bangumi_runner_up) = pick_best_candidate(bangumi_candidates);
+
+    // 2. TMDB offset
+    let mut tmdb_offsets = BTreeSet::from([0]);
+    if let Some(min_number) = tmdb_numbers.keys().min() {
+        tmdb_offsets.insert(min_number - sub_min);
+    }
+    for episode in &subgroup {
+        let bangumi_airdate = match bangumi_sorts.get(&(episode + bangumi_best.offset)) {
+            Some(airdate) => airdate,
+            None => continue,
+        };
+        for tmdb_episode in tmdb_episodes {
+            if airdate_agrees(bangumi_airdate, &tmdb_episode.air_date) == Some(true) {
+                tmdb_offsets.insert(tmdb_episode.episode_number - episode);
+            }
+        }
+    }
+    let tmdb_candidates = tmdb_offsets.iter().map(|offset| {
+        let mut candidate = OffsetCandidate {
+            offset: *offset,
+            covered: 0,
+            date_compared: 0,
+            date_agreed: 0,
+        };
+        for episode in &subgroup {
+            let tmdb_airdate = match tmdb_numbers.get(&(episode + offset)) {
+                Some(airdate) => airdate,
+                None => continue,
+            };
+            candidate.covered += 1;
+            let bangumi_airdate = match bangumi_sorts.get(&(episode + bangumi_best.offset)) {
+                Some(airdate) => airdate,
+                None => continue,
+            };
+            if let Some(agrees) = airdate_agrees(bangumi_airdate, tmdb_airdate) {
+                candidate.date_compared += 1;
+                if agrees {
+                    candidate.date_agreed += 1;
+                }
+            }
+        }
+        candidate
+    }).collect();
+    let (tmdb_best, tmdb_runner_up) = pick_best_candidate(tmdb_candidates);
+
+    // 3. Confidence
+    let total = subgroup.len();
+    let unambiguous = |best: &OffsetCandidate, runner_up: &Option<OffsetCandidate>| {
+        match runner_up {
+            Some(runner_up) => runner_up.covered < best.covered || best.offset == 0,
+            None => true,
+        }
+    };
+    let bangumi_full = !bangumi_sorts.is_empty() && bangumi_best.covered == total;
+    let tmdb_full = !tmdb_numbers.is_empty() && tmdb_best.covered == total;
+    let dates_agree = if tmdb_best.date_compared == 0 {
+        unambiguous(&tmdb_best, &tmdb_runner_up)
+    } else {
+        tmdb_best.date_agreed * 10 >= tmdb_best.date_compared * 8
+    };
+    let confidence = if bangumi_full && tmdb_full && dates_agree && unambiguous(&bangumi_best, &bangumi_runner_up) {
+        OffsetConfidence::High
+    } else if (bangumi_sorts.is_empty() || bangumi_best.covered * 2 >= total)
+        && (tmdb_numbers.is_empty() || tmdb_best.covered * 2 >= total)
+        && !(bangumi_sorts.is_empty() && tmdb_numbers.is_empty()) {
+        OffsetConfidence::Medium
+    } else {
+        OffsetConfidence::Low
+    };
+
+    let mut explanation = vec![format!("字幕组剧集 {}", format_range(subgroup.iter()))];
+    if bangumi_sorts.is_empty() {
+        explanation.push("Bangumi 无正片剧集信息，偏移保持 0".to_string());
+    } else {
+        explanation.push(format!("Bangumi 正片 {}，偏移 {:+}，覆盖 {}/{}",
+                                 format_range(bangumi_sorts.keys()), bangumi_best.offset, bangumi_best.covered, total));
+    }
+    if tmdb_numbers.is_empty() {
+        explanation.push("TMDB 无本季剧集信息，偏移保持 0".to_string());
+    } else {
+        let mut tmdb_explanation = format!("TMDB 本季 {}，偏移 {:+}，覆盖 {}/{}",
+                                           format_range(tmdb_numbers.keys()), tmdb_best.offset, tmdb_best.covered, total);
+        if tmdb_best.date_compared > 0 {
+            tmdb_explanation.push_str(&format!("，播出日期吻合 {}/{}", tmdb_best.date_agreed, tmdb_best.date_compared));
+        }
+        explanation.push(tmdb_explanation);
+    }
+
+    EpisodeOffsetProposal {
+        tmdb_episode_offset: tmdb_best.offset,
+        bangumi_episode_offset: bangumi_best.offset,
+        confidence,
+        explanation: explanation.join("；"),
+    }
+}
+
+/// Fetch the Bangumi and TMDB episode lists of the season and infer its offsets.
+pub fn infer_season_episode_offset(season: &AnimeSeason, subgroup_episodes: &[i32]) -> EpisodeOffsetProposal {
+    let bangumi_episodes = if season.bangumi_subject_id > 0 {
+        get_bangumi_episodes(season.bangumi_subject_id).unwrap_or_else(|e| {
+            log::warn!("Failed to get bangumi episodes of {}: {}", season.bangumi_subject_id, e);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+    let tmdb_episodes = if season.tmdb_series_id > 0 && season.tmdb_season_num >= 0 {
+        tmdb_get_season_episodes(season.tmdb_series_id as i64, season.tmdb_season_num as i64).unwrap_or_else(|e| {
+            log::warn!("Failed to get tmdb episodes of {} season {}: {}", season.tmdb_series_id, season.tmdb_season_num, e);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+    infer_episode_offset(subgroup_episodes, &bangumi_episodes, &tmdb_episodes)
+}
+
+/// Infer the episode offsets of every season whose subgroup episode range changed since the last
+/// inference. High confidence proposals are applied once, and only to seasons whose offsets were
+/// never tuned by hand.
+pub fn auto_episode_offset_infer() {
+    for season in read_seasons() {
+        let items = read_season_items(season.mikan_subject_id, season.mikan_subgroup_id);
+        let subgroup_episodes: Vec<i32> = items.iter().map(|x| x.mikan_parsed_episode_num).collect();
+        let ep_num_min = subgroup_episodes.iter().copied().min().unwrap_or(-1);
+        let ep_num_max = subgroup_episodes.iter().copied().max().unwrap_or(-1);
+
+        let stored = read_episode_offset_proposal(season.mikan_subject_id, season.mikan_subgroup_id);
+        if let Some(stored) = &stored {
+            if stored.ep_num_min == ep_num_min && stored.ep_num_max == ep_num_max {
+                continue;
+            }
+        }
+        let applied = stored.map_or(false, |x| x.applied);
+
+        let proposal = infer_season_episode_offset(&season, &subgroup_episodes);
+        log::debug!("Episode offset proposal of {} ({}): {:?}", season.mikan_subject_name, season.mikan_subgroup_id, proposal);
+        save_episode_offset_proposal(&EpisodeOffsetProposalRecord {
+            mikan_subject_id: season.mikan_subject_id,
+            mikan_subgroup_id: season.mikan_subgroup_id,
+            ep_num_min,
+            ep_num_max,
+            tmdb_episode_offset: proposal.tmdb_episode_offset,
+            bangumi_episode_offset: proposal.bangumi_episode_offset,
+            confidence: proposal.confidence as i32,
+            explanation: proposal.explanation.clone(),
+            applied,
+        });
+
+        if applied || proposal.confidence != OffsetConfidence::High {
+            continue;
+        }
+        if season.conf_tmdb_episode_offset != 0 || season.conf_bangumi_episode_offset != 0 {
+            // Tuned by hand, never touch it
+            set_episode_offset_proposal_applied(season.mikan_subject_id, season.mikan_subgroup_id);
+            continue;
+        }
+        if proposal.tmdb_episode_offset == 0 && proposal.bangumi_episode_offset == 0 {
+            continue;
+        }
+        log::info!("Applying inferred episode offsets to {}: tmdb {:+}, bangumi {:+}",
+                   season.mikan_subject_name, proposal.tmdb_episode_offset, proposal.bangumi_episode_offset);
+        let mikan_subject_id = season.mikan_subject_id;
+        let mikan_subgroup_id = season.mikan_subgroup_id;
+        update_season_config(&AnimeSeason {
+            conf_tmdb_episode_offset: proposal.tmdb_episode_offset,
+            conf_bangumi_episode_offset: proposal.bangumi_episode_offset,
+            ..season
+        },
+                             false,
+                             false,
+        );
+        set_episode_offset_proposal_applied(mikan_subject_id, mikan_subgroup_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bangumi_episode(sort: i32, ep: i32, airdate: &str) -> BangumiEpisode {
+        BangumiEpisode {
+            subject_id: 1,
+            episode_id: sort,
+            episode_type: 0,
+            episode_ep: ep,
+            episode_sort: sort.to_string(),
+            episode_name: "".to_string(),
+            episode_name_cn: "".to_string(),
+            episode_airdate: airdate.to_string(),
+        }
+    }
+
+    fn tmdb_episode(number: i32, air_date: &str) -> TMDBEpisode {
+        TMDBEpisode {
+            episode_number: number,
+            air_date: air_date.to_string(),
+        }
+    }
+
+    fn weekly(start: &str, count: i32) -> Vec<String> {
+        let start = NaiveDate::parse_from_str(start, "%Y-%m-%d").unwrap();
+        (0..count).map(|i| (start + chrono::Duration::days(7 * i as i64)).format("%Y-%m-%d").to_string()).collect()
+    }
+
+    #[test]
+    fn test_infer_no_offset() {
+        let dates = weekly("2024-04-06", 12);
+        let bangumi: Vec<BangumiEpisode> = (1..=12).map(|i| bangumi_episode(i, i, &dates[i as usize - 1])).collect();
+        let tmdb: Vec<TMDBEpisode> = (1..=12).map(|i| tmdb_episode(i, &dates[i as usize - 1])).collect();
+        let proposal = infer_episode_offset(&[1, 2, 3, 4], &bangumi, &tmdb);
+        assert_eq!(proposal.tmdb_episode_offset, 0);
+        assert_eq!(proposal.bangumi_episode_offset, 0);
+        assert_eq!(proposal.confidence, OffsetConfidence::High);
+    }
+
+    #[test]
+    fn test_infer_continued_numbering() {
+        // Season 2 numbered 13-24 by the subgroup and by Bangumi, 1-12 by TMDB
+        let dates = weekly("2024-10-05", 12);
+        let bangumi: Vec<BangumiEpisode> = (1..=12).map(|i| bangumi_episode(i + 12, i, &dates[i as usize - 1])).collect();
+        let tmdb: Vec<TMDBEpisode> = (1..=12).map(|i| tmdb_episode(i, &dates[i as usize - 1])).collect();
+        let proposal = infer_episode_offset(&[13, 14, 15], &bangumi, &tmdb);
+        assert_eq!(proposal.tmdb_episode_offset, -12);
+        assert_eq!(proposal.bangumi_episode_offset, 0);
+        assert_eq!(proposal.confidence, OffsetConfidence::High);
+    }
+
+    #[test]
+    fn test_infer_bangumi_offset() {
+        // Subgroup restarts from 1 while Bangumi continues from 13
+        let dates = weekly("2024-10-05", 12);
+        let bangumi: Vec<BangumiEpisode> = (1..=12).map(|i| bangumi_episode(i + 12, i, &dates[i as usize - 1])).collect();
+        let tmdb: Vec<TMDBEpisode> = (1..=12).map(|i| tmdb_episode(i, &dates[i as usize - 1])).collect();
+        let proposal = infer_episode_offset(&[1, 2, 3, 4, 5], &bangumi, &tmdb);
+        assert_eq!(proposal.tmdb_episode_offset, 0);
+        assert_eq!(proposal.bangumi_episode_offset, 12);
+        assert_eq!(proposal.confidence, OffsetConfidence::High);
+    }
+
+    #[test]
+    fn test_infer_split_cour_by_airdate() {
+        // TMDB lists both cours as one 24 episode season, only the airdates tell the cours apart
+        let dates = weekly("2024-01-06", 24);
+        let bangumi: Vec<BangumiEpisode> = (1..=12).map(|i| bangumi_episode(i + 12, i, &dates[i as usize + 11])).collect();
+        let tmdb: Vec<TMDBEpisode> = (1..=24).map(|i| tmdb_episode(i, &dates[i as usize - 1])).collect();
+        let proposal = infer_episode_offset(&[1, 2, 3], &bangumi, &tmdb);
+        assert_eq!(proposal.bangumi_episode_offset, 12);
+        assert_eq!(proposal.tmdb_episode_offset, 12);
+        assert_eq!(proposal.confidence, OffsetConfidence::High);
+    }
+
+    #[test]
+    fn test_infer_without_metadata() {
+        let proposal = infer_episode_offset(&[1, 2], &[], &[]);
+        assert_eq!(proposal.tmdb_episode_offset, 0);
+        assert_eq!(proposal.bangumi_episode_offset, 0);
+        assert_eq!(proposal.confidence, OffsetConfidence::Low);
+
+        let proposal = infer_episode_offset(&[], &[], &[]);
+        assert_eq!(proposal.confidence, OffsetConfidence::Low);
+    }
+}
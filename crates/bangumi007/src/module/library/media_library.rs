@@ -1,79 +1,104 @@
 use std::collections::HashSet;
+use std::error::Error;
 
+use crate::module::config::CONFIG;
+use crate::module::database::activity::{Activity, ActivityKind};
 use crate::module::database::cache::rss;
-use crate::module::database::cache::rss::MikanItem;
-use crate::module::database::get_connection;
-use crate::module::database::library::{AnimeSeason, create_item, create_season, delete_item, read_season_info, read_season_items, read_seasons};
+use crate::module::database::cache::title::{clear_title_backfill_failed, is_title_backfill_failed, mark_title_backfill_failed, read_subject_titles, select_title, TITLE_KIND_SEASON, TITLE_KIND_SERIES};
+use crate::module::database::cache::rss::MikanSubject;
+use crate::module::database::item_state::{apply_item_event_in, ItemEvent};
+use crate::module::database::subject_override::{apply_subject_override, read_subject_overrides};
+use crate::module::database::library::{AnimeSeason, create_item_in, read_seasons, renumber_items_in};
+use crate::module::database::repository::{ActivityRepository, SeasonItemRepository, SeasonRepository, SeasonTombstoneRepository, SeriesRepository, SqliteRepository, with_repository, with_transaction};
+use crate::module::database::series::link_season_series_in;
 use crate::module::parser::mikan_parser;
+use crate::module::utils::error::new_err;
+
+/// Display series name, season number and season name of a Mikan subject,
+/// in the preferred title languages, falling back to TMDB then Bangumi.
+/// The display names of the subject override take precedence.
+pub fn subject_disp_info(subject: &MikanSubject) -> (String, i32, String) {
+    let title_languages = CONFIG.read().unwrap().display_config.title_languages.clone();
+    let mut subject = subject.clone();
+    if let Some(title) = select_title(&title_languages, &read_subject_titles(subject.mikan_subject_id, TITLE_KIND_SERIES)) {
+        subject.tmdb_series_name = title;
+    }
+    if let Some(title) = select_title(&title_languages, &read_subject_titles(subject.mikan_subject_id, TITLE_KIND_SEASON)) {
+        subject.tmdb_season_name = title;
+    }
+    apply_subject_override(&mut subject);
+    let disp_series_name = if subject.tmdb_series_name == "" {
+        subject.bangumi_subject_name.clone()
+    } else {
+        subject.tmdb_series_name.clone()
+    };
+    let disp_season_num = if subject.tmdb_season_num == -1 {
+        subject.bangumi_season_num
+    } else {
+        subject.tmdb_season_num
+    };
+    // TODO: Check invalid tmdb season name logic
+    let disp_season_name = if subject.tmdb_season_name == "" {
+        format!("第 {} 季", disp_season_num)
+    } else {
+        subject.tmdb_season_name.clone()
+    };
+    (disp_series_name, disp_season_num, disp_season_name)
+}
 
 pub fn update_library(items: &Vec<rss::MikanItem>) {
+    // All items are added in one transaction, a failure leaves the library as before
+    if let Err(e) = with_transaction(|repo| update_library_in(repo, items)) {
+        log::error!("Failed to update library: {}", e);
+    }
+}
+
+/// (mikan_subject_id, mikan_subgroup_id) of the seasons in the library or removed from it,
+/// whose feed items are added incrementally, without expanding their history again
+pub fn known_seasons() -> HashSet<(i32, i32)> {
+    with_repository("read seasons", |repo| {
+        let mut known: HashSet<(i32, i32)> = repo.list_seasons()?.into_iter()
+            .map(|x| (x.mikan_subject_id, x.mikan_subgroup_id))
+            .collect();
+        known.extend(repo.list_season_tombstones()?.into_iter().map(|x| (x.mikan_subject_id, x.mikan_subgroup_id)));
+        Ok(known)
+    })
+}
+
+pub fn update_library_in<R: SeasonRepository + SeasonItemRepository + SeriesRepository + SeasonTombstoneRepository + ActivityRepository>(repo: &R, items: &Vec<rss::MikanItem>) -> Result<(), Box<dyn Error>> {
     // For each item in the fetched updating list,
     // Match the item with the corresponding anime season
     // If the season is not found, insert the season into the database
 
     for item in items {
+        // season removed by the user
+        if repo.is_season_tombstoned(item.mikan_subject_id, item.mikan_subgroup_id)? {
+            log::debug!("Skip item {} of removed season {}-{}", item.mikan_item_title, item.mikan_subject_id, item.mikan_subgroup_id);
+            continue;
+        }
         // season in library
-        if let Some(season) = read_season_info(item.mikan_subject_id, item.mikan_subgroup_id) {
-            // If the season is found, insert the item into the database if the item obeys the language and codec restriction
+        if let Some(season) = repo.get_season(item.mikan_subject_id, item.mikan_subgroup_id)? {
+            // If the season is found, insert the item into the database, accepted if the item obeys the language and codec restriction,
+            // otherwise filtered, so that it is accepted once the restriction changes
             // TODO: RSS parser parse only the language and codec configured
-            if season.conf_language != "" && season.conf_language != item.mikan_parsed_language {
-                continue;
-            }
-            if season.conf_codec != "" && season.conf_codec != item.mikan_parsed_codec {
-                continue;
+            let accepted = obeys_season_conf(&season, &item.mikan_parsed_language, &item.mikan_parsed_codec);
+            // Seasons added before subgroup names were parsed are named by their next item
+            if season.disp_subgroup_name.is_empty() && !item.mikan_subgroup_name.is_empty() {
+                repo.upsert_season(&AnimeSeason { disp_subgroup_name: item.mikan_subgroup_name.clone(), ..season })?;
             }
-            create_item(&item); // episode offset logic inside.
+            // episode offset logic inside.
+            add_item_in(repo, item, accepted)?;
         } else {
             // season in rss cache
             let season_cache = rss::fetch_mikan_subject_info(item.mikan_subject_id);
             match season_cache {
-                Some(season) => {
-                    let disp_series_name = if season.tmdb_series_name == "" {
-                        season.bangumi_subject_name.clone()
-                    } else {
-                        season.tmdb_series_name.clone()
-                    };
-                    let disp_season_num = if season.tmdb_season_num == -1 {
-                        season.bangumi_season_num
-                    } else {
-                        season.tmdb_season_num
-                    };
-                    // TODO: Check invalid tmdb season name logic
-                    let disp_season_name = if season.tmdb_season_name == "" {
-                        format!("第 {} 季", disp_season_num)
-                    } else {
-                        season.tmdb_season_name.clone()
-                    };
-                    // TODO: fetch subgroup name
-                    let disp_subgroup_name = "字幕组名称".to_string();
-                    // TODO: Parse episode offset between subgroup epinum and tmdb epinum
-
-                    let season = AnimeSeason {
-                        mikan_subject_id: item.mikan_subject_id,
-                        mikan_subgroup_id: item.mikan_subgroup_id,
-                        mikan_subject_name: season.bangumi_subject_name.clone(),
-                        mikan_subject_image: season.mikan_subject_image_url,
-                        bangumi_subject_id: season.bangumi_subject_id,
-                        bangumi_subject_name: season.bangumi_subject_name,
-                        bangumi_season_num: season.bangumi_season_num,
-                        bangumi_subject_image: season.bangumi_subject_image_url,
-                        tmdb_series_id: season.tmdb_series_id,
-                        tmdb_series_name: season.tmdb_series_name,
-                        tmdb_season_num: season.tmdb_season_num,
-                        tmdb_season_name: season.tmdb_season_name,
-                        bangumi_to_tmdb_episode_offset: season.bangumi_to_tmdb_episode_offset,
-                        disp_series_name,
-                        disp_season_name,
-                        disp_subgroup_name,
-                        disp_season_num,
-                        conf_tmdb_episode_offset: 0,
-                        conf_language: "".to_string(),
-                        conf_codec: "".to_string(),
-                        conf_season_num: -1,
-                        conf_bangumi_episode_offset: 0,
-                    };
-                    create_season(&season);
-                    create_item(&item);
+                Some(subject) => {
+                    // Episode offsets are inferred once the season has items, see auto_episode_offset_infer
+                    let season = new_season(subject, item.mikan_subgroup_id, item.mikan_subgroup_name.clone());
+                    repo.upsert_season(&season)?;
+                    link_season_series_in(repo, season.mikan_subject_id, season.mikan_subgroup_id)?;
+                    repo.insert_activity(&Activity::of_season(ActivityKind::SeasonAdded, &season))?;
+                    add_item_in(repo, item, true)?;
                 }
                 None => {
                     // If the season is not found in the cache, warn and skip
@@ -83,14 +108,125 @@ pub fn update_library(items: &Vec<rss::MikanItem>) {
             }
         }
     }
+    Ok(())
 }
 
+/// A season of a subject in the library, with the default config
+pub(crate) fn new_season(subject: MikanSubject, mikan_subgroup_id: i32, disp_subgroup_name: String) -> AnimeSeason {
+    let (disp_series_name, disp_season_num, disp_season_name) = subject_disp_info(&subject);
+    AnimeSeason {
+        mikan_subject_id: subject.mikan_subject_id,
+        mikan_subgroup_id,
+        mikan_subject_name: subject.bangumi_subject_name.clone(),
+        mikan_subject_image: subject.mikan_subject_image_url,
+        bangumi_subject_id: subject.bangumi_subject_id,
+        bangumi_subject_name: subject.bangumi_subject_name,
+        bangumi_season_num: subject.bangumi_season_num,
+        bangumi_subject_image: subject.bangumi_subject_image_url,
+        tmdb_series_id: subject.tmdb_series_id,
+        tmdb_series_name: subject.tmdb_series_name,
+        tmdb_season_num: subject.tmdb_season_num,
+        tmdb_season_name: subject.tmdb_season_name,
+        bangumi_to_tmdb_episode_offset: subject.bangumi_to_tmdb_episode_offset,
+        disp_series_name,
+        disp_season_name,
+        disp_subgroup_name,
+        disp_season_num,
+        conf_tmdb_episode_offset: 0,
+        conf_language: "".to_string(),
+        conf_codec: "".to_string(),
+        conf_season_num: -1,
+        conf_bangumi_episode_offset: 0,
+        anilist_id: subject.anilist_id,
+        mal_id: subject.mal_id,
+        anidb_id: subject.anidb_id,
+        series_id: -1,
+        ..Default::default()
+    }
+}
 
-pub fn auto_season_config_clean() {
+/// Add an item to the library, accepted or filtered by the feed, recording it in the activity history if it is new and accepted
+fn add_item_in<R: SeasonRepository + SeasonItemRepository + ActivityRepository>(repo: &R, item: &rss::MikanItem, accepted: bool) -> Result<(), Box<dyn Error>> {
+    let is_new = repo.get_season_item(&item.mikan_item_uuid)?.is_none();
+    create_item_in(repo, item)?;
+    apply_item_event_in(repo, &item.mikan_item_uuid, if accepted { ItemEvent::Accept } else { ItemEvent::Filter })?;
+    if is_new && accepted {
+        if let Some(item) = repo.get_season_item(&item.mikan_item_uuid)? {
+            repo.insert_activity(&Activity::of_item(ActivityKind::ItemAdded, &item))?;
+        }
+    }
+    Ok(())
+}
+
+/// Refresh the seasons whose metadata does not follow their subject override yet,
+/// e.g. overrides imported from a file.
+pub fn auto_subject_override_apply() {
     let seasons = read_seasons();
+    for subject_override in read_subject_overrides() {
+        let mikan_subject_id = subject_override.mikan_subject_id;
+        let subject = match rss::fetch_mikan_subject_info(mikan_subject_id) {
+            Some(subject) => subject,
+            None => continue,
+        };
+        let (disp_series_name, _, disp_season_name) = subject_disp_info(&subject);
+        let outdated = seasons.iter()
+            .filter(|season| season.mikan_subject_id == mikan_subject_id)
+            .any(|season| !subject_override.matches(&subject)
+                || season.disp_series_name != disp_series_name
+                || season.disp_season_name != disp_season_name);
+        if outdated {
+            if let Err(e) = refresh_subject_metadata(mikan_subject_id) {
+                log::warn!("Failed to apply override of subject {}: {:?}", mikan_subject_id, e);
+            }
+        }
+    }
+}
+
+/// Fetch the titles of the subjects in the library that were parsed before titles were cached,
+/// then rename their seasons in the preferred title languages.
+/// A subject failing, e.g. without a Bangumi subject, is tried again once its metadata is refreshed, see `refresh_subject_metadata`.
+pub fn auto_subject_title_backfill() {
+    let mikan_subject_ids: HashSet<i32> = read_seasons().iter().map(|x| x.mikan_subject_id).collect();
+    let mut backfilled = false;
+    for mikan_subject_id in mikan_subject_ids {
+        if !read_subject_titles(mikan_subject_id, TITLE_KIND_SERIES).is_empty() || is_title_backfill_failed(mikan_subject_id) {
+            continue;
+        }
+        let subject = match rss::fetch_mikan_subject_info(mikan_subject_id) {
+            Some(subject) => subject,
+            None => continue,
+        };
+        log::info!("Backfilling titles of subject {}", mikan_subject_id);
+        let result = match subject.bangumi_subject_id {
+            bangumi_subject_id if bangumi_subject_id > 0 => mikan_parser::backfill_subject_titles(&subject),
+            _ => Err(new_err("No Bangumi subject")),
+        };
+        match result {
+            Ok(()) => backfilled = true,
+            Err(e) => {
+                log::warn!("Failed to backfill titles of subject {}: {:?}", mikan_subject_id, e);
+                if let Err(e) = mark_title_backfill_failed(mikan_subject_id) {
+                    log::error!("Failed to save the title backfill of subject {}: {}", mikan_subject_id, e);
+                }
+            }
+        }
+    }
+    if backfilled {
+        apply_display_language();
+    }
+}
+
+pub fn auto_season_config_clean() {
+    if let Err(e) = with_transaction(auto_season_config_clean_in) {
+        log::error!("Failed to clean season config: {}", e);
+    }
+}
+
+fn auto_season_config_clean_in(repo: &SqliteRepository) -> Result<(), Box<dyn Error>> {
+    let seasons = repo.list_seasons()?;
     for season in seasons {
         // get episode list, add (language, codec) pair config to candidates
-        let items = read_season_items(season.mikan_subject_id, season.mikan_subgroup_id);
+        let items = repo.list_season_items(season.mikan_subject_id, season.mikan_subgroup_id)?;
         let mut conf_candidates = HashSet::new();
         for item in items {
             conf_candidates.insert((item.mikan_parsed_language.clone(), item.mikan_parsed_codec.clone()));
@@ -113,59 +249,25 @@ pub fn auto_season_config_clean() {
             conf_rank.push((conf.0.clone(), conf.1.clone(), rank));
         }
         conf_rank.sort_by(|a, b| b.2.cmp(&a.2));
-        let best_conf = (conf_rank[0].0.clone(), conf_rank[0].1.clone());
+        // A season without items keeps its config
+        let best_conf = match conf_rank.first() {
+            Some(conf) => (conf.0.clone(), conf.1.clone()),
+            None => continue,
+        };
         // TODO: configure the filter, leaving only one type of language and codec
-        update_season_config(&AnimeSeason {
+        update_season_config_in(repo, &AnimeSeason {
             conf_language: best_conf.0,
             conf_codec: best_conf.1,
             ..season
-        },
-                             true,
-                             false,
-        );
-    }
-}
-
-pub fn update_season_config(season: &AnimeSeason, delete_items: bool, fetch_items: bool) {
-    let conn = get_connection().unwrap();
-
-    conn.execute(
-        "update library_anime_season set conf_tmdb_episode_offset = ?1, conf_language = ?2, conf_codec = ?3, conf_bangumi_episode_offset = ?4 where mikan_subject_id = ?5 and mikan_subgroup_id = ?6",
-        &[
-            &season.conf_tmdb_episode_offset.to_string(),
-            &season.conf_language,
-            &season.conf_codec,
-            &season.conf_bangumi_episode_offset.to_string(),
-            &season.mikan_subject_id.to_string(),
-            &season.mikan_subgroup_id.to_string(),
-        ],
-    ).unwrap();
-
-    // delete items that do not obey the language and codec restriction
-    if delete_items {
-        let items = read_season_items(season.mikan_subject_id, season.mikan_subgroup_id);
-        for item in items {
-            if season.conf_language != "" && season.conf_language != item.mikan_parsed_language {
-                delete_item(&item.mikan_item_uuid);
-            } else if season.conf_codec != "" && season.conf_codec != item.mikan_parsed_codec {
-                delete_item(&item.mikan_item_uuid);
-            }
-        }
+        }, true)?;
     }
+    Ok(())
+}
 
-    // Update the episode number by new offset
-    // for items in library_anime_season_item, 
-    // where mikan_subject_id = season.mikan_subject_id 
-    // and mikan_subgroup_id = season.mikan_subgroup_id,
-    // update disp_episode_num = mikan_parsed_episode_num + conf_tmdb_episode_offset
-    conn.execute(
-        "update library_anime_season_item set disp_episode_num = mikan_parsed_episode_num + ?1 where mikan_subject_id = ?2 and mikan_subgroup_id = ?3",
-        &[
-            &season.conf_tmdb_episode_offset.to_string(),
-            &season.mikan_subject_id.to_string(),
-            &season.mikan_subgroup_id.to_string(),
-        ],
-    ).unwrap();
+pub fn update_season_config(season: &AnimeSeason, filter_items: bool, fetch_items: bool) {
+    if let Err(e) = with_transaction(|repo| update_season_config_in(repo, season, filter_items)) {
+        log::error!("Failed to update season config of {}: {}", season.mikan_subject_name, e);
+    }
 
     // fetch items from the rss feed
     // TODO: fetch with updated config
@@ -176,3 +278,140 @@ pub fn update_season_config(season: &AnimeSeason, delete_items: bool, fetch_item
         update_library(&items);
     }
 }
+
+/// Apply the config edited in the season config dialog as one operation,
+/// the episodes are renumbered by the new offset in the same transaction.
+pub fn apply_season_conf(mikan_subject_id: i32, mikan_subgroup_id: i32, conf_season_num: i32, disp_season_num: i32,
+                         conf_tmdb_episode_offset: i32, conf_bangumi_episode_offset: i32) -> Result<(), Box<dyn Error>> {
+    with_transaction(|repo| {
+        let season = repo.get_season(mikan_subject_id, mikan_subgroup_id)?
+            .ok_or_else(|| new_err(format!("Season {}-{} not found", mikan_subject_id, mikan_subgroup_id).as_str()))?;
+        repo.set_season_disp_season_num(mikan_subject_id, mikan_subgroup_id, disp_season_num)?;
+        update_season_config_in(repo, &AnimeSeason {
+            conf_season_num,
+            conf_tmdb_episode_offset,
+            conf_bangumi_episode_offset,
+            ..season
+        }, false)
+    })
+}
+
+/// Save the config of a season, filter the items not obeying its language and codec restriction,
+/// and renumber its items by the new offset.
+pub fn update_season_config_in<R: SeasonRepository + SeasonItemRepository + ActivityRepository>(repo: &R, season: &AnimeSeason, filter_items: bool) -> Result<(), Box<dyn Error>> {
+    if let Some(before) = repo.get_season(season.mikan_subject_id, season.mikan_subgroup_id)? {
+        let (before_conf, after_conf) = (season_conf_summary(&before), season_conf_summary(season));
+        if before_conf != after_conf {
+            repo.insert_activity(&Activity::of_season(ActivityKind::SeasonConfChanged, &before).change(before_conf, after_conf))?;
+        }
+    }
+    repo.update_season_conf(season)?;
+
+    // filter items that do not obey the language and codec restriction, and accept the filtered ones obeying it again
+    if filter_items {
+        let items = repo.list_season_items(season.mikan_subject_id, season.mikan_subgroup_id)?;
+        for item in items {
+            let event = match obeys_season_conf(season, &item.mikan_parsed_language, &item.mikan_parsed_codec) {
+                true => ItemEvent::Accept,
+                false => ItemEvent::Filter,
+            };
+            apply_item_event_in(repo, &item.mikan_item_uuid, event)?;
+        }
+    }
+
+    // Update the episode number by new offset
+    renumber_items_in(repo, season.mikan_subject_id, season.mikan_subgroup_id)
+}
+
+/// Whether an item obeys the language and codec restriction of its season, an empty restriction accepts all
+pub(crate) fn obeys_season_conf(season: &AnimeSeason, language: &str, codec: &str) -> bool {
+    (season.conf_language == "" || season.conf_language == language) && (season.conf_codec == "" || season.conf_codec == codec)
+}
+
+fn season_conf_summary(season: &AnimeSeason) -> String {
+    format!("TMDB偏移 {}，Bangumi偏移 {}，季 {}，语言 {}，编码 {}",
+            season.conf_tmdb_episode_offset, season.conf_bangumi_episode_offset, season.conf_season_num,
+            season.conf_language, season.conf_codec)
+}
+
+/// Parse the metadata of a Mikan subject again (e.g. after choosing another TMDB series),
+/// and update its seasons in the library while keeping their configs. A failed title backfill of the subject is tried again.
+pub fn refresh_subject_metadata(mikan_subject_id: i32) -> Result<(), Box<dyn Error>> {
+    clear_title_backfill_failed(mikan_subject_id)?;
+    let mikan_subject_image_url = rss::fetch_mikan_subject_info(mikan_subject_id)
+        .map_or("".to_string(), |x| x.mikan_subject_image_url);
+    let subject = mikan_parser::parse_mikan_subject_info(mikan_subject_id, mikan_subject_image_url)?;
+    let (disp_series_name, disp_season_num, disp_season_name) = subject_disp_info(&subject);
+
+    // Seasons of every subgroup are updated together
+    with_transaction(|repo| {
+        for season in repo.list_seasons()? {
+            if season.mikan_subject_id != mikan_subject_id {
+                continue;
+            }
+            log::info!("Refreshing metadata of {}: {} -> {}", season.mikan_subject_name, season.disp_series_name, disp_series_name);
+            repo.upsert_season(&AnimeSeason {
+                bangumi_subject_id: subject.bangumi_subject_id,
+                bangumi_subject_name: subject.bangumi_subject_name.clone(),
+                bangumi_season_num: subject.bangumi_season_num,
+                bangumi_subject_image: subject.bangumi_subject_image_url.clone(),
+                tmdb_series_id: subject.tmdb_series_id,
+                tmdb_series_name: subject.tmdb_series_name.clone(),
+                tmdb_season_num: subject.tmdb_season_num,
+                tmdb_season_name: subject.tmdb_season_name.clone(),
+                anilist_id: subject.anilist_id,
+                mal_id: subject.mal_id,
+                anidb_id: subject.anidb_id,
+                disp_series_name: disp_series_name.clone(),
+                disp_season_name: disp_season_name.clone(),
+                disp_season_num: if season.conf_season_num != -1 { season.conf_season_num } else { disp_season_num },
+                ..season
+            })?;
+            // Another TMDB series may belong to another series in the library
+            link_season_series_in(repo, season.mikan_subject_id, season.mikan_subgroup_id)?;
+        }
+        Ok(())
+    })
+}
+
+/// Recompute the display names of all seasons from the cached titles, e.g. after the title languages are changed.
+/// Nothing is downloaded.
+///
+/// ## Output
+///
+/// Seasons whose display names changed
+pub fn apply_display_language() -> Vec<AnimeSeason> {
+    let mut changed = Vec::new();
+    for season in read_seasons() {
+        let subject = match rss::fetch_mikan_subject_info(season.mikan_subject_id) {
+            Some(subject) => subject,
+            None => continue,
+        };
+        let (disp_series_name, _, disp_season_name) = subject_disp_info(&subject);
+        if disp_series_name == season.disp_series_name && disp_season_name == season.disp_season_name {
+            continue;
+        }
+        log::info!("Display name of {}: {} -> {}", season.mikan_subject_name, season.disp_series_name, disp_series_name);
+        changed.push(AnimeSeason {
+            disp_series_name,
+            disp_season_name,
+            ..season
+        });
+    }
+    // Seasons are renamed together with their series
+    let result = with_transaction(|repo| {
+        for season in &changed {
+            repo.upsert_season(season)?;
+        }
+        let series_ids: HashSet<i32> = changed.iter().map(|x| x.series_id).collect();
+        for series_id in series_ids {
+            repo.sync_series(series_id)?;
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        log::error!("Failed to rename seasons: {}", e);
+        return Vec::new();
+    }
+    changed
+}
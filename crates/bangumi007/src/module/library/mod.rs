@@ -1,4 +1,9 @@
 pub use media_library::*;
 
+pub mod arrangement;
+pub mod disk_import;
+pub mod episode_gap;
 pub mod media_library;
-
+pub mod episode_offset;
+pub mod removal;
+pub mod season_status;
\ No newline at end of file
@@ -0,0 +1,290 @@
+use std::error::Error;
+
+use crate::module::config::CONFIG;
+use crate::module::database::activity::{Activity, ActivityKind};
+use crate::module::database::library::{AnimeSeasonItem, read_seasons, SeasonTombstone};
+use crate::module::database::repository::{ActivityRepository, EpisodeGapRepository, SeasonItemRepository, SeasonRepository, SeasonTombstoneRepository, SeriesRepository, with_transaction};
+use crate::module::downloader::delete_torrents;
+use crate::module::library::arrangement::{arrange_library_in, Arrangement};
+use crate::module::utils::error::new_err;
+
+/// What is done to the downloads of a removed season
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeasonCleanup {
+    KeepFiles,
+    DeleteTorrents,
+    DeleteTorrentsAndFiles,
+}
+
+impl SeasonCleanup {
+    pub const ALL: [SeasonCleanup; 3] = [SeasonCleanup::KeepFiles, SeasonCleanup::DeleteTorrents, SeasonCleanup::DeleteTorrentsAndFiles];
+
+    pub fn disp_name(&self) -> &'static str {
+        match self {
+            SeasonCleanup::KeepFiles => "保留文件",
+            SeasonCleanup::DeleteTorrents => "仅删除种子",
+            SeasonCleanup::DeleteTorrentsAndFiles => "删除种子和文件",
+        }
+    }
+}
+
+/// Remove a season from the library
+///
+/// ## Input
+///
+/// mikan_subject_id : `i32`
+/// mikan_subgroup_id : `i32`
+/// cleanup : `SeasonCleanup`, what is done to the torrents in the downloader
+/// deactivate_feed : `bool`, whether to deactivate the subscriptions of the season, see `season_feed`
+///
+/// ## Procedure
+///
+/// 1. Delete the season and its items in one transaction, and record a tombstone so that its feed items are not added again,
+///    see `remove_season_in`
+/// 2. Delete the torrents of the removed items as chosen, a failure is logged, the season is removed anyway
+/// 3. Deactivate the subscriptions of the season, a subscription of the whole subject only if no other subgroup of it is left
+///
+/// ## Output
+///
+/// Active items shown elsewhere after the removal, to be moved and renamed in the downloader : `Vec<AnimeSeasonItem>`
+pub fn remove_season(mikan_subject_id: i32, mikan_subgroup_id: i32, cleanup: SeasonCleanup, deactivate_feed: bool) -> Result<Vec<AnimeSeasonItem>, Box<dyn Error>> {
+    let subject_left = read_seasons().iter()
+        .any(|x| x.mikan_subject_id == mikan_subject_id && x.mikan_subgroup_id != mikan_subgroup_id);
+    let deactivated = |url: &str| deactivate_feed && match season_feed(url) {
+        Some((subject_id, Some(subgroup_id))) => subject_id == mikan_subject_id && subgroup_id == mikan_subgroup_id,
+        Some((subject_id, None)) => subject_id == mikan_subject_id && !subject_left,
+        None => false,
+    };
+    let feed_active = CONFIG.read().unwrap().rss_config.list.iter()
+        .any(|x| x.active && feed_covers(&x.url, mikan_subject_id, mikan_subgroup_id) && !deactivated(&x.url));
+
+    let (removed_items, relocated_items) = with_transaction(|repo| remove_season_in(repo, mikan_subject_id, mikan_subgroup_id, cleanup, feed_active))?;
+
+    // Items imported from disk have no torrent
+    let torrents: Vec<AnimeSeasonItem> = removed_items.into_iter()
+        .filter(|x| x.state.in_downloader() && !x.mikan_item_magnet_link.is_empty())
+        .collect();
+    let result = match cleanup {
+        SeasonCleanup::KeepFiles => Ok(()),
+        SeasonCleanup::DeleteTorrents => delete_torrents(&torrents, false),
+        SeasonCleanup::DeleteTorrentsAndFiles => delete_torrents(&torrents, true),
+    };
+    if let Err(e) = result {
+        log::error!("Failed to delete torrents of season {}-{}: {}", mikan_subject_id, mikan_subgroup_id, e);
+    }
+
+    let mut config = CONFIG.write().unwrap();
+    let mut changed = false;
+    for rss in config.rss_config.list.iter_mut().filter(|x| x.active && deactivated(&x.url)) {
+        log::info!("Deactivating subscription {}", rss.url);
+        rss.active = false;
+        changed = true;
+    }
+    if changed {
+        config.save();
+    }
+    Ok(relocated_items)
+}
+
+/// Delete a season and its items, and record a tombstone of the season.
+/// Seasons merged into it and items moved to it are shown on their own again,
+/// its items moved to another season are kept there.
+///
+/// ## Input
+///
+/// feed_active : `bool`, whether a subscription of the season is still active, see `restore_resubscribed_seasons_in`
+///
+/// ## Output
+///
+/// (removed items, active items shown elsewhere after the removal) : `(Vec<AnimeSeasonItem>, Vec<AnimeSeasonItem>)`
+pub fn remove_season_in<R>(repo: &R, mikan_subject_id: i32, mikan_subgroup_id: i32, cleanup: SeasonCleanup, feed_active: bool) -> Result<(Vec<AnimeSeasonItem>, Vec<AnimeSeasonItem>), Box<dyn Error>>
+where R: SeasonRepository + SeasonItemRepository + SeriesRepository + SeasonTombstoneRepository + EpisodeGapRepository + ActivityRepository {
+    let season_key = (mikan_subject_id, mikan_subgroup_id);
+    let season = repo.get_season(mikan_subject_id, mikan_subgroup_id)?
+        .ok_or_else(|| new_err(format!("Season {}-{} not found", mikan_subject_id, mikan_subgroup_id).as_str()))?;
+
+    let mut relocated = Vec::new();
+    let mut removed = Vec::new();
+    for merged in repo.list_seasons()?.into_iter().filter(|x| x.merged_into() == Some(season_key)) {
+        arrange_library_in(repo, &Arrangement::UnmergeSeason { season: (merged.mikan_subject_id, merged.mikan_subgroup_id) })?;
+        relocated.extend(repo.list_season_items(merged.mikan_subject_id, merged.mikan_subgroup_id)?);
+    }
+    for moved in repo.list_all_items()?.into_iter().filter(|x| x.conf_placement() == Some(season_key) && (x.mikan_subject_id, x.mikan_subgroup_id) != season_key) {
+        // Kept from a season removed before, it has nowhere else to be shown
+        if repo.get_season(moved.mikan_subject_id, moved.mikan_subgroup_id)?.is_none() {
+            repo.delete_season_item(&moved.mikan_item_uuid)?;
+            removed.push(moved);
+            continue;
+        }
+        arrange_library_in(repo, &Arrangement::MoveItem { mikan_item_uuid: moved.mikan_item_uuid.clone(), target: None, disp_episode_num: None })?;
+        relocated.extend(repo.get_season_item(&moved.mikan_item_uuid)?);
+    }
+
+    let mut removed_count = 0;
+    for item in repo.list_season_items(mikan_subject_id, mikan_subgroup_id)? {
+        let placed_elsewhere = match item.conf_placement() {
+            Some((subject_id, subgroup_id)) if (subject_id, subgroup_id) != season_key => repo.get_season(subject_id, subgroup_id)?.is_some(),
+            _ => false,
+        };
+        if placed_elsewhere {
+            continue;
+        }
+        repo.delete_season_item(&item.mikan_item_uuid)?;
+        removed.push(item);
+        removed_count += 1;
+    }
+    repo.delete_season(mikan_subject_id, mikan_subgroup_id)?;
+    repo.replace_season_gaps(mikan_subject_id, mikan_subgroup_id, &[])?;
+    if season.series_id > 0 {
+        repo.sync_series(season.series_id)?;
+    }
+    repo.insert_season_tombstone(&season, feed_active)?;
+    repo.insert_activity(&Activity::of_season(ActivityKind::SeasonRemoved, &season)
+        .change(format!("{} 集", removed_count), cleanup.disp_name()))?;
+    Ok((removed, relocated.into_iter().filter(|x| x.state.is_active()).collect()))
+}
+
+/// Let removed seasons be added again once they are subscribed again
+///
+/// ## Input
+///
+/// active_feeds : `&[String]`, URLs of the active subscriptions
+///
+/// ## Procedure
+///
+/// For each tombstone, see `remove_season_in`,
+/// 1. If no active subscription covers the season, remember it
+/// 2. If one covers it again after that, i.e. the subscription was re-added or reactivated, delete the tombstone
+///
+/// A season removed while keeping its subscription stays removed until the subscription is deactivated and activated again.
+///
+/// ## Output
+///
+/// Restored tombstones : `Vec<SeasonTombstone>`
+pub fn restore_resubscribed_seasons_in<R: SeasonTombstoneRepository>(repo: &R, active_feeds: &[String]) -> Result<Vec<SeasonTombstone>, Box<dyn Error>> {
+    let mut restored = Vec::new();
+    for tombstone in repo.list_season_tombstones()? {
+        let covered = active_feeds.iter().any(|x| feed_covers(x, tombstone.mikan_subject_id, tombstone.mikan_subgroup_id));
+        match (tombstone.feed_active, covered) {
+            (true, false) => repo.set_season_tombstone_feed_active(tombstone.mikan_subject_id, tombstone.mikan_subgroup_id, false)?,
+            (false, true) => {
+                repo.delete_season_tombstone(tombstone.mikan_subject_id, tombstone.mikan_subgroup_id)?;
+                restored.push(tombstone);
+            }
+            _ => {}
+        }
+    }
+    Ok(restored)
+}
+
+/// Check the tombstones against the active subscriptions of the config, see `restore_resubscribed_seasons_in`
+pub fn restore_resubscribed_seasons() {
+    let active_feeds: Vec<String> = CONFIG.read().unwrap().rss_config.list.iter()
+        .filter(|x| x.active)
+        .map(|x| x.url.clone())
+        .collect();
+    match with_transaction(|repo| restore_resubscribed_seasons_in(repo, &active_feeds)) {
+        Ok(restored) => for tombstone in restored {
+            log::info!("Season {}-{} {} is subscribed again, no longer removed", tombstone.mikan_subject_id, tombstone.mikan_subgroup_id, tombstone.disp_series_name);
+        },
+        Err(e) => log::error!("Failed to restore resubscribed seasons: {}", e),
+    }
+}
+
+/// Whether a subscription lists the items of a season, by its subgroup or by the whole subject
+fn feed_covers(url: &str, mikan_subject_id: i32, mikan_subgroup_id: i32) -> bool {
+    match season_feed(url) {
+        Some((subject_id, subgroup_id)) => subject_id == mikan_subject_id && subgroup_id.map_or(true, |x| x == mikan_subgroup_id),
+        None => false,
+    }
+}
+
+/// (mikan_subject_id, mikan_subgroup_id) of a Mikan subscription of a subject, without a subgroup if it lists every subgroup.
+/// `None` for other feeds, e.g. the personal feed.
+///
+/// "https://mikanime.tv/RSS/Bangumi?bangumiId=3141&subgroupid=382" -> Some((3141, Some(382)))
+pub fn season_feed(url: &str) -> Option<(i32, Option<i32>)> {
+    let (_, query) = url.split_once('?')?;
+    let param = |name: &str| query.split('&')
+        .filter_map(|x| x.split_once('='))
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .and_then(|(_, value)| value.parse::<i32>().ok());
+    Some((param("bangumiId")?, param("subgroupid")))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::module::database::item_state::ItemState;
+    use crate::module::database::library::create_item_in;
+    use crate::module::database::repository::{open_in_memory_database, SqliteRepository, test_item, test_season};
+    use crate::module::library::update_library_in;
+
+    use super::*;
+
+    #[test]
+    fn test_remove_season() {
+        let conn = open_in_memory_database().unwrap();
+        let repo = SqliteRepository::new(&conn);
+        for (mikan_subject_id, disp_season_num) in [(3141, 1), (3500, 2)] {
+            repo.upsert_season(&test_season(mikan_subject_id, disp_season_num)).unwrap();
+        }
+        create_item_in(&repo, &test_item("a", 3141, 1)).unwrap();
+        create_item_in(&repo, &test_item("b", 3500, 1)).unwrap();
+        create_item_in(&repo, &test_item("c", 3500, 2)).unwrap();
+        create_item_in(&repo, &test_item("e", 3141, 2)).unwrap();
+        repo.set_item_state("c", ItemState::Completed).unwrap();
+        arrange_library_in(&repo, &Arrangement::MoveItem { mikan_item_uuid: "e".to_string(), target: Some((3500, 382)), disp_episode_num: Some(3) }).unwrap();
+        arrange_library_in(&repo, &Arrangement::MergeSeason { season: (3500, 382), target: (3141, 382) }).unwrap();
+
+        let (removed, relocated) = remove_season_in(&repo, 3141, 382, SeasonCleanup::DeleteTorrents, true).unwrap();
+        assert_eq!(removed.iter().map(|x| x.mikan_item_uuid.as_str()).collect::<Vec<_>>(), vec!["a"]);
+        assert!(repo.get_season(3141, 382).unwrap().is_none());
+        assert!(repo.get_season_item("a").unwrap().is_none());
+        // The item moved to another season is kept there
+        assert_eq!(repo.get_season_item("e").unwrap().unwrap().conf_placement(), Some((3500, 382)));
+        // The merged season is shown on its own again, its active items are moved in the downloader
+        assert_eq!(repo.get_season(3500, 382).unwrap().unwrap().merged_into(), None);
+        assert_eq!(relocated.iter().map(|x| x.mikan_item_uuid.as_str()).collect::<Vec<_>>(), vec!["c"]);
+
+        // Items of the removed season in the feed are not added again
+        update_library_in(&repo, &vec![test_item("a", 3141, 1), test_item("d", 3141, 2)]).unwrap();
+        assert!(repo.get_season(3141, 382).unwrap().is_none());
+        assert!(repo.get_season_item("d").unwrap().is_none());
+        assert!(repo.is_season_tombstoned(3141, 382).unwrap());
+        assert!(!repo.is_season_tombstoned(3500, 382).unwrap());
+
+        // Removing the other season removes the item kept there
+        let (removed, _) = remove_season_in(&repo, 3500, 382, SeasonCleanup::KeepFiles, false).unwrap();
+        assert_eq!(removed.len(), 3);
+        assert!(repo.get_season_item("e").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_restore_resubscribed_seasons() {
+        let conn = open_in_memory_database().unwrap();
+        let repo = SqliteRepository::new(&conn);
+        repo.insert_season_tombstone(&test_season(3141, 1), true).unwrap();
+        repo.insert_season_tombstone(&test_season(3500, 2), false).unwrap();
+        let subject_feed = "https://mikanani.me/RSS/Bangumi?bangumiId=3141".to_string();
+        let subgroup_feed = "https://mikanani.me/RSS/Bangumi?bangumiId=3500&subgroupid=382".to_string();
+
+        // Removed while subscribed, the subscription is kept
+        assert!(restore_resubscribed_seasons_in(&repo, &[subject_feed.clone()]).unwrap().is_empty());
+        // Deactivated, then reactivated
+        assert!(restore_resubscribed_seasons_in(&repo, &[]).unwrap().is_empty());
+        let restored = restore_resubscribed_seasons_in(&repo, &[subject_feed]).unwrap();
+        assert_eq!(restored.iter().map(|x| x.mikan_subject_id).collect::<Vec<_>>(), vec![3141]);
+        assert!(!repo.is_season_tombstoned(3141, 382).unwrap());
+        // Removed with its subscription, then subscribed again
+        assert!(restore_resubscribed_seasons_in(&repo, &["https://mikanani.me/RSS/Bangumi?bangumiId=3500&subgroupid=583".to_string()]).unwrap().is_empty());
+        restore_resubscribed_seasons_in(&repo, &[subgroup_feed]).unwrap();
+        assert!(repo.list_season_tombstones().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_season_feed() {
+        assert_eq!(season_feed("https://mikanime.tv/RSS/Bangumi?bangumiId=3141&subgroupid=382"), Some((3141, Some(382))));
+        assert_eq!(season_feed("https://mikanani.me/RSS/Bangumi?bangumiId=3141"), Some((3141, None)));
+        assert_eq!(season_feed("https://mikanime.tv/RSS/MyBangumi?token=abc"), None);
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+use chrono::NaiveDate;
+
+use crate::module::config::CONFIG;
+use crate::module::database::activity::{Activity, ActivityKind};
+use crate::module::database::cache::rss::BangumiEpisode;
+use crate::module::database::item_state::ItemState;
+use crate::module::database::library::{AnimeSeason, AnimeSeasonItem, read_seasons};
+use crate::module::database::repository::{ActivityRepository, SeasonItemRepository, SeasonRepository, with_repository, with_transaction};
+use crate::module::database::season_status::SeasonStatus;
+use crate::module::library::removal::season_feed;
+use crate::module::parser::bangumi_parser::{fetch_bangumi_episodes, get_bangumi_episodes};
+use crate::module::utils::error::new_err;
+
+/// Status of a season by `today`, archiving is left to the user
+///
+/// ## Input
+///
+/// season : `AnimeSeason`
+/// items : `&[AnimeSeasonItem]`, every item of the season in any state
+/// bangumi_episodes : `&[BangumiEpisode]`, episodes of the Bangumi subject of the season
+/// today : `NaiveDate`
+///
+/// ## Procedure
+///
+/// 1. Only main-story episodes with an integer sort count, as in `find_episode_gaps`;
+///    without any, the season is airing as far as we know
+/// 2. Airing if an episode has no airdate or airs after `today`
+/// 3. Complete if every episode has a downloaded item, numbered as Bangumi does; ignored items are skipped by the user and count as downloaded
+/// 4. Finished otherwise
+///
+/// ## Output
+///
+/// `SeasonStatus`, never `Archived`
+pub fn season_status(season: &AnimeSeason, items: &[AnimeSeasonItem], bangumi_episodes: &[BangumiEpisode], today: NaiveDate) -> SeasonStatus {
+    let main_episodes: Vec<(i32, &BangumiEpisode)> = bangumi_episodes.iter()
+        .filter(|x| x.episode_type == 0)
+        .filter_map(|x| x.episode_sort.parse::<i32>().ok().map(|sort| (sort, x)))
+        .collect();
+    if main_episodes.is_empty() {
+        return SeasonStatus::Airing;
+    }
+    let aired = main_episodes.iter()
+        .all(|(_, x)| NaiveDate::parse_from_str(&x.episode_airdate, "%Y-%m-%d").map_or(false, |airdate| airdate <= today));
+    if !aired {
+        return SeasonStatus::Airing;
+    }
+    let downloaded: HashSet<i32> = items.iter()
+        .filter(|x| matches!(x.state, ItemState::Completed | ItemState::Organized | ItemState::Watched | ItemState::Ignored))
+        .map(|x| x.mikan_parsed_episode_num + season.conf_bangumi_episode_offset)
+        .collect();
+    if main_episodes.iter().all(|(sort, _)| downloaded.contains(sort)) {
+        SeasonStatus::Complete
+    } else {
+        SeasonStatus::Finished
+    }
+}
+
+/// Status of a season by today from its items and the episodes of its Bangumi subject,
+/// `None` if the season has no Bangumi subject or its episodes are not available
+fn current_season_status(season: &AnimeSeason) -> Option<SeasonStatus> {
+    if season.bangumi_subject_id <= 0 {
+        return None;
+    }
+    let bangumi_episodes = match get_bangumi_episodes(season.bangumi_subject_id) {
+        Ok(episodes) => episodes,
+        Err(e) => {
+            log::warn!("Failed to get Bangumi episodes of {}: {}", season.bangumi_subject_name, e);
+            return None;
+        }
+    };
+    let items = with_repository("read season items", |repo| repo.list_season_items(season.mikan_subject_id, season.mikan_subgroup_id));
+    Some(season_status(season, &items, &bangumi_episodes, chrono::Local::now().date_naive()))
+}
+
+/// Save the status of a season, recording the change in the activity history
+pub fn set_season_status_in<R: SeasonRepository + ActivityRepository>(repo: &R, season: &AnimeSeason, status: SeasonStatus) -> Result<(), Box<dyn Error>> {
+    if season.status == status {
+        return Ok(());
+    }
+    log::info!("Season {}: {:?} -> {:?}", season.mikan_subject_name, season.status, status);
+    repo.set_season_status(season.mikan_subject_id, season.mikan_subgroup_id, status)?;
+    repo.insert_activity(&Activity::of_season(ActivityKind::SeasonStatusChanged, season)
+        .change(season.status.disp_name(), status.disp_name()))?;
+    Ok(())
+}
+
+/// Episodes of the Bangumi subjects of the seasons not archived, fetched once per subject
+/// for the status of the seasons and their missing episodes, see `auto_episode_gap_detect`
+///
+/// ## Procedure
+///
+/// 1. The episodes of a subject with a season not complete are fetched again, as its airdates change while airing;
+///    the cached ones are used otherwise
+/// 2. Fetching stops at the first failure, e.g. when offline, the seasons left out keep their status and gaps
+///
+/// ## Output
+///
+/// Episodes by Bangumi subject id : `HashMap<i32, Vec<BangumiEpisode>>`
+pub fn fetch_season_bangumi_episodes() -> HashMap<i32, Vec<BangumiEpisode>> {
+    let seasons: Vec<AnimeSeason> = read_seasons().into_iter()
+        .filter(|x| x.bangumi_subject_id > 0 && x.status != SeasonStatus::Archived)
+        .collect();
+    let mut bangumi_episodes = HashMap::new();
+    for season in seasons.iter() {
+        if bangumi_episodes.contains_key(&season.bangumi_subject_id) {
+            continue;
+        }
+        let refresh = seasons.iter().any(|x| x.bangumi_subject_id == season.bangumi_subject_id && x.status != SeasonStatus::Complete);
+        let episodes = if refresh {
+            fetch_bangumi_episodes(season.bangumi_subject_id)
+        } else {
+            get_bangumi_episodes(season.bangumi_subject_id)
+        };
+        match episodes {
+            Ok(episodes) => {
+                bangumi_episodes.insert(season.bangumi_subject_id, episodes);
+            }
+            Err(e) => {
+                log::warn!("Failed to get Bangumi episodes of {}, skip the remaining seasons: {}", season.bangumi_subject_name, e);
+                break;
+            }
+        }
+    }
+    bangumi_episodes
+}
+
+/// Compute the status of every season not archived, see `season_status`,
+/// and archive the complete ones if `auto_archive_complete` is configured.
+/// Subscriptions of the seasons polled again are reactivated, see `reactivate_polled_feeds`.
+///
+/// ## Input
+///
+/// bangumi_episodes : `&HashMap<i32, Vec<BangumiEpisode>>`, episodes by Bangumi subject id, see `fetch_season_bangumi_episodes`
+pub fn auto_season_status_update(bangumi_episodes: &HashMap<i32, Vec<BangumiEpisode>>) {
+    let auto_archive = CONFIG.read().unwrap().rss_config.auto_archive_complete;
+    let today = chrono::Local::now().date_naive();
+    for season in read_seasons().into_iter().filter(|x| x.status != SeasonStatus::Archived) {
+        let episodes = match bangumi_episodes.get(&season.bangumi_subject_id) {
+            Some(episodes) => episodes,
+            None => continue,
+        };
+        let items = with_repository("read season items", |repo| repo.list_season_items(season.mikan_subject_id, season.mikan_subgroup_id));
+        let status = match season_status(&season, &items, episodes, today) {
+            SeasonStatus::Complete if auto_archive => SeasonStatus::Archived,
+            status => status,
+        };
+        if let Err(e) = with_transaction(|repo| set_season_status_in(repo, &season, status)) {
+            log::error!("Failed to update status of {}: {}", season.mikan_subject_name, e);
+        }
+    }
+    reactivate_polled_feeds();
+}
+
+/// Archive a season, or bring it back to the status computed from its episodes.
+/// Subscriptions retired with the season are reactivated once it is polled again.
+pub fn archive_season(mikan_subject_id: i32, mikan_subgroup_id: i32, archived: bool) -> Result<(), Box<dyn Error>> {
+    let season = with_repository("read season", |repo| repo.get_season(mikan_subject_id, mikan_subgroup_id))
+        .ok_or_else(|| new_err(format!("Season {}-{} not found", mikan_subject_id, mikan_subgroup_id).as_str()))?;
+    let status = if archived {
+        SeasonStatus::Archived
+    } else {
+        current_season_status(&season).unwrap_or_default()
+    };
+    with_transaction(|repo| set_season_status_in(repo, &season, status))?;
+    reactivate_polled_feeds();
+    Ok(())
+}
+
+/// Whether a subscription only lists seasons no longer polled,
+/// a subscription of the whole subject only if every season of the subject in the library is.
+/// Other feeds, e.g. the personal feed, are never retired.
+pub fn is_retired_feed(url: &str, seasons: &[AnimeSeason]) -> bool {
+    match season_feed(url) {
+        Some((subject_id, Some(subgroup_id))) => seasons.iter()
+            .any(|x| x.mikan_subject_id == subject_id && x.mikan_subgroup_id == subgroup_id && !x.status.is_polled()),
+        Some((subject_id, None)) => {
+            let mut subject_seasons = seasons.iter().filter(|x| x.mikan_subject_id == subject_id).peekable();
+            subject_seasons.peek().is_some() && subject_seasons.all(|x| !x.status.is_polled())
+        }
+        None => false,
+    }
+}
+
+/// Whether a subscription lists a season still polled
+pub fn is_polled_feed(url: &str, seasons: &[AnimeSeason]) -> bool {
+    match season_feed(url) {
+        Some((subject_id, subgroup_id)) => seasons.iter()
+            .filter(|x| x.mikan_subject_id == subject_id && subgroup_id.map_or(true, |id| x.mikan_subgroup_id == id))
+            .any(|x| x.status.is_polled()),
+        None => false,
+    }
+}
+
+/// Deactivate the subscriptions of the seasons no longer polled, see `is_retired_feed`,
+/// marked as retired so that `reactivate_polled_feeds` brings them back
+pub fn retire_finished_feeds() {
+    let seasons = read_seasons();
+    let mut config = CONFIG.write().unwrap();
+    let mut changed = false;
+    for rss in config.rss_config.list.iter_mut().filter(|x| x.active) {
+        if is_retired_feed(&rss.url, &seasons) {
+            log::info!("Retiring subscription {}", rss.url);
+            rss.active = false;
+            rss.retired = true;
+            changed = true;
+        }
+    }
+    if changed {
+        config.save();
+    }
+}
+
+/// Reactivate the subscriptions retired by `retire_finished_feeds` that list a season polled again,
+/// e.g. brought back from the archive or airing a new cour
+pub fn reactivate_polled_feeds() {
+    let seasons = read_seasons();
+    let mut config = CONFIG.write().unwrap();
+    let mut changed = false;
+    for rss in config.rss_config.list.iter_mut().filter(|x| x.retired) {
+        if is_polled_feed(&rss.url, &seasons) {
+            log::info!("Reactivating subscription {}", rss.url);
+            rss.active = true;
+            rss.retired = false;
+            changed = true;
+        }
+    }
+    if changed {
+        config.save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::module::database::cache::rss::bangumi_episode;
+
+    use super::*;
+
+    #[test]
+    fn test_season_status() {
+        let season = AnimeSeason { mikan_subject_id: 3141, mikan_subgroup_id: 382, conf_bangumi_episode_offset: 12, ..Default::default() };
+        let item = |episode_num: i32, state: ItemState| AnimeSeasonItem { mikan_parsed_episode_num: episode_num, state, ..Default::default() };
+        let episodes = vec![
+            bangumi_episode("13", 0, "2024-01-05"),
+            bangumi_episode("14", 0, "2024-01-12"),
+            bangumi_episode("1", 1, "2024-03-01"),
+            bangumi_episode("14.5", 0, ""),
+        ];
+        let today = NaiveDate::from_ymd_opt(2024, 1, 30).unwrap();
+        let items = vec![item(1, ItemState::Organized), item(2, ItemState::Downloading)];
+        assert_eq!(season_status(&season, &items, &episodes, NaiveDate::from_ymd_opt(2024, 1, 10).unwrap()), SeasonStatus::Airing);
+        assert_eq!(season_status(&season, &items, &episodes, today), SeasonStatus::Finished);
+        // Specials and episodes without an integer sort do not count, ignored episodes are skipped by the user
+        let items = vec![item(1, ItemState::Watched), item(2, ItemState::Ignored)];
+        assert_eq!(season_status(&season, &items, &episodes, today), SeasonStatus::Complete);
+        // No air date yet
+        let episodes = vec![bangumi_episode("13", 0, "2024-01-05"), bangumi_episode("14", 0, "")];
+        assert_eq!(season_status(&season, &items, &episodes, today), SeasonStatus::Airing);
+        assert_eq!(season_status(&season, &items, &[], today), SeasonStatus::Airing);
+    }
+
+    #[test]
+    fn test_is_retired_feed() {
+        let season = |mikan_subgroup_id: i32, status: SeasonStatus| AnimeSeason { mikan_subject_id: 3141, mikan_subgroup_id, status, ..Default::default() };
+        let seasons = vec![season(382, SeasonStatus::Complete), season(583, SeasonStatus::Airing)];
+        assert!(is_retired_feed("https://mikanime.tv/RSS/Bangumi?bangumiId=3141&subgroupid=382", &seasons));
+        assert!(!is_retired_feed("https://mikanime.tv/RSS/Bangumi?bangumiId=3141&subgroupid=583", &seasons));
+        assert!(!is_retired_feed("https://mikanime.tv/RSS/Bangumi?bangumiId=3141", &seasons));
+        assert!(!is_retired_feed("https://mikanime.tv/RSS/Bangumi?bangumiId=3310", &seasons));
+        assert!(!is_retired_feed("https://mikanime.tv/RSS/MyBangumi?token=abc", &seasons));
+        assert!(is_polled_feed("https://mikanime.tv/RSS/Bangumi?bangumiId=3141", &seasons));
+        assert!(!is_polled_feed("https://mikanime.tv/RSS/Bangumi?bangumiId=3141&subgroupid=382", &seasons));
+
+        // A finished season is no longer polled, a feed is not brought back without a season polled
+        let seasons = vec![season(382, SeasonStatus::Finished), season(583, SeasonStatus::Archived)];
+        assert!(is_retired_feed("https://mikanime.tv/RSS/Bangumi?bangumiId=3141", &seasons));
+        assert!(!is_polled_feed("https://mikanime.tv/RSS/Bangumi?bangumiId=3141", &seasons));
+        assert!(!is_polled_feed("https://mikanime.tv/RSS/Bangumi?bangumiId=3310", &seasons));
+    }
+}
\ No newline at end of file
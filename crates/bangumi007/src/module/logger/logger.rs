@@ -9,6 +9,7 @@ use log4rs::config::{Appender, Logger};
 use log4rs::encode::pattern::PatternEncoder;
 
 use crate::module::config::CONFIG;
+use crate::module::config::data_dir::DATA_DIR;
 
 lazy_static! {
     pub static ref LOG_HANDLE: RwLock<Handle> = RwLock::new(init_logging());
@@ -21,7 +22,7 @@ pub fn get_logging_config() -> Config {
 
     let file = FileAppender::builder()
         .encoder(Box::new(PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S%.6f)} {h({l}):<5.5} [{M}] {m}{n}")))
-        .build("log/bangumi007.log")
+        .build(DATA_DIR.log_file())
         .unwrap();
     
     // TODO: mpsc appender
@@ -57,8 +58,15 @@ pub fn get_logging_config() -> Config {
 }
 
 fn init_logging() -> Handle {   // TODO: add mpsc here
+    // Before the file appender opens the new log
+    let migrated = DATA_DIR.migrate_legacy_log();
     let handle = log4rs::init_config(get_logging_config()).unwrap();        // Pass the mpsc to get_logging_config
     log::debug!("Log level: {:?}", CONFIG.read().unwrap().log_config.log_level);
+    match migrated {
+        Ok(Some(legacy)) => log::info!("Moved the log of older builds from {} to {}", legacy.display(), DATA_DIR.log_file().display()),
+        Ok(None) => {}
+        Err(e) => log::warn!("Failed to move the log of older builds, it is left in ./log: {}", e),
+    }
     handle
 }
 
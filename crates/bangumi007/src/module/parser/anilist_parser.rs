@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use retry::delay::Fixed;
+
+use crate::module::config::{MetadataSource, TitleLanguage};
+use crate::module::parser::bangumi_parser::BangumiSubject;
+use crate::module::parser::metadata_provider::{METADATA_MATCH_SCORE, MetadataProvider, ProviderMetadata, score_metadata_match};
+use crate::module::utils::error::{new_err, new_warn};
+
+const ANILIST_API_URL: &str = "https://graphql.anilist.co";
+
+const ANILIST_SEARCH_QUERY: &str = "query ($search: String) {
+    Page(perPage: 10) {
+        media(search: $search, type: ANIME) {
+            id
+            idMal
+            title { romaji english native }
+            episodes
+            startDate { year month day }
+        }
+    }
+}";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AniListMedia {
+    pub id: i64,
+    pub id_mal: i64,        // -1 if unknown
+    pub title_romaji: String,
+    pub title_english: String,
+    pub title_native: String,
+    pub episodes: i32,      // 0 if unknown
+    pub start_date: String, // e.g. "2023-09-29", empty if unknown
+}
+
+pub fn anilist_search_anime(search: &str) -> Result<Vec<AniListMedia>, Box<dyn Error>> {
+    let body = serde_json::json!({
+        "query": ANILIST_SEARCH_QUERY,
+        "variables": { "search": search },
+    });
+
+    let response = retry::retry(Fixed::from_millis(5000).take(5), || {
+        match reqwest::blocking::Client::new().post(ANILIST_API_URL).json(&body).send() {
+            Ok(response) => {
+                if response.status().is_success() {
+                    Ok(response.text().unwrap())
+                } else {
+                    Err(new_warn(format!("Failed to search anilist, status code is not 200: {}", response.status()).as_str()))
+                }
+            }
+            Err(_) => Err(new_warn("Failed to search anilist"))
+        }
+    }).map_err(|_| new_err("Failed to search anilist"))?;
+
+    let json: serde_json::Value = serde_json::from_str(&response)
+        .map_err(|_| new_err("Failed to parse json"))?;
+
+    anilist_parse_search_results(&json)
+}
+
+pub fn anilist_parse_search_results(json: &serde_json::Value) -> Result<Vec<AniListMedia>, Box<dyn Error>> {
+    let media_list = json.pointer("/data/Page/media")
+        .and_then(|x| x.as_array())
+        .ok_or_else(|| new_err("Failed to get media as array"))?;
+
+    let mut result = Vec::new();
+    for media in media_list {
+        let id = match media.get("id").and_then(|x| x.as_i64()) {
+            Some(id) => id,
+            None => continue,
+        };
+        let get_title = |key: &str| media.get("title")
+            .and_then(|x| x.get(key))
+            .and_then(|x| x.as_str())
+            .unwrap_or("")
+            .to_string();
+        let get_date = |key: &str| media.get("startDate").and_then(|x| x.get(key)).and_then(|x| x.as_i64());
+        let start_date = match (get_date("year"), get_date("month"), get_date("day")) {
+            (Some(year), Some(month), Some(day)) => format!("{:04}-{:02}-{:02}", year, month, day),
+            _ => "".to_string(),
+        };
+        result.push(AniListMedia {
+            id,
+            id_mal: media.get("idMal").and_then(|x| x.as_i64()).unwrap_or(-1),
+            title_romaji: get_title("romaji"),
+            title_english: get_title("english"),
+            title_native: get_title("native"),
+            episodes: media.get("episodes").and_then(|x| x.as_i64()).unwrap_or(0) as i32,
+            start_date,
+        });
+    }
+    Ok(result)
+}
+
+impl From<AniListMedia> for ProviderMetadata {
+    fn from(media: AniListMedia) -> Self {
+        let mut titles = HashMap::new();
+        titles.insert(TitleLanguage::AniListRomaji.key().to_string(), media.title_romaji);
+        titles.insert(TitleLanguage::AniListEnglish.key().to_string(), media.title_english);
+        titles.insert(TitleLanguage::AniListNative.key().to_string(), media.title_native);
+        let mut cross_ids = HashMap::new();
+        if media.id_mal > 0 {
+            cross_ids.insert(MetadataSource::MyAnimeList, media.id_mal);
+        }
+        ProviderMetadata {
+            source: MetadataSource::AniList,
+            id: media.id,
+            titles,
+            date: media.start_date,
+            eps: media.episodes,
+            cross_ids,
+        }
+    }
+}
+
+pub struct AniListProvider;
+
+impl MetadataProvider for AniListProvider {
+    /// Search the Japanese name first (AniList knows native titles best), then the other aliases.
+    fn resolve(&self, subject: &BangumiSubject) -> Result<ProviderMetadata, Box<dyn Error>> {
+        let mut queries = vec![subject.name.clone()];
+        queries.extend(subject.aliases.iter().cloned());
+        queries.dedup();
+
+        for query in queries.iter().filter(|x| !x.is_empty()) {
+            let best = anilist_search_anime(query)?
+                .into_iter()
+                .map(|media| {
+                    let score = score_metadata_match(
+                        &[&media.title_native, &media.title_romaji, &media.title_english],
+                        &media.start_date, media.episodes, subject);
+                    (score, media)
+                })
+                .filter(|(score, _)| *score >= METADATA_MATCH_SCORE)
+                .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal).then(b.1.id.cmp(&a.1.id)));
+            if let Some((_, media)) = best {
+                return Ok(media.into());
+            }
+        }
+        Err(new_warn("No matching anime in anilist"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anilist_parse_search_results() {
+        let json: serde_json::Value = serde_json::from_str(r#"{"data": {"Page": {"media": [
+            {"id": 154587, "idMal": 52991, "title": {"romaji": "Sousou no Frieren", "english": "Frieren: Beyond Journey's End", "native": "葬送のフリーレン"},
+             "episodes": 28, "startDate": {"year": 2023, "month": 9, "day": 29}},
+            {"id": 170068, "idMal": null, "title": {"romaji": "Sousou no Frieren: ●● no Mahou", "english": null, "native": "葬送のフリーレン　～●●の魔法～"},
+             "episodes": null, "startDate": {"year": 2023, "month": null, "day": null}}
+        ]}}}"#).unwrap();
+        let media = anilist_parse_search_results(&json).unwrap();
+        assert_eq!(media.len(), 2);
+        assert_eq!(media[0].id_mal, 52991);
+        assert_eq!(media[0].start_date, "2023-09-29");
+        assert_eq!(media[1].id_mal, -1);
+        assert_eq!(media[1].title_english, "");
+        assert_eq!(media[1].start_date, "");
+
+        let metadata: ProviderMetadata = media[0].clone().into();
+        assert_eq!(metadata.cross_ids.get(&MetadataSource::MyAnimeList), Some(&52991));
+        assert_eq!(metadata.titles.get("anilist:native").unwrap(), "葬送のフリーレン");
+    }
+}
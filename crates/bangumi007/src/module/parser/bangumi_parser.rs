@@ -6,12 +6,17 @@ use retry::delay::Fixed;
 use crate::module::database::cache::rss::BangumiEpisode;
 use crate::module::utils::error::{new_err, new_warn};
 
+#[derive(Debug, Clone)]
 pub struct BangumiSubject {
     pub bangumi_subject_id: i32,
     pub image_url: String,
     pub aliases: Vec<String>,
+    pub name: String,
+    pub name_cn: String,
     pub media_type: String,
     pub season_num: i32,
+    pub date: String,
+    pub eps: i32,
 }
 
 pub fn get_bangumi_subject(bangumi_subject_id: i32) -> rusqlite::Result<BangumiSubject, Box<dyn Error>> {
@@ -22,15 +27,33 @@ pub fn get_bangumi_subject(bangumi_subject_id: i32) -> rusqlite::Result<BangumiS
         .and_then(|x| x.as_str().ok_or_else(|| new_warn("Failed to get image url as str")))
         .map(|x| x.to_string())?;
     let aliases = get_bangumi_subject_aliases(&json)?;
+    let get_str = |key: &str| json.get(key).and_then(|x| x.as_str()).unwrap_or("").to_string();
+    let name = get_str("name");
+    let name_cn = get_str("name_cn");
     let media_type = get_bangumi_media_type(&json)?;
     let season_num = parse_season_num_from_aliases(&aliases).unwrap_or(-1);
+    // Air date of the subject, e.g. "2024-04-06", may be null
+    let date = json.get("date")
+        .and_then(|x| x.as_str())
+        .unwrap_or("")
+        .to_string();
+    // Number of episodes, 0 if unknown
+    let eps = json.get("total_episodes")
+        .and_then(|x| x.as_i64())
+        .filter(|x| *x > 0)
+        .or_else(|| json.get("eps").and_then(|x| x.as_i64()))
+        .unwrap_or(0) as i32;
 
     Ok(BangumiSubject {
         bangumi_subject_id,
         image_url,
         aliases,
+        name,
+        name_cn,
         media_type,
         season_num,
+        date,
+        eps,
     })
 }
 
@@ -75,6 +98,60 @@ fn get_bangumi_subject_json(bangumi_subject_id: i32) -> Result<serde_json::Value
 }
 
 
+/// Search anime subjects on Bangumi
+///
+/// ## Input
+///
+/// keyword : `&str`, e.g. the name of a series folder
+///
+/// ## Procedure
+///
+/// 1. POST https://api.bgm.tv/v0/search/subjects?limit=10 with the keyword, filtered to anime subjects
+/// 2. Parse the ids of the results, see `bangumi_parse_search_results`
+///
+/// ## Output
+///
+/// Bangumi subject ids, in the order of relevance : `Vec<i32>`
+pub fn bangumi_search_subjects(keyword: &str) -> Result<Vec<i32>, Box<dyn Error>> {
+    let url = "https://api.bgm.tv/v0/search/subjects?limit=10";
+    let client = reqwest::blocking::Client::builder()
+        .user_agent("MapleWithered/Bangumi007 (https://github.com/MapleWithered/Bangumi007)")
+        .build()
+        .unwrap();
+    let body = serde_json::json!({
+        "keyword": keyword,
+        "filter": { "type": [2] },
+    });
+
+    let response = retry::retry(Fixed::from_millis(5000).take(3), || {
+        match client.post(url).json(&body).send() {
+            Ok(response) => {
+                if response.status().is_success() {
+                    Ok(response.text().unwrap())
+                } else {
+                    Err(new_warn(format!("Failed to search subjects, status code is not 200: {}",
+                                         response.status()).as_str()))
+                }
+            }
+            Err(_) => Err(new_warn("Failed to search subjects"))
+        }
+    }).map_err(|_| new_err("Failed to search subjects"))?;
+
+    let json: serde_json::Value = serde_json::from_str(&response)
+        .map_err(|_| new_err("Failed to parse json"))?;
+    bangumi_parse_search_results(&json)
+}
+
+pub fn bangumi_parse_search_results(json: &serde_json::Value) -> Result<Vec<i32>, Box<dyn Error>> {
+    let data = json.get("data")
+        .and_then(|x| x.as_array())
+        .ok_or_else(|| new_warn("Failed to get search results"))?;
+    Ok(data.iter()
+        .filter_map(|x| x.get("id").and_then(|x| x.as_i64()))
+        .map(|x| x as i32)
+        .collect())
+}
+
 /// Get the Bangumi subject aliases of the Bangumi subject
 ///
 /// ## Input
@@ -235,7 +312,11 @@ pub fn get_bangumi_episodes(bangumi_subject_id: i32) -> Result<Vec<BangumiEpisod
     if !cache_result.is_empty() {
         return Ok(cache_result);
     }
-    
+    fetch_bangumi_episodes(bangumi_subject_id)
+}
+
+/// Fetch the episodes of a subject from Bangumi, replacing the cached ones, e.g. when the airdates of an airing season change
+pub fn fetch_bangumi_episodes(bangumi_subject_id: i32) -> Result<Vec<BangumiEpisode>, Box<dyn Error>> {
     let url = format!("https://api.bgm.tv/v0/episodes?subject_id={}", bangumi_subject_id);
     // add user-agent
     let client = reqwest::blocking::Client::builder()
@@ -243,7 +324,7 @@ pub fn get_bangumi_episodes(bangumi_subject_id: i32) -> Result<Vec<BangumiEpisod
         .build()
         .unwrap();
 
-    let response = retry::retry(Fixed::from_millis(5000), || {
+    let response = retry::retry(Fixed::from_millis(5000).take(3), || {
         match client.get(&url).send() {
             Ok(response) => {
                 if response.status().is_success() {
@@ -359,6 +440,7 @@ pub fn get_bangumi_episodes(bangumi_subject_id: i32) -> Result<Vec<BangumiEpisod
     }
     
     // Insert into cache
+    crate::module::database::cache::rss::delete_bangumi_episodes_from_cache(bangumi_subject_id)?;
     for episode in &vec_episodes {
         crate::module::database::cache::rss::insert_bangumi_episode_to_cache(episode).unwrap();
     }
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use retry::delay::Fixed;
+
+use crate::module::config::{MetadataSource, TitleLanguage};
+use crate::module::parser::bangumi_parser::BangumiSubject;
+use crate::module::parser::metadata_provider::{METADATA_MATCH_SCORE, MetadataProvider, ProviderMetadata, score_metadata_match};
+use crate::module::utils::error::{new_err, new_warn};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MALAnime {
+    pub mal_id: i64,
+    pub title: String,
+    pub title_english: String,
+    pub title_japanese: String,
+    pub episodes: i32,      // 0 if unknown
+    pub aired_from: String, // e.g. "2023-09-29", empty if unknown
+}
+
+/// Search MyAnimeList through the Jikan API, which needs no client id.
+pub fn mal_search_anime(query: &str) -> Result<Vec<MALAnime>, Box<dyn Error>> {
+    let url = reqwest::Url::parse_with_params(
+        "https://api.jikan.moe/v4/anime",
+        &[("q", query), ("limit", "10")],
+    )?;
+
+    let response = retry::retry(Fixed::from_millis(5000).take(5), || {
+        match reqwest::blocking::get(url.clone()) {
+            Ok(response) => {
+                if response.status().is_success() {
+                    Ok(response.text().unwrap())
+                } else {
+                    // 429 if more than 3 requests per second
+                    Err(new_warn(format!("Failed to search mal, status code is not 200: {}", response.status()).as_str()))
+                }
+            }
+            Err(_) => Err(new_warn("Failed to search mal"))
+        }
+    }).map_err(|_| new_err("Failed to search mal"))?;
+
+    let json: serde_json::Value = serde_json::from_str(&response)
+        .map_err(|_| new_err("Failed to parse json"))?;
+
+    mal_parse_search_results(&json)
+}
+
+pub fn mal_parse_search_results(json: &serde_json::Value) -> Result<Vec<MALAnime>, Box<dyn Error>> {
+    let data = json.get("data")
+        .and_then(|x| x.as_array())
+        .ok_or_else(|| new_err("Failed to get data as array"))?;
+
+    let mut result = Vec::new();
+    for anime in data {
+        let mal_id = match anime.get("mal_id").and_then(|x| x.as_i64()) {
+            Some(mal_id) => mal_id,
+            None => continue,
+        };
+        let get_str = |key: &str| anime.get(key).and_then(|x| x.as_str()).unwrap_or("").to_string();
+        result.push(MALAnime {
+            mal_id,
+            title: get_str("title"),
+            title_english: get_str("title_english"),
+            title_japanese: get_str("title_japanese"),
+            episodes: anime.get("episodes").and_then(|x| x.as_i64()).unwrap_or(0) as i32,
+            // "2023-09-29T00:00:00+00:00"
+            aired_from: anime.pointer("/aired/from")
+                .and_then(|x| x.as_str())
+                .and_then(|x| x.get(0..10))
+                .unwrap_or("")
+                .to_string(),
+        });
+    }
+    Ok(result)
+}
+
+impl From<MALAnime> for ProviderMetadata {
+    fn from(anime: MALAnime) -> Self {
+        let mut titles = HashMap::new();
+        titles.insert(TitleLanguage::MALTitle.key().to_string(), anime.title);
+        titles.insert(TitleLanguage::MALTitleEnglish.key().to_string(), anime.title_english);
+        ProviderMetadata {
+            source: MetadataSource::MyAnimeList,
+            id: anime.mal_id,
+            titles,
+            date: anime.aired_from,
+            eps: anime.episodes,
+            cross_ids: HashMap::new(),
+        }
+    }
+}
+
+pub struct MyAnimeListProvider;
+
+impl MetadataProvider for MyAnimeListProvider {
+    fn resolve(&self, subject: &BangumiSubject) -> Result<ProviderMetadata, Box<dyn Error>> {
+        let mut queries = vec![subject.name.clone()];
+        queries.extend(subject.aliases.iter().cloned());
+        queries.dedup();
+
+        for query in queries.iter().filter(|x| !x.is_empty()) {
+            let best = mal_search_anime(query)?
+                .into_iter()
+                .map(|anime| {
+                    let score = score_metadata_match(
+                        &[&anime.title_japanese, &anime.title, &anime.title_english],
+                        &anime.aired_from, anime.episodes, subject);
+                    (score, anime)
+                })
+                .filter(|(score, _)| *score >= METADATA_MATCH_SCORE)
+                .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal).then(b.1.mal_id.cmp(&a.1.mal_id)));
+            if let Some((_, anime)) = best {
+                return Ok(anime.into());
+            }
+        }
+        Err(new_warn("No matching anime in mal"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mal_parse_search_results() {
+        let json: serde_json::Value = serde_json::from_str(r#"{"data": [
+            {"mal_id": 52991, "title": "Sousou no Frieren", "title_english": "Frieren: Beyond Journey's End", "title_japanese": "葬送のフリーレン",
+             "episodes": 28, "aired": {"from": "2023-09-29T00:00:00+00:00", "to": "2024-03-22T00:00:00+00:00"}},
+            {"mal_id": 56885, "title": "Sousou no Frieren: ●● no Mahou", "title_english": null, "title_japanese": "葬送のフリーレン　～●●の魔法～",
+             "episodes": null, "aired": {"from": null, "to": null}}
+        ]}"#).unwrap();
+        let anime = mal_parse_search_results(&json).unwrap();
+        assert_eq!(anime.len(), 2);
+        assert_eq!(anime[0].aired_from, "2023-09-29");
+        assert_eq!(anime[1].episodes, 0);
+        assert_eq!(anime[1].aired_from, "");
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::module::config::MetadataSource;
+use crate::module::parser::anilist_parser::AniListProvider;
+use crate::module::parser::bangumi_parser::BangumiSubject;
+use crate::module::parser::mal_parser::MyAnimeListProvider;
+use crate::module::parser::name_similarity::name_similarity;
+
+/// Minimum `score_metadata_match` of a search result to be taken as the same anime.
+pub const METADATA_MATCH_SCORE: f64 = 0.5;
+
+/// Metadata of an anime in one provider.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProviderMetadata {
+    pub source: MetadataSource,
+    pub id: i64,
+    pub titles: HashMap<String, String>,        // by TitleLanguage key
+    pub date: String,                           // first air date, e.g. "2023-10-07"
+    pub eps: i32,                               // 0 if unknown
+    pub cross_ids: HashMap<MetadataSource, i64>,    // ids in other providers known by this one
+}
+
+/// A metadata source that can find the entry of a Bangumi subject.
+///
+/// Bangumi is the root of the resolution, since Mikan links every subject to Bangumi.
+/// Bangumi and TMDB are fixed sources and have no provider: the parser always resolves them itself,
+/// TMDB with the subject override and the cross-reference, see `parse_mikan_subject_info`.
+/// The source of a provider is told by `ProviderMetadata::source` of what it resolves.
+pub trait MetadataProvider {
+    fn resolve(&self, subject: &BangumiSubject) -> Result<ProviderMetadata, Box<dyn Error>>;
+}
+
+/// `None` for sources without a provider: the fixed Bangumi and TMDB, and AniDB whose ids only come from cross ids.
+pub fn get_provider(source: MetadataSource) -> Option<Box<dyn MetadataProvider>> {
+    match source {
+        MetadataSource::AniList => Some(Box::new(AniListProvider)),
+        MetadataSource::MyAnimeList => Some(Box::new(MyAnimeListProvider)),
+        MetadataSource::Bangumi | MetadataSource::TMDB | MetadataSource::AniDB => None,
+    }
+}
+
+/// Score a search result of a provider against a Bangumi subject in `[0, 1]`:
+/// best title similarity (0.5), first air date (0.3) and episode count (0.2).
+pub fn score_metadata_match(titles: &[&str], date: &str, eps: i32, subject: &BangumiSubject) -> f64 {
+    let name_score = titles.iter()
+        .filter(|title| !title.is_empty())
+        .flat_map(|title| subject.aliases.iter().map(move |alias| name_similarity(alias, title)))
+        .fold(0.0, f64::max);
+
+    let parse_date = |date: &str| chrono::NaiveDate::parse_from_str(date.get(0..10).unwrap_or(""), "%Y-%m-%d").ok();
+    let date_score = match (parse_date(date), parse_date(&subject.date)) {
+        (Some(date), Some(subject_date)) if (date - subject_date).num_days().abs() <= 7 => 1.0,
+        (Some(date), Some(subject_date)) if date.format("%Y").to_string() == subject_date.format("%Y").to_string() => 0.5,
+        _ => 0.0,
+    };
+
+    let episode_score = if eps > 0 && eps == subject.eps { 1.0 } else { 0.0 };
+
+    name_score * 0.5 + date_score * 0.3 + episode_score * 0.2
+}
+
+/// Resolve a Bangumi subject in every source, failed sources are logged and skipped.
+pub fn resolve_metadata(subject: &BangumiSubject, sources: &[MetadataSource]) -> Vec<ProviderMetadata> {
+    let mut result = Vec::new();
+    for source in sources {
+        let provider = match get_provider(*source) {
+            Some(provider) => provider,
+            None => continue,
+        };
+        match provider.resolve(subject) {
+            Ok(metadata) => {
+                log::debug!("{} of Bangumi subject {}: {}", source.disp_name(), subject.bangumi_subject_id, metadata.id);
+                result.push(metadata);
+            }
+            Err(e) => log::warn!("Failed to resolve Bangumi subject {} in {}: {}", subject.bangumi_subject_id, source.disp_name(), e),
+        }
+    }
+    result
+}
+
+/// Id of a source among resolved metadata, either resolved directly or known as a cross id, -1 if unknown.
+pub fn find_metadata_id(metadata: &[ProviderMetadata], source: MetadataSource) -> i32 {
+    metadata.iter()
+        .find(|x| x.source == source)
+        .map(|x| x.id)
+        .or_else(|| metadata.iter().find_map(|x| x.cross_ids.get(&source).copied()))
+        .map_or(-1, |x| x as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subject() -> BangumiSubject {
+        BangumiSubject {
+            bangumi_subject_id: 400602,
+            image_url: "".to_string(),
+            aliases: vec!["葬送のフリーレン".to_string(), "葬送的芙莉莲".to_string(), "Frieren".to_string()],
+            name: "葬送のフリーレン".to_string(),
+            name_cn: "葬送的芙莉莲".to_string(),
+            media_type: "TV".to_string(),
+            season_num: -1,
+            date: "2023-09-29".to_string(),
+            eps: 28,
+        }
+    }
+
+    #[test]
+    fn test_score_metadata_match() {
+        let subject = subject();
+        let score = score_metadata_match(&["Sousou no Frieren", "葬送のフリーレン"], "2023-09-29", 28, &subject);
+        assert!((score - 1.0).abs() < 1e-9);
+        let score = score_metadata_match(&["Sousou no Frieren", "葬送のフリーレン"], "2023-09-29T00:00:00+00:00", 0, &subject);
+        assert!((score - 0.8).abs() < 1e-9);
+        assert!(score_metadata_match(&["Dungeon Meshi"], "2024-01-04", 24, &subject) < METADATA_MATCH_SCORE);
+    }
+
+    #[test]
+    fn test_find_metadata_id() {
+        let anilist = ProviderMetadata {
+            source: MetadataSource::AniList,
+            id: 154587,
+            titles: HashMap::new(),
+            date: "".to_string(),
+            eps: 0,
+            cross_ids: [(MetadataSource::MyAnimeList, 52991)].into_iter().collect(),
+        };
+        let metadata = vec![anilist];
+        assert_eq!(find_metadata_id(&metadata, MetadataSource::AniList), 154587);
+        assert_eq!(find_metadata_id(&metadata, MetadataSource::MyAnimeList), 52991);
+        assert_eq!(find_metadata_id(&metadata, MetadataSource::AniDB), -1);
+    }
+}
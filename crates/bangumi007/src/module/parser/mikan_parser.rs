@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::thread::sleep;
 
@@ -12,14 +12,19 @@ use rusqlite::Result;
 
 use cache::rss::{fetch_cached_items, filter_uncached_items, insert_item_to_cache};
 
+use crate::module::config::{CONFIG, MetadataSource, TitleLanguage};
 use crate::module::database::cache;
+use crate::module::database::cache::title::{save_subject_titles, TITLE_KIND_ALIAS, TITLE_KIND_SEASON, TITLE_KIND_SERIES};
+use crate::module::database::cache::xref::lookup_anime_xref;
 use crate::module::database::cache::rss::{fetch_mikan_subject_info, insert_subject_to_cache, MikanItem, MikanSubject};
 use crate::module::parser::bangumi_parser;
 use crate::module::parser::bangumi_parser::{parse_bangumi_episode, parse_season_num_from_aliases};
-use crate::module::parser::tmdb_parser::bangumi_parse_tmdb_info;
+use crate::module::database::subject_override::{apply_subject_override, get_subject_override};
+use crate::module::parser::metadata_provider::{find_metadata_id, resolve_metadata};
+use crate::module::parser::tmdb_parser::{bangumi_parse_tmdb_info, tmdb_get_media_info, tmdb_parse_media_name, tmdb_parse_seasons};
 use crate::module::utils::error::{new_err, new_warn};
 
-fn parse_filename_to_codec(title: &str) -> String {
+pub(crate) fn parse_filename_to_codec(title: &str) -> String {
     // avc: H.264 AVC MP4
     // hevc: H.265 HEVC MKV
     // vp9: VP9
@@ -44,7 +49,7 @@ fn parse_filename_to_codec(title: &str) -> String {
     result
 }
 
-fn parse_filename_to_language(title: &str) -> String {
+pub(crate) fn parse_filename_to_language(title: &str) -> String {
     // hans: CHS GB 简
     // hant: CHT BIG5 繁
     // jpn: JP 日 双语
@@ -68,7 +73,7 @@ fn parse_filename_to_language(title: &str) -> String {
     result
 }
 
-fn parse_filename_to_episode(filename: &str) -> Option<i32> {
+pub(crate) fn parse_filename_to_episode(filename: &str) -> Option<i32> {
     // Parse the filename to get the episode number
     let rules = [
         r"(.*) - (\d{1,4}(?!\d|p)|\d{1,4}\.\d{1,2}(?!\d|p))(?:v\d{1,2})?(?: )?(?:END)?(.*)",
@@ -186,6 +191,7 @@ pub fn update_rss(url: &str) -> Result<Vec<MikanItem>, Box<dyn Error>> {
             mikan_item_uuid: uuid.to_string(),          // Item UUID
             mikan_subject_id: -1,
             mikan_subgroup_id: -1,
+            mikan_subgroup_name: "".to_string(),
             mikan_subject_name: "".to_string(),
             mikan_item_title: title.to_string(),        // Item Title
             mikan_item_magnet_link: "".to_string(),
@@ -197,9 +203,6 @@ pub fn update_rss(url: &str) -> Result<Vec<MikanItem>, Box<dyn Error>> {
             mikan_parsed_episode_num: parse_filename_to_episode(&title).unwrap_or(-1),  // Episode Number
             mikan_parsed_language: parse_filename_to_language(&title),           // Language
             mikan_parsed_codec: parse_filename_to_codec(&title),                 // Codec
-            bangumi_parsed_episode_id: -1,
-            bangumi_parsed_episode_ep: -1,
-            bangumi_parsed_episode_sort: "".to_string(),
         });
     }
 
@@ -374,64 +377,15 @@ fn fill_episode_information(item: &MikanItem) -> Result<MikanItem, Box<dyn Error
         })
         .unwrap_or("".to_string());
 
-    let mikan_subject_info = match fetch_mikan_subject_info(mikan_subject_id) {
-        Some(info) => Some(info),       // Use cached info
-        None => {
-            // 1. Parse Bangumi subject id
-            // 2. Parse the season number using all the names fetched by Bangumi API
-            // 3. Parse the series name by searching in TMDB API
-            // 4. For failed season-num parses, try to find the name in TMDB Subject's Seasons.
-
-            // 1. Parse Bangumi subject id
-            let bangumi_subject_id = get_bangumi_subject_id(mikan_subject_id).map_or(-1, |x| x);
-            log::debug!("Bangumi Subject ID: {}", bangumi_subject_id);
-
-            // 2. Parse the season number using all the names fetched by Bangumi API
-            let bangumi_subject_info = bangumi_parser::get_bangumi_subject(bangumi_subject_id)?;
-
-            let bangumi_aliases = bangumi_subject_info.aliases;
-            let bangumi_season_num = bangumi_subject_info.season_num;
-            let bangumi_subject_name = bangumi_aliases.iter().next().unwrap().clone();
-            let bangumi_subject_image_url = bangumi_subject_info.image_url;
-
-            // 3-4: Parse using TMDB API
-            let tmdb_info = bangumi_parse_tmdb_info(bangumi_subject_id)
-                .map_err(|e| new_warn(&format!("Failed to parse TMDB info: {}", e)));
-            match tmdb_info {
-                Ok(tmdb_info) => {
-                    let subject = MikanSubject {
-                        mikan_subject_id,
-                        mikan_subject_image_url,
-                        bangumi_subject_id,
-                        bangumi_subject_name,
-                        bangumi_season_num,
-                        bangumi_subject_image_url,
-                        tmdb_series_id: tmdb_info.media_id as i32,
-                        tmdb_series_name: tmdb_info.media_name,
-                        tmdb_season_num: tmdb_info.season_number as i32,
-                        tmdb_season_name: tmdb_info.season_name,
-                        bangumi_to_tmdb_episode_offset: 0,      // TODO: parse episode offset between tmdb v.s. filename
-                    };
-                    // Insert the subject info into the database
-                    insert_subject_to_cache(&subject).unwrap();
-                    Some(subject)
-                }
-                Err(_) => Some(MikanSubject {
-                    mikan_subject_id,
-                    mikan_subject_image_url,
-                    bangumi_subject_id,
-                    bangumi_subject_name,
-                    bangumi_season_num,
-                    bangumi_subject_image_url,
-                    tmdb_series_id: -1,
-                    tmdb_series_name: "".to_string(),
-                    tmdb_season_num: -1,
-                    tmdb_season_name: "".to_string(),
-                    bangumi_to_tmdb_episode_offset: 0,
-                })
-            }
-        }
+    let subject_override = get_subject_override(mikan_subject_id).unwrap_or_default();
+    let mut mikan_subject_info = match fetch_mikan_subject_info(mikan_subject_id) {
+        // Use cached info, unless it was parsed before the ids were pinned
+        Some(info) if subject_override.matches(&info) => Some(info),
+        _ => Some(parse_mikan_subject_info(mikan_subject_id, mikan_subject_image_url)?),
     };
+    if let Some(info) = mikan_subject_info.as_mut() {
+        apply_subject_override(info);
+    }
 
     // // Using tmdb season num as default.
     // let season_num = match &mikan_subject_info {
@@ -487,6 +441,7 @@ fn fill_episode_information(item: &MikanItem) -> Result<MikanItem, Box<dyn Error
         mikan_item_uuid: item.mikan_item_uuid.to_string(),
         mikan_subject_id,
         mikan_subgroup_id: subgid,
+        mikan_subgroup_name: subgroup.to_string(),
         mikan_subject_name: title.to_string(),
         mikan_item_title: item.mikan_item_title.to_string(),
         mikan_item_magnet_link: magnet.to_string(),
@@ -498,12 +453,185 @@ fn fill_episode_information(item: &MikanItem) -> Result<MikanItem, Box<dyn Error
         mikan_parsed_episode_num: item.mikan_parsed_episode_num + episode_offset,
         mikan_parsed_language: item.mikan_parsed_language.to_string(),
         mikan_parsed_codec: item.mikan_parsed_codec.to_string(),
-        bangumi_parsed_episode_id: -1,
-        bangumi_parsed_episode_ep: -1,
-        bangumi_parsed_episode_sort: "".to_string(),
     })
 }
 
+/// # Parse Mikan subject
+///
+/// ## Input
+///
+/// Mikanani subject id : `i32`, Mikanani subject image url : `String`
+///
+/// ## Procedure
+///
+/// 1. Parse Bangumi subject id
+/// 2. Parse the season number using all the names fetched by Bangumi API
+/// 3. Parse the series name by searching in TMDB API
+/// 4. For failed season-num parses, try to find the name in TMDB Subject's Seasons.
+///
+/// Ids pinned in the subject override are used instead of the parsed ones.
+///
+/// ## Output
+///
+/// `MikanSubject`, cached in database if the TMDB parse succeeded
+pub fn parse_mikan_subject_info(mikan_subject_id: i32, mikan_subject_image_url: String) -> Result<MikanSubject, Box<dyn Error>> {
+    let subject_override = get_subject_override(mikan_subject_id).unwrap_or_default();
+
+    // 1. Parse Bangumi subject id
+    let bangumi_subject_id = match subject_override.bangumi_subject_id {
+        Some(bangumi_subject_id) => bangumi_subject_id,
+        None => get_bangumi_subject_id(mikan_subject_id).map_or(-1, |x| x),
+    };
+    log::debug!("Bangumi Subject ID: {}", bangumi_subject_id);
+
+    // Cross ids from the offline datasets first, the providers they cover are not asked
+    let xref = lookup_anime_xref(MetadataSource::Bangumi, bangumi_subject_id).unwrap_or_default();
+
+    // 2. Parse the season number using all the names fetched by Bangumi API
+    let bangumi_subject_info = bangumi_parser::get_bangumi_subject(bangumi_subject_id)?;
+
+    let bangumi_aliases = bangumi_subject_info.aliases.clone();
+    let bangumi_season_num = bangumi_subject_info.season_num;
+    let bangumi_subject_name = bangumi_aliases.iter().next().unwrap().clone();
+    let bangumi_subject_image_url = bangumi_subject_info.image_url.clone();
+
+    // Titles of every language, the displayed one is selected by the display config
+    let mut series_titles = HashMap::new();
+    series_titles.insert(TitleLanguage::BangumiName.key().to_string(), bangumi_subject_info.name.clone());
+    series_titles.insert(TitleLanguage::BangumiNameCn.key().to_string(), bangumi_subject_info.name_cn.clone());
+    let mut season_titles = HashMap::new();
+
+    // Titles and the ids missing in the cross ids from the other configured providers
+    let metadata_sources: Vec<MetadataSource> = CONFIG.read().unwrap().parser_config.metadata_providers.iter()
+        .filter(|x| xref.get(**x) <= 0)
+        .copied()
+        .collect();
+    let metadata = resolve_metadata(&bangumi_subject_info, &metadata_sources);
+    for provider_metadata in metadata.iter() {
+        series_titles.extend(provider_metadata.titles.clone());
+    }
+    let find_id = |source| match xref.get(source) {
+        id if id > 0 => id,
+        _ => find_metadata_id(&metadata, source),
+    };
+    let anilist_id = find_id(MetadataSource::AniList);
+    let mal_id = find_id(MetadataSource::MyAnimeList);
+    let anidb_id = find_id(MetadataSource::AniDB);
+
+    let aliases_titles: HashMap<String, String> = bangumi_aliases.iter().enumerate()
+        .map(|(i, alias)| (format!("bangumi:alias:{}", i), alias.clone()))
+        .collect();
+    save_subject_titles(mikan_subject_id, TITLE_KIND_ALIAS, &aliases_titles)?;
+
+    // 3-4: Parse using TMDB API
+    let tmdb_info = bangumi_parse_tmdb_info(&bangumi_subject_info, subject_override.tmdb_series_id, subject_override.tmdb_season_num)
+        .map_err(|e| new_warn(&format!("Failed to parse TMDB info: {}", e)));
+    match tmdb_info {
+        Ok(tmdb_info) => {
+            for (lang, name) in tmdb_info.media_names.iter() {
+                series_titles.insert(format!("tmdb:{}", lang), name.clone());
+            }
+            for (lang, name) in tmdb_info.season_names.iter() {
+                season_titles.insert(format!("tmdb:{}", lang), name.clone());
+            }
+            save_subject_titles(mikan_subject_id, TITLE_KIND_SERIES, &series_titles)?;
+            save_subject_titles(mikan_subject_id, TITLE_KIND_SEASON, &season_titles)?;
+            let subject = MikanSubject {
+                mikan_subject_id,
+                mikan_subject_image_url,
+                bangumi_subject_id,
+                bangumi_subject_name,
+                bangumi_season_num,
+                bangumi_subject_image_url,
+                tmdb_series_id: tmdb_info.media_id as i32,
+                tmdb_series_name: tmdb_info.media_name,
+                tmdb_season_num: tmdb_info.season_number as i32,
+                tmdb_season_name: tmdb_info.season_name,
+                bangumi_to_tmdb_episode_offset: 0,      // Offsets depend on the subgroup, inferred per season in library::episode_offset
+                anilist_id,
+                mal_id,
+                anidb_id,
+            };
+            // Insert the subject info into the database
+            insert_subject_to_cache(&subject).unwrap();
+            Ok(subject)
+        }
+        Err(_) => {
+            save_subject_titles(mikan_subject_id, TITLE_KIND_SERIES, &series_titles)?;
+            save_subject_titles(mikan_subject_id, TITLE_KIND_SEASON, &season_titles)?;
+            Ok(MikanSubject {
+                mikan_subject_id,
+                mikan_subject_image_url,
+                bangumi_subject_id,
+                bangumi_subject_name,
+                bangumi_season_num,
+                bangumi_subject_image_url,
+                tmdb_series_id: -1,
+                tmdb_series_name: "".to_string(),
+                tmdb_season_num: -1,
+                tmdb_season_name: "".to_string(),
+                bangumi_to_tmdb_episode_offset: 0,
+                anilist_id,
+                mal_id,
+                anidb_id,
+            })
+        }
+    }
+}
+
+
+/// Fetch the titles of a subject parsed before titles were cached
+///
+/// ## Input
+///
+/// Cached `MikanSubject`, its Bangumi and TMDB ids are kept as they are
+///
+/// ## Procedure
+///
+/// 1. Bangumi names and aliases of the subject
+/// 2. Titles of the other configured providers, e.g. AniList romaji
+/// 3. TMDB series and season names in every language, if the subject has a TMDB series
+///
+/// ## Output
+///
+/// Titles saved in the title cache
+pub fn backfill_subject_titles(subject: &MikanSubject) -> Result<(), Box<dyn Error>> {
+    let bangumi_subject_info = bangumi_parser::get_bangumi_subject(subject.bangumi_subject_id)?;
+    let mut series_titles = HashMap::new();
+    series_titles.insert(TitleLanguage::BangumiName.key().to_string(), bangumi_subject_info.name.clone());
+    series_titles.insert(TitleLanguage::BangumiNameCn.key().to_string(), bangumi_subject_info.name_cn.clone());
+    let mut season_titles = HashMap::new();
+
+    let metadata_sources = CONFIG.read().unwrap().parser_config.metadata_providers.clone();
+    for provider_metadata in resolve_metadata(&bangumi_subject_info, &metadata_sources) {
+        series_titles.extend(provider_metadata.titles);
+    }
+
+    if subject.tmdb_series_id > 0 {
+        let media_infos = tmdb_get_media_info("tv", subject.tmdb_series_id as i64)?;
+        for (lang, json) in media_infos.iter() {
+            if let Ok(name) = tmdb_parse_media_name(json) {
+                series_titles.insert(format!("tmdb:{}", lang), name);
+            }
+        }
+        let season_names = tmdb_parse_seasons(&media_infos).into_iter()
+            .find(|x| x.season_number == subject.tmdb_season_num as i64)
+            .map(|x| x.names)
+            .unwrap_or_default();
+        for (lang, name) in season_names {
+            season_titles.insert(format!("tmdb:{}", lang), name);
+        }
+    }
+
+    let aliases_titles: HashMap<String, String> = bangumi_subject_info.aliases.iter().enumerate()
+        .map(|(i, alias)| (format!("bangumi:alias:{}", i), alias.clone()))
+        .collect();
+    save_subject_titles(subject.mikan_subject_id, TITLE_KIND_ALIAS, &aliases_titles)?;
+    save_subject_titles(subject.mikan_subject_id, TITLE_KIND_SERIES, &series_titles)?;
+    save_subject_titles(subject.mikan_subject_id, TITLE_KIND_SEASON, &season_titles)?;
+    Ok(())
+}
+
 
 /// Get the Bangumi subject ID of the Mikanani subject
 ///
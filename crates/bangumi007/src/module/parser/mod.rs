@@ -1,3 +1,7 @@
 pub mod mikan_parser;
 pub mod tmdb_parser;
-pub mod bangumi_parser;
\ No newline at end of file
+pub mod bangumi_parser;
+pub mod name_similarity;
+pub mod metadata_provider;
+pub mod anilist_parser;
+pub mod mal_parser;
\ No newline at end of file
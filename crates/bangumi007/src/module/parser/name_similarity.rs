@@ -0,0 +1,147 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref SEASON_MARK_PATTERNS: Vec<Regex> = vec![
+        // 第2季, 第二期, 第 3 シーズン
+        Regex::new(r"第\s*(\d+)\s*(?:季|期|部|シーズン)").unwrap(),
+        // シーズン2
+        Regex::new(r"シーズン\s*(\d+)").unwrap(),
+        // 2nd Season
+        Regex::new(r"(?i)(\d+)(?:st|nd|rd|th)\s*season").unwrap(),
+        // Season 2
+        Regex::new(r"(?i)season\s*(\d+)").unwrap(),
+    ];
+    static ref SEASON_TOKEN: Regex = Regex::new(r"season(\d+)").unwrap();
+    static ref CHINESE_SEASON_MARK: Regex = Regex::new(r"第\s*([一二三四五六七八九十]+)\s*(季|期|部|シーズン)").unwrap();
+}
+
+/// Value of a Chinese numeral below 100, e.g. 十二 -> 12, 二十 -> 20.
+fn chinese_numeral_value(numeral: &str) -> Option<i32> {
+    let digit = |c: char| "一二三四五六七八九".chars().position(|x| x == c).map(|x| x as i32 + 1);
+    let chars: Vec<char> = numeral.chars().collect();
+    match chars.as_slice() {
+        ['十'] => Some(10),
+        ['十', ones] => Some(10 + digit(*ones)?),
+        [tens, '十'] => Some(digit(*tens)? * 10),
+        [tens, '十', ones] => Some(digit(*tens)? * 10 + digit(*ones)?),
+        [ones] => digit(*ones),
+        _ => None,
+    }
+}
+
+/// Rewrite every season mark (第二季, シーズン2, 2nd Season, Season 2, ...) as `season2`,
+/// so that the marks of different languages compare equal.
+pub fn normalize_season_marks(name: &str) -> String {
+    // Full-width digits to half-width
+    let mut name: String = name.chars()
+        .map(|c| match c {
+            '\u{FF10}'..='\u{FF19}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+            _ => c,
+        })
+        .collect();
+    // Chinese numerals only inside 第...季-like marks, to keep titles such as 一拳超人 untouched
+    name = CHINESE_SEASON_MARK
+        .replace_all(&name, |caps: &regex::Captures| {
+            match chinese_numeral_value(&caps[1]) {
+                Some(value) => format!("第{}{}", value, &caps[2]),
+                None => caps[0].to_string(),
+            }
+        })
+        .to_string();
+    for pattern in SEASON_MARK_PATTERNS.iter() {
+        name = pattern.replace_all(&name, " season$1 ").to_string();
+    }
+    name
+}
+
+/// Season number written in a name, see `normalize_season_marks`.
+pub fn parse_season_mark(name: &str) -> Option<i32> {
+    SEASON_TOKEN.captures(&normalize_season_marks(name))
+        .and_then(|caps| caps[1].parse::<i32>().ok())
+}
+
+/// Name without its season mark, e.g. "间谍过家家 第二季" -> "间谍过家家", see `normalize_season_marks`.
+pub fn strip_season_marks(name: &str) -> String {
+    SEASON_TOKEN.replace_all(&normalize_season_marks(name), "").trim().to_string()
+}
+
+/// Normalize a title for comparison: full-width to half-width, lower case, and drop everything that
+/// is not a letter or a digit (spaces, punctuation, brackets).
+pub fn normalize_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+            _ => c,
+        })
+        .flat_map(|c| c.to_lowercase())
+        .filter(|c| c.is_alphanumeric())
+        .collect()
+}
+
+/// Length of the longest common subsequence, O(n*m) time and O(m) memory.
+fn lcs_len(a: &[char], b: &[char]) -> usize {
+    let mut row = vec![0usize; b.len() + 1];
+    for ca in a {
+        let mut diag = 0;
+        for (j, cb) in b.iter().enumerate() {
+            let up = row[j + 1];
+            row[j + 1] = if ca == cb {
+                diag + 1
+            } else {
+                row[j + 1].max(row[j])
+            };
+            diag = up;
+        }
+    }
+    row[b.len()]
+}
+
+/// Similarity of two normalized strings in `[0, 1]`, `2 * LCS / (len_a + len_b)`.
+pub fn normalized_similarity(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    2.0 * lcs_len(&a, &b) as f64 / (a.len() + b.len()) as f64
+}
+
+/// Similarity of two titles in `[0, 1]`, see `normalize_name`.
+pub fn name_similarity(a: &str, b: &str) -> f64 {
+    normalized_similarity(&normalize_name(a), &normalize_name(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_name() {
+        assert_eq!(normalize_name("ＳＰＹ×ＦＡＭＩＬＹ"), "spyfamily");
+        assert_eq!(normalize_name("【我推的孩子】 第二季"), "我推的孩子第二季");
+    }
+
+    #[test]
+    fn test_season_marks() {
+        assert_eq!(chinese_numeral_value("二十三"), Some(23));
+        assert_eq!(parse_season_mark("间谍过家家 第二季"), Some(2));
+        assert_eq!(parse_season_mark("SPY×FAMILY 第２期"), Some(2));
+        assert_eq!(parse_season_mark("シーズン3"), Some(3));
+        assert_eq!(parse_season_mark("Mushoku Tensei 2nd Season"), Some(2));
+        assert_eq!(parse_season_mark("Season 12"), Some(12));
+        assert_eq!(parse_season_mark("第十一季"), Some(11));
+        assert_eq!(parse_season_mark("一拳超人"), None);
+        assert_eq!(strip_season_marks("间谍过家家 第二季"), "间谍过家家");
+        assert_eq!(strip_season_marks("Mushoku Tensei 2nd Season"), "Mushoku Tensei");
+        assert_eq!(normalize_name(&normalize_season_marks("第 2 季")), normalize_name(&normalize_season_marks("第二期")));
+    }
+
+    #[test]
+    fn test_name_similarity() {
+        assert_eq!(name_similarity("葬送的芙莉莲", "葬送的芙莉莲"), 1.0);
+        assert_eq!(name_similarity("", "葬送的芙莉莲"), 0.0);
+        assert!(name_similarity("Spy x Family", "SPY×FAMILY") > 0.9);
+        assert!(name_similarity("葬送的芙莉莲", "葬送のフリーレン") > name_similarity("葬送的芙莉莲", "迷宫饭"));
+    }
+}
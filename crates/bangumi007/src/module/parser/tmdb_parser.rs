@@ -1,24 +1,45 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::error::Error;
 
 use log::trace;
 use retry::delay::Fixed;
 
-use crate::module::config::CONFIG;
-use crate::module::parser::bangumi_parser::{get_bangumi_subject, get_bangumi_subject_aliases};
+use crate::module::config::{CONFIG, MetadataSource};
+use crate::module::database::cache::xref::lookup_anime_xref;
+use crate::module::database::cache::tmdb::{get_tmdb_series_choice, save_tmdb_candidates, TMDBCandidate};
+use crate::module::parser::bangumi_parser::BangumiSubject;
+use crate::module::parser::name_similarity::{name_similarity, normalize_name, normalize_season_marks, normalized_similarity, parse_season_mark};
 use crate::module::utils::error::{new_err, new_warn};
 
-pub fn tmdb_search_tv(series_name: &str) -> rusqlite::Result<i64, Box<dyn Error>> {
+#[derive(Debug, Clone)]
+pub struct TMDBSearchCandidate {
+    pub media_id: i64,
+    pub name: String,
+    pub original_name: String,
+    pub original_language: String,
+    pub first_air_date: String,
+    pub genre_ids: Vec<i64>,
+    pub score: f64,
+}
+
+const TMDB_GENRE_ANIMATION: i64 = 16;
+// A match is trusted when the best candidate scores at least this much and leads the runner-up by
+// TMDB_CONFIDENT_MARGIN, otherwise the candidates are kept for the user to review.
+const TMDB_CONFIDENT_SCORE: f64 = 0.75;
+const TMDB_CONFIDENT_MARGIN: f64 = 0.1;
+
+pub fn tmdb_search_tv_candidates(series_name: &str) -> rusqlite::Result<Vec<TMDBSearchCandidate>, Box<dyn Error>> {
 
     // curl --request GET \
-    //      --url 'https://api.themoviedb.org/3/search/multi?query={name}&include_adult=false&language=en-US&page=1' \
+    //      --url 'https://api.themoviedb.org/3/search/tv?query={name}&include_adult=false&language=zh-CN' \
     //      --header 'Authorization: Bearer {Access Token Auth}' \
     //      --header 'accept: application/json'
 
     let api_access_token_auth = CONFIG.read().unwrap().parser_config.tmdb_config.api_access_token_auth.clone();
     let include_adult = CONFIG.read().unwrap().parser_config.tmdb_config.include_adult;
 
-    let url = format!("https://api.themoviedb.org/3/search/tv?query={}&include_adult={}&language=zh-CN", series_name, include_adult);
+    let url = format!("https://api.themoviedb.org/3/search/tv?query={}&include_adult={}&language=zh-CN", urlencoding::encode(series_name), include_adult);
 
     let mut headers = reqwest::header::HeaderMap::new();
     headers.insert("Authorization", format!("Bearer {}", api_access_token_auth).parse().unwrap());
@@ -41,20 +62,112 @@ pub fn tmdb_search_tv(series_name: &str) -> rusqlite::Result<i64, Box<dyn Error>
     let json: serde_json::Value = serde_json::from_str(&response)
         .map_err(|_| new_err("Failed to parse json"))?;
 
-    // Get json['results'][0]['id'] and json['results'][0]['media_type']
-    let first_result = json.get("results")
+    tmdb_parse_search_results(&json)
+}
+
+pub fn tmdb_parse_search_results(json: &serde_json::Value) -> Result<Vec<TMDBSearchCandidate>, Box<dyn Error>> {
+    let results = json.get("results")
         .ok_or_else(|| new_err("Failed to get results"))?
         .as_array()
-        .ok_or_else(|| new_err("Failed to get results as array"))?
-        .get(0)
-        .ok_or_else(|| new_warn(format!("TMDB Search result empty for {}", series_name).as_str()))?;
+        .ok_or_else(|| new_err("Failed to get results as array"))?;
 
-    let media_id = first_result.get("id")
-        .ok_or_else(|| new_err("Failed to get id"))?
-        .as_i64()
-        .ok_or_else(|| new_err("Failed to get id as i64"))?;
+    let mut candidates = Vec::new();
+    for result in results {
+        let media_id = match result.get("id").and_then(|x| x.as_i64()) {
+            Some(media_id) => media_id,
+            None => continue,
+        };
+        let get_str = |key: &str| result.get(key).and_then(|x| x.as_str()).unwrap_or("").to_string();
+        candidates.push(TMDBSearchCandidate {
+            media_id,
+            name: get_str("name"),
+            original_name: get_str("original_name"),
+            original_language: get_str("original_language"),
+            first_air_date: get_str("first_air_date"),
+            genre_ids: result.get("genre_ids")
+                .and_then(|x| x.as_array())
+                .map(|x| x.iter().filter_map(|x| x.as_i64()).collect())
+                .unwrap_or_default(),
+            score: 0.0,
+        });
+    }
+    Ok(candidates)
+}
+
+/// Score a search result in `[0, 1]`:
+/// first air year against the Bangumi date (0.3), original language `ja` (0.2),
+/// the Animation genre (0.2) and the best alias similarity (0.3).
+pub fn tmdb_score_candidate(candidate: &TMDBSearchCandidate, aliases: &Vec<String>, bangumi_date: &str) -> f64 {
+    let year = |date: &str| date.get(0..4).and_then(|x| x.parse::<i32>().ok());
+    let year_score = match (year(&candidate.first_air_date), year(bangumi_date)) {
+        (Some(tmdb_year), Some(bangumi_year)) if tmdb_year == bangumi_year => 0.3,
+        // Later seasons are listed under a series that started earlier
+        (Some(tmdb_year), Some(bangumi_year)) if tmdb_year < bangumi_year => 0.15,
+        (Some(_), Some(_)) => 0.0,
+        _ => 0.1,
+    };
+    let language_score = if candidate.original_language == "ja" { 0.2 } else { 0.0 };
+    let genre_score = if candidate.genre_ids.contains(&TMDB_GENRE_ANIMATION) { 0.2 } else { 0.0 };
+    let alias_score = aliases.iter()
+        .map(|alias| name_similarity(alias, &candidate.name).max(name_similarity(alias, &candidate.original_name)))
+        .fold(0.0, f64::max) * 0.3;
+    year_score + language_score + genre_score + alias_score
+}
 
-    Ok(media_id)
+/// # Ranked TMDB series search
+///
+/// ## Input
+///
+/// Bangumi subject aliases : `Vec of String`, Bangumi air date : `&str`
+///
+/// ## Procedure
+///
+/// 1. Search every alias in TMDB, collect the results without duplicates
+/// 2. If nothing is found, retry with the first half of each alias
+/// 3. Score every candidate against the full aliases, see `tmdb_score_candidate`
+///
+/// ## Output
+///
+/// Candidates sorted by score (descending), then by TMDB id.
+pub fn tmdb_rank_series_candidates(aliases: &Vec<String>, bangumi_date: &str) -> Vec<TMDBSearchCandidate> {
+    let mut candidates: HashMap<i64, TMDBSearchCandidate> = HashMap::new();
+    let search = |query: &str, candidates: &mut HashMap<i64, TMDBSearchCandidate>| {
+        let results = match tmdb_search_tv_candidates(query) {
+            Ok(results) => results,
+            Err(_) => return,
+        };
+        for mut result in results {
+            result.score = tmdb_score_candidate(&result, aliases, bangumi_date);
+            log::trace!("TMDB candidate for {}: {:?}", query, result);
+            candidates.insert(result.media_id, result);
+        }
+    };
+    for alias in aliases {
+        search(alias, &mut candidates);
+    }
+    if candidates.is_empty() {
+        for alias in aliases {
+            // only take the first half of the alias
+            let char_count = alias.chars().count();
+            let alias = alias.chars().take(char_count / 2).collect::<String>();
+            if !alias.is_empty() {
+                search(&alias, &mut candidates);
+            }
+        }
+    }
+
+    let mut candidates: Vec<TMDBSearchCandidate> = candidates.into_values().collect();
+    candidates.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal).then(a.media_id.cmp(&b.media_id)));
+    candidates
+}
+
+/// Whether the best ranked candidate can be used without asking the user.
+pub fn tmdb_candidates_confident(candidates: &[TMDBSearchCandidate]) -> bool {
+    match candidates {
+        [] => false,
+        [best] => best.score >= TMDB_CONFIDENT_SCORE,
+        [best, runner_up, ..] => best.score >= TMDB_CONFIDENT_SCORE && best.score - runner_up.score >= TMDB_CONFIDENT_MARGIN,
+    }
 }
 
 pub fn tmdb_search_multi(series_name: &str) -> rusqlite::Result<(String, i64), Box<dyn Error>> {
@@ -146,7 +259,7 @@ fn tmdb_get_media_info_internal(media_type: &str, media_id: i64, lang: &str) ->
 }
 
 pub fn tmdb_get_media_info(media_type: &str, media_id: i64) -> Result<HashMap<String, serde_json::Value>, Box<dyn Error>> {
-    let languages = vec!["ja", "zh-CN", "en-US"];
+    let languages = vec!["ja", "zh-CN", "zh-TW", "en-US"];
     let mut result_dict = HashMap::new();
 
     for lang in languages {
@@ -170,118 +283,214 @@ pub fn tmdb_parse_media_name(json: &serde_json::Value) -> Result<String, Box<dyn
     Ok(media_name.to_string())
 }
 
-pub fn tmdb_search_season_in_infos(lang_json: &HashMap<String, serde_json::Value>, aliases: &Vec<String>) -> Result<(i64, String), Box<dyn Error>> {
-    let mut season_num_to_names: HashMap<i64, HashMap<String, String>> = HashMap::new();
-
-    for (lang, lang_info) in lang_json {
-        let seasons = lang_info.get("seasons")
-            .ok_or_else(|| new_err("Failed to get seasons"))?
-            .as_array()
-            .ok_or_else(|| new_err("Failed to get seasons as array"))?;
-
-        for season in seasons {
-            let season_name = season.get("name")
-                .ok_or_else(|| new_err("Failed to get season name"))?
-                .as_str()
-                .ok_or_else(|| new_err("Failed to get season name as str"))?;
-
-            let season_number = season.get("season_number")
-                .ok_or_else(|| new_err("Failed to get season number"))?
-                .as_i64()
-                .ok_or_else(|| new_err("Failed to get season number as i64"))?;
-
-            if !season_num_to_names.contains_key(&season_number) {
-                season_num_to_names.insert(season_number, HashMap::new());
-            }
+#[derive(Debug, Clone)]
+pub struct TMDBEpisode {
+    pub episode_number: i32,
+    pub air_date: String,
+}
 
-            season_num_to_names.get_mut(&season_number).unwrap().insert(lang.to_string(), season_name.to_string());
-        }
-    }
+pub fn tmdb_get_season_episodes(media_id: i64, season_number: i64) -> Result<Vec<TMDBEpisode>, Box<dyn Error>> {
+    // curl --request GET \
+    //      --url 'https://api.themoviedb.org/3/tv/{media_id}/season/{season_number}?language=zh-CN' \
+    //      --header 'Authorization: Bearer {Access Token Auth}' \
+    //      --header 'accept: application/json'
 
-    log::trace!("SeasonNumToNames: {:?}", season_num_to_names);
+    let api_access_token_auth = CONFIG.read().unwrap().parser_config.tmdb_config.api_access_token_auth.clone();
 
+    let url = format!("https://api.themoviedb.org/3/tv/{}/season/{}?language=zh-CN", media_id, season_number);
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert("Authorization", format!("Bearer {}", api_access_token_auth).parse().unwrap());
+    headers.insert("accept", "application/json".parse().unwrap());
 
-    // For each season, match the name with all aliases and get the longest match as match
-    // Choose the longest match as the season
-    let mut max_match = -1;
-    let mut res_season_number = 0;
-    let mut res_season_name: String = "".to_string();
-    for (season_number, season_name_dict) in &season_num_to_names {
-        log::trace!("SeasonNumber: {}, SeasonNameDict: {:?}", season_number, season_name_dict);
-        for alias in aliases {
-            log::trace!("Alias: {}", alias);
-            // Replace chinese number with arabic number in alias (第一季 -> 第1季)
-            let alias = alias
-                .replace("十一", "11")
-                .replace("十二", "12")
-                .replace("十三", "13")
-                .replace("十四", "14")
-                .replace("十五", "15")
-                .replace("十六", "16")
-                .replace("十七", "17")
-                .replace("十八", "18")
-                .replace("十九", "19")
-                .replace("十", "10")
-                .replace("一", "1")
-                .replace("二", "2")
-                .replace("三", "3")
-                .replace("四", "4")
-                .replace("五", "5")
-                .replace("六", "6")
-                .replace("七", "7")
-                .replace("八", "8")
-                .replace("九", "9");
-            let zh_cn_season_name = season_name_dict.get("zh-CN").unwrap().clone();
-            for (_, season_name) in season_name_dict {
-                // Replace 第 1 季 as 第1季
-                let season_name = season_name.replace("第 ", "第").replace(" 季", "季");
-                // Replace シーズン3 or シーズン 3 as 第3シーズン
-                let reg_season = regex::Regex::new(r"シーズン ?(\d+)").unwrap();
-                let season_name = reg_season.replace_all(&season_name, "第$1シーズン").to_string();
-                let alias = reg_season.replace_all(&alias, "第$1シーズン").to_string();
-                // alias与season_name的最长公共子串
-                let mut match_len = 0;
-                let mut i = 0;
-                let mut j = 0;
-                while i < alias.chars().count() && j < season_name.chars().count() {
-                    if alias.chars().nth(i).unwrap() == season_name.chars().nth(j).unwrap() {
-                        match_len += 1;
-                        i += 1;
-                        j += 1;
-                    } else {
-                        i += 1;
-                    }
-                }
-                log::trace!("Alias: {}, SeasonName: {}, MatchLen: {}", alias, season_name, match_len);
-                if match_len > max_match {
-                    max_match = match_len;
-                    res_season_number = *season_number;
-                    res_season_name = zh_cn_season_name.clone();
-                    log::debug!("update match! season_number: {}, season_name: {}, alias: {}, match_len: {}", season_number, season_name, alias, match_len);
+    let response = retry::retry(Fixed::from_millis(5000).take(5), || {
+        match reqwest::blocking::Client::new().get(&url).headers(headers.clone()).send() {
+            Ok(response) => {
+                if response.status().is_success() {
+                    Ok(response.text().unwrap())
+                } else {
+                    Err(new_warn(format!("Failed to get tmdb season info, status code is {}", response.status()).as_str()))
                 }
             }
+            Err(_) => Err(new_warn("Failed to get tmdb season info"))
         }
+    }).map_err(|_| new_err("Failed to get tmdb season info"))?;
+
+    // Parse json
+    let json: serde_json::Value = serde_json::from_str(&response)
+        .map_err(|_| new_err("Failed to parse json"))?;
+
+    tmdb_parse_season_episodes(&json)
+}
+
+pub fn tmdb_parse_season_episodes(json: &serde_json::Value) -> Result<Vec<TMDBEpisode>, Box<dyn Error>> {
+
+    // Get json['episodes'][*]['episode_number', 'air_date']
+    let episodes = json.get("episodes")
+        .ok_or_else(|| new_err("Failed to get episodes"))?
+        .as_array()
+        .ok_or_else(|| new_err("Failed to get episodes as array"))?;
+
+    let mut result = Vec::new();
+    for episode in episodes {
+        let episode_number = match episode.get("episode_number").and_then(|x| x.as_i64()) {
+            Some(episode_number) => episode_number as i32,
+            None => continue,
+        };
+        let air_date = episode.get("air_date")
+            .and_then(|x| x.as_str())
+            .unwrap_or("")
+            .to_string();
+        result.push(TMDBEpisode {
+            episode_number,
+            air_date,
+        });
     }
-    if max_match == 0 {
-        res_season_number = 1;
-        let try_operations = || -> Result<String, Box<dyn Error>> {
-            let season_name = season_num_to_names.get(&1).unwrap().get("zh-CN").unwrap().clone();
-            Ok(season_name)
+
+    Ok(result)
+}
+
+/// A TMDB season merged from the media infos of all languages.
+#[derive(Debug, Clone, Default)]
+pub struct TMDBSeasonInfo {
+    pub season_number: i64,
+    pub air_date: String,
+    pub episode_count: i32,
+    pub names: HashMap<String, String>,
+}
+
+impl TMDBSeasonInfo {
+    /// Season name for display, zh-CN first.
+    pub fn disp_name(&self) -> String {
+        ["zh-CN", "ja", "en-US"].iter()
+            .filter_map(|lang| self.names.get(*lang))
+            .chain(self.names.values())
+            .find(|name| !name.is_empty())
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+pub fn tmdb_parse_seasons(lang_json: &HashMap<String, serde_json::Value>) -> Vec<TMDBSeasonInfo> {
+    let mut seasons: HashMap<i64, TMDBSeasonInfo> = HashMap::new();
+    for (lang, lang_info) in lang_json {
+        let lang_seasons = match lang_info.get("seasons").and_then(|x| x.as_array()) {
+            Some(lang_seasons) => lang_seasons,
+            None => continue,
         };
-        res_season_name = try_operations().unwrap_or("".to_string());
-        if res_season_name.is_empty() {
-            let try_operations = || -> Result<String, Box<dyn Error>> {
-                let season_name = lang_json.get("zh-CN").unwrap().get("name").unwrap().as_str().unwrap().to_string();
-                Ok(season_name)
+        for season in lang_seasons {
+            let season_number = match season.get("season_number").and_then(|x| x.as_i64()) {
+                Some(season_number) => season_number,
+                None => continue,
             };
-            res_season_name = try_operations().unwrap_or("".to_string());
+            let info = seasons.entry(season_number).or_insert_with(|| TMDBSeasonInfo {
+                season_number,
+                ..Default::default()
+            });
+            if let Some(name) = season.get("name").and_then(|x| x.as_str()) {
+                info.names.insert(lang.to_string(), name.to_string());
+            }
+            if info.air_date.is_empty() {
+                info.air_date = season.get("air_date").and_then(|x| x.as_str()).unwrap_or("").to_string();
+            }
+            if info.episode_count == 0 {
+                info.episode_count = season.get("episode_count").and_then(|x| x.as_i64()).unwrap_or(0) as i32;
+            }
         }
-        log::debug!("No match, use the first season: {}", res_season_name)
     }
+    let mut seasons: Vec<TMDBSeasonInfo> = seasons.into_values().collect();
+    seasons.sort_by_key(|x| x.season_number);
+    seasons
+}
+
+/// Score a season in `[0, 1]`:
+/// season name similarity (0.3), season number written in the aliases (0.3),
+/// Bangumi date inside the season's airing span (0.3) and episode count (0.1).
+///
+/// The airing span of a season ends when the next season starts, `next_air_date` is empty for the last one.
+pub fn tmdb_score_season(season: &TMDBSeasonInfo, next_air_date: &str, aliases: &Vec<String>, bangumi_date: &str, bangumi_eps: i32) -> f64 {
+    let aliases: Vec<String> = aliases.iter().map(|x| normalize_name(&normalize_season_marks(x))).collect();
+    let name_score = season.names.values()
+        .map(|name| normalize_name(&normalize_season_marks(name)))
+        .flat_map(|name| aliases.iter().map(move |alias| normalized_similarity(alias, &name)))
+        .fold(0.0, f64::max);
+
+    let alias_season_numbers: Vec<i32> = aliases.iter().filter_map(|x| parse_season_mark(x)).collect();
+    let number_score = if alias_season_numbers.contains(&(season.season_number as i32)) { 1.0 } else { 0.0 };
+
+    let parse_date = |date: &str| chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").ok();
+    let date_score = match (parse_date(bangumi_date), parse_date(&season.air_date)) {
+        (Some(bangumi_date), Some(air_date)) => {
+            let days = (bangumi_date - air_date).num_days();
+            let before_next = parse_date(next_air_date).map_or(true, |next| bangumi_date < next - chrono::Duration::days(7));
+            if days.abs() <= 7 {
+                1.0
+            } else if days > 0 && before_next {
+                // Later cour of the same season
+                0.7
+            } else {
+                0.0
+            }
+        }
+        _ => 0.0,
+    };
 
-    log::debug!("Best match length: {}, SeasonNumber: {}, SeasonName: {}", max_match, res_season_number, res_season_name);
+    let episode_score = match (bangumi_eps, season.episode_count) {
+        (0, _) | (_, 0) => 0.0,
+        (bangumi_eps, episode_count) if bangumi_eps == episode_count => 1.0,
+        // Split cours are listed as one season in TMDB
+        (bangumi_eps, episode_count) if bangumi_eps < episode_count => 0.5,
+        _ => 0.0,
+    };
 
-    Ok((res_season_number, res_season_name))
+    name_score * 0.3 + number_score * 0.3 + date_score * 0.3 + episode_score * 0.1
+}
+
+/// # Match the TMDB season of a Bangumi subject
+///
+/// ## Input
+///
+/// TMDB media infos by language : `HashMap`, Bangumi aliases : `Vec of String`,
+/// Bangumi air date : `&str`, Bangumi episode count : `i32` (0 if unknown)
+///
+/// ## Procedure
+///
+/// 1. Merge the seasons of all languages, specials (season 0) are only used if there is nothing else
+/// 2. Score every season, see `tmdb_score_season`
+/// 3. Take the best score, ties are broken by the smaller season number
+///
+/// ## Output
+///
+/// (season number, season name), season 1 with the series name if the media has no season
+pub fn tmdb_search_season_in_infos(lang_json: &HashMap<String, serde_json::Value>, aliases: &Vec<String>, bangumi_date: &str, bangumi_eps: i32) -> Result<(i64, String), Box<dyn Error>> {
+    let mut seasons = tmdb_parse_seasons(lang_json);
+    if seasons.iter().any(|x| x.season_number > 0) {
+        seasons.retain(|x| x.season_number > 0);
+    }
+
+    let mut scored: Vec<(f64, &TMDBSeasonInfo)> = seasons.iter().enumerate()
+        .map(|(i, season)| {
+            let next_air_date = seasons.get(i + 1).map_or("", |x| x.air_date.as_str());
+            let score = tmdb_score_season(season, next_air_date, aliases, bangumi_date, bangumi_eps);
+            log::trace!("Season {} {:?}: score {:.3}", season.season_number, season.names, score);
+            (score, season)
+        })
+        .collect();
+    scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal).then(a.1.season_number.cmp(&b.1.season_number)));
+
+    match scored.first() {
+        Some((score, season)) => {
+            log::debug!("Best season score: {:.3}, SeasonNumber: {}, SeasonName: {}", score, season.season_number, season.disp_name());
+            Ok((season.season_number, season.disp_name()))
+        }
+        None => {
+            let series_name = ["zh-CN", "ja", "en-US"].iter()
+                .filter_map(|lang| lang_json.get(*lang))
+                .find_map(|json| tmdb_parse_media_name(json).ok())
+                .unwrap_or_default();
+            log::debug!("No season found, use the series name: {}", series_name);
+            Ok((1, series_name))
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -291,63 +500,108 @@ pub struct TMDBParseResult {
     pub media_name: String,
     pub season_number: i64,
     pub season_name: String,
+    pub media_names: HashMap<String, String>,       // by TMDB language
+    pub season_names: HashMap<String, String>,      // by TMDB language
+}
+
+/// Name of a season in the media info, `None` if the season does not exist.
+pub fn tmdb_parse_season_name(json: &serde_json::Value, season_number: i64) -> Option<String> {
+    json.get("seasons")?
+        .as_array()?
+        .iter()
+        .find(|season| season.get("season_number").and_then(|x| x.as_i64()) == Some(season_number))?
+        .get("name")?
+        .as_str()
+        .map(|x| x.to_string())
 }
 
-pub fn bangumi_parse_tmdb_info(bangumi_subject_id: i32) -> Result<TMDBParseResult, Box<dyn Error>> {
-    let bangumi_info = get_bangumi_subject(bangumi_subject_id)?;
-    let aliases = bangumi_info.aliases;
+/// # Parse TMDB info of a Bangumi subject
+///
+/// ## Input
+///
+/// Bangumi subject : `&BangumiSubject`, pinned TMDB series id : `Option<i64>`, pinned TMDB season number : `Option<i32>`
+///
+/// ## Procedure
+///
+/// 1. Use the pinned series, or the series chosen by the user, or the best ranked search result
+/// 2. Use the pinned season, or search the season by the Bangumi aliases
+///
+/// ## Output
+///
+/// `TMDBParseResult`
+pub fn bangumi_parse_tmdb_info(bangumi_info: &BangumiSubject, pinned_media_id: Option<i64>, pinned_season_num: Option<i32>) -> Result<TMDBParseResult, Box<dyn Error>> {
+    let bangumi_subject_id = bangumi_info.bangumi_subject_id;
+    // Use the series pinned or picked by the user if any, then the offline cross-reference,
+    // otherwise rank the search results. Nothing is downloaded before these are looked up.
+    let chosen_media_id = pinned_media_id.or_else(|| get_tmdb_series_choice(bangumi_subject_id));
+    let xref = lookup_anime_xref(MetadataSource::Bangumi, bangumi_subject_id)
+        .filter(|x| x.tmdb_id > 0 && chosen_media_id.map_or(true, |id| id == x.tmdb_id as i64));
+    let aliases = bangumi_info.aliases.clone();
     if bangumi_info.media_type == "剧场版" || bangumi_info.media_type == "OVA" {
         return Err(new_err(format!("Media type is {}. Not implemented.", bangumi_info.media_type).as_str()));
     }
-    // For each alias, search in tmdb
-    // If found, get media_name and season_number
-    // If Err, continue to next alias
-    let mut alias_id = 0;
-    let search_result = loop {
-        let alias = &aliases[alias_id];
-        let result = tmdb_search_tv(alias);
-        if result.is_ok() {
-            break result;
+    let pinned_season_num = pinned_season_num.or_else(|| xref.map(|x| x.tmdb_season).filter(|x| *x >= 0));
+    let media_id = match chosen_media_id.or_else(|| xref.map(|x| x.tmdb_id as i64)) {
+        Some(media_id) => {
+            log::debug!("Using TMDB series {} chosen for Bangumi subject {}", media_id, bangumi_subject_id);
+            media_id
         }
-        alias_id += 1;
-        if alias_id >= aliases.len() {
-            break Err(new_warn("Failed to search media in tmdb"));
+        None => {
+            let candidates = tmdb_rank_series_candidates(&aliases, &bangumi_info.date);
+            let best = candidates.first()
+                .ok_or_else(|| new_warn("Failed to search media in tmdb"))?;
+            if tmdb_candidates_confident(&candidates) {
+                save_tmdb_candidates(bangumi_subject_id, &[])?;
+            } else {
+                log::warn!("Low confidence TMDB match for {}: {} ({:.2}), keeping candidates for review", aliases[0], best.name, best.score);
+                let records: Vec<TMDBCandidate> = candidates.iter().take(10).map(|x| TMDBCandidate {
+                    bangumi_subject_id,
+                    tmdb_series_id: x.media_id,
+                    tmdb_series_name: x.name.clone(),
+                    tmdb_original_name: x.original_name.clone(),
+                    tmdb_first_air_date: x.first_air_date.clone(),
+                    score: x.score,
+                }).collect();
+                save_tmdb_candidates(bangumi_subject_id, &records)?;
+            }
+            best.media_id
         }
     };
-    let search_result = match search_result {
-        Ok(search_result) => Ok(search_result),
-        Err(_) => {
-            let mut alias_id = 0;
-            let search_result = loop {
-                // only take the first half of the alias
-                let char_count = aliases[alias_id].chars().count();
-                // let alias = &aliases[alias_id][..char_count / 2];
-                let alias = &aliases[alias_id].chars().take(char_count / 2).collect::<String>();
-                let result = tmdb_search_tv(alias);
-                if result.is_ok() {
-                    break result;
-                }
-                alias_id += 1;
-                if alias_id >= aliases.len() {
-                    break Err(new_warn("Failed to search media in tmdb"));
-                }
-            };
-            search_result
+    let media_infos = tmdb_get_media_info("tv", media_id)?;
+    let media_name = ["zh-CN", "ja", "en-US"].iter()
+        .filter_map(|lang| media_infos.get(*lang))
+        .find_map(|json| tmdb_parse_media_name(json).ok())
+        .ok_or_else(|| new_err("Failed to get media name"))?;
+    let (season_number, season_name) = match pinned_season_num {
+        Some(season_number) => {
+            let season_number = season_number as i64;
+            let season_name = ["zh-CN", "ja", "en-US"].iter()
+                .filter_map(|lang| media_infos.get(*lang))
+                .find_map(|json| tmdb_parse_season_name(json, season_number))
+                .unwrap_or_else(|| format!("第 {} 季", season_number));
+            (season_number, season_name)
         }
+        None => tmdb_search_season_in_infos(&media_infos, &aliases, &bangumi_info.date, bangumi_info.eps)?,
     };
-    let media_id = search_result?;
-    let media_infos = tmdb_get_media_info("tv", media_id)?;
-    let media_name = tmdb_parse_media_name(&media_infos["zh-CN"])?;
-    let (season_number, season_name) = tmdb_search_season_in_infos(&media_infos, &aliases)?;
 
     println!("BangumiSubject: {}, TMDBSeries: {}, TMDBSeason: {}, SeasonNumber: {}", aliases[0], media_name, season_name, season_number);
 
+    let media_names = media_infos.iter()
+        .filter_map(|(lang, json)| tmdb_parse_media_name(json).ok().map(|name| (lang.clone(), name)))
+        .collect();
+    let season_names = tmdb_parse_seasons(&media_infos).into_iter()
+        .find(|x| x.season_number == season_number)
+        .map(|x| x.names)
+        .unwrap_or_default();
+
     Ok(TMDBParseResult {
         bangumi_subject_id,
         media_name,
         media_id,
         season_name,
         season_number,
+        media_names,
+        season_names,
     })
 }
 
@@ -356,10 +610,18 @@ mod tests {
     use log::debug;
 
     use crate::module::logger;
-    use crate::module::parser::bangumi_parser::get_bangumi_subject_aliases;
+    use crate::module::parser::bangumi_parser::get_bangumi_subject;
 
     use super::*;
 
+    /// First search result, what the parser used before candidates were ranked
+    fn tmdb_search_tv(series_name: &str) -> Result<i64, Box<dyn Error>> {
+        let candidates = tmdb_search_tv_candidates(series_name)?;
+        let first_result = candidates.first()
+            .ok_or_else(|| new_warn(format!("TMDB Search result empty for {}", series_name).as_str()))?;
+        Ok(first_result.media_id)
+    }
+
     #[test]
     fn test_tmdb_search_media() {
         logger::init();
@@ -369,6 +631,72 @@ mod tests {
         println!("{:?}", result.unwrap());
     }
 
+    #[test]
+    fn test_tmdb_score_candidates() {
+        let json: serde_json::Value = serde_json::from_str(r#"{"results": [
+            {"id": 209867, "name": "葬送的芙莉莲", "original_name": "葬送のフリーレン", "original_language": "ja", "first_air_date": "2023-09-29", "genre_ids": [16, 10759]},
+            {"id": 1, "name": "芙莉莲", "original_name": "Frieren", "original_language": "en", "first_air_date": "2025-01-01", "genre_ids": [18]},
+            {"name": "no id"}
+        ]}"#).unwrap();
+        let mut candidates = tmdb_parse_search_results(&json).unwrap();
+        assert_eq!(candidates.len(), 2);
+
+        let aliases = vec!["葬送のフリーレン".to_string(), "葬送的芙莉莲".to_string()];
+        for candidate in candidates.iter_mut() {
+            candidate.score = tmdb_score_candidate(candidate, &aliases, "2023-09-29");
+        }
+        assert!((candidates[0].score - 1.0).abs() < 1e-9);
+        assert!(candidates[1].score < 0.3);
+        assert!(tmdb_candidates_confident(&candidates));
+
+        candidates[1].score = candidates[0].score - 0.05;
+        assert!(!tmdb_candidates_confident(&candidates));
+        assert!(!tmdb_candidates_confident(&[]));
+    }
+
+    fn load_media_info_fixture(json: &str) -> HashMap<String, serde_json::Value> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_tmdb_search_season_by_name() {
+        let media_infos = load_media_info_fixture(include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tmdb/tv_120089.json")));
+        let aliases = vec!["SPY×FAMILY 第2期".to_string(), "间谍过家家 第二季".to_string()];
+        let result = tmdb_search_season_in_infos(&media_infos, &aliases, "2023-10-07", 12).unwrap();
+        assert_eq!(result, (2, "第 2 季".to_string()));
+    }
+
+    #[test]
+    fn test_tmdb_search_season_by_date() {
+        // Second cour of season 1, listed as a separate subject in Bangumi
+        let media_infos = load_media_info_fixture(include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tmdb/tv_120089.json")));
+        let aliases = vec!["SPY×FAMILY".to_string(), "间谍过家家".to_string()];
+        let result = tmdb_search_season_in_infos(&media_infos, &aliases, "2022-10-01", 13).unwrap();
+        assert_eq!(result, (1, "第 1 季".to_string()));
+    }
+
+    #[test]
+    fn test_tmdb_search_season_without_zh_cn() {
+        let media_infos = load_media_info_fixture(include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tmdb/tv_94664_no_zh.json")));
+        let aliases = vec!["無職転生 ～異世界行ったら本気だす～ 第2期".to_string()];
+        let result = tmdb_search_season_in_infos(&media_infos, &aliases, "2023-07-03", 12).unwrap();
+        assert_eq!(result, (2, "シーズン2".to_string()));
+    }
+
+    #[test]
+    fn test_tmdb_search_season_tie_break() {
+        let media_infos = load_media_info_fixture(include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tmdb/tv_120089.json")));
+        let aliases = vec!["unrelated".to_string()];
+        for _ in 0..5 {
+            let result = tmdb_search_season_in_infos(&media_infos, &aliases, "", 0).unwrap();
+            assert_eq!(result, (1, "第 1 季".to_string()));
+        }
+
+        let media_infos = load_media_info_fixture(r#"{"zh-CN": {"name": "间谍过家家"}}"#);
+        let result = tmdb_search_season_in_infos(&media_infos, &aliases, "", 0).unwrap();
+        assert_eq!(result, (1, "间谍过家家".to_string()));
+    }
+
     #[test]
     fn test_tmdb_get_media_info() {
         logger::init();
@@ -425,7 +753,7 @@ mod tests {
             let media_id = search_result.unwrap();
             let media_infos = tmdb_get_media_info("tv", media_id).unwrap();
             let media_name = tmdb_parse_media_name(&media_infos["zh-CN"]).unwrap();
-            let (season_number, season_name) = tmdb_search_season_in_infos(&media_infos, &aliases).unwrap();
+            let (season_number, season_name) = tmdb_search_season_in_infos(&media_infos, &aliases, &bangumi_subject.date, bangumi_subject.eps).unwrap();
 
             debug!("BangumiSubject: {}, TMDBSeries: {}, TMDBSeason: {}, SeasonNumber: {}", aliases[0], media_name, season_name, season_number);
         }
@@ -0,0 +1,117 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use eframe::egui;
+use egui::{Color32, RichText};
+
+use crate::module::database::activity::{Activity, ActivityFilter, ActivityKind, read_activities};
+use crate::ui::apps::season_conf_dialog_window::SeasonConfDialogWindow;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ActivityApp {
+    filter: ActivityFilter,
+    activities: Option<Vec<Activity>>,      // None if the filter changed since the last query
+}
+
+impl ActivityApp {
+    /// Query the activities again on the next frame
+    pub fn reload(&mut self) {
+        self.activities = None;
+    }
+
+    pub(crate) fn ui(&mut self, ui: &mut egui::Ui, season_conf_dialog_window: Rc<RefCell<SeasonConfDialogWindow>>) {
+        ui.vertical(|ui| {
+            self.filter_ui(ui);
+            ui.separator();
+
+            let filter = self.filter.clone();
+            let activities = self.activities.get_or_insert_with(|| read_activities(&filter));
+            if activities.is_empty() {
+                ui.label("暂无记录");
+                return;
+            }
+            let mut season_filter = None;
+            egui::ScrollArea::vertical().auto_shrink([false, false]).show(ui, |ui| {
+                egui::Grid::new("activity_grid").striped(true).num_columns(5).show(ui, |ui| {
+                    for activity in activities.iter() {
+                        ui.label(disp_timestamp(&activity.timestamp));
+                        ui.label(activity.kind.disp_name());
+                        if activity.mikan_subject_id == -1 {
+                            ui.label(&activity.title);
+                        } else {
+                            let title = match activity.episode_num {
+                                -1 => activity.title.clone(),
+                                episode_num => format!("{} [E{:02}]", activity.title, episode_num),
+                            };
+                            let link = ui.link(title).on_hover_text("左键打开季度设置，右键只看该季度");
+                            if link.clicked() {
+                                open_season_conf(&season_conf_dialog_window, activity);
+                            }
+                            if link.secondary_clicked() {
+                                season_filter = Some((activity.mikan_subject_id, activity.mikan_subgroup_id));
+                            }
+                        }
+                        match (activity.before_value.as_str(), activity.after_value.as_str()) {
+                            ("", "") => ui.label(""),
+                            ("", after) => ui.label(after),
+                            (before, after) => ui.label(format!("{} → {}", before, after)),
+                        };
+                        if activity.success {
+                            ui.label(RichText::new("成功").color(Color32::LIGHT_GREEN));
+                        } else {
+                            ui.label(RichText::new("失败").color(Color32::LIGHT_RED)).on_hover_text(&activity.error);
+                        }
+                        ui.end_row();
+                    }
+                });
+            });
+            if season_filter.is_some() {
+                self.filter.season = season_filter;
+                self.reload();
+            }
+        });
+    }
+
+    fn filter_ui(&mut self, ui: &mut egui::Ui) {
+        let filter_before = self.filter.clone();
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_source("activity_kind")
+                .selected_text(self.filter.kind.map_or("全部类型", |x| x.disp_name()))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.filter.kind, None, "全部类型");
+                    for kind in ActivityKind::ALL {
+                        ui.selectable_value(&mut self.filter.kind, Some(kind), kind.disp_name());
+                    }
+                });
+            ui.checkbox(&mut self.filter.failed_only, "仅失败");
+            ui.add(egui::TextEdit::singleline(&mut self.filter.keyword).hint_text("搜索标题").desired_width(160.));
+            if let Some((mikan_subject_id, mikan_subgroup_id)) = self.filter.season {
+                if ui.button(format!("季度 {}-{} ✕", mikan_subject_id, mikan_subgroup_id)).on_hover_text("显示全部季度").clicked() {
+                    self.filter.season = None;
+                }
+            }
+            if ui.button("刷新").clicked() {
+                self.reload();
+            }
+        });
+        if self.filter != filter_before {
+            self.reload();
+        }
+    }
+}
+
+fn open_season_conf(season_conf_dialog_window: &Rc<RefCell<SeasonConfDialogWindow>>, activity: &Activity) {
+    let mut season_conf_dialog_window = season_conf_dialog_window.borrow_mut();
+    season_conf_dialog_window.subject_id = activity.mikan_subject_id;
+    season_conf_dialog_window.subgroup_id = activity.mikan_subgroup_id;
+    *season_conf_dialog_window.open.borrow_mut() = true;
+    season_conf_dialog_window.open_my = true;
+    season_conf_dialog_window.inited = false;
+}
+
+/// "2024-01-01T12:00:00+08:00" -> "01-01 12:00:00"
+fn disp_timestamp(timestamp: &str) -> String {
+    chrono::DateTime::parse_from_rfc3339(timestamp)
+        .map(|x| x.format("%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|_| timestamp.to_string())
+}
@@ -2,14 +2,20 @@
 
 use std::cell::RefCell;
 use std::rc::Rc;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, TryLockError};
 use lazy_static::lazy_static;
 
 use eframe::egui;
-use eframe::egui::{RichText, vec2};
+use eframe::egui::{Align, Color32, RichText, Stroke, vec2};
 use eframe::egui::CursorIcon::PointingHand;
 
+use crate::module::config::{CONFIG, TitleLanguage};
+use crate::module::database::episode_gap::EpisodeGap;
+use crate::module::database::item_state::{apply_item_event, ItemEvent, ItemState};
 use crate::module::database::library::AnimeSeason;
+use crate::module::database::season_status::SeasonStatus;
+use crate::module::library::arrangement::Arrangement;
+use crate::module::library::removal::SeasonCleanup;
 use crate::ui::apps::season_conf_dialog_window::SeasonConfDialogWindow;
 use crate::module::scrobbler::bangumi::{BangumiEpisodeStatus, BangumiEpisodeType};
 
@@ -19,19 +25,47 @@ pub struct LibraryApp {
 }
 
 lazy_static!(
-    // flag for bangumi status update
-    pub static ref BANGUMI_STATUS_UPDATE: Arc<RwLock<bool>> = Arc::new(RwLock::new(false));
+    // held by the background task changing the library, one at a time
+    static ref LIBRARY_TASK: Mutex<()> = Mutex::new(());
 );
 
+/// What is chosen in the menus of the library
+#[derive(Debug, Clone, PartialEq)]
+enum LibraryAction {
+    ItemEvent(String, ItemEvent),   // (episode_hash, event)
+    Arrange(Arrangement),
+    RemoveSeason { season: (i32, i32), cleanup: SeasonCleanup, deactivate_feed: bool },
+    BackfillGap(EpisodeGap),
+    ArchiveSeason { season: (i32, i32), archived: bool },
+}
+
 impl LibraryApp {
-    fn series_layout(&mut self, ui: &mut egui::Ui, series: &AppAnimeSeries, season_conf_dialog_window: Rc<RefCell<SeasonConfDialogWindow>>) {
+    /// Wait for the background task changing the library to finish, and hold it off until the guard is dropped.
+    /// A panicked task does not block the next ones.
+    pub fn lock_task() -> MutexGuard<'static, ()> {
+        LIBRARY_TASK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Whether a background task is changing the library
+    pub fn is_busy() -> bool {
+        matches!(LIBRARY_TASK.try_lock(), Err(TryLockError::WouldBlock))
+    }
+
+    /// ## Output
+    ///
+    /// Action chosen in the menu of a season or an episode : `Option<LibraryAction>`
+    fn series_layout(&mut self, ui: &mut egui::Ui, series: &AppAnimeSeries, season_conf_dialog_window: Rc<RefCell<SeasonConfDialogWindow>>, jump_to_season: &mut Option<(i32, i32)>) -> Option<LibraryAction> {
+        let mut action = None;
+        let title_languages = CONFIG.read().unwrap().display_config.title_languages.clone();
+        let show_archived = CONFIG.read().unwrap().display_config.show_archived_seasons;
+        let jump_target = *jump_to_season;
         ui.add_space(3.);
         ui.vertical(|ui| {
             ui.add_space(3.);
             let title = ui.label(RichText::new(series.disp_series_name.clone()).size(16.0));
-            for season in &series.seasons {
+            for season in series.seasons.iter().filter(|x| x.is_shown(show_archived, jump_target)) {
                 ui.add_space(8.);
-                ui.horizontal(|ui| {
+                let season_row = ui.horizontal(|ui| {
                     // replace mikanani.me with mikanime.tv
                     let image_url = season.disp_thumbnail_url.clone()
                         .replace("mikanime.tv", "mikanani.me")
@@ -63,6 +97,9 @@ impl LibraryApp {
                             }
                             disp_season_name
                         };
+                        if season.status != SeasonStatus::Airing {
+                            disp_season_name = format!("{} [{}]", disp_season_name, season.status.disp_name());
+                        }
                         let season_title = ui.heading(RichText::new(disp_season_name).size(14.0)).on_hover_cursor(PointingHand);
                         if season_title.clicked() {
                             let mut season_conf_dialog_window = season_conf_dialog_window.borrow_mut();
@@ -72,26 +109,195 @@ impl LibraryApp {
                             season_conf_dialog_window.open_my = true;
                             season_conf_dialog_window.inited = false;
                         }
+                        let season_key = (season.mikan_subject_id, season.mikan_subgroup_id);
+                        // Seasons of the series an episode or a season can be shown in
+                        let targets: Vec<&AppAnimeSeason> = series.seasons.iter()
+                            .filter(|x| !x.split_part && (x.mikan_subject_id, x.mikan_subgroup_id) != season_key)
+                            .collect();
+                        // The first season number after this one not shown by another subject of the series, see `taken_season_nums`
+                        let split_season_num = (season.disp_season_num + 1..)
+                            .find(|num| !series.seasons.iter().any(|x| x.mikan_subject_id != season.mikan_subject_id && x.disp_season_num == *num))
+                            .unwrap_or(season.disp_season_num + 1);
+                        season_title.context_menu(|ui| {
+                            if season.split_part {
+                                if ui.button("取消拆分").clicked() {
+                                    action = Some(LibraryAction::Arrange(Arrangement::UnsplitSeason { season: season_key }));
+                                    ui.close_menu();
+                                }
+                                return;
+                            }
+                            if season.merged_seasons.is_empty() && !targets.is_empty() {
+                                ui.menu_button("并入", |ui| {
+                                    for target in targets.iter() {
+                                        if ui.button(format!("第 {} 季 - {}", target.disp_season_num, target.disp_season_name)).clicked() {
+                                            action = Some(LibraryAction::Arrange(Arrangement::MergeSeason {
+                                                season: season_key,
+                                                target: (target.mikan_subject_id, target.mikan_subgroup_id),
+                                            }));
+                                            ui.close_menu();
+                                        }
+                                    }
+                                });
+                            }
+                            for (merged_season, merged_season_name) in season.merged_seasons.iter() {
+                                if ui.button(format!("取消并入 {}", merged_season_name)).clicked() {
+                                    action = Some(LibraryAction::Arrange(Arrangement::UnmergeSeason { season: *merged_season }));
+                                    ui.close_menu();
+                                }
+                            }
+                            ui.separator();
+                            let archived = season.status == SeasonStatus::Archived;
+                            if ui.button(if archived { "取消归档" } else { "归档" }).clicked() {
+                                action = Some(LibraryAction::ArchiveSeason { season: season_key, archived: !archived });
+                                ui.close_menu();
+                            }
+                            // The chosen cleanup waits for a confirmation, forgotten once the menu is closed
+                            let pending_cleanup_id = season_title.id.with("pending_cleanup");
+                            let removal_menu = ui.menu_button("移除季度", |ui| {
+                                // Kept across frames while the menu is open
+                                let deactivate_feed_id = ui.id().with("deactivate_feed");
+                                let mut deactivate_feed = ui.data_mut(|x| *x.get_temp_mut_or(deactivate_feed_id, true));
+                                match ui.data(|x| x.get_temp::<SeasonCleanup>(pending_cleanup_id)) {
+                                    None => {
+                                        ui.checkbox(&mut deactivate_feed, "停用对应订阅源");
+                                        ui.data_mut(|x| x.insert_temp(deactivate_feed_id, deactivate_feed));
+                                        for cleanup in SeasonCleanup::ALL {
+                                            if ui.button(cleanup.disp_name()).clicked() {
+                                                ui.data_mut(|x| x.insert_temp(pending_cleanup_id, cleanup));
+                                            }
+                                        }
+                                    }
+                                    Some(cleanup) => {
+                                        ui.label(format!("移除 {}，{}{}？", season.disp_season_name, cleanup.disp_name(),
+                                                         if deactivate_feed { "，停用对应订阅源" } else { "" }));
+                                        ui.horizontal(|ui| {
+                                            if ui.button("确认移除").clicked() {
+                                                action = Some(LibraryAction::RemoveSeason { season: season_key, cleanup, deactivate_feed });
+                                                ui.data_mut(|x| x.remove::<SeasonCleanup>(pending_cleanup_id));
+                                                ui.close_menu();
+                                            }
+                                            if ui.button("取消").clicked() {
+                                                ui.data_mut(|x| x.remove::<SeasonCleanup>(pending_cleanup_id));
+                                            }
+                                        });
+                                    }
+                                }
+                            });
+                            if removal_menu.inner.is_none() {
+                                ui.data_mut(|x| x.remove::<SeasonCleanup>(pending_cleanup_id));
+                            }
+                        });
                         ui.add_space(3.);
                         ui.horizontal_wrapped(|ui| {
                             ui.style_mut().spacing.item_spacing = vec2(3.0, 3.0);
+                            let mut gaps = season.gaps.iter().peekable();
                             for episode in &season.episodes {
+                                while let Some(gap) = gaps.next_if(|x| x.disp_episode_num < episode.disp_episode_num) {
+                                    if let Some(chosen) = Self::gap_button(ui, gap) {
+                                        action = Some(chosen);
+                                    }
+                                }
                                 // small button with small text (rich text)
                                 let button = ui
                                     .add_sized([18., 18.],
                                                egui::Button::new(RichText::new(format!("{:02}", episode.disp_episode_num)).monospace().size(9.0).color(episode.bangumi_status.get_text_color(episode.bangumi_airdate.clone()))).fill(episode.bangumi_status.get_fill_color(episode.bangumi_airdate.clone())),
                                     );
+                                let episode_title = episode.disp_title(&title_languages);
+                                let button = if episode_title.is_empty() {
+                                    button.on_hover_text(episode.state.disp_name())
+                                } else {
+                                    button.on_hover_text(format!("{}\n{}", episode_title, episode.state.disp_name()))
+                                };
+                                button.context_menu(|ui| {
+                                    for (event, label) in [(ItemEvent::Reset, "重新下载"), (ItemEvent::Ignore, "忽略此集")] {
+                                        if episode.state.on(event).is_some() && ui.button(label).clicked() {
+                                            action = Some(LibraryAction::ItemEvent(episode.episode_hash.clone(), event));
+                                            ui.close_menu();
+                                        }
+                                    }
+                                    ui.separator();
+                                    // Only episodes shown in their own season under their own number can start a split
+                                    let own_episode = !episode.moved && !season.split_part
+                                        && (episode.mikan_subject_id, episode.mikan_subgroup_id) == season_key;
+                                    if own_episode && ui.button(format!("从此集起拆分为第 {} 季", split_season_num)).clicked() {
+                                        action = Some(LibraryAction::Arrange(Arrangement::SplitSeason {
+                                            season: season_key,
+                                            split_episode_num: episode.disp_episode_num,
+                                            split_season_num,
+                                        }));
+                                        ui.close_menu();
+                                    }
+                                    if !targets.is_empty() {
+                                        ui.menu_button("移动到", |ui| {
+                                            for target in targets.iter() {
+                                                if ui.button(format!("第 {} 季 - {}", target.disp_season_num, target.disp_season_name)).clicked() {
+                                                    action = Some(LibraryAction::Arrange(Arrangement::MoveItem {
+                                                        mikan_item_uuid: episode.episode_hash.clone(),
+                                                        target: Some((target.mikan_subject_id, target.mikan_subgroup_id)),
+                                                        disp_episode_num: None,
+                                                    }));
+                                                    ui.close_menu();
+                                                }
+                                            }
+                                        });
+                                    }
+                                    if episode.moved && ui.button("移回原季度").clicked() {
+                                        action = Some(LibraryAction::Arrange(Arrangement::MoveItem {
+                                            mikan_item_uuid: episode.episode_hash.clone(),
+                                            target: None,
+                                            disp_episode_num: None,
+                                        }));
+                                        ui.close_menu();
+                                    }
+                                });
+                            }
+                            for gap in gaps {
+                                if let Some(chosen) = Self::gap_button(ui, gap) {
+                                    action = Some(chosen);
+                                }
                             }
                         });
                     });
                 });
+                if *jump_to_season == Some((season.mikan_subject_id, season.mikan_subgroup_id)) {
+                    season_row.response.scroll_to_me(Some(Align::Center));
+                    season_row.response.highlight();
+                    *jump_to_season = None;
+                }
             }
             ui.add_space(7.);
             // ui.separator();      // Buggy separator
         });
+        action
     }
 
-    pub fn ui(&mut self, ui: &mut egui::Ui, season_conf_dialog_window: Rc<RefCell<SeasonConfDialogWindow>>) {
+    /// Placeholder of a missing episode, see `auto_episode_gap_detect`
+    fn gap_button(ui: &mut egui::Ui, gap: &EpisodeGap) -> Option<LibraryAction> {
+        let mut action = None;
+        let color = Color32::from_rgb(204, 51, 51);
+        let button = ui.add_sized(
+            [18., 18.],
+            egui::Button::new(RichText::new(format!("{:02}", gap.disp_episode_num)).monospace().size(9.0).color(color))
+                .fill(Color32::TRANSPARENT)
+                .stroke(Stroke::new(1.0, color)),
+        );
+        let replacement = if gap.has_replacement() {
+            format!("可替代：{}", gap.replacement_title)
+        } else {
+            "其他字幕组暂无此集".to_string()
+        };
+        button.on_hover_text(format!("缺集 {}\n播出：{}\n{}", gap.episode_name, gap.episode_airdate, replacement))
+            .context_menu(|ui| {
+                if ui.add_enabled(gap.has_replacement(), egui::Button::new("使用替代发布")).clicked() {
+                    action = Some(LibraryAction::BackfillGap(gap.clone()));
+                    ui.close_menu();
+                }
+            });
+        action
+    }
+
+    /// Show the library, scrolled to `jump_to_season` if it is set, which is then cleared
+    pub fn ui(&mut self, ui: &mut egui::Ui, season_conf_dialog_window: Rc<RefCell<SeasonConfDialogWindow>>, jump_to_season: &mut Option<(i32, i32)>) {
         let library = self.library.clone();
         let library = library.try_read();
         if library.is_err() {
@@ -101,6 +307,11 @@ impl LibraryApp {
             return;
         }
         let library = library.unwrap();
+        // Series with every season archived are hidden as well
+        let show_archived = CONFIG.read().unwrap().display_config.show_archived_seasons;
+        let library: Vec<&AppAnimeSeries> = library.iter()
+            .filter(|series| series.seasons.iter().any(|x| x.is_shown(show_archived, *jump_to_season)))
+            .collect();
         if library.is_empty() {
             ui.centered_and_justified(|ui| {
                 ui.label("媒体库无内容");
@@ -108,6 +319,7 @@ impl LibraryApp {
             return;
         }
 
+        let mut action = None;
         egui::ScrollArea::vertical()
             .max_height(f32::INFINITY)
             .auto_shrink(false)
@@ -123,7 +335,9 @@ impl LibraryApp {
                             // For the first half of the library
                             ui.vertical(|ui| {
                                 for series in &library[(col_index as f32 * library.len() as f32 / columns as f32).ceil() as usize..((col_index + 1) as f32 * library.len() as f32 / columns as f32).ceil() as usize] {
-                                    self.series_layout(ui, series, season_conf_dialog_window.clone());
+                                    if let Some(chosen) = self.series_layout(ui, series, season_conf_dialog_window.clone(), jump_to_season) {
+                                        action = Some(chosen);
+                                    }
                                 }
                             });
                             ui.add_space(2.);
@@ -133,6 +347,29 @@ impl LibraryApp {
                 });
             })
         ;
+        match action {
+            Some(LibraryAction::ItemEvent(episode_hash, event)) => {
+                drop(library);
+                apply_item_event(&episode_hash, event);
+            }
+            Some(LibraryAction::Arrange(arrangement)) => {
+                drop(library);
+                self.arrange_library(arrangement);
+            }
+            Some(LibraryAction::RemoveSeason { season, cleanup, deactivate_feed }) => {
+                drop(library);
+                self.remove_season(season, cleanup, deactivate_feed);
+            }
+            Some(LibraryAction::BackfillGap(gap)) => {
+                drop(library);
+                self.backfill_episode_gap(gap);
+            }
+            Some(LibraryAction::ArchiveSeason { season, archived }) => {
+                drop(library);
+                self.archive_season(season, archived);
+            }
+            None => {}
+        }
     }
 }
 
@@ -142,6 +379,10 @@ impl LibraryApp {
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct AppAnimeEpisode {
     pub episode_hash: String,
+    pub mikan_subject_id: i32,      // of its own season, which may be merged into the season it is shown in
+    pub mikan_subgroup_id: i32,
+    pub bangumi_subject_id: i32,
+    pub moved: bool,                // moved to the season it is shown in by the user
     pub disp_episode_num: i32,
     pub bangumi_sort: String,
     pub bangumi_airdate: String,
@@ -149,6 +390,23 @@ pub struct AppAnimeEpisode {
     pub bangumi_name_cn: String,
     pub bangumi_ep_type: BangumiEpisodeType,
     pub bangumi_status: BangumiEpisodeStatus,
+    pub state: ItemState,
+}
+
+impl AppAnimeEpisode {
+    /// Episode title in the preferred title languages, only Bangumi titles are known for episodes.
+    pub fn disp_title(&self, title_languages: &[TitleLanguage]) -> String {
+        title_languages.iter()
+            .filter_map(|language| match language {
+                TitleLanguage::BangumiNameCn => Some(&self.bangumi_name_cn),
+                TitleLanguage::BangumiName => Some(&self.bangumi_name),
+                _ => None,
+            })
+            .chain([&self.bangumi_name_cn, &self.bangumi_name])
+            .find(|title| !title.is_empty())
+            .cloned()
+            .unwrap_or_default()
+    }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -156,6 +414,7 @@ pub struct AppAnimeSeason {
     pub mikan_subject_id: i32,
     pub mikan_subgroup_id: i32,
     pub bangumi_subject_id: i32,
+    pub tmdb_series_id: i64,
     pub disp_season_name: String,
     pub disp_season_num: i32,
     pub disp_thumbnail_url: String,
@@ -163,15 +422,38 @@ pub struct AppAnimeSeason {
     pub conf_season_num: i32,
     pub conf_tmdb_episode_offset: i32,
     pub conf_bangumi_episode_offset: i32,
+    pub split_part: bool,                               // the episodes split from the season as another season
+    pub merged_seasons: Vec<((i32, i32), String)>,      // ((mikan_subject_id, mikan_subgroup_id), name) of the seasons merged into this one
     pub episodes: Vec<AppAnimeEpisode>,
+    pub gaps: Vec<EpisodeGap>,                          // missing episodes shown in this season, see `auto_episode_gap_detect`
+    pub status: SeasonStatus,
+}
+
+impl AppAnimeSeason {
+    /// Archived seasons are hidden unless configured otherwise, or jumped to from the search
+    pub fn is_shown(&self, show_archived: bool, jump_to_season: Option<(i32, i32)>) -> bool {
+        show_archived || self.status != SeasonStatus::Archived
+            || jump_to_season == Some((self.mikan_subject_id, self.mikan_subgroup_id))
+    }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct AppAnimeSeries {
+    pub series_id: i32,     // -1 if the season is not linked to a series yet
     pub disp_series_name: String,
     pub seasons: Vec<AppAnimeSeason>,
 }
 
+impl AppAnimeSeries {
+    /// Whether a season is shown in this series, on its own, merged, or by its episodes and gaps shown elsewhere
+    pub fn involves(&self, season: (i32, i32)) -> bool {
+        self.seasons.iter().any(|x| (x.mikan_subject_id, x.mikan_subgroup_id) == season
+            || x.merged_seasons.iter().any(|(merged, _)| *merged == season)
+            || x.episodes.iter().any(|episode| (episode.mikan_subject_id, episode.mikan_subgroup_id) == season)
+            || x.gaps.iter().any(|gap| (gap.mikan_subject_id, gap.mikan_subgroup_id) == season))
+    }
+}
+
 // AnimeSeason -> AppAnimeSeason
 impl From<AnimeSeason> for AppAnimeSeason {
     fn from(season: AnimeSeason) -> Self {
@@ -179,6 +461,7 @@ impl From<AnimeSeason> for AppAnimeSeason {
             mikan_subject_id: season.mikan_subject_id,
             mikan_subgroup_id: season.mikan_subgroup_id,
             bangumi_subject_id: season.bangumi_subject_id,
+            tmdb_series_id: season.tmdb_series_id as i64,
             disp_season_name: season.disp_season_name,
             disp_season_num: season.disp_season_num,
             disp_thumbnail_url: season.mikan_subject_image,
@@ -191,6 +474,10 @@ impl From<AnimeSeason> for AppAnimeSeason {
             conf_tmdb_episode_offset: season.conf_tmdb_episode_offset,
             conf_bangumi_episode_offset: season.conf_bangumi_episode_offset,
             conf_season_num: season.conf_season_num,
+            split_part: false,
+            merged_seasons: vec![],
+            gaps: vec![],
+            status: season.status,
         }
     }
 }
@@ -198,8 +485,13 @@ impl From<AnimeSeason> for AppAnimeSeason {
 // AnimeSeasonItem -> AppAnimeEpisode
 impl From<crate::module::database::library::AnimeSeasonItem> for AppAnimeEpisode {
     fn from(episode: crate::module::database::library::AnimeSeasonItem) -> Self {
+        let moved = episode.conf_placement().is_some();
         Self {
             episode_hash: episode.mikan_item_uuid,
+            mikan_subject_id: episode.mikan_subject_id,
+            mikan_subgroup_id: episode.mikan_subgroup_id,
+            bangumi_subject_id: -1,
+            moved,
             disp_episode_num: episode.disp_episode_num,
             bangumi_sort: "".to_string(),
             bangumi_airdate: "".to_string(),
@@ -207,6 +499,7 @@ impl From<crate::module::database::library::AnimeSeasonItem> for AppAnimeEpisode
             bangumi_name_cn: "".to_string(),
             bangumi_ep_type: BangumiEpisodeType::from(episode.bangumi_episode_type),
             bangumi_status: BangumiEpisodeStatus::NotCollected,
+            state: episode.state,
         }
     }
 }
\ No newline at end of file
@@ -1,5 +1,7 @@
+pub mod activityapp;
 pub mod logapp;
 pub mod settingsapp;
 pub mod libraryapp;
 pub mod panel;
+pub mod searchapp;
 pub mod season_conf_dialog_window;
\ No newline at end of file
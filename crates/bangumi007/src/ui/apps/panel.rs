@@ -5,6 +5,8 @@
 pub enum Panel {
     Library,
     Log,
+    Activity,
+    Search,
     Settings,
 }
 
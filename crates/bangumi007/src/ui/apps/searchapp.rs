@@ -0,0 +1,63 @@
+use eframe::egui;
+use egui::{Color32, RichText};
+
+use crate::module::database::search::{search_library, SearchResult};
+
+#[derive(Debug, Clone, Default)]
+pub struct SearchApp {
+    pub query: String,
+    result: Option<SearchResult>,   // None if the query changed since the last search
+}
+
+impl SearchApp {
+    /// Search again on the next frame
+    pub fn reload(&mut self) {
+        self.result = None;
+    }
+
+    /// ## Output
+    ///
+    /// (mikan_subject_id, mikan_subgroup_id) of the season to jump to in the library : `Option<(i32, i32)>`
+    pub(crate) fn ui(&mut self, ui: &mut egui::Ui) -> Option<(i32, i32)> {
+        let mut jump_to_season = None;
+        let query = self.query.clone();
+        let result = self.result.get_or_insert_with(|| search_library(&query));
+        egui::ScrollArea::vertical().auto_shrink([false, false]).show(ui, |ui| {
+            ui.vertical(|ui| {
+                ui.heading(RichText::new(format!("季度 ({})", result.seasons.len())).size(14.0));
+                if result.seasons.is_empty() {
+                    ui.label("无匹配的季度");
+                }
+                for season in result.seasons.iter() {
+                    let title = match season.disp_subgroup_name.as_str() {
+                        "" => format!("{} 第 {} 季", season.disp_series_name, season.disp_season_num),
+                        subgroup => format!("{} 第 {} 季 [{}]", season.disp_series_name, season.disp_season_num, subgroup),
+                    };
+                    if ui.link(title).on_hover_text("在媒体库中显示").clicked() {
+                        jump_to_season = Some((season.mikan_subject_id, season.mikan_subgroup_id));
+                    }
+                }
+                ui.separator();
+
+                ui.heading(RichText::new(format!("发布 ({})", result.releases.len())).size(14.0));
+                if result.releases.is_empty() {
+                    ui.label("无匹配的发布");
+                    return;
+                }
+                egui::Grid::new("search_release_grid").striped(true).num_columns(3).show(ui, |ui| {
+                    for release in result.releases.iter() {
+                        ui.label(&release.item.mikan_item_title);
+                        ui.label(&release.item.mikan_item_pub_date);
+                        match release.state {
+                            Some(state) if state.is_active() => ui.label(RichText::new(state.disp_name()).color(Color32::LIGHT_GREEN)),
+                            Some(state) => ui.label(state.disp_name()),
+                            None => ui.label(RichText::new("未收录").color(Color32::GRAY)),
+                        };
+                        ui.end_row();
+                    }
+                });
+            });
+        });
+        jump_to_season
+    }
+}
@@ -5,8 +5,59 @@ use std::sync::{Arc, RwLock};
 use eframe::egui;
 use eframe::egui::{Align, RichText};
 
+use crate::module::database::cache::tmdb::{read_tmdb_candidates, TMDBCandidate};
+use crate::module::database::subject_override::{get_subject_override, MikanSubjectOverride};
+use crate::module::database::library::{EpisodeOffsetProposalRecord, read_episode_offset_proposal};
+use crate::module::library::episode_offset::OffsetConfidence;
 use crate::ui::apps::libraryapp::AppAnimeSeries;
-use crate::ui::binding::season_conf::{SeasonConf, update_conf};
+use crate::ui::binding::season_conf::{choose_tmdb_series, SeasonConf, update_conf};
+
+/// Text inputs of the subject override, empty means not overridden.
+#[derive(Debug, Clone, Default)]
+pub struct SubjectOverrideInput {
+    pub bangumi_subject_id: String,
+    pub tmdb_series_id: String,
+    pub tmdb_season_num: String,
+    pub disp_series_name: String,
+    pub disp_season_name: String,
+}
+
+impl SubjectOverrideInput {
+    pub fn from_override(subject_override: &MikanSubjectOverride) -> Self {
+        let to_input = |x: Option<String>| x.unwrap_or_default();
+        Self {
+            bangumi_subject_id: to_input(subject_override.bangumi_subject_id.map(|x| x.to_string())),
+            tmdb_series_id: to_input(subject_override.tmdb_series_id.map(|x| x.to_string())),
+            tmdb_season_num: to_input(subject_override.tmdb_season_num.map(|x| x.to_string())),
+            disp_series_name: to_input(subject_override.disp_series_name.clone()),
+            disp_season_name: to_input(subject_override.disp_season_name.clone()),
+        }
+    }
+
+    /// `None` if any of the ids is not a number.
+    pub fn to_override(&self, mikan_subject_id: i32) -> Option<MikanSubjectOverride> {
+        fn parse_id<T: std::str::FromStr>(input: &str) -> Option<Option<T>> {
+            let input = input.trim();
+            if input.is_empty() {
+                Some(None)
+            } else {
+                input.parse::<T>().ok().map(Some)
+            }
+        }
+        let parse_name = |input: &str| {
+            let input = input.trim();
+            if input.is_empty() { None } else { Some(input.to_string()) }
+        };
+        Some(MikanSubjectOverride {
+            mikan_subject_id,
+            bangumi_subject_id: parse_id(&self.bangumi_subject_id)?,
+            tmdb_series_id: parse_id(&self.tmdb_series_id)?,
+            tmdb_season_num: parse_id(&self.tmdb_season_num)?,
+            disp_series_name: parse_name(&self.disp_series_name),
+            disp_season_name: parse_name(&self.disp_season_name),
+        })
+    }
+}
 
 #[derive(Debug, Clone, Default)]
 pub struct SeasonConfDialogWindow {
@@ -22,6 +73,12 @@ pub struct SeasonConfDialogWindow {
     pub ep_num_max: i32,
     pub conf_tmdb_ep_offset: i32,
     pub conf_bangumi_ep_offset: i32,
+    pub offset_proposal: Option<EpisodeOffsetProposalRecord>,
+    pub bangumi_subject_id: i32,
+    pub tmdb_series_id: i64,
+    pub tmdb_candidates: Vec<TMDBCandidate>,
+    pub subject_override: MikanSubjectOverride,
+    pub subject_override_input: SubjectOverrideInput,
 }
 
 impl SeasonConfDialogWindow {
@@ -39,6 +96,12 @@ impl SeasonConfDialogWindow {
             ep_num_max: -1,
             conf_tmdb_ep_offset: 0,
             conf_bangumi_ep_offset: 0,
+            offset_proposal: None,
+            bangumi_subject_id: -1,
+            tmdb_series_id: -1,
+            tmdb_candidates: Vec::new(),
+            subject_override: MikanSubjectOverride::default(),
+            subject_override_input: SubjectOverrideInput::default(),
         }
     }
 
@@ -62,8 +125,18 @@ impl SeasonConfDialogWindow {
 
                         self.conf_tmdb_ep_offset = season.conf_tmdb_episode_offset;
                         self.conf_bangumi_ep_offset = season.conf_bangumi_episode_offset;
-                        self.ep_num_min = season.episodes.iter().map(|e| e.disp_episode_num - self.conf_tmdb_ep_offset).min().unwrap();
-                        self.ep_num_max = season.episodes.iter().map(|e| e.disp_episode_num - self.conf_tmdb_ep_offset).max().unwrap();
+                        // A season may have no active items, e.g. all of them filtered
+                        self.ep_num_min = season.episodes.iter().map(|e| e.disp_episode_num - self.conf_tmdb_ep_offset).min().unwrap_or(0);
+                        self.ep_num_max = season.episodes.iter().map(|e| e.disp_episode_num - self.conf_tmdb_ep_offset).max().unwrap_or(0);
+                        self.offset_proposal = read_episode_offset_proposal(self.subject_id, self.subgroup_id);
+                        self.bangumi_subject_id = season.bangumi_subject_id;
+                        self.tmdb_series_id = season.tmdb_series_id;
+                        self.tmdb_candidates = read_tmdb_candidates(season.bangumi_subject_id);
+                        self.subject_override = get_subject_override(self.subject_id).unwrap_or(MikanSubjectOverride {
+                            mikan_subject_id: self.subject_id,
+                            ..Default::default()
+                        });
+                        self.subject_override_input = SubjectOverrideInput::from_override(&self.subject_override);
                         break 'outer;
                     }
                 }
@@ -165,8 +238,79 @@ impl SeasonConfDialogWindow {
                         //     ui.label("(未更改)");
                         // }
                         ui.end_row();
+                        if let Some(proposal) = &self.offset_proposal {
+                            ui.label("推断偏移：").on_hover_text("根据Bangumi与TMDB剧集列表推断的偏移量");
+                            ui.horizontal_centered(|ui| {
+                                ui.label(format!("TMDB {:+} / Bgm {:+} ({})",
+                                                 proposal.tmdb_episode_offset,
+                                                 proposal.bangumi_episode_offset,
+                                                 OffsetConfidence::from(proposal.confidence).disp_name()))
+                                    .on_hover_text(&proposal.explanation);
+                                let button = ui.button("采用").on_hover_text(&proposal.explanation);
+                                if button.clicked() {
+                                    self.conf_tmdb_ep_offset = proposal.tmdb_episode_offset;
+                                    self.conf_bangumi_ep_offset = proposal.bangumi_episode_offset;
+                                }
+                            });
+                            ui.end_row();
+                        }
+                        if !self.tmdb_candidates.is_empty() {
+                            ui.label("TMDB匹配：").on_hover_text("TMDB搜索结果置信度较低，请确认匹配的剧集");
+                            ui.horizontal_centered(|ui| {
+                                let selected_text = self.tmdb_candidates.iter()
+                                    .find(|c| c.tmdb_series_id == self.tmdb_series_id)
+                                    .map_or("(未匹配)".to_string(), |c| c.disp_name());
+                                egui::ComboBox::from_id_source("tmdb_candidate")
+                                    .selected_text(selected_text)
+                                    .width(160.)
+                                    .show_ui(ui, |ui| {
+                                        for candidate in self.tmdb_candidates.iter() {
+                                            ui.selectable_value(&mut self.tmdb_series_id, candidate.tmdb_series_id, candidate.disp_name())
+                                                .on_hover_text(format!("{} (匹配度 {:.2})", candidate.tmdb_original_name, candidate.score));
+                                        }
+                                    });
+                                let button = ui.button("确认").on_hover_text("使用所选TMDB剧集并重新刮削");
+                                if button.clicked() {
+                                    choose_tmdb_series(self.subject_id, self.bangumi_subject_id, self.tmdb_series_id);
+                                    self.tmdb_candidates = Vec::new();
+                                }
+                            });
+                            ui.end_row();
+                        }
                     },
                     );
+                egui::CollapsingHeader::new("元数据覆盖")
+                    .default_open(!self.subject_override.is_empty())
+                    .show(ui, |ui| {
+                        egui::Grid::new("season_conf_dialog_override")
+                            .num_columns(2)
+                            .min_col_width(120.)
+                            .min_row_height(25.)
+                            .striped(true)
+                            .show(ui, |ui| {
+                                let hint = "(自动)";
+                                let input = &mut self.subject_override_input;
+                                ui.label("Bangumi ID：").on_hover_text("固定Bangumi条目，留空则从蜜柑计划解析");
+                                ui.add(egui::TextEdit::singleline(&mut input.bangumi_subject_id).hint_text(hint).desired_width(160.));
+                                ui.end_row();
+                                ui.label("TMDB ID：").on_hover_text("固定TMDB剧集，留空则自动搜索");
+                                ui.add(egui::TextEdit::singleline(&mut input.tmdb_series_id).hint_text(hint).desired_width(160.));
+                                ui.end_row();
+                                ui.label("TMDB季度：").on_hover_text("固定TMDB季度，留空则根据名称匹配");
+                                ui.add(egui::TextEdit::singleline(&mut input.tmdb_season_num).hint_text(hint).desired_width(160.));
+                                ui.end_row();
+                                ui.label("显示名称：");
+                                ui.add(egui::TextEdit::singleline(&mut input.disp_series_name).hint_text(hint).desired_width(160.));
+                                ui.end_row();
+                                ui.label("季度名称：");
+                                ui.add(egui::TextEdit::singleline(&mut input.disp_season_name).hint_text(hint).desired_width(160.));
+                                ui.end_row();
+                            });
+                        if self.subject_override_input.to_override(self.subject_id).is_none() {
+                            ui.colored_label(ui.visuals().error_fg_color, "ID与季度须为数字");
+                        }
+                    });
+                let subject_override = self.subject_override_input.to_override(self.subject_id);
                 ui.add_space(8.);
                 ui.columns(2, |cols| {
                     cols[0].vertical_centered(|ui| {
@@ -180,7 +324,7 @@ impl SeasonConfDialogWindow {
                         }
                     });
                     cols[1].vertical_centered(|ui| {
-                        let btn_apply = ui.button("应用").on_hover_text("应用修改");
+                        let btn_apply = ui.add_enabled(subject_override.is_some(), egui::Button::new("应用")).on_hover_text("应用修改");
                         if btn_apply.clicked() {
                             update_conf(SeasonConf {
                                 subject_id: self.subject_id,
@@ -192,8 +336,8 @@ impl SeasonConfDialogWindow {
                                 ep_num_max: self.ep_num_max,
                                 conf_tmdb_ep_offset: self.conf_tmdb_ep_offset,
                                 conf_bangumi_ep_offset: self.conf_bangumi_ep_offset,
-                            },
-                                        library.clone());
+                                subject_override: subject_override.clone().filter(|x| *x != self.subject_override),
+                            });
                             self.open_my = false;
                             self.inited = false;
                             self.subgroup_id = -1;
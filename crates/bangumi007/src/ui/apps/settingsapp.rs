@@ -1,14 +1,401 @@
 // ----------------------------------------------------------------------------
 
+use std::sync::{Arc, RwLock};
+use std::thread;
+
 use eframe::egui;
 
-use crate::ui::apps::libraryapp::AppAnimeSeries;
+use crate::module::config::{BackupConfig, CONFIG, DisplayConfig, DownloaderKind, MetadataSource, TitleLanguage};
+use crate::module::config::data_dir::{DATA_DIR, DATA_DIR_ARG, PROFILE_ARG};
+use crate::module::database::backup::create_scheduled_backup;
+use crate::module::database::cache::xref::refresh_anime_xref;
+use crate::module::database::export::{export_library, import_library, ImportConflict, ImportConflictPolicy};
+use crate::module::database::subject_override::{export_subject_overrides, import_subject_overrides};
+use crate::ui::apps::libraryapp::{AppAnimeSeries, LibraryApp};
+use crate::ui::binding::settings::update_display_config;
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default)]
 pub struct SettingsApp {
-    library: Vec<AppAnimeSeries>,
+    subject_override_status: String,
+    title_languages: Option<Vec<TitleLanguage>>,      // editing copy of the display config
+    xref_paths: Option<String>,                       // editing copy of the xref datasets, one path per line
+    xref_status: Arc<RwLock<String>>,                 // set by the import thread
+    library_backup: Arc<RwLock<LibraryBackupStatus>>, // set by the export, import and backup threads
+    backup_config: Option<BackupConfig>,              // editing copy of the backup config
+    library_roots: Option<String>,                    // editing copy of the extra library roots, one path per line
+    local_import_status: String,
+    profiles: Option<Vec<String>>,                    // listed once, profiles only take effect after a restart
+}
+
+#[derive(Debug, Clone, Default)]
+struct LibraryBackupStatus {
+    running: bool,
+    message: String,
+    import_conflicts: Vec<ImportConflict>,
+}
+
+/// Run an export, import or backup in a thread, one at a time, the task updates the status when done
+fn spawn_library_backup(status: &Arc<RwLock<LibraryBackupStatus>>, ctx: &egui::Context, task: impl FnOnce(&mut LibraryBackupStatus) + Send + 'static) {
+    let mut result = {
+        let mut status = status.write().unwrap();
+        status.running = true;
+        status.message = "正在处理".to_string();
+        status.clone()
+    };
+    let status = status.clone();
+    let ctx = ctx.clone();
+    thread::spawn(move || {
+        task(&mut result);
+        result.running = false;
+        *status.write().unwrap() = result;
+        ctx.request_repaint();
+    });
 }
 
 impl SettingsApp {
-    pub(crate) fn ui(&mut self, ui: &mut egui::Ui) {}
+    pub(crate) fn ui(&mut self, ui: &mut egui::Ui, library: Arc<RwLock<Vec<AppAnimeSeries>>>) {
+        ui.vertical(|ui| {
+            self.title_language_ui(ui);
+            ui.add_space(8.);
+            self.downloader_ui(ui);
+            ui.add_space(8.);
+            self.metadata_provider_ui(ui);
+            ui.add_space(8.);
+            self.episode_gap_ui(ui);
+            ui.add_space(8.);
+            self.season_status_ui(ui);
+            ui.add_space(8.);
+            ui.heading("元数据覆盖");
+            let subject_override_path = DATA_DIR.config_dir().join("subject_overrides.json");
+            let subject_override_path_text = subject_override_path.display().to_string();
+            ui.horizontal(|ui| {
+                if ui.button("导出").on_hover_text(&subject_override_path_text).clicked() {
+                    self.subject_override_status = match export_subject_overrides(&subject_override_path) {
+                        Ok(count) => format!("已导出 {} 条覆盖", count),
+                        Err(e) => {
+                            log::error!("Failed to export subject overrides: {:?}", e);
+                            "导出失败".to_string()
+                        }
+                    };
+                }
+                if ui.button("导入").on_hover_text(&subject_override_path_text).clicked() {
+                    self.subject_override_status = match import_subject_overrides(&subject_override_path) {
+                        Ok(count) => format!("已导入 {} 条覆盖，下次更新订阅时生效", count),
+                        Err(e) => {
+                            log::error!("Failed to import subject overrides: {:?}", e);
+                            "导入失败".to_string()
+                        }
+                    };
+                }
+                ui.label(&self.subject_override_status);
+            });
+            ui.add_space(8.);
+            self.library_backup_ui(ui);
+            ui.add_space(8.);
+            self.local_import_ui(ui, library);
+            ui.add_space(8.);
+            self.data_dir_ui(ui);
+        });
+    }
+
+    fn library_backup_ui(&mut self, ui: &mut egui::Ui) {
+        let library_export_path = DATA_DIR.export_dir().join("library.json");
+        let library_export_path_text = library_export_path.display().to_string();
+        let running = self.library_backup.read().unwrap().running;
+
+        ui.heading("媒体库备份").on_hover_text("导出季度、季度设置、剧集与订阅，导入时合并到当前媒体库");
+        ui.horizontal(|ui| {
+            if ui.add_enabled(!running, egui::Button::new("导出")).on_hover_text(&library_export_path_text).clicked() {
+                let path = library_export_path.clone();
+                spawn_library_backup(&self.library_backup, ui.ctx(), move |status| {
+                    status.message = match export_library(&path) {
+                        Ok(count) => format!("已导出 {} 季", count),
+                        Err(e) => {
+                            log::error!("Failed to export library: {:?}", e);
+                            "导出失败".to_string()
+                        }
+                    };
+                });
+            }
+            let mut policy = None;
+            if ui.add_enabled(!running, egui::Button::new("导入（保留本地设置）")).on_hover_text(&library_export_path_text).clicked() {
+                policy = Some(ImportConflictPolicy::KeepLocal);
+            }
+            if ui.add_enabled(!running, egui::Button::new("导入（覆盖本地设置）")).on_hover_text(&library_export_path_text).clicked() {
+                policy = Some(ImportConflictPolicy::UseImported);
+            }
+            if let Some(policy) = policy {
+                let path = library_export_path.clone();
+                spawn_library_backup(&self.library_backup, ui.ctx(), move |status| {
+                    let _task = LibraryApp::lock_task();
+                    match import_library(&path, policy) {
+                        Ok(report) => {
+                            status.message = format!("已导入 {} 季，{} 集，{} 个订阅，{} 处冲突",
+                                                     report.seasons_added, report.items_added, report.subscriptions_added, report.conflicts.len());
+                            status.import_conflicts = report.conflicts;
+                        }
+                        Err(e) => {
+                            log::error!("Failed to import library: {:?}", e);
+                            status.message = "导入失败".to_string();
+                        }
+                    }
+                });
+            }
+            ui.label(self.library_backup.read().unwrap().message.as_str());
+        });
+        let import_conflicts = self.library_backup.read().unwrap().import_conflicts.clone();
+        if !import_conflicts.is_empty() {
+            egui::CollapsingHeader::new("导入冲突").show(ui, |ui| {
+                for conflict in import_conflicts.iter() {
+                    ui.label(format!("{} {}：本地 {}，导入 {}", conflict.entry, conflict.field, conflict.local, conflict.imported));
+                }
+            });
+        }
+
+        let saved_backup_config = CONFIG.read().unwrap().backup_config.clone();
+        let backup_config = self.backup_config.get_or_insert_with(|| saved_backup_config.clone());
+        // A dragged or typed value is saved once it is let go, not on every step
+        let committed = |response: egui::Response| response.drag_stopped() || response.lost_focus()
+            || (response.changed() && !response.dragged() && !response.has_focus());
+        let mut commit = false;
+        ui.horizontal(|ui| {
+            commit |= ui.checkbox(&mut backup_config.enabled, "定时备份数据库").changed();
+            commit |= committed(ui.add(egui::DragValue::new(&mut backup_config.interval_hours).clamp_range(1..=720).suffix(" 小时")));
+            ui.label("保留");
+            commit |= committed(ui.add(egui::DragValue::new(&mut backup_config.keep).clamp_range(1..=100).suffix(" 份")));
+            if ui.add_enabled(!running, egui::Button::new("立即备份")).on_hover_text(DATA_DIR.database_backup_dir().display().to_string()).clicked() {
+                let keep = backup_config.keep;
+                spawn_library_backup(&self.library_backup, ui.ctx(), move |status| {
+                    status.message = match create_scheduled_backup(keep) {
+                        Ok(_) => "已备份数据库".to_string(),
+                        Err(e) => {
+                            log::error!("Failed to back up database: {:?}", e);
+                            "备份失败".to_string()
+                        }
+                    };
+                });
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("动态保留");
+            let response = ui.add(egui::DragValue::new(&mut backup_config.activity_keep).clamp_range(100..=1000000).suffix(" 条"))
+                .on_hover_text("更早的动态记录将被删除");
+            commit |= committed(response);
+        });
+        if commit {
+            if *backup_config != saved_backup_config {
+                let mut config = CONFIG.write().unwrap();
+                config.backup_config = backup_config.clone();
+                config.save();
+            }
+            self.backup_config = None;
+        }
+    }
+
+    fn local_import_ui(&mut self, ui: &mut egui::Ui, library: Arc<RwLock<Vec<AppAnimeSeries>>>) {
+        let saved_library_roots = CONFIG.read().unwrap().downloader_config.library_roots.join("\n");
+        let library_roots = self.library_roots.get_or_insert_with(|| saved_library_roots.clone());
+
+        ui.heading("导入本地文件").on_hover_text("扫描下载目录与以下目录中按 剧集/Season N/ 整理的番剧，匹配Bangumi条目后作为已完成剧集加入媒体库");
+        ui.label("其他媒体库目录：").on_hover_text("每行一个路径");
+        ui.add(egui::TextEdit::multiline(library_roots).desired_rows(2).hint_text("D:/Anime"));
+        let mut import = false;
+        ui.horizontal(|ui| {
+            if ui.button("保存并扫描").clicked() {
+                import = true;
+            }
+            ui.label(&self.local_import_status);
+        });
+        if import {
+            if *library_roots != saved_library_roots {
+                let mut config = CONFIG.write().unwrap();
+                config.downloader_config.library_roots = library_roots.lines()
+                    .map(|x| x.trim().to_string())
+                    .filter(|x| !x.is_empty())
+                    .collect();
+                config.save();
+            }
+            LibraryApp { library }.import_local_library();
+            self.local_import_status = "正在后台扫描，结果见活动记录".to_string();
+        }
+    }
+
+    fn data_dir_ui(&mut self, ui: &mut egui::Ui) {
+        ui.heading("数据目录").on_hover_text(format!("启动时以 {} <路径> 与 {} <名称> 指定，重启后生效", DATA_DIR_ARG, PROFILE_ARG));
+        ui.label(format!("当前档案：{}", DATA_DIR.profile));
+        ui.label(format!("位置：{}", DATA_DIR.profile_dir().display()));
+        let profiles = self.profiles.get_or_insert_with(|| DATA_DIR.list_profiles());
+        ui.label(format!("全部档案：{}", profiles.join("，")));
+    }
+
+    fn title_language_ui(&mut self, ui: &mut egui::Ui) {
+        let saved = CONFIG.read().unwrap().display_config.title_languages.clone();
+        let title_languages = self.title_languages.get_or_insert_with(|| saved.clone());
+
+        ui.heading("标题语言").on_hover_text("按顺序选用第一个可用的标题，用于媒体库显示与文件命名");
+        let mut move_up = None;
+        let mut remove = None;
+        for (i, language) in title_languages.iter().enumerate() {
+            ui.horizontal(|ui| {
+                ui.label(format!("{}. {}", i + 1, language.disp_name()));
+                if ui.add_enabled(i > 0, egui::Button::new("⬆")).clicked() {
+                    move_up = Some(i);
+                }
+                if ui.button("x").on_hover_text("移除").clicked() {
+                    remove = Some(i);
+                }
+            });
+        }
+        if let Some(i) = move_up {
+            title_languages.swap(i - 1, i);
+        }
+        if let Some(i) = remove {
+            title_languages.remove(i);
+        }
+        ui.horizontal_wrapped(|ui| {
+            for language in TitleLanguage::ALL.iter() {
+                if !title_languages.contains(language) && ui.button(format!("+ {}", language.disp_name())).clicked() {
+                    title_languages.push(*language);
+                }
+            }
+        });
+        let mut reset = false;
+        ui.horizontal(|ui| {
+            let changed = *title_languages != saved;
+            if ui.add_enabled(changed, egui::Button::new("应用")).clicked() {
+                update_display_config(DisplayConfig { title_languages: title_languages.clone(), ..CONFIG.read().unwrap().display_config.clone() });
+            }
+            if ui.add_enabled(changed, egui::Button::new("取消")).clicked() {
+                reset = true;
+            }
+        });
+        if reset {
+            self.title_languages = None;
+        }
+    }
+
+    fn downloader_ui(&mut self, ui: &mut egui::Ui) {
+        let mut kind = CONFIG.read().unwrap().downloader_config.kind;
+
+        ui.heading("下载器").on_hover_text("地址、端口与账号在配置文件中设置，aria2 以密码作为RPC密钥");
+        ui.horizontal(|ui| {
+            ui.label("下载器类型：");
+            egui::ComboBox::from_id_source("downloader_kind")
+                .selected_text(kind.disp_name())
+                .show_ui(ui, |ui| {
+                    for option in DownloaderKind::ALL {
+                        if ui.selectable_value(&mut kind, option, option.disp_name()).changed() {
+                            let mut config = CONFIG.write().unwrap();
+                            config.downloader_config.kind = kind;
+                            config.save();
+                        }
+                    }
+                });
+        });
+        if !kind.can_rename() {
+            ui.label("aria2 无法重命名或移动已下载的文件，文件将保留发布时的名称");
+        }
+    }
+
+    fn episode_gap_ui(&mut self, ui: &mut egui::Ui) {
+        let mut auto_backfill_gaps = CONFIG.read().unwrap().rss_config.auto_backfill_gaps;
+
+        ui.heading("缺集补全").on_hover_text("更新订阅时按Bangumi的正片列表与播出日期检查各季度缺失的剧集，并在同一番剧其他字幕组的发布中查找替代");
+        if ui.checkbox(&mut auto_backfill_gaps, "自动使用其他字幕组的发布补全缺集").changed() {
+            let mut config = CONFIG.write().unwrap();
+            config.rss_config.auto_backfill_gaps = auto_backfill_gaps;
+            config.save();
+        }
+    }
+
+    fn season_status_ui(&mut self, ui: &mut egui::Ui) {
+        let mut auto_archive_complete = CONFIG.read().unwrap().rss_config.auto_archive_complete;
+        let mut show_archived_seasons = CONFIG.read().unwrap().display_config.show_archived_seasons;
+
+        ui.heading("完结季度").on_hover_text("更新订阅时按Bangumi的播出日期与已下载的剧集更新季度状态，已下载全部或已归档的季度不再更新，其订阅源将被停用");
+        if ui.checkbox(&mut auto_archive_complete, "自动归档已下载全部剧集的季度").changed() {
+            let mut config = CONFIG.write().unwrap();
+            config.rss_config.auto_archive_complete = auto_archive_complete;
+            config.save();
+        }
+        if ui.checkbox(&mut show_archived_seasons, "在媒体库中显示已归档的季度").changed() {
+            let mut config = CONFIG.write().unwrap();
+            config.display_config.show_archived_seasons = show_archived_seasons;
+            config.save();
+        }
+    }
+
+    fn metadata_provider_ui(&mut self, ui: &mut egui::Ui) {
+        let mut parser_config = CONFIG.read().unwrap().parser_config.clone();
+        let mut changed = false;
+
+        ui.heading("元数据来源").on_hover_text("除Bangumi与TMDB外，额外获取标题与ID的来源，对新条目生效");
+        ui.horizontal(|ui| {
+            for source in [MetadataSource::AniList, MetadataSource::MyAnimeList] {
+                let mut enabled = parser_config.metadata_providers.contains(&source);
+                if ui.checkbox(&mut enabled, source.disp_name()).changed() {
+                    if enabled {
+                        parser_config.metadata_providers.push(source);
+                    } else {
+                        parser_config.metadata_providers.retain(|x| *x != source);
+                    }
+                    changed = true;
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("文件夹ID标记：").on_hover_text("在剧集文件夹名中写入ID，如 [tmdbid-1234]，供媒体服务器刮削插件识别");
+            let selected_text = parser_config.folder_id_source.map_or("无", |x| x.disp_name());
+            egui::ComboBox::from_id_source("folder_id_source")
+                .selected_text(selected_text)
+                .show_ui(ui, |ui| {
+                    changed |= ui.selectable_value(&mut parser_config.folder_id_source, None, "无").changed();
+                    for source in MetadataSource::ALL {
+                        changed |= ui.selectable_value(&mut parser_config.folder_id_source, Some(source), source.disp_name()).changed();
+                    }
+                });
+        });
+
+        let saved_xref_paths = parser_config.xref_paths.join("\n");
+        let xref_paths = self.xref_paths.get_or_insert_with(|| saved_xref_paths.clone());
+        ui.label("离线ID对照数据：").on_hover_text("anime-offline-database 或 Bangumi-MAL 等ID对照JSON文件，每行一个路径，靠前的优先；文件更新后在下次更新订阅时重新导入");
+        ui.add(egui::TextEdit::multiline(xref_paths).desired_rows(2).hint_text("data/xref/anime-offline-database.json"));
+        let mut import = false;
+        ui.horizontal(|ui| {
+            if ui.button("保存并导入").clicked() {
+                import = true;
+            }
+            ui.label(self.xref_status.read().unwrap().as_str());
+        });
+        if import {
+            parser_config.xref_paths = xref_paths.lines()
+                .map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty())
+                .collect();
+            changed = true;
+        }
+
+        if changed {
+            let mut config = CONFIG.write().unwrap();
+            config.parser_config = parser_config;
+            config.save();
+        }
+        if import {
+            self.xref_paths = None;
+            *self.xref_status.write().unwrap() = "正在导入".to_string();
+            let xref_status = self.xref_status.clone();
+            let ctx = ui.ctx().clone();
+            thread::spawn(move || {
+                let status = match refresh_anime_xref(true) {
+                    Ok(count) => format!("已导入 {} 条对照", count),
+                    Err(e) => {
+                        log::error!("Failed to import xref datasets: {:?}", e);
+                        "导入失败".to_string()
+                    }
+                };
+                *xref_status.write().unwrap() = status;
+                ctx.request_repaint();
+            });
+        }
+    }
 }
@@ -1,15 +1,300 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, mpsc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time::Duration;
+use eframe::egui;
 use rand::Rng;
-use crate::module::database::library::{AnimeSeason, read_all_items, read_season_items, read_seasons};
-use crate::module::downloader::qbittorrent::{clean_empty_folders, download_items, rename_torrents_files};
-use crate::module::library::{auto_season_config_clean, update_library};
+use crate::module::database::episode_gap::{EpisodeGap, read_episode_gaps, read_season_gaps};
+use crate::module::database::library::{AnimeSeason, AnimeSeasonItem, read_all_items, read_placed_items, read_season_items, read_seasons};
+use crate::module::database::library_event::{LibraryEvent, subscribe_library_events};
+use crate::module::database::series::read_series_list;
+use crate::module::library::arrangement::{arrange_library, Arrangement, item_placement};
+use crate::module::library::disk_import;
+use crate::module::library::removal::{remove_season, restore_resubscribed_seasons, SeasonCleanup};
+use crate::module::library::season_status::{archive_season, auto_season_status_update, fetch_season_bangumi_episodes, retire_finished_feeds};
+use crate::module::downloader::{clean_empty_folders, download_items, rename_torrents_files};
+use crate::module::database::cache::xref::refresh_anime_xref;
+use crate::module::library::{auto_season_config_clean, auto_subject_override_apply, auto_subject_title_backfill, known_seasons, update_library};
+use crate::module::library::episode_gap::{auto_episode_gap_detect, backfill_episode_gap, fetch_episode_gap_releases};
+use crate::module::library::episode_offset::auto_episode_offset_infer;
 use crate::module::parser::mikan_parser::{expand_history_episodes, update_rss};
 use crate::module::scrobbler::bangumi::BangumiEpisodeType::MainStory;
 use crate::module::scrobbler::bangumi::{BangumiEpisodeCollection, get_bangumi_episode_collection_status};
-use crate::ui::apps::libraryapp::{AppAnimeEpisode, AppAnimeSeason, AppAnimeSeries, BANGUMI_STATUS_UPDATE, LibraryApp};
+use crate::ui::apps::libraryapp::{AppAnimeEpisode, AppAnimeSeason, AppAnimeSeries, LibraryApp};
+
+/// Seasons of the library with their episodes, grouped by series and sorted by name and season number.
+/// A season not linked to a series yet is shown as a series on its own.
+/// Episodes are shown where they are placed, see `item_placement`, a season merged into another one is not shown on its own.
+/// Missing episodes are shown where an item of their number would be.
+pub(crate) fn read_library_series() -> Vec<AppAnimeSeries> {
+    let series_names: HashMap<i32, String> = read_series_list().into_iter().map(|x| (x.series_id, x.disp_series_name)).collect();
+    let seasons: HashMap<(i32, i32), AnimeSeason> = read_seasons().into_iter()
+        .map(|x| ((x.mikan_subject_id, x.mikan_subgroup_id), x))
+        .collect();
+    build_library_series(&series_names, &seasons, read_all_items(), read_episode_gaps(), |_| true)
+}
+
+/// Series of the library a changed season is shown in, see `read_library_series`,
+/// only their seasons are read again with their items and gaps.
+///
+/// ## Input
+///
+/// shown_series : `HashSet<(i32, String)>`, keys `(series_id, disp_series_name)` of the series shown before the change
+/// changed_seasons : `&HashSet<(i32, i32)>`, seasons whose series, and series their items are placed in, are rebuilt as well
+pub(crate) fn read_library_series_of(mut shown_series: HashSet<(i32, String)>, changed_seasons: &HashSet<(i32, i32)>) -> Vec<AppAnimeSeries> {
+    let series_names: HashMap<i32, String> = read_series_list().into_iter().map(|x| (x.series_id, x.disp_series_name)).collect();
+    let seasons: HashMap<(i32, i32), AnimeSeason> = read_seasons().into_iter()
+        .map(|x| ((x.mikan_subject_id, x.mikan_subgroup_id), x))
+        .collect();
+    let get_season = |subject_id, subgroup_id| seasons.get(&(subject_id, subgroup_id)).cloned();
+    for &(mikan_subject_id, mikan_subgroup_id) in changed_seasons {
+        if let Some(season) = seasons.get(&(mikan_subject_id, mikan_subgroup_id)) {
+            shown_series.insert(shown_series_key(season, &seasons, &series_names));
+        }
+        for item in read_season_items(mikan_subject_id, mikan_subgroup_id) {
+            if let Some(placement) = item_placement(&item, get_season) {
+                shown_series.insert(shown_series_key(&placement, &seasons, &series_names));
+            }
+        }
+    }
+
+    let mut items: HashMap<String, AnimeSeasonItem> = HashMap::new();
+    let mut gaps = Vec::new();
+    for season in seasons.values().filter(|x| shown_series.contains(&shown_series_key(x, &seasons, &series_names))) {
+        let (mikan_subject_id, mikan_subgroup_id) = (season.mikan_subject_id, season.mikan_subgroup_id);
+        for item in read_season_items(mikan_subject_id, mikan_subgroup_id).into_iter().chain(read_placed_items(mikan_subject_id, mikan_subgroup_id)) {
+            items.insert(item.mikan_item_uuid.clone(), item);
+        }
+        gaps.extend(read_season_gaps(mikan_subject_id, mikan_subgroup_id));
+    }
+    build_library_series(&series_names, &seasons, items.into_values().collect(), gaps, |x| shown_series.contains(x))
+}
+
+/// Key `(series_id, disp_series_name)` of the series a season is shown in, that of the season it is merged into if any
+fn shown_series_key(season: &AnimeSeason, seasons: &HashMap<(i32, i32), AnimeSeason>, series_names: &HashMap<i32, String>) -> (i32, String) {
+    let season = season.merged_into().and_then(|x| seasons.get(&x)).unwrap_or(season);
+    match series_names.get(&season.series_id) {
+        Some(name) => (season.series_id, name.clone()),
+        None => (-1, season.disp_series_name.clone()),
+    }
+}
+
+/// Group the given items and gaps by the seasons and series they are shown in, see `read_library_series`
+///
+/// ## Input
+///
+/// shown : `impl Fn(&(i32, String)) -> bool`, whether a series is built, by its key `(series_id, disp_series_name)`
+fn build_library_series(series_names: &HashMap<i32, String>, seasons: &HashMap<(i32, i32), AnimeSeason>, items: Vec<AnimeSeasonItem>, gaps: Vec<EpisodeGap>, shown: impl Fn(&(i32, String)) -> bool) -> Vec<AppAnimeSeries> {
+    // Shown seasons by (mikan_subject_id, mikan_subgroup_id, disp_season_num), so that a split part is shown apart from its season
+    let mut shown_seasons: HashMap<(i32, i32, i32), ((i32, String), AppAnimeSeason)> = HashMap::new();
+    for season in seasons.values() {
+        if season.merged_into().map_or(false, |x| seasons.contains_key(&x)) {
+            continue;
+        }
+        let series_key = shown_series_key(season, seasons, series_names);
+        if !shown(&series_key) {
+            continue;
+        }
+        for (part, split_part) in [(Some(season.clone()), false), (season.split_part(), true)] {
+            if let Some(part) = part {
+                let mut app_anime_season: AppAnimeSeason = part.clone().into();
+                app_anime_season.split_part = split_part;
+                shown_seasons.insert((part.mikan_subject_id, part.mikan_subgroup_id, part.disp_season_num), (series_key.clone(), app_anime_season));
+            }
+        }
+    }
+    for season in seasons.values() {
+        if let Some((mikan_subject_id, mikan_subgroup_id)) = season.merged_into() {
+            if let Some(target) = seasons.get(&(mikan_subject_id, mikan_subgroup_id)) {
+                if let Some((_, app_anime_season)) = shown_seasons.get_mut(&(mikan_subject_id, mikan_subgroup_id, target.disp_season_num)) {
+                    app_anime_season.merged_seasons.push(((season.mikan_subject_id, season.mikan_subgroup_id), season.mikan_subject_name.clone()));
+                }
+            }
+        }
+    }
+    for item in items {
+        let season = match seasons.get(&(item.mikan_subject_id, item.mikan_subgroup_id)) {
+            Some(season) => season,
+            None => continue,
+        };
+        let placement = match item_placement(&item, |subject_id, subgroup_id| seasons.get(&(subject_id, subgroup_id)).cloned()) {
+            Some(placement) => placement,
+            None => continue,
+        };
+        // Bangumi episodes are those of the subject of its own season
+        let bangumi_sort = (item.mikan_parsed_episode_num + season.conf_bangumi_episode_offset).to_string();
+        let mut episode: AppAnimeEpisode = item.into();
+        episode.bangumi_subject_id = season.bangumi_subject_id;
+        episode.bangumi_sort = bangumi_sort;
+        if let Some((_, app_anime_season)) = shown_seasons.get_mut(&(placement.mikan_subject_id, placement.mikan_subgroup_id, placement.disp_season_num)) {
+            app_anime_season.episodes.push(episode);
+        }
+    }
+    for gap in gaps {
+        let placeholder = AnimeSeasonItem {
+            mikan_subject_id: gap.mikan_subject_id,
+            mikan_subgroup_id: gap.mikan_subgroup_id,
+            mikan_parsed_episode_num: gap.mikan_parsed_episode_num,
+            ..Default::default()
+        };
+        if let Some(placement) = item_placement(&placeholder, |subject_id, subgroup_id| seasons.get(&(subject_id, subgroup_id)).cloned()) {
+            if let Some((_, app_anime_season)) = shown_seasons.get_mut(&(placement.mikan_subject_id, placement.mikan_subgroup_id, placement.disp_season_num)) {
+                app_anime_season.gaps.push(gap);
+            }
+        }
+    }
+
+    let mut serieses: HashMap<(i32, String), Vec<AppAnimeSeason>> = HashMap::new();
+    for (series_key, mut app_anime_season) in shown_seasons.into_values() {
+        // sort episodes by disp_episode_num, ascending
+        app_anime_season.episodes.sort_by(|a, b| a.disp_episode_num.cmp(&b.disp_episode_num));
+        app_anime_season.gaps.sort_by(|a, b| a.disp_episode_num.cmp(&b.disp_episode_num));
+        serieses.entry(series_key).or_default().push(app_anime_season);
+    }
+    let mut library = Vec::new();
+    for ((series_id, disp_series_name), mut seasons) in serieses {
+        // sort seasons, ascending
+        seasons.sort_by(|a, b| a.disp_season_num.cmp(&b.disp_season_num));
+        library.push(AppAnimeSeries {
+            series_id,
+            disp_series_name,
+            seasons,
+        });
+    }
+    sort_library_series(&mut library);
+    library
+}
+
+fn sort_library_series(library: &mut [AppAnimeSeries]) {
+    library.sort_by(|a, b| a.disp_series_name.cmp(&b.disp_series_name).then(a.series_id.cmp(&b.series_id)));
+}
+
+/// Replace the series shown involving the changed seasons by their rebuilt version, before and after the change,
+/// so that a season moved, merged or removed leaves its former series as well. See `AppAnimeSeries::involves`.
+/// The Bangumi status of the episodes already shown is kept.
+///
+/// ## Output
+///
+/// Bangumi subjects of the episodes not shown before, whose status is to be fetched : `HashSet<i32>`
+pub(crate) fn merge_library_series(library: &mut Vec<AppAnimeSeries>, rebuilt: Vec<AppAnimeSeries>, changed_seasons: &HashSet<(i32, i32)>) -> HashSet<i32> {
+    let series_key = |series: &AppAnimeSeries| (series.series_id, series.disp_series_name.clone());
+    let changed_series: HashSet<(i32, String)> = library.iter().chain(rebuilt.iter())
+        .filter(|series| changed_seasons.iter().any(|x| series.involves(*x)))
+        .map(series_key)
+        .collect();
+    let shown_episodes: HashMap<String, AppAnimeEpisode> = library.iter()
+        .filter(|series| changed_series.contains(&series_key(series)))
+        .flat_map(|series| series.seasons.iter())
+        .flat_map(|season| season.episodes.iter())
+        .map(|episode| (episode.episode_hash.clone(), episode.clone()))
+        .collect();
+
+    library.retain(|series| !changed_series.contains(&series_key(series)));
+    let mut new_subjects = HashSet::new();
+    for mut series in rebuilt.into_iter().filter(|series| changed_series.contains(&series_key(series))) {
+        for episode in series.seasons.iter_mut().flat_map(|season| season.episodes.iter_mut()) {
+            match shown_episodes.get(&episode.episode_hash) {
+                Some(shown) if shown.bangumi_subject_id == episode.bangumi_subject_id && shown.bangumi_sort == episode.bangumi_sort => {
+                    episode.bangumi_airdate = shown.bangumi_airdate.clone();
+                    episode.bangumi_name = shown.bangumi_name.clone();
+                    episode.bangumi_name_cn = shown.bangumi_name_cn.clone();
+                    episode.bangumi_status = shown.bangumi_status.clone();
+                }
+                _ if episode.bangumi_subject_id > 0 => {
+                    new_subjects.insert(episode.bangumi_subject_id);
+                }
+                _ => {}
+            }
+        }
+        library.push(series);
+    }
+    sort_library_series(library);
+    new_subjects
+}
+
+// Set while an update of the library is to end with the watch status of every subject shown, see `LibraryApp::update_rss`
+static WATCH_STATUS_REFRESH_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Holds off the watch status fetched for the library events until dropped, also when the update panics
+struct PendingWatchStatusRefresh;
+
+impl PendingWatchStatusRefresh {
+    fn start() -> Self {
+        WATCH_STATUS_REFRESH_PENDING.store(true, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Drop for PendingWatchStatusRefresh {
+    fn drop(&mut self) {
+        WATCH_STATUS_REFRESH_PENDING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Apply change events of the library to the library shown
+///
+/// ## Procedure
+///
+/// 1. The state of an episode shown is changed in place, unless the episode leaves the library, see `ItemState::is_active`
+/// 2. The series showing the seasons changed otherwise, or with episodes entering or leaving the library,
+///    are read again from the database without holding the lock, then merged, see `read_library_series_of` and `merge_library_series`
+/// 3. The watch status of the Bangumi subjects updated by the scrobbler, and of the new episodes, is fetched again,
+///    unless an update of the library fetches that of every subject shown once it is done
+fn apply_library_events(library_handle: Arc<RwLock<Vec<AppAnimeSeries>>>, events: Vec<LibraryEvent>) {
+    let mut changed_seasons = HashSet::new();
+    let mut watch_subjects = HashSet::new();
+    let shown_series: HashSet<(i32, String)> = {
+        let mut library = match library_handle.write() {
+            Ok(library) => library,
+            Err(e) => {
+                log::error!("Library lock poisoned: {:?}", e);
+                return;
+            }
+        };
+        for event in events {
+            match event {
+                LibraryEvent::ItemStateChanged { season, mikan_item_uuid, state } => {
+                    let episode = library.iter_mut()
+                        .flat_map(|series| series.seasons.iter_mut())
+                        .flat_map(|season| season.episodes.iter_mut())
+                        .find(|episode| episode.episode_hash == mikan_item_uuid);
+                    match episode {
+                        Some(episode) if state.is_active() => episode.state = state,
+                        _ => {
+                            changed_seasons.insert(season);
+                        }
+                    }
+                }
+                LibraryEvent::WatchStatusChanged { bangumi_subject_id } => {
+                    watch_subjects.insert(bangumi_subject_id);
+                }
+                event => changed_seasons.extend(event.season()),
+            }
+        }
+        library.iter()
+            .filter(|series| changed_seasons.iter().any(|x| series.involves(*x)))
+            .map(|series| (series.series_id, series.disp_series_name.clone()))
+            .collect()
+    };
+
+    if !changed_seasons.is_empty() {
+        log::debug!("Rebuilding the series of seasons {:?}", changed_seasons);
+        let rebuilt = read_library_series_of(shown_series, &changed_seasons);
+        let mut library = match library_handle.write() {
+            Ok(library) => library,
+            Err(e) => {
+                log::error!("Library lock poisoned: {:?}", e);
+                return;
+            }
+        };
+        watch_subjects.extend(merge_library_series(&mut library, rebuilt, &changed_seasons));
+    }
+
+    if !watch_subjects.is_empty() && !WATCH_STATUS_REFRESH_PENDING.load(Ordering::SeqCst) {
+        LibraryApp::fetch_bangumi_watch_status_of(library_handle, watch_subjects.into_iter().collect());
+    }
+}
 
 impl LibraryApp {
     pub fn update_rss(&mut self) {
@@ -22,62 +307,46 @@ impl LibraryApp {
 
             let library_handle = library.clone();
 
-            let library = library_handle.write();
-            if let Err(e) = library {
-                log::error!("Library lock poisoned: {:?}", e);
-                return;
-            }
-            log::debug!("Library locked successfully.");
-            let mut library = library.unwrap();
+            let task = Self::lock_task();
             log::info!("Start updating rss");
+            // The watch status of the episodes added meanwhile is fetched with every other one at the end
+            let pending_watch_status = PendingWatchStatusRefresh::start();
 
-            // Fetch RSS feeds
+            // Refresh the offline id cross-reference before parsing new subjects
+            if let Err(e) = refresh_anime_xref(false) {
+                log::warn!("Failed to refresh xref datasets: {}", e);
+            }
+            // Removed seasons subscribed again are added again
+            restore_resubscribed_seasons();
+            // Fetch RSS feeds, those of the seasons no longer polled are retired
+            retire_finished_feeds();
+            let known = known_seasons();
             let rss_list = crate::module::config::CONFIG.read().unwrap().rss_config.list.clone();
             for rss in rss_list {
                 if rss.active {
                     let items = update_rss(&*rss.url).unwrap();
-                    // By default, only incremental, not expanding the history
-                    let items = expand_history_episodes(items);
+                    // Only incremental, the history is expanded once for the seasons new to the library
+                    let (known_items, new_items): (Vec<_>, Vec<_>) = items.into_iter()
+                        .partition(|x| known.contains(&(x.mikan_subject_id, x.mikan_subgroup_id)));
+                    let mut items = expand_history_episodes(new_items);
+                    items.extend(known_items);
                     update_library(&items);
                 }
             }
 
             // Rearrange the media library
+            auto_subject_override_apply();
+            auto_subject_title_backfill();
             auto_season_config_clean();
+            auto_episode_offset_infer();
+            drop(task);
 
-            *library = Vec::new();
-            // Output media library
-            let seasons = read_seasons();
-            // Season arrange by series name
-            let serieses: HashMap<String, Vec<AnimeSeason>> = seasons.into_iter().fold(HashMap::new(), |mut acc, season| {
-                let series_name = season.disp_series_name.clone();
-                if acc.contains_key(&series_name) {
-                    acc.get_mut(&series_name).unwrap().push(season);
-                } else {
-                    acc.insert(series_name, vec![season]);
-                }
-                acc
-            });
-            for (series, seasons) in serieses {
-                let mut series = AppAnimeSeries {
-                    disp_series_name: series,
-                    seasons: Vec::new(),
-                };
-                for season in &seasons {
-                    let season_episodes = read_season_items(season.mikan_subject_id, season.mikan_subgroup_id);
-                    let mut app_anime_season: AppAnimeSeason = <AnimeSeason as Clone>::clone(&(*season)).into();
-                    for episode in season_episodes {
-                        app_anime_season.episodes.push(episode.into());
-                    }
-                    // sort episodes by disp_episode_num, ascending
-                    app_anime_season.episodes.sort_by(|a, b| a.disp_episode_num.cmp(&b.disp_episode_num));
-                    series.seasons.push(app_anime_season);
-                }
-                // sort seasons, ascending
-                series.seasons.sort_by(|a, b| a.disp_season_num.cmp(&b.disp_season_num));
-                (*library).push(series);
-            }
-            library.sort_by(|a, b| a.disp_series_name.cmp(&b.disp_series_name));
+            // Episodes and missing releases are looked up without holding off the other library tasks
+            let bangumi_episodes = fetch_season_bangumi_episodes();
+            fetch_episode_gap_releases(&bangumi_episodes);
+            let task = Self::lock_task();
+            auto_episode_gap_detect(&bangumi_episodes);
+            auto_season_status_update(&bangumi_episodes);
 
             // for season in read_seasons() {
             //     println!("Season: {:?}", season.mikan_subject_name);
@@ -96,13 +365,126 @@ impl LibraryApp {
             clean_empty_folders("".to_string());
 
             log::info!("RSS updated successfully.");
-            drop(library);
+            drop(task);
 
+            drop(pending_watch_status);
             if Self::fetch_bangumi_watch_status(library_handle) { return; }
 
         });
     }
 
+    /// Merge, split or move in the library, then move and rename the files in the downloader
+    pub fn arrange_library(&mut self, arrangement: Arrangement) {
+
+        log::info!("Arrange library: {:?}", arrangement);
+
+        thread::spawn(move || {
+
+            let _task = Self::lock_task();
+
+            let library_items = match arrange_library(&arrangement) {
+                Ok(library_items) => library_items,
+                Err(e) => {
+                    log::error!("Failed to arrange library: {}", e);
+                    return;
+                }
+            };
+
+            // Save paths and file names follow the seasons the items are shown in
+            if let Err(e) = download_items(&library_items, true) {
+                log::error!("Failed to move torrents: {:?}", e);
+            }
+            if let Err(e) = rename_torrents_files(&library_items) {
+                log::error!("Failed to rename torrent files: {:?}", e);
+            }
+            clean_empty_folders("".to_string());
+        });
+    }
+
+    /// Remove a season from the library, the items shown in it elsewhere are moved and renamed in the downloader
+    pub fn remove_season(&mut self, season: (i32, i32), cleanup: SeasonCleanup, deactivate_feed: bool) {
+
+        log::info!("Remove season {}-{}: {:?}", season.0, season.1, cleanup);
+
+        thread::spawn(move || {
+
+            let _task = Self::lock_task();
+
+            let library_items = match remove_season(season.0, season.1, cleanup, deactivate_feed) {
+                Ok(library_items) => library_items,
+                Err(e) => {
+                    log::error!("Failed to remove season: {}", e);
+                    return;
+                }
+            };
+
+            if let Err(e) = download_items(&library_items, true) {
+                log::error!("Failed to move torrents: {:?}", e);
+            }
+            if let Err(e) = rename_torrents_files(&library_items) {
+                log::error!("Failed to rename torrent files: {:?}", e);
+            }
+            clean_empty_folders("".to_string());
+        });
+    }
+
+    /// Archive a season or bring it back to the library, see `archive_season`
+    pub fn archive_season(&mut self, season: (i32, i32), archived: bool) {
+
+        log::info!("Archive season {}-{}: {}", season.0, season.1, archived);
+
+        thread::spawn(move || {
+
+            let _task = Self::lock_task();
+
+            if let Err(e) = archive_season(season.0, season.1, archived) {
+                log::error!("Failed to archive season: {}", e);
+            }
+        });
+    }
+
+    /// Fill a missing episode with the release of another subgroup, then download it
+    pub fn backfill_episode_gap(&mut self, gap: EpisodeGap) {
+
+        log::info!("Backfill episode {} of season {}-{} with {}", gap.disp_episode_num, gap.mikan_subject_id, gap.mikan_subgroup_id, gap.replacement_title);
+
+        thread::spawn(move || {
+
+            let _task = Self::lock_task();
+
+            let item = match backfill_episode_gap(gap.mikan_subject_id, gap.mikan_subgroup_id, gap.bangumi_episode_sort) {
+                Ok(item) => item,
+                Err(e) => {
+                    log::error!("Failed to backfill episode gap: {}", e);
+                    return;
+                }
+            };
+
+            if let Err(e) = download_items(&vec![item], true) {
+                log::error!("Failed to download backfilled episode: {:?}", e);
+            }
+        });
+    }
+
+    /// Import the shows already on disk in the background, see `match_local_library`
+    pub fn import_local_library(&mut self) {
+
+        log::info!("Import local library");
+
+        thread::spawn(move || {
+
+            // Subjects are looked up without holding off the other library tasks
+            let (matched, mut report) = disk_import::match_local_library();
+
+            let _task = Self::lock_task();
+
+            disk_import::import_matched_seasons(matched, &mut report);
+            if !report.unmatched.is_empty() {
+                log::warn!("Folders not matched to a Bangumi subject: {:?}", report.unmatched);
+            }
+        });
+    }
+
     pub fn fetch_library(&mut self) {
 
         let library = self.library.clone();
@@ -123,39 +505,8 @@ impl LibraryApp {
 
             log::info!("Start updating library");
 
-            *library = Vec::new();
             // Output media library
-            let seasons = read_seasons();
-            // Season arrange by series name
-            let serieses: HashMap<String, Vec<AnimeSeason>> = seasons.into_iter().fold(HashMap::new(), |mut acc, season| {
-                let series_name = season.disp_series_name.clone();
-                if acc.contains_key(&series_name) {
-                    acc.get_mut(&series_name).unwrap().push(season);
-                } else {
-                    acc.insert(series_name, vec![season]);
-                }
-                acc
-            });
-            for (series, seasons) in serieses {
-                let mut series = AppAnimeSeries {
-                    disp_series_name: series,
-                    seasons: Vec::new(),
-                };
-                for season in seasons {
-                    let season_episodes = read_season_items(season.mikan_subject_id, season.mikan_subgroup_id);
-                    let mut app_anime_season: AppAnimeSeason = season.into();
-                    for episode in season_episodes {
-                        app_anime_season.episodes.push(episode.into());
-                    }
-                    // sort episodes by disp_episode_num, ascending
-                    app_anime_season.episodes.sort_by(|a, b| a.disp_episode_num.cmp(&b.disp_episode_num));
-                    series.seasons.push(app_anime_season);
-                }
-                // sort seasons, ascending
-                series.seasons.sort_by(|a, b| a.disp_season_num.cmp(&b.disp_season_num));
-                (*library).push(series);
-            }
-            library.sort_by(|a, b| a.disp_series_name.cmp(&b.disp_series_name));
+            *library = read_library_series();
 
             log::debug!("Library updated successfully.");
             drop(library);
@@ -164,6 +515,21 @@ impl LibraryApp {
         });
     }
 
+    /// Keep the library shown up to date with the change events of the library as they come, see `apply_library_events`
+    pub fn listen_library_events(&self, ctx: egui::Context) {
+        let library = self.library.clone();
+        let events = subscribe_library_events();
+        thread::spawn(move || {
+            while let Ok(event) = events.recv() {
+                // The events of an operation are published together, they are applied at once
+                let mut batch = vec![event];
+                batch.extend(events.try_iter());
+                apply_library_events(library.clone(), batch);
+                ctx.request_repaint();
+            }
+        });
+    }
+
     pub fn fetch_bangumi_watch_status(library_handle: Arc<RwLock<Vec<AppAnimeSeries>>>) -> bool {
         // self.fetch_bangumi_watch_status();
 
@@ -175,14 +541,22 @@ impl LibraryApp {
         log::debug!("(fetch_bangumi_watch_status) Library locked successfully.");
         let mut library = library.unwrap();
 
-        let subject_ids: Vec<i32> = library.iter().map(|series| {
-            series.seasons.iter().map(|season| {
-                season.bangumi_subject_id
-            }).collect::<Vec<i32>>()
-        }).flatten().collect();
+        // Episodes of a season may come from several subjects, see `read_library_series`
+        let mut subject_ids: Vec<i32> = library.iter()
+            .flat_map(|series| series.seasons.iter())
+            .flat_map(|season| season.episodes.iter().map(|episode| episode.bangumi_subject_id))
+            .collect();
+        subject_ids.sort();
+        subject_ids.dedup();
 
         drop(library);
 
+        Self::fetch_bangumi_watch_status_of(library_handle, subject_ids)
+    }
+
+    /// Fetch the watch status of the episodes of some Bangumi subjects
+    pub fn fetch_bangumi_watch_status_of(library_handle: Arc<RwLock<Vec<AppAnimeSeries>>>, subject_ids: Vec<i32>) -> bool {
+
         // Spawn a thread for each subject ID,
         // getting Episode status using get_bangumi_episode_collection_status
         // result passed by mpsc channel, for each result, once received, update the library immedialy (TODO)
@@ -201,6 +575,8 @@ impl LibraryApp {
                 tx.send((subject_id, status)).unwrap();
             })
         }).collect();
+        // The results end once every thread is done, also when none succeeds
+        drop(tx);
 
         let mut succ_count = 0;
         for handle in handles {
@@ -231,20 +607,16 @@ impl LibraryApp {
             // Update library for a SUBJECT
             for series in library.iter_mut() {
                 for season in series.seasons.iter_mut() {
-                    if season.bangumi_subject_id == subject_id {
-                        // Match a SEASON in the labrary with subject_id
-                        for episode in season.episodes.iter_mut() {
-                            // Match an EPISODE's sort in the SEASON with disp_episode_num - season's tmdb_episode_offset + bangumi_episode_offset
-                            let episode_sort = (episode.disp_episode_num - season.conf_tmdb_episode_offset + season.conf_bangumi_episode_offset).to_string();
-                            for s in status.iter() {
-                                if s.sort == episode_sort && s.ep_type == episode.bangumi_ep_type {
-                                    // episode.bangumi_sort = s.sort.clone();
-                                    episode.bangumi_airdate = s.airdate.clone();
-                                    episode.bangumi_name = s.name.clone();
-                                    episode.bangumi_name_cn = s.name_cn.clone();
-                                    // episode.bangumi_ep_type = s.ep_type.clone();
-                                    episode.bangumi_status = s.status.clone();
-                                }
+                    // Match the EPISODEs in the library with subject_id, by the sort of their own season
+                    for episode in season.episodes.iter_mut().filter(|x| x.bangumi_subject_id == subject_id) {
+                        for s in status.iter() {
+                            if s.sort == episode.bangumi_sort && s.ep_type == episode.bangumi_ep_type {
+                                // episode.bangumi_sort = s.sort.clone();
+                                episode.bangumi_airdate = s.airdate.clone();
+                                episode.bangumi_name = s.name.clone();
+                                episode.bangumi_name_cn = s.name_cn.clone();
+                                // episode.bangumi_ep_type = s.ep_type.clone();
+                                episode.bangumi_status = s.status.clone();
                             }
                         }
                     }
@@ -259,10 +631,6 @@ impl LibraryApp {
         log::debug!("Bangumi status fetched successfully.");
         drop(library);
 
-        let mut flag_handle = BANGUMI_STATUS_UPDATE.write().unwrap();
-        *flag_handle = false;
-        drop(flag_handle);
-
         false
     }
 }
\ No newline at end of file
@@ -1,3 +1,3 @@
 pub mod library;
 pub mod season_conf;
-
+pub mod settings;
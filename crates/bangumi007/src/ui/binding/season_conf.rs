@@ -1,11 +1,12 @@
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
 use std::thread;
-use crate::module::database::library::{AnimeSeason, read_all_items, read_season_items, read_seasons, set_season_bangumi_episode_offset, set_season_conf_season_num, set_season_disp_season_num, set_season_tmdb_episode_offset};
-use crate::module::downloader::qbittorrent::{clean_empty_folders, download_items, rename_torrents_files};
+use crate::module::database::cache::tmdb::set_tmdb_series_choice;
+use crate::module::database::subject_override::{MikanSubjectOverride, set_subject_override};
+use crate::module::database::library::{read_season_items, read_seasons};
+use crate::module::downloader::{clean_empty_folders, download_items, rename_torrents_files};
 use crate::module::library::{auto_season_config_clean, update_library};
+use crate::module::library::media_library::{apply_season_conf, refresh_subject_metadata};
 use crate::module::parser::mikan_parser::{expand_history_episodes, update_rss};
-use crate::ui::apps::libraryapp::{AppAnimeSeason, AppAnimeSeries, LibraryApp};
+use crate::ui::apps::libraryapp::LibraryApp;
 use crate::ui::apps::season_conf_dialog_window::SeasonConfDialogWindow;
 
 #[derive(Debug, Clone, Default)]
@@ -19,87 +20,81 @@ pub struct SeasonConf {
     pub ep_num_max: i32,
     pub conf_tmdb_ep_offset: i32,
     pub conf_bangumi_ep_offset: i32,
+    pub subject_override: Option<MikanSubjectOverride>,     // Some if changed
 }
 
-pub fn update_conf(conf: SeasonConf, library: Arc<RwLock<Vec<AppAnimeSeries>>>) {
-
-    let library_handle = library.clone();
+pub fn update_conf(conf: SeasonConf) {
 
     log::info!("Update season conf");
 
-    let library = library_handle.clone();
-
     let handle = thread::spawn(move || {
 
-        let library = library.write();
-
-        if let Err(e) = library {
-            log::error!("Library lock poisoned: {:?}", e);
-            return;
-        }
-        log::debug!("Library locked successfully.");
-
-        let mut library = library.unwrap();
+        let task = LibraryApp::lock_task();
 
         log::info!("Start updating season conf");
 
-        if conf.conf_season_changed {
-            set_season_conf_season_num(conf.subject_id, conf.subgroup_id, conf.conf_season);
-        }
-        else {
-            set_season_conf_season_num(conf.subject_id, conf.subgroup_id, -1);
-        }
-        set_season_disp_season_num(conf.subject_id, conf.subgroup_id, conf.conf_season);
-
-        set_season_tmdb_episode_offset(conf.subject_id, conf.subgroup_id, conf.conf_tmdb_ep_offset);
-        set_season_bangumi_episode_offset(conf.subject_id, conf.subgroup_id, conf.conf_bangumi_ep_offset);
-
-        *library = Vec::new();
-        // Output media library
-        let seasons = read_seasons();
-        // Season arrange by series name
-        let serieses: HashMap<String, Vec<AnimeSeason>> = seasons.into_iter().fold(HashMap::new(), |mut acc, season| {
-            let series_name = season.disp_series_name.clone();
-            if acc.contains_key(&series_name) {
-                acc.get_mut(&series_name).unwrap().push(season);
-            } else {
-                acc.insert(series_name, vec![season]);
-            }
-            acc
-        });
-        for (series, seasons) in serieses {
-            let mut series = AppAnimeSeries {
-                disp_series_name: series,
-                seasons: Vec::new(),
-            };
-            for season in seasons {
-                let season_episodes = read_season_items(season.mikan_subject_id, season.mikan_subgroup_id);
-                let mut app_anime_season: AppAnimeSeason = season.into();
-                for episode in season_episodes {
-                    app_anime_season.episodes.push(episode.into());
-                }
-                // sort episodes by disp_episode_num, ascending
-                app_anime_season.episodes.sort_by(|a, b| a.disp_episode_num.cmp(&b.disp_episode_num));
-                series.seasons.push(app_anime_season);
+        // Metadata of the whole subject is parsed again with the override
+        if let Some(subject_override) = &conf.subject_override {
+            if let Err(e) = set_subject_override(subject_override) {
+                log::error!("Failed to save subject override: {:?}", e);
+            } else if let Err(e) = refresh_subject_metadata(conf.subject_id) {
+                log::error!("Failed to refresh subject metadata: {:?}", e);
             }
-            // sort seasons, ascending
-            series.seasons.sort_by(|a, b| a.disp_season_num.cmp(&b.disp_season_num));
-            (*library).push(series);
         }
-        library.sort_by(|a, b| a.disp_series_name.cmp(&b.disp_series_name));
 
-        // Add torrents to downloader
-        let library_items = read_season_items(conf.subject_id, conf.subgroup_id);
+        let conf_season_num = if conf.conf_season_changed { conf.conf_season } else { -1 };
+        let result = apply_season_conf(conf.subject_id, conf.subgroup_id, conf_season_num, conf.conf_season,
+                                       conf.conf_tmdb_ep_offset, conf.conf_bangumi_ep_offset);
+        if let Err(e) = result {
+            log::error!("Failed to save season conf: {:?}", e);
+        }
+
+        // Add torrents to downloader, the override renames every subgroup of the subject
+        let library_items = match conf.subject_override {
+            Some(_) => read_seasons().iter()
+                .filter(|s| s.mikan_subject_id == conf.subject_id)
+                .flat_map(|s| read_season_items(s.mikan_subject_id, s.mikan_subgroup_id))
+                .collect(),
+            None => read_season_items(conf.subject_id, conf.subgroup_id),
+        };
         download_items(&library_items, true).unwrap();
         rename_torrents_files(&library_items).unwrap();
         clean_empty_folders("".to_string());
 
         log::info!("RSS updated successfully.");
-        drop(library);
-
-        if LibraryApp::fetch_bangumi_watch_status(library_handle) { return; }
+        drop(task);
 
     });
 
 }
 
+
+pub fn choose_tmdb_series(subject_id: i32, bangumi_subject_id: i32, tmdb_series_id: i64) {
+
+    log::info!("Choose TMDB series {} for bangumi subject {}", tmdb_series_id, bangumi_subject_id);
+
+    thread::spawn(move || {
+
+        if let Err(e) = set_tmdb_series_choice(bangumi_subject_id, tmdb_series_id) {
+            log::error!("Failed to save TMDB series choice: {:?}", e);
+            return;
+        }
+
+        let _task = LibraryApp::lock_task();
+        if let Err(e) = refresh_subject_metadata(subject_id) {
+            log::error!("Failed to refresh subject metadata: {:?}", e);
+        }
+
+        // Files are renamed by series name
+        for season in read_seasons().iter().filter(|s| s.mikan_subject_id == subject_id) {
+            let library_items = read_season_items(season.mikan_subject_id, season.mikan_subgroup_id);
+            if let Err(e) = download_items(&library_items, true) {
+                log::error!("Failed to add torrents: {:?}", e);
+            }
+            if let Err(e) = rename_torrents_files(&library_items) {
+                log::error!("Failed to rename torrent files: {:?}", e);
+            }
+        }
+        clean_empty_folders("".to_string());
+    });
+}
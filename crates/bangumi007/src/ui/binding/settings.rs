@@ -0,0 +1,35 @@
+use std::thread;
+
+use crate::module::config::{CONFIG, DisplayConfig};
+use crate::module::database::library::read_season_items;
+use crate::module::downloader::{clean_empty_folders, rename_torrents_files};
+use crate::module::library::apply_display_language;
+use crate::ui::apps::libraryapp::LibraryApp;
+
+pub fn update_display_config(display_config: DisplayConfig) {
+
+    log::info!("Update display config: {:?}", display_config);
+
+    thread::spawn(move || {
+
+        {
+            let mut config = CONFIG.write().unwrap();
+            config.display_config = display_config;
+            config.save();
+        }
+
+        let _task = LibraryApp::lock_task();
+        let changed = apply_display_language();
+
+        // Files are named by the display names
+        for season in changed.iter() {
+            let library_items = read_season_items(season.mikan_subject_id, season.mikan_subgroup_id);
+            if let Err(e) = rename_torrents_files(&library_items) {
+                log::error!("Failed to rename torrent files: {:?}", e);
+            }
+        }
+        if !changed.is_empty() {
+            clean_empty_folders("".to_string());
+        }
+    });
+}
@@ -12,10 +12,12 @@ use eframe::egui::{Align, ecolor};
 
 use crate::module::core::init::run_init;
 use crate::ui::mainapp::egui::RichText;
-use crate::ui::apps::libraryapp::{BANGUMI_STATUS_UPDATE, LibraryApp};
+use crate::ui::apps::activityapp::ActivityApp;
+use crate::ui::apps::libraryapp::LibraryApp;
 use crate::ui::apps::logapp::LogApp;
 use crate::ui::apps::panel::Panel;
 use crate::ui::apps::panel::Panel::Library;
+use crate::ui::apps::searchapp::SearchApp;
 use crate::ui::apps::season_conf_dialog_window::SeasonConfDialogWindow;
 use crate::ui::apps::settingsapp::SettingsApp;
 
@@ -76,6 +78,7 @@ impl MainApp {
         visuals.override_text_color = Some(ecolor::Color32::from_rgba_premultiplied(220, 220, 220, 255));
         &cc.egui_ctx.set_visuals(visuals);
         let mut app = Self::default();
+        app.library_app.listen_library_events(cc.egui_ctx.clone());
         app.library_app.fetch_library();
         app
     }
@@ -87,9 +90,12 @@ impl Default for MainApp {
         Self {
             library_app: LibraryApp::default(),
             log_app: LogApp::default(),
+            activity_app: ActivityApp::default(),
+            search_app: SearchApp::default(),
             settings_app: SettingsApp::default(),
             open_panel: Panel::default(),
             season_conf_dialog_window: Rc::new(RefCell::new(SeasonConfDialogWindow::new())),
+            jump_to_season: None,
         }
     }
 }
@@ -101,8 +107,11 @@ pub struct MainApp {
     pub open_panel: Panel,
     pub library_app: LibraryApp,
     pub log_app: LogApp,
+    pub activity_app: ActivityApp,
+    pub search_app: SearchApp,
     pub settings_app: SettingsApp,
     pub season_conf_dialog_window: Rc<RefCell<SeasonConfDialogWindow>>,
+    pub jump_to_season: Option<(i32, i32)>,     // season to scroll to in the library
 }
 
 
@@ -118,11 +127,14 @@ impl eframe::App for MainApp {
                 ui.add_space(5.0);
                 ui.selectable_value(&mut self.open_panel, Panel::Log, RichText::new("RSS").size(14.0));
                 ui.selectable_value(&mut self.open_panel, Panel::Log, RichText::new("日志").size(14.0));
+                if ui.selectable_value(&mut self.open_panel, Panel::Activity, RichText::new("动态").size(14.0)).clicked() {
+                    self.activity_app.reload();
+                }
                 ui.selectable_value(&mut self.open_panel, Panel::Settings, RichText::new("设置").size(14.0));
                 ui.with_layout(egui::Layout::right_to_left(Align::RIGHT), |ui| {
                     ui.add_space(5.0);
                     ui.horizontal_centered(|ui| {
-                        if self.library_app.library.try_read().is_ok() {
+                        if !LibraryApp::is_busy() {
                             let mut refresh_rss = ui.button(RichText::new("更新订阅").size(13.0));
                             if refresh_rss.clicked() {
                                 self.library_app.update_rss();
@@ -133,8 +145,16 @@ impl eframe::App for MainApp {
                         }
                     });
                     ui.add_space(5.0);
+                    let search = ui.add(egui::TextEdit::singleline(&mut self.search_app.query).hint_text("搜索").desired_width(120.));
+                    if search.changed() || (search.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter))) {
+                        if !self.search_app.query.trim().is_empty() {
+                            self.open_panel = Panel::Search;
+                            self.search_app.reload();
+                        }
+                    }
+                    ui.add_space(5.0);
                     ui.horizontal_centered(|ui| {
-                        if self.library_app.library.try_read().is_err() {
+                        if LibraryApp::is_busy() {
                             ui.spinner();
                         }
                     });
@@ -149,13 +169,22 @@ impl eframe::App for MainApp {
                 ui.add_space(3.0);
                 match self.open_panel {
                     Panel::Library => {
-                        self.library_app.ui(ui, self.season_conf_dialog_window.clone());
+                        self.library_app.ui(ui, self.season_conf_dialog_window.clone(), &mut self.jump_to_season);
                     }
                     Panel::Log => {
                         self.log_app.ui(ui);
                     }
+                    Panel::Activity => {
+                        self.activity_app.ui(ui, self.season_conf_dialog_window.clone());
+                    }
+                    Panel::Search => {
+                        if let Some(season) = self.search_app.ui(ui) {
+                            self.jump_to_season = Some(season);
+                            self.open_panel = Panel::Library;
+                        }
+                    }
                     Panel::Settings => {
-                        self.settings_app.ui(ui);
+                        self.settings_app.ui(ui, self.library_app.library.clone());
                     }
                 }
                 ui.add_space(3.0);
@@ -177,15 +206,6 @@ impl eframe::App for MainApp {
             season_conf_dialog_window.show(ctx, series);
         }
 
-        let flag_handle = BANGUMI_STATUS_UPDATE.read().unwrap();
-        if (*flag_handle) {
-            drop(flag_handle);
-
-            let library_handle = self.library_app.library.clone();
-            LibraryApp::fetch_bangumi_watch_status(library_handle);
-
-        }
-
 
     }
 }
\ No newline at end of file